@@ -0,0 +1,183 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::{UPGRADE, USER_AGENT};
+use hyper::{Method, Request, StatusCode};
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::{AccessLog, AccessLogSink};
+
+/// Writes an entry per request with only the fields [`AccessLog`] enables.
+#[derive(Debug)]
+pub(crate) struct AccessLogger {
+    settings: AccessLog,
+    file: Option<Mutex<File>>,
+}
+
+/// What is known about a request when it arrives, to be logged once it is answered.
+#[derive(Debug)]
+pub(crate) struct Pending {
+    method: Method,
+    path: String,
+    client_addr: Option<SocketAddr>,
+    user_agent: Option<String>,
+    started: Instant,
+}
+
+impl AccessLogger {
+    pub(crate) fn open(settings: AccessLog) -> io::Result<Self> {
+        let file = match &settings.sink {
+            AccessLogSink::Tracing => None,
+            AccessLogSink::JsonFile(path) =>
+                Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        };
+        Ok(Self { settings, file })
+    }
+
+    pub(crate) fn start<B>(&self, req: &Request<B>, client_addr: Option<SocketAddr>) -> Pending {
+        let path = if self.settings.full_path {
+            req.uri().path().to_owned()
+        } else {
+            path_class(req).to_owned()
+        };
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .filter(|_| self.settings.user_agent)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+        Pending {
+            method: req.method().clone(),
+            path,
+            client_addr: client_addr.filter(|_| self.settings.client_addr),
+            user_agent,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn finish(&self, pending: Pending, status: StatusCode) {
+        let latency_ms = pending.started.elapsed().as_millis() as u64;
+        let file = match &self.file {
+            Some(file) => file,
+            None => {
+                info!(
+                    target: "ohttp_relay::access",
+                    method = %pending.method,
+                    path = %pending.path,
+                    status = status.as_u16(),
+                    latency_ms,
+                    client_addr = pending.client_addr.map(tracing::field::display),
+                    user_agent = pending.user_agent.as_deref(),
+                    "request"
+                );
+                return;
+            }
+        };
+        let line = json_line(&pending, status, latency_ms);
+        if let Err(e) = file.lock().expect("access log poisoned").write_all(line.as_bytes()) {
+            error!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// A coarse description of what was requested that never includes a gateway named in the path.
+pub(crate) fn path_class<B>(req: &Request<B>) -> &'static str {
+    match (req.method(), req.uri().path()) {
+        (&Method::OPTIONS, _) => "preflight",
+        (&Method::CONNECT, _) => "bootstrap",
+        (&Method::GET, _) if req.headers().contains_key(UPGRADE) => "bootstrap",
+        (_, "/health" | "/ready") => "health",
+        (_, "/ohttp-keys" | "/.well-known/ohttp-gateway") => "ohttp-keys",
+        (_, "/.well-known/ohttp-relay") => "capabilities",
+        (_, path) if path.starts_with("/admin/") => "admin",
+        (&Method::POST, "/") => "relay",
+        (&Method::POST, _) => "relay-path",
+        _ => "other",
+    }
+}
+
+fn json_line(pending: &Pending, status: StatusCode, latency_ms: u64) -> String {
+    let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    let mut line = format!("{{\"timestamp_ms\":{},\"method\":", timestamp_ms);
+    push_json_str(&mut line, pending.method.as_str());
+    line.push_str(",\"path\":");
+    push_json_str(&mut line, &pending.path);
+    let _ = write!(line, ",\"status\":{},\"latency_ms\":{}", status.as_u16(), latency_ms);
+    if let Some(client_addr) = pending.client_addr {
+        let _ = write!(line, ",\"client_addr\":\"{}\"", client_addr);
+    }
+    if let Some(user_agent) = &pending.user_agent {
+        line.push_str(",\"user_agent\":");
+        push_json_str(&mut line, user_agent);
+    }
+    line.push_str("}\n");
+    line
+}
+
+pub(crate) fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(method: Method, path: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(USER_AGENT, "agent \"x\"\t")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn only_enabled_fields_logged() {
+        let client_addr = Some(SocketAddr::from(([192, 0, 2, 1], 4000)));
+        let logger = AccessLogger::open(AccessLog::default()).unwrap();
+        let req = request(Method::POST, "/https://gateway.example/");
+        let pending = logger.start(&req, client_addr);
+        let line = json_line(&pending, StatusCode::OK, 3);
+        assert!(
+            line.contains(r#""method":"POST","path":"relay-path","status":200,"latency_ms":3}"#)
+        );
+        assert!(!line.contains("192.0.2.1") && !line.contains("gateway.example"));
+
+        let settings = AccessLog {
+            client_addr: true,
+            user_agent: true,
+            full_path: true,
+            ..AccessLog::default()
+        };
+        let logger = AccessLogger::open(settings).unwrap();
+        let pending = logger.start(&req, client_addr);
+        let line = json_line(&pending, StatusCode::OK, 3);
+        assert!(line.contains(r#""path":"/https://gateway.example/""#));
+        assert!(line.contains(r#""client_addr":"192.0.2.1:4000""#));
+        assert!(line.contains(r#""user_agent":"agent \"x\"\u0009""#));
+    }
+
+    #[test]
+    fn paths_classified() {
+        assert_eq!(path_class(&request(Method::POST, "/")), "relay");
+        assert_eq!(path_class(&request(Method::GET, "/ready")), "health");
+        assert_eq!(path_class(&request(Method::GET, "/admin/inflight")), "admin");
+        assert_eq!(path_class(&request(Method::OPTIONS, "/")), "preflight");
+        assert_eq!(path_class(&request(Method::GET, "/anything")), "other");
+    }
+}
@@ -0,0 +1,77 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::StreamExt;
+use rustls::ServerConfig;
+use rustls_acme::acme::{ACME_TLS_ALPN_NAME, LETS_ENCRYPT_PRODUCTION_DIRECTORY};
+use rustls_acme::caches::DirCache;
+use rustls_acme::{AcmeConfig, AcmeState};
+use tracing::{error, info};
+
+/// Certificates for the relay's TCP listeners, obtained and renewed from an ACME CA such as
+/// Let's Encrypt. The TLS-ALPN-01 challenge is answered on the relay's own port, so the CA must
+/// reach it on port 443 of each domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acme {
+    /// The public hostnames the certificate covers.
+    pub domains: Vec<String>,
+    /// Contact URLs for the account, e.g. `mailto:admin@example.com`.
+    pub contact: Vec<String>,
+    /// Where the account key and certificates are kept, so restarts reuse them.
+    pub cache_dir: PathBuf,
+    /// The CA's directory URL, e.g. Let's Encrypt's staging directory while testing.
+    pub directory: String,
+}
+
+impl Acme {
+    /// Certificates for `domains` from Let's Encrypt, with their state kept in `cache_dir`.
+    pub fn new(domains: Vec<String>, cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            domains,
+            contact: Vec::new(),
+            cache_dir: cache_dir.into(),
+            directory: LETS_ENCRYPT_PRODUCTION_DIRECTORY.to_owned(),
+        }
+    }
+}
+
+/// A TLS configuration serving the certificates `acme` obtains, offering HTTP/2 and HTTP/1.1
+/// with ALPN, along with the future obtaining and renewing them. Certificates are only served
+/// while it is polled.
+pub(crate) fn start(acme: &Acme) -> (Arc<ServerConfig>, impl Future<Output = Infallible>) {
+    let state = AcmeConfig::new(&acme.domains)
+        .contact(&acme.contact)
+        .directory(&acme.directory)
+        .cache(DirCache::new(acme.cache_dir.clone()))
+        .state();
+    let mut config =
+        ServerConfig::builder().with_no_client_auth().with_cert_resolver(state.resolver());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN_NAME.to_vec()];
+    (Arc::new(config), renew(state))
+}
+
+async fn renew(mut state: AcmeState<std::io::Error>) -> Infallible {
+    loop {
+        // The state retries failed orders with backoff on its own.
+        match state.next().await {
+            Some(Ok(event)) => info!("ACME: {:?}", event),
+            Some(Err(e)) => error!("ACME certificate renewal failed: {:?}", e),
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn challenges_are_answered_on_the_tls_port() {
+        let acme = Acme::new(vec!["relay.example".to_owned()], "acme");
+        let (config, _renew) = start(&acme);
+        assert!(config.alpn_protocols.iter().any(|protocol| protocol == ACME_TLS_ALPN_NAME));
+        assert_eq!(config.alpn_protocols[0], b"h2");
+    }
+}
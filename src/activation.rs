@@ -0,0 +1,78 @@
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+
+/// The first file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket inherited from the process that started the relay.
+#[derive(Debug)]
+pub(crate) enum Inherited {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Inherited {
+    /// Take ownership of the listening socket `fd`, whether TCP or unix.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open listening socket that nothing else in the process uses or closes.
+    pub(crate) unsafe fn from_fd(fd: RawFd) -> std::io::Result<Self> {
+        let tcp = std::net::TcpListener::from_raw_fd(fd);
+        // Only a TCP socket has an IP address.
+        let inherited = if tcp.local_addr().is_ok() {
+            Self::Tcp(tcp)
+        } else {
+            Self::Unix(std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()))
+        };
+        match &inherited {
+            Self::Tcp(listener) => listener.set_nonblocking(true)?,
+            Self::Unix(listener) => listener.set_nonblocking(true)?,
+        }
+        Ok(inherited)
+    }
+}
+
+/// The socket systemd passed to this process, following `sd_listen_fds(3)`.
+pub(crate) fn listen_fd() -> Result<RawFd, Box<dyn std::error::Error + Send + Sync>> {
+    let pid: u32 = std::env::var("LISTEN_PID")
+        .map_err(|_| "LISTEN_PID is not set; was the relay socket activated?")?
+        .parse()
+        .map_err(|_| "Invalid LISTEN_PID")?;
+    if pid != std::process::id() {
+        return Err("LISTEN_PID names another process".into());
+    }
+    let fds: u32 = std::env::var("LISTEN_FDS")
+        .map_err(|_| "LISTEN_FDS is not set")?
+        .parse()
+        .map_err(|_| "Invalid LISTEN_FDS")?;
+    match fds {
+        0 => Err("No socket was passed in LISTEN_FDS".into()),
+        1 => Ok(SD_LISTEN_FDS_START),
+        _ => {
+            tracing::warn!("{} sockets passed, serving only the first", fds);
+            Ok(SD_LISTEN_FDS_START)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tcp_and_unix_sockets_told_apart() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        match unsafe { Inherited::from_fd(tcp.into_raw_fd()) }.unwrap() {
+            Inherited::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+            inherited => panic!("Expected TCP, got {:?}", inherited),
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let unix = std::os::unix::net::UnixListener::bind(dir.path().join("relay.sock")).unwrap();
+        assert!(matches!(
+            unsafe { Inherited::from_fd(unix.into_raw_fd()) }.unwrap(),
+            Inherited::Unix(_)
+        ));
+    }
+}
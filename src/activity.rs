@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Tracks whether a connection has requests in progress, since when it has had none, whether
+/// it is draining, and how many requests it has been sent.
+#[derive(Debug)]
+pub(crate) struct Activity {
+    busy: AtomicUsize,
+    idle_since: Mutex<Instant>,
+    draining: AtomicBool,
+    requests: AtomicU64,
+    exhausted: Notify,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            busy: AtomicUsize::new(0),
+            idle_since: Mutex::new(Instant::now()),
+            draining: AtomicBool::new(false),
+            requests: AtomicU64::new(0),
+            exhausted: Notify::new(),
+        }
+    }
+}
+
+impl Activity {
+    /// Count a request as in progress until the returned guard is dropped.
+    pub(crate) fn busy(self: &Arc<Self>) -> Busy {
+        self.busy.fetch_add(1, Ordering::SeqCst);
+        Busy(self.clone())
+    }
+
+    /// Mark the connection as closing once its in-flight requests finish.
+    pub(crate) fn drain(&self) { self.draining.store(true, Ordering::SeqCst); }
+
+    pub(crate) fn is_draining(&self) -> bool { self.draining.load(Ordering::SeqCst) }
+
+    /// Count a request, draining the connection once it is the `max`th.
+    pub(crate) fn count_request(&self, max: Option<u64>) {
+        let requests = self.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        if max == Some(requests) {
+            self.drain();
+            self.exhausted.notify_one();
+        }
+    }
+
+    /// Resolve once the connection has been sent as many requests as it may.
+    pub(crate) async fn exhausted(&self) { self.exhausted.notified().await }
+
+    /// Resolve once no request has been in progress for `timeout`.
+    pub(crate) async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let idle_since = *self.idle_since.lock().expect("activity poisoned");
+            let idle = self.busy.load(Ordering::SeqCst) == 0;
+            if idle && idle_since.elapsed() >= timeout {
+                return;
+            }
+            let deadline = if idle { idle_since + timeout } else { Instant::now() + timeout };
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Marks a request as in progress until dropped.
+#[derive(Debug)]
+pub(crate) struct Busy(Arc<Activity>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        if let Ok(mut idle_since) = self.0.idle_since.lock() {
+            if self.0.busy.fetch_sub(1, Ordering::SeqCst) == 1 {
+                *idle_since = Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn idle_only_without_requests() {
+        let timeout = Duration::from_millis(100);
+        let activity = Arc::new(Activity::default());
+        let busy = activity.busy();
+        let idle = tokio::time::timeout(timeout * 3, activity.idle_for(timeout));
+        assert!(idle.await.is_err());
+
+        drop(busy);
+        let started = Instant::now();
+        activity.idle_for(timeout).await;
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[tokio::test]
+    async fn exhausted_after_max_requests() {
+        let activity = Activity::default();
+        activity.count_request(Some(2));
+        assert!(!activity.is_draining());
+        activity.count_request(Some(2));
+        assert!(activity.is_draining());
+        let exhausted = tokio::time::timeout(Duration::from_secs(1), activity.exhausted());
+        assert!(exhausted.await.is_ok());
+    }
+}
@@ -0,0 +1,249 @@
+use std::sync::Arc;
+
+use http::Uri;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::task::JoinHandle;
+use tokio_util::net::Listener;
+use tracing::{debug, error, info};
+
+use crate::body::BoxError;
+#[cfg(unix)]
+use crate::socket_file;
+use crate::{empty, full, health_check, readiness_check, Config, Listen, Relay, RelayError};
+
+/// The largest body accepted by `PUT /config/gateways`.
+const MAX_UPDATE_SIZE: usize = 64 * 1024;
+
+/// Builds the settings applied by `POST /reload`, see [`Admin::reload`].
+pub type AdminReload = Arc<dyn Fn() -> Result<Config, BoxError> + Send + Sync>;
+
+/// A listener of its own for the relay's operational endpoints, so they are never exposed to
+/// relay clients. It serves `GET /health`, `/ready`, `/inflight` and `/config/gateways`, and
+/// with the `metrics` feature `/metrics`, `/stats` and `/gateways`. `PUT /config/gateways`
+/// replaces the default gateway or allowlist, e.g. with `gateway_origin = "https://new.example"`
+/// as TOML, and `POST /dns/flush` forgets cached gateway lookups. While it is set the relay's listeners answer none of the operational endpoints.
+#[derive(Clone)]
+pub struct Admin {
+    /// A unix socket or a TCP loopback address; anything else is refused at startup.
+    pub listen: Listen,
+    /// Serve `POST /reload`, applying the settings this builds as [`crate::Reload`] does.
+    pub reload: Option<AdminReload>,
+}
+
+impl Admin {
+    /// Operational endpoints on `listen`, without `POST /reload`.
+    pub fn new(listen: Listen) -> Self { Self { listen, reload: None } }
+}
+
+impl std::fmt::Debug for Admin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Admin")
+            .field("listen", &self.listen)
+            .field("reload", &self.reload.is_some())
+            .finish()
+    }
+}
+
+/// Serves the operational endpoints of [`Config::admin`](crate::Config::admin) on their own
+/// listener until dropped.
+#[derive(Debug)]
+pub(crate) struct AdminServer {
+    task: JoinHandle<()>,
+    #[cfg(unix)]
+    _unlink: Option<socket_file::Unlink>,
+}
+
+impl AdminServer {
+    pub(crate) async fn bind(admin: &Admin, relay: Arc<Relay>) -> Result<Self, RelayError> {
+        match &admin.listen {
+            Listen::Tcp(addr) if addr.ip().is_loopback() => {
+                let listener = TcpListener::bind(addr).await?;
+                info!("Admin listening on tcp://{}", listener.local_addr()?);
+                Ok(Self {
+                    task: tokio::spawn(serve_admin(listener, relay)),
+                    #[cfg(unix)]
+                    _unlink: None,
+                })
+            }
+            #[cfg(unix)]
+            Listen::Socket(path) => {
+                let settings = &relay.config.socket_file;
+                let listener: UnixListener = socket_file::bind(path, settings)?;
+                info!("Admin listening on socket: {}", path.display());
+                let unlink = settings.unlink_on_shutdown.then(|| socket_file::Unlink(path.clone()));
+                Ok(Self { task: tokio::spawn(serve_admin(listener, relay)), _unlink: unlink })
+            }
+            _ => Err(RelayError::Config(
+                "The admin listener must be a unix socket or a loopback address".into(),
+            )),
+        }
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) { self.task.abort(); }
+}
+
+async fn serve_admin<L>(mut listener: L, relay: Arc<Relay>)
+where
+    L: Listener + Unpin,
+    L::Io: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("Admin accept failed: {}", e);
+                continue;
+            }
+        };
+        let relay = relay.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let relay = relay.clone();
+                async move { Ok::<_, hyper::Error>(route(req, &relay).await) }
+            });
+            if let Err(e) =
+                http1::Builder::new().serve_connection(TokioIo::new(stream), service).await
+            {
+                debug!("Error serving admin connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn route(req: Request<Incoming>, relay: &Relay) -> Response<BoxBody<Bytes, hyper::Error>> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET | &Method::HEAD, "/health") => health_check().await,
+        (&Method::GET | &Method::HEAD, "/ready") => readiness_check(&relay.reloadable().gateways),
+        (&Method::GET, "/inflight") => json(relay.inflight.to_json()),
+        #[cfg(feature = "metrics")]
+        (&Method::GET, "/metrics") => {
+            let mut res = Response::new(full(relay.metrics.render()));
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(crate::metrics::CONTENT_TYPE_TEXT));
+            res
+        }
+        #[cfg(feature = "metrics")]
+        (&Method::GET, "/stats") => json(relay.metrics.stats_json()),
+        #[cfg(feature = "metrics")]
+        (&Method::GET, "/gateways") => json(relay.metrics.gateways_json()),
+        (&Method::POST, "/reload") => reload(relay),
+        (&Method::POST, "/dns/flush") => {
+            relay.config.resolver.flush();
+            info!("Flushed cached DNS lookups");
+            status(StatusCode::NO_CONTENT)
+        }
+        (&Method::GET, "/config/gateways") => json(gateways_json(relay)),
+        (&Method::PUT, "/config/gateways") => put_gateways(req, relay).await,
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Apply the settings built by [`Admin::reload`], answering 202 Accepted as they take effect
+/// in the background.
+fn reload(relay: &Relay) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let source = match relay.config.admin.as_ref().and_then(|admin| admin.reload.as_ref()) {
+        Some(source) => source,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    match source() {
+        Ok(config) => {
+            relay.config.reload.reload(config);
+            status(StatusCode::ACCEPTED)
+        }
+        Err(e) => {
+            error!("Keeping previous settings, reload failed: {}", e);
+            let mut res = Response::new(full(e.to_string()));
+            *res.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            res
+        }
+    }
+}
+
+/// The default gateway and allowlist new requests are forwarded with.
+fn gateways_json(relay: &Relay) -> String {
+    let reloadable = relay.reloadable();
+    // A `Uri` never contains quotes, backslashes or control characters.
+    let allowed: Vec<String> = reloadable
+        .gateways
+        .allowed()
+        .iter()
+        .map(|gateway| format!(r#""{}""#, gateway.origin()))
+        .collect();
+    format!(
+        r#"{{"gateway_origin":"{}","allowed_gateways":[{}]}}"#,
+        reloadable.gateways.default_gateway().origin(),
+        allowed.join(",")
+    )
+}
+
+/// Gateways to forward to from now on, given as TOML with the keys of the same name in
+/// [`ConfigFile`](crate::config_file::ConfigFile). Omitted keys are left as they are.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GatewaysUpdate {
+    gateway_origin: Option<String>,
+    allowed_gateways: Option<Vec<String>>,
+}
+
+impl GatewaysUpdate {
+    fn parse(body: &[u8]) -> Result<(Option<Uri>, Option<Vec<Uri>>), BoxError> {
+        let update: Self = toml::from_str(std::str::from_utf8(body)?)?;
+        let origin = update.gateway_origin.map(|origin| origin.parse()).transpose()?;
+        let allowed = update
+            .allowed_gateways
+            .map(|allowed| allowed.iter().map(|uri| uri.parse()).collect::<Result<_, _>>())
+            .transpose()?;
+        Ok((origin, allowed))
+    }
+}
+
+/// Replace the default gateway and allowlist, answering 202 Accepted as they take effect in
+/// the background.
+async fn put_gateways(
+    req: Request<Incoming>,
+    relay: &Relay,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = match Limited::new(req.into_body(), MAX_UPDATE_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(_) => return status(StatusCode::BAD_REQUEST),
+    };
+    match GatewaysUpdate::parse(&body)
+        .and_then(|(origin, allowed)| Ok(relay.replace_gateways(origin, allowed)?))
+    {
+        Ok(()) => {
+            info!("Replacing gateways");
+            status(StatusCode::ACCEPTED)
+        }
+        Err(e) => {
+            let mut res = Response::new(full(e.to_string()));
+            *res.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            res
+        }
+    }
+}
+
+fn json(body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = Response::new(full(body));
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    res
+}
+
+fn status(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = Response::new(empty());
+    *res.status_mut() = status;
+    res
+}
@@ -0,0 +1,157 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{HeaderMap, Method, StatusCode};
+
+mod privacy_pass;
+
+pub use privacy_pass::PrivacyPass;
+
+/// What an [`Authorizer`] can see of a request: its transport metadata and headers, but never
+/// the encapsulated body.
+#[derive(Debug)]
+pub struct RequestMeta<'a> {
+    /// The address of the connected peer, or `None` when the relay is not serving TCP.
+    pub peer_addr: Option<SocketAddr>,
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub headers: &'a HeaderMap,
+}
+
+/// The outcome of an authorization check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    Allow,
+    /// Reject the request with the given status, or 403 Forbidden if `None`.
+    Deny(Option<StatusCode>),
+    /// Reject the request with 401 Unauthorized and this `WWW-Authenticate` challenge.
+    Challenge(HeaderValue),
+}
+
+/// Decides whether a request may be forwarded to the gateway.
+///
+/// The check runs before anything is forwarded, for both relayed OHTTP requests and bootstrap
+/// tunnels. Health checks and CORS preflight requests are never authorized.
+pub trait Authorizer: Debug + Send + Sync {
+    fn authorize<'a>(
+        &'a self,
+        req: &'a RequestMeta<'a>,
+    ) -> Pin<Box<dyn Future<Output = Authorization> + Send + 'a>>;
+}
+
+/// Allows every request. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize<'a>(
+        &'a self,
+        _: &'a RequestMeta<'a>,
+    ) -> Pin<Box<dyn Future<Output = Authorization> + Send + 'a>> {
+        Box::pin(async { Authorization::Allow })
+    }
+}
+
+/// Allows only requests carrying `Authorization: Bearer <token>` for one of a set of shared
+/// secret tokens, denying everything else with 401 Unauthorized and a `WWW-Authenticate: Bearer`
+/// challenge.
+#[derive(Clone)]
+pub struct StaticToken {
+    tokens: Vec<String>,
+}
+
+impl StaticToken {
+    pub fn new(token: impl Into<String>) -> Self { Self { tokens: vec![token.into()] } }
+
+    /// Accept any of `tokens`, e.g. to rotate them without downtime.
+    pub fn any<T: Into<String>>(tokens: impl IntoIterator<Item = T>) -> Self {
+        Self { tokens: tokens.into_iter().map(Into::into).collect() }
+    }
+}
+
+impl Debug for StaticToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticToken").finish_non_exhaustive()
+    }
+}
+
+impl Authorizer for StaticToken {
+    fn authorize<'a>(
+        &'a self,
+        req: &'a RequestMeta<'a>,
+    ) -> Pin<Box<dyn Future<Output = Authorization> + Send + 'a>> {
+        let presented = req
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        // Every token is compared, so timing doesn't reveal which one matched.
+        let allowed = presented.is_some_and(|presented| {
+            self.tokens
+                .iter()
+                .fold(false, |found, token| found | constant_time_eq(presented, token))
+        });
+        Box::pin(async move {
+            if allowed {
+                Authorization::Allow
+            } else {
+                Authorization::Challenge(HeaderValue::from_static(BEARER_CHALLENGE))
+            }
+        })
+    }
+}
+
+const BEARER_CHALLENGE: &str = r#"Bearer realm="ohttp-relay""#;
+
+/// Compare secrets without leaking the position of the first differing byte through timing.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn check(authorizer: &dyn Authorizer, headers: &HeaderMap) -> Authorization {
+        let meta = RequestMeta { peer_addr: None, method: &Method::POST, path: "/", headers };
+        authorizer.authorize(&meta).await
+    }
+
+    #[tokio::test]
+    async fn static_token_allows_matching_bearer() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cret"));
+        assert_eq!(check(&StaticToken::new("s3cret"), &headers).await, Authorization::Allow);
+    }
+
+    #[tokio::test]
+    async fn static_token_denies_wrong_or_missing_bearer() {
+        let denied = Authorization::Challenge(HeaderValue::from_static(BEARER_CHALLENGE));
+        let authorizer = StaticToken::new("s3cret");
+        assert_eq!(check(&authorizer, &HeaderMap::new()).await, denied);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cre7"));
+        assert_eq!(check(&authorizer, &headers).await, denied);
+    }
+
+    #[tokio::test]
+    async fn static_token_allows_any_of_its_tokens() {
+        let authorizer = StaticToken::any(["old", "new"]);
+        for token in ["Bearer old", "Bearer new"] {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_static(token));
+            assert_eq!(check(&authorizer, &headers).await, Authorization::Allow);
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer older"));
+        assert!(matches!(check(&authorizer, &headers).await, Authorization::Challenge(_)));
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use base64::alphabet::URL_SAFE;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::HeaderMap;
+use ring::digest::{digest, SHA256};
+use ring::signature::{UnparsedPublicKey, RSA_PSS_2048_8192_SHA384};
+
+use super::{Authorization, Authorizer, RequestMeta};
+use crate::body::BoxError;
+
+/// The publicly verifiable token type of RFC 9578, signed with blind RSA.
+const TOKEN_TYPE: u16 = 0x0002;
+/// The signed part of a token: its type, nonce, challenge digest and token key ID.
+const TOKEN_INPUT_LEN: usize = 2 + 32 + 32 + 32;
+/// The length of a token's signature, for the 2048-bit keys of this token type.
+const AUTHENTICATOR_LEN: usize = 256;
+/// How many spent tokens are remembered to refuse replays, forgetting the oldest first.
+const MAX_SPENT: usize = 1 << 20;
+
+/// Base64url as RFC 9577 encodes challenges, keys and tokens, accepted with or without padding.
+const BASE64URL: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Allows only requests redeeming a Privacy Pass token, see RFC 9577, so a public relay can
+/// turn away abuse without learning who its clients are. Tokens must be of the publicly
+/// verifiable type of RFC 9578, issued by one issuer with a 2048-bit key. They are not bound
+/// to a redemption context, so clients may fetch them ahead of time, but each is accepted only
+/// once. Requests without a valid, unspent token are denied with 401 Unauthorized and a
+/// `WWW-Authenticate: PrivateToken` challenge naming the issuer and its key.
+pub struct PrivacyPass {
+    issuer_name: String,
+    /// The issuer's RSA public key, as PKCS#1 `RSAPublicKey`.
+    public_key: Vec<u8>,
+    token_key_id: [u8; 32],
+    challenge_digest: [u8; 32],
+    challenge: HeaderValue,
+    spent: Mutex<Spent>,
+}
+
+impl PrivacyPass {
+    /// Accept tokens from the issuer called `issuer_name`, e.g. `issuer.example`, whose public
+    /// key is `token_key`: the base64url `SubjectPublicKeyInfo` its directory publishes.
+    pub fn new(issuer_name: &str, token_key: &str) -> Result<Self, BoxError> {
+        let spki = BASE64URL.decode(token_key.trim())?;
+        let public_key = rsa_public_key(&spki).ok_or("Invalid Privacy Pass token key")?.to_owned();
+        let issuer_len = u16::try_from(issuer_name.len())
+            .ok()
+            .filter(|len| *len > 0)
+            .ok_or("Invalid Privacy Pass issuer name")?;
+        // A TokenChallenge with an empty redemption context and no origin info.
+        let mut challenge = TOKEN_TYPE.to_be_bytes().to_vec();
+        challenge.extend_from_slice(&issuer_len.to_be_bytes());
+        challenge.extend_from_slice(issuer_name.as_bytes());
+        challenge.extend_from_slice(&[0, 0, 0]);
+        let header = format!(
+            r#"PrivateToken challenge="{}", token-key="{}""#,
+            BASE64URL.encode(&challenge),
+            BASE64URL.encode(&spki)
+        );
+        Ok(Self {
+            issuer_name: issuer_name.to_owned(),
+            public_key,
+            token_key_id: sha256(&spki),
+            challenge_digest: sha256(&challenge),
+            challenge: HeaderValue::from_str(&header)?,
+            spent: Mutex::default(),
+        })
+    }
+
+    /// Whether `token` is valid for this issuer and unspent, spending it if so.
+    fn redeem(&self, token: &[u8]) -> bool {
+        if token.len() != TOKEN_INPUT_LEN + AUTHENTICATOR_LEN {
+            return false;
+        }
+        let (input, authenticator) = token.split_at(TOKEN_INPUT_LEN);
+        if input[..2] != TOKEN_TYPE.to_be_bytes()
+            || input[34..66] != self.challenge_digest
+            || input[66..98] != self.token_key_id
+        {
+            return false;
+        }
+        let key = UnparsedPublicKey::new(&RSA_PSS_2048_8192_SHA384, &self.public_key);
+        if key.verify(input, authenticator).is_err() {
+            return false;
+        }
+        let nonce = input[2..34].try_into().expect("nonces are 32 bytes");
+        self.spent.lock().expect("spent tokens poisoned").spend(nonce)
+    }
+}
+
+impl Debug for PrivacyPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrivacyPass")
+            .field("issuer_name", &self.issuer_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Authorizer for PrivacyPass {
+    fn authorize<'a>(
+        &'a self,
+        req: &'a RequestMeta<'a>,
+    ) -> Pin<Box<dyn Future<Output = Authorization> + Send + 'a>> {
+        let allowed = presented_token(req.headers).is_some_and(|token| self.redeem(&token));
+        Box::pin(async move {
+            match allowed {
+                true => Authorization::Allow,
+                false => Authorization::Challenge(self.challenge.clone()),
+            }
+        })
+    }
+}
+
+/// The nonces of spent tokens, oldest first.
+#[derive(Default)]
+struct Spent {
+    nonces: HashSet<[u8; 32]>,
+    order: VecDeque<[u8; 32]>,
+}
+
+impl Spent {
+    /// Whether `nonce` was unspent, remembering it as spent.
+    fn spend(&mut self, nonce: [u8; 32]) -> bool {
+        if !self.nonces.insert(nonce) {
+            return false;
+        }
+        self.order.push_back(nonce);
+        if self.order.len() > MAX_SPENT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.nonces.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// The token of an `Authorization: PrivateToken token="..."` header.
+fn presented_token(headers: &HeaderMap) -> Option<Vec<u8>> {
+    headers.get_all(AUTHORIZATION).iter().filter_map(|value| value.to_str().ok()).find_map(
+        |value| {
+            let (scheme, params) = value.trim().split_once(' ')?;
+            if !scheme.eq_ignore_ascii_case("PrivateToken") {
+                return None;
+            }
+            params.split(',').find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                match name.trim().eq_ignore_ascii_case("token") {
+                    true => BASE64URL.decode(value.trim().trim_matches('"')).ok(),
+                    false => None,
+                }
+            })
+        },
+    )
+}
+
+/// The `RSAPublicKey` in the DER `SubjectPublicKeyInfo` `spki`, whichever RSA algorithm it
+/// names.
+fn rsa_public_key(spki: &[u8]) -> Option<&[u8]> {
+    let (spki, rest) = der_element(spki, 0x30)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    let (_algorithm, rest) = der_element(spki, 0x30)?;
+    let (bits, rest) = der_element(rest, 0x03)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    // A bit string of whole bytes.
+    bits.strip_prefix(&[0])
+}
+
+/// The contents of the DER element tagged `tag` at the start of `der`, and what follows it.
+fn der_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&found, der) = der.split_first()?;
+    let (&len, mut der) = der.split_first()?;
+    if found != tag {
+        return None;
+    }
+    let len = match len {
+        len if len < 0x80 => usize::from(len),
+        0x81 | 0x82 => {
+            let (len_bytes, rest) = der.split_at(usize::from(len - 0x80).min(der.len()));
+            der = rest;
+            len_bytes.iter().fold(0, |len, byte| len << 8 | usize::from(*byte))
+        }
+        _ => return None,
+    };
+    match der.len() >= len {
+        true => Some(der.split_at(len)),
+        false => None,
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    digest(&SHA256, data).as_ref().try_into().expect("SHA-256 digests are 32 bytes")
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Method;
+    use ring::rand::SystemRandom;
+    use ring::signature::{RsaKeyPair, RSA_PSS_SHA384};
+
+    use super::*;
+
+    /// An issuer's 2048-bit RSA key, as PKCS#1 DER.
+    const ISSUER_KEY: &str = "MIIEpAIBAAKCAQEAuErjSiO9wUt3tnUyu9zEUAMaUw17Sczz6YI1fFOjAoix8mAoMdwNLtsfzbIcNWBeTJphY4yC\
+                           y2NF3Pzb7GDZCYqBI8EJVeH1SRJk3U+wATJxEhLGgQAMRV63o+4UVYRcz7c5FSQg135Lptl8FIXZXVm4Db0tdM7y\
+                           ERUSl5GwPaKcclV3RfOIKsXXibdFAoshzxosYfCUof0pksgfgcxRNErVdhkUM8kwKQHYDP601QkdYEHvBJSMatVv\
+                           lUycZJwnnP+NTAXEUsF0yF0Bzdiv7oLTpfagtLFZEhuYfTvJ8kqkzUUMbWvfHfDrineN2hA1wyL6NNK7TW8APSuy\
+                           jRsyzwIDAQABAoIBABSE21yjBnAcl8txWMDmRNdHGSm7xl6CWs/vcZwSRdk04LcvR0UR4/KJGY08nPvBFeQVNkMF\
+                           XyoHt2dyTIbmMOlpNJVcsKORdnl4elF9G11uwScgKbOC5AWJ6mFuqHCYaFWvkplM006a9GUIhvz+pXke0al58YiK\
+                           uBSjJ4BHtiXjzNm8vigN8G1WmJqT6RSMFpWpb9Hty/pvRt5qfWiHoZxiCUhsLEJYEZI3rzQQVhdPytC05REXWU5a\
+                           uam4ff99hUH/vgZWuEXrHUIswvmDuAl/7Sf3OKGddRceVHjnaPtN7sVG8XZHKMgmOvVmN47dqwYxE0DyEF7SwGqP\
+                           7mQO7gkCgYEA3DEF6sWmEQvIWqIk9sUCInYzhqRIYl1nqTiDhNYQZdKUaHQ+321e7NJuno9aXAwSoHRJ9tq2+qe2\
+                           gg+ctRa92TpcC03MbbrMxZg1MGq5QKxaNrqlH9lB7eUFiGFDrST3uM70HtkCwxFZHF55YTnIpJgicO6h0LxkWXdz\
+                           FO5BLykCgYEA1kNTJI4lRw7uxzYtEmaWwGF6ajDip6ldXNXU+VZzNBqXvsD0dD8q5iPUSMRSlCuyxx40/Of7M4Cu\
+                           z7V0OWyPZOuJDEjMKl8eSBQg+KpY/lP3BESWW4opI4bTx+AY3H9t7Abo9HXHOzFUwTE50vABVVTvck+oi+cpeE39\
+                           3GQ4qTcCgYEAqQq1uTQ9tq5uBS6f5BcA+YX3LrGmpGWC9IXfajaQ5irRJpO/xPWJP5SZuf7h5vKZxjcgpXPpr+rb\
+                           kWP88YVhAIjuKvtyKveSx0t/4gi86eqnY4bMx/OZytHc+oN5Wk6cI3Q2NAx0M195Iu9gjgS3ha0KG9sHvWE0RP8Q\
+                           CYyT3GkCgYB+sZCYLPUd71aBtScxmjfJydn4sK9VNHBgVJB6XwP/6zmPMclac3+KVeVQocWyl6Q+2T27zfBbo9u6\
+                           NjFjQy4HHr8K5IDVDSl6tWxJnXDB8iQ+AdBmkGuC9laD+Z65wx/cULmu03XbKnBTTChE8mXXb0Gm9jNThnyfBn71\
+                           ss1OQQKBgQCxLZeUXxZJn8x9Oxof1zfeeVYO4R7MJIBcH8GG5Two9AKf319dJSlufTGDwjjdVC+i+KPbU2d5CouW\
+                           29kdtvCIkf+qZiI4DKqUXq+c/+tKn4qq8W1MyvsXAk5m6SKMZye9ytcnn2hRotuBDQSs2DXQ8n7Q7/Nn9QpHwdag\
+                           gn9VdQ==";
+    /// The issuer's public key, as a `SubjectPublicKeyInfo` in DER.
+    const ISSUER_SPKI: &str = "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAuErjSiO9wUt3tnUyu9zEUAMaUw17Sczz6YI1fFOjAoix\
+                            8mAoMdwNLtsfzbIcNWBeTJphY4yCy2NF3Pzb7GDZCYqBI8EJVeH1SRJk3U+wATJxEhLGgQAMRV63o+4UVYRcz7c5\
+                            FSQg135Lptl8FIXZXVm4Db0tdM7yERUSl5GwPaKcclV3RfOIKsXXibdFAoshzxosYfCUof0pksgfgcxRNErVdhkU\
+                            M8kwKQHYDP601QkdYEHvBJSMatVvlUycZJwnnP+NTAXEUsF0yF0Bzdiv7oLTpfagtLFZEhuYfTvJ8kqkzUUMbWvf\
+                            HfDrineN2hA1wyL6NNK7TW8APSuyjRsyzwIDAQAB";
+
+    fn token_key() -> String {
+        let spki = base64::engine::general_purpose::STANDARD.decode(ISSUER_SPKI).unwrap();
+        BASE64URL.encode(spki)
+    }
+
+    /// A token for `authorizer`'s challenge signed by the issuer, as a finalized blind
+    /// signature would be.
+    fn issue(authorizer: &PrivacyPass, nonce: u8) -> String {
+        let key = base64::engine::general_purpose::STANDARD.decode(ISSUER_KEY).unwrap();
+        let key = RsaKeyPair::from_der(&key).unwrap();
+        let mut token = TOKEN_TYPE.to_be_bytes().to_vec();
+        token.extend_from_slice(&[nonce; 32]);
+        token.extend_from_slice(&authorizer.challenge_digest);
+        token.extend_from_slice(&authorizer.token_key_id);
+        let mut authenticator = vec![0; key.public().modulus_len()];
+        key.sign(&RSA_PSS_SHA384, &SystemRandom::new(), &token, &mut authenticator).unwrap();
+        token.extend_from_slice(&authenticator);
+        format!(r#"PrivateToken token="{}""#, BASE64URL.encode(token))
+    }
+
+    async fn check(authorizer: &PrivacyPass, authorization: Option<&str>) -> Authorization {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        }
+        let meta =
+            RequestMeta { peer_addr: None, method: &Method::POST, path: "/", headers: &headers };
+        authorizer.authorize(&meta).await
+    }
+
+    #[tokio::test]
+    async fn valid_token_allowed_once() {
+        let authorizer = PrivacyPass::new("issuer.example", &token_key()).unwrap();
+        let token = issue(&authorizer, 1);
+        assert_eq!(check(&authorizer, Some(&token)).await, Authorization::Allow);
+        // Replays are refused.
+        assert!(matches!(check(&authorizer, Some(&token)).await, Authorization::Challenge(_)));
+        assert_eq!(check(&authorizer, Some(&issue(&authorizer, 2))).await, Authorization::Allow);
+    }
+
+    #[tokio::test]
+    async fn missing_or_foreign_token_challenged() {
+        let authorizer = PrivacyPass::new("issuer.example", &token_key()).unwrap();
+        let challenge = match check(&authorizer, None).await {
+            Authorization::Challenge(challenge) => challenge,
+            other => panic!("unexpected authorization: {:?}", other),
+        };
+        let challenge = challenge.to_str().unwrap();
+        assert!(challenge.starts_with(r#"PrivateToken challenge=""#), "{}", challenge);
+        assert!(challenge.contains(&format!(r#"token-key="{}""#, token_key())), "{}", challenge);
+
+        // A token for another issuer's challenge.
+        let other = PrivacyPass::new("other.example", &token_key()).unwrap();
+        let token = issue(&other, 1);
+        assert!(matches!(check(&authorizer, Some(&token)).await, Authorization::Challenge(_)));
+        assert!(matches!(
+            check(&authorizer, Some("PrivateToken token=\"AAAA\"")).await,
+            Authorization::Challenge(_)
+        ));
+    }
+
+    #[test]
+    fn invalid_keys_refused() {
+        assert!(PrivacyPass::new("issuer.example", "not base64!").is_err());
+        assert!(PrivacyPass::new("issuer.example", &BASE64URL.encode([0x30, 0x00])).is_err());
+        assert!(PrivacyPass::new("", &token_key()).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Load generation against a running relay, for capacity planning and catching regressions.
+//! Part of the `cli` feature, for the binary's `bench` subcommand.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::header::CONTENT_TYPE;
+use http::{HeaderValue, Method, Request, Uri};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_rustls::HttpsConnectorBuilder;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+
+use crate::body::BoxError;
+
+/// Sends synthetic `message/ohttp-req` POSTs to a relay, `concurrency` at a time.
+///
+/// Unless `body` is a real encapsulated request, the gateway cannot decrypt it and answers with
+/// an error of its own, so the report mostly measures the relay and its path to the gateway.
+#[derive(Debug, Clone)]
+pub struct Bench {
+    /// The relay URL requests are posted to, e.g. `http://127.0.0.1:3000/`.
+    pub relay: Uri,
+    /// How many requests are in flight at once.
+    pub concurrency: usize,
+    /// How many requests are sent in total.
+    pub requests: usize,
+    /// The body of every request.
+    pub body: Bytes,
+}
+
+impl Bench {
+    /// Send the requests, returning once every one is answered or has failed.
+    pub async fn run(&self) -> Result<Report, BoxError> {
+        let https = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .build();
+        let client = Client::builder(TokioExecutor::new()).build(https);
+        let remaining = Arc::new(AtomicUsize::new(self.requests));
+        let started = Instant::now();
+        let workers: Vec<_> = (0..self.concurrency.max(1))
+            .map(|_| {
+                let (client, remaining, bench) = (client.clone(), remaining.clone(), self.clone());
+                tokio::spawn(async move {
+                    let mut report = Report::default();
+                    while take(&remaining) {
+                        let mut req = Request::new(Full::new(bench.body.clone()));
+                        *req.method_mut() = Method::POST;
+                        *req.uri_mut() = bench.relay.clone();
+                        req.headers_mut()
+                            .insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-req"));
+                        let sent = Instant::now();
+                        let res = match client.request(req).await {
+                            Ok(res) => res,
+                            Err(e) => {
+                                tracing::debug!("Benchmark request failed: {}", e);
+                                report.failures += 1;
+                                continue;
+                            }
+                        };
+                        let status = res.status().as_u16();
+                        match res.into_body().collect().await {
+                            Ok(_) => {
+                                report.latencies.push(sent.elapsed());
+                                *report.statuses.entry(status).or_default() += 1;
+                            }
+                            Err(_) => report.failures += 1,
+                        }
+                    }
+                    report
+                })
+            })
+            .collect();
+        let mut report = Report::default();
+        for worker in workers {
+            let worker = worker.await?;
+            report.latencies.extend(worker.latencies);
+            for (status, count) in worker.statuses {
+                *report.statuses.entry(status).or_default() += count;
+            }
+            report.failures += worker.failures;
+        }
+        report.latencies.sort();
+        report.elapsed = started.elapsed();
+        Ok(report)
+    }
+}
+
+/// Claim one of the requests left to send.
+fn take(remaining: &AtomicUsize) -> bool {
+    remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok()
+}
+
+/// The outcome of a [`Bench`] run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// How long each answered request took until its response was read, sorted.
+    pub latencies: Vec<Duration>,
+    /// How many responses had each status.
+    pub statuses: BTreeMap<u16, usize>,
+    /// Requests that got no complete response, e.g. because the connection failed.
+    pub failures: usize,
+    /// How long the whole run took.
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// The latency `p` percent of answered requests took at most, e.g. `99.0` for the p99.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * last as f64).round() as usize;
+        self.latencies.get(rank).copied()
+    }
+
+    /// The share of requests that failed or were answered with a 5xx status.
+    pub fn error_rate(&self) -> f64 {
+        let server_errors: usize =
+            self.statuses.range(500..600).map(|(_, count)| count).sum::<usize>();
+        let total = self.latencies.len() + self.failures;
+        match total {
+            0 => 0.0,
+            total => (self.failures + server_errors) as f64 / total as f64,
+        }
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let total = self.latencies.len() + self.failures;
+        let rate = total as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(f, "{} requests in {:.2?} ({:.1}/s)", total, self.elapsed, rate)?;
+        for (label, p) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("max", 100.0)] {
+            if let Some(latency) = self.percentile(p) {
+                writeln!(f, "  {}: {:.2?}", label, latency)?;
+            }
+        }
+        for (status, count) in &self.statuses {
+            writeln!(f, "  status {}: {}", status, count)?;
+        }
+        writeln!(f, "  failed: {}", self.failures)?;
+        write!(f, "  error rate: {:.2}%", self.error_rate() * 100.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles_and_error_rate() {
+        let report = Report {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            statuses: [(200, 97), (502, 3)].into_iter().collect(),
+            failures: 0,
+            elapsed: Duration::from_secs(1),
+        };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(51)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+        assert!((report.error_rate() - 0.03).abs() < f64::EPSILON);
+        assert_eq!(Report::default().percentile(50.0), None);
+        assert_eq!(Report::default().error_rate(), 0.0);
+    }
+
+    #[test]
+    fn requests_claimed_once() {
+        let remaining = AtomicUsize::new(2);
+        assert!(take(&remaining) && take(&remaining));
+        assert!(!take(&remaining));
+    }
+}
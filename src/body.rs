@@ -0,0 +1,735 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::HeaderMap;
+use tokio::time::{Instant, Sleep};
+
+use crate::error::Error;
+use crate::metrics::Metrics;
+use crate::BandwidthLimit;
+
+pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A body that fails if the bytes streamed differ from the declared `Content-Length`.
+#[derive(Debug)]
+pub(crate) struct ExactLength<B> {
+    inner: B,
+    expected: u64,
+    received: u64,
+}
+
+impl<B> ExactLength<B> {
+    pub(crate) fn new(inner: B, expected: u64) -> Self { Self { inner, expected, received: 0 } }
+
+    fn mismatch(&self) -> BoxError {
+        Box::new(ContentLengthMismatch { expected: self.expected, received: self.received })
+    }
+}
+
+impl<B> Body for ExactLength<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let self_mut = self.get_mut();
+        match Pin::new(&mut self_mut.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self_mut.received += data.len() as u64;
+                    if self_mut.received > self_mut.expected {
+                        return Poll::Ready(Some(Err(self_mut.mismatch())));
+                    }
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) if self_mut.received != self_mut.expected =>
+                Poll::Ready(Some(Err(self_mut.mismatch()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        // A short body must still be polled to completion so the mismatch is reported.
+        self.received == self.expected && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint { self.inner.size_hint() }
+}
+
+#[derive(Debug)]
+pub(crate) struct ContentLengthMismatch {
+    expected: u64,
+    received: u64,
+}
+
+impl std::fmt::Display for ContentLengthMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Body length does not match Content-Length: expected {} bytes, received at least {}",
+            self.expected, self.received
+        )
+    }
+}
+
+impl std::error::Error for ContentLengthMismatch {}
+
+/// A body that fails once more than `limit` bytes have streamed through it.
+#[derive(Debug)]
+pub(crate) struct LengthLimit<B> {
+    inner: B,
+    limit: u64,
+    received: u64,
+}
+
+impl<B> LengthLimit<B> {
+    pub(crate) fn new(inner: B, limit: u64) -> Self { Self { inner, limit, received: 0 } }
+}
+
+impl<B> Body for LengthLimit<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let self_mut = self.get_mut();
+        match Pin::new(&mut self_mut.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self_mut.received += data.len() as u64;
+                    if self_mut.received > self_mut.limit {
+                        return Poll::Ready(Some(Err(Box::new(BodyTooLarge {
+                            limit: self_mut.limit,
+                        }))));
+                    }
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool { self.inner.is_end_stream() }
+
+    fn size_hint(&self) -> SizeHint { self.inner.size_hint() }
+}
+
+#[derive(Debug)]
+pub(crate) struct BodyTooLarge {
+    limit: u64,
+}
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Body exceeds the limit of {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// A body that fails if no frame arrives within `timeout` of the previous one.
+///
+/// The timer starts on the first poll, so time spent connecting to the gateway before the body
+/// is read does not count against the client.
+#[derive(Debug)]
+pub(crate) struct IdleTimeout<B> {
+    inner: B,
+    timeout: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> IdleTimeout<B> {
+    pub(crate) fn new(inner: B, timeout: Duration) -> Self { Self { inner, timeout, sleep: None } }
+}
+
+impl<B> Body for IdleTimeout<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let self_mut = self.get_mut();
+        match Pin::new(&mut self_mut.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(sleep) = self_mut.sleep.as_mut() {
+                    sleep.as_mut().reset(Instant::now() + self_mut.timeout);
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                let timeout = self_mut.timeout;
+                let sleep =
+                    self_mut.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Some(Err(Box::new(BodyReadTimeout)))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool { self.inner.is_end_stream() }
+
+    fn size_hint(&self) -> SizeHint { self.inner.size_hint() }
+}
+
+#[derive(Debug)]
+pub(crate) struct BodyReadTimeout;
+
+impl std::fmt::Display for BodyReadTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Timed out waiting for the request body")
+    }
+}
+
+impl std::error::Error for BodyReadTimeout {}
+
+/// A body that can start being read before it is forwarded.
+///
+/// Hyper only answers `Expect: 100-continue` once the body is read, which would otherwise leave
+/// the client waiting until the gateway connection is ready.
+pub(crate) struct ReadAhead<B: Body> {
+    inner: B,
+    first: Option<Option<FrameResult<B>>>,
+}
+
+type FrameResult<B> = Result<Frame<<B as Body>::Data>, <B as Body>::Error>;
+
+impl<B: Body + Unpin> ReadAhead<B> {
+    pub(crate) fn new(inner: B) -> Self { Self { inner, first: None } }
+
+    /// Poll the body once, keeping a frame that is already there for the first read.
+    pub(crate) async fn start(&mut self) { PollOnce(self).await }
+}
+
+struct PollOnce<'a, B: Body>(&'a mut ReadAhead<B>);
+
+impl<B: Body + Unpin> Future for PollOnce<'_, B> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let body = &mut *self.0;
+        if body.first.is_none() {
+            if let Poll::Ready(frame) = Pin::new(&mut body.inner).poll_frame(cx) {
+                body.first = Some(frame);
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<B> Body for ReadAhead<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let self_mut = self.get_mut();
+        match self_mut.first.take() {
+            Some(first) => Poll::Ready(first),
+            None => Pin::new(&mut self_mut.inner).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match &self.first {
+            Some(first) => first.is_none(),
+            None => self.inner.is_end_stream(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let mut hint = self.inner.size_hint();
+        // The inner body no longer counts a frame that was read ahead.
+        if let Some(Some(Ok(frame))) = &self.first {
+            let len = frame.data_ref().map_or(0, |data| data.len() as u64);
+            if let Some(upper) = hint.upper() {
+                hint.set_upper(upper + len);
+            }
+            hint.set_lower(hint.lower() + len);
+        }
+        hint
+    }
+}
+
+/// A body that merges data frames which are ready together into frames of about `threshold`
+/// bytes. Whatever is ready is passed on as soon as the inner body would wait, so nothing is held
+/// back for more to arrive.
+#[derive(Debug)]
+pub(crate) struct Coalesce<B: Body> {
+    inner: B,
+    threshold: usize,
+    /// A trailers frame or error that ended the previous merge, passed on next.
+    held: Option<Result<Frame<Bytes>, B::Error>>,
+    ended: bool,
+}
+
+impl<B: Body> Coalesce<B> {
+    pub(crate) fn new(inner: B, threshold: usize) -> Self {
+        Self { inner, threshold, held: None, ended: false }
+    }
+}
+
+impl<B> Body for Coalesce<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let self_mut = self.get_mut();
+        if let Some(held) = self_mut.held.take() {
+            return Poll::Ready(Some(held));
+        }
+        let mut merged = Vec::new();
+        while !self_mut.ended && merged.len() < self_mut.threshold {
+            let next = match Pin::new(&mut self_mut.inner).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    // A large enough frame is passed on without copying.
+                    Ok(data) if merged.is_empty() && data.len() >= self_mut.threshold =>
+                        return Poll::Ready(Some(Ok(Frame::data(data)))),
+                    Ok(data) => {
+                        merged.extend_from_slice(&data);
+                        continue;
+                    }
+                    Err(frame) => Ok(frame),
+                },
+                Poll::Ready(Some(Err(e))) => Err(e),
+                Poll::Ready(None) => {
+                    self_mut.ended = true;
+                    break;
+                }
+                Poll::Pending => break,
+            };
+            if merged.is_empty() {
+                return Poll::Ready(Some(next));
+            }
+            self_mut.held = Some(next);
+            break;
+        }
+        match (merged.is_empty(), self_mut.ended) {
+            (false, _) => Poll::Ready(Some(Ok(Frame::data(merged.into())))),
+            (true, true) => Poll::Ready(None),
+            (true, false) => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.held.is_none() && (self.ended || self.inner.is_end_stream())
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.ended {
+            true => SizeHint::with_exact(0),
+            false => self.inner.size_hint(),
+        }
+    }
+}
+
+/// The token bucket a client connection's bodies draw from, see [`BandwidthLimit`].
+#[derive(Debug, Clone)]
+pub(crate) struct Throttle(Arc<Mutex<Bucket>>);
+
+#[derive(Debug)]
+struct Bucket {
+    burst: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(limit: &BandwidthLimit) -> Self {
+        let burst = f64::from(limit.burst);
+        let per_second = f64::from(limit.per_second);
+        Self(Arc::new(Mutex::new(Bucket {
+            burst,
+            per_second,
+            tokens: burst,
+            updated: Instant::now(),
+        })))
+    }
+
+    /// Take `bytes` from the bucket, returning when it is back in credit if that overdrew it.
+    fn spend(&self, bytes: usize) -> Option<Instant> {
+        let now = Instant::now();
+        let mut bucket = self.0.lock().expect("throttle poisoned");
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * bucket.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(bucket.burst) - bytes as f64;
+        bucket.updated = now;
+        match bucket.tokens < 0.0 {
+            true => Some(now + Duration::from_secs_f64(-bucket.tokens / bucket.per_second)),
+            false => None,
+        }
+    }
+}
+
+/// A body whose data frames are paid for from a [`Throttle`], waiting before the next frame
+/// while the throttle is overdrawn.
+#[derive(Debug)]
+pub(crate) struct Throttled<B> {
+    inner: B,
+    throttle: Option<Throttle>,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> Throttled<B> {
+    pub(crate) fn new(inner: B, throttle: Option<Throttle>) -> Self {
+        Self { inner, throttle, wait: None }
+    }
+}
+
+impl<B> Body for Throttled<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let self_mut = self.get_mut();
+        if let Some(wait) = self_mut.wait.as_mut() {
+            if wait.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self_mut.wait = None;
+        }
+        let frame = Pin::new(&mut self_mut.inner).poll_frame(cx);
+        if let (Poll::Ready(Some(Ok(frame))), Some(throttle)) = (&frame, &self_mut.throttle) {
+            if let Some(until) = frame.data_ref().and_then(|data| throttle.spend(data.len())) {
+                self_mut.wait = Some(Box::pin(tokio::time::sleep_until(until)));
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool { self.inner.is_end_stream() }
+
+    fn size_hint(&self) -> SizeHint { self.inner.size_hint() }
+}
+
+/// A body read in full, to be sent again as it was received, trailers included.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Buffered {
+    data: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl Buffered {
+    /// Read all of `body`.
+    pub(crate) async fn read<B: Body<Data = Bytes> + Unpin>(body: B) -> Result<Self, B::Error> {
+        let collected = body.collect().await?;
+        let trailers = collected.trailers().cloned();
+        Ok(Self { data: Some(collected.to_bytes()), trailers })
+    }
+}
+
+impl Body for Buffered {
+    type Data = Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let self_mut = self.get_mut();
+        let frame = match self_mut.data.take() {
+            Some(data) if !data.is_empty() => Some(Frame::data(data)),
+            _ => self_mut.trailers.take().map(Frame::trailers),
+        };
+        Poll::Ready(frame.map(Ok))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.as_ref().is_none_or(Bytes::is_empty) && self.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // Trailers cannot follow a body framed by `Content-Length` on HTTP/1.
+        match (&self.data, &self.trailers) {
+            (data, None) => SizeHint::with_exact(data.as_ref().map_or(0, |data| data.len() as u64)),
+            (_, Some(_)) => SizeHint::default(),
+        }
+    }
+}
+
+/// A response body that fails once `deadline` passes before it has been fully streamed,
+/// aborting the client connection, see [`crate::Config::request_deadline`].
+#[derive(Debug)]
+pub(crate) struct Deadline<B> {
+    inner: B,
+    sleep: Option<Pin<Box<Sleep>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<B> Deadline<B> {
+    pub(crate) fn new(inner: B, deadline: Option<Instant>, metrics: Arc<Metrics>) -> Self {
+        let sleep = deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline)));
+        Self { inner, sleep, metrics }
+    }
+}
+
+impl<B> Body for Deadline<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let self_mut = self.get_mut();
+        if let Some(sleep) = self_mut.sleep.as_mut() {
+            if sleep.as_mut().poll(cx).is_ready() {
+                self_mut.sleep = None;
+                self_mut.metrics.deadline_exceeded();
+                return Poll::Ready(Some(Err(Box::new(DeadlineExceeded))));
+            }
+        }
+        match Pin::new(&mut self_mut.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => Poll::Ready(Some(Ok(frame))),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool { self.inner.is_end_stream() }
+
+    fn size_hint(&self) -> SizeHint { self.inner.size_hint() }
+}
+
+#[derive(Debug)]
+pub(crate) struct DeadlineExceeded;
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Request deadline exceeded while streaming the response")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// The error to answer the client with if `err` was caused by its request body.
+pub(crate) fn request_body_error(err: &(dyn std::error::Error + 'static)) -> Option<Error> {
+    if has_source::<ContentLengthMismatch>(err) {
+        Some(Error::BadRequest("Body length does not match Content-Length".to_owned()))
+    } else if has_source::<BodyTooLarge>(err) {
+        Some(Error::PayloadTooLarge)
+    } else if has_source::<BodyReadTimeout>(err) {
+        Some(Error::RequestTimeout)
+    } else {
+        None
+    }
+}
+
+/// Whether `err` or any of its sources is an `E`.
+fn has_source<E: std::error::Error + 'static>(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if e.is::<E>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use http_body_util::{BodyExt, Full};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn exact_length_accepted() {
+        let body = ExactLength::new(Full::new(Bytes::from_static(b"hello")), 5);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+    }
+
+    #[tokio::test]
+    async fn shorter_body_rejected() {
+        let body = ExactLength::new(Full::new(Bytes::from_static(b"hi")), 5);
+        let err = body.collect().await.unwrap_err();
+        assert!(has_source::<ContentLengthMismatch>(err.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn longer_body_rejected() {
+        let body = ExactLength::new(Full::new(Bytes::from_static(b"hello world")), 5);
+        let err = body.collect().await.unwrap_err();
+        assert!(has_source::<ContentLengthMismatch>(err.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn body_over_limit_rejected() {
+        let body = LengthLimit::new(Full::new(Bytes::from_static(b"hello")), 5);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+
+        let body = LengthLimit::new(Full::new(Bytes::from_static(b"hello world")), 5);
+        let err = body.collect().await.unwrap_err();
+        assert!(matches!(request_body_error(err.as_ref()), Some(Error::PayloadTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn frame_read_ahead_kept() {
+        let mut body = ReadAhead::new(Full::new(Bytes::from_static(b"hello")));
+        body.start().await;
+        assert_eq!(body.size_hint().exact(), Some(5));
+        assert!(!body.is_end_stream());
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+    }
+
+    #[tokio::test]
+    async fn stalled_body_times_out() {
+        let body = IdleTimeout::new(Stalled { sent: false }, Duration::from_millis(50));
+        let err = body.collect().await.unwrap_err();
+        assert!(has_source::<BodyReadTimeout>(err.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn ready_frames_coalesced() {
+        let chunks = ["a", "bb", "ccc", "dddddddd", "e"].map(Bytes::from);
+        let mut body = Coalesce::new(Ready(chunks.into_iter().collect()), 4);
+        let mut frames = Vec::new();
+        while let Some(frame) = body.frame().await {
+            frames.push(frame.unwrap().into_data().unwrap());
+        }
+        assert_eq!(frames, ["abbccc", "dddddddd", "e"]);
+    }
+
+    #[tokio::test]
+    async fn coalescing_never_waits_for_more() {
+        let mut body = Coalesce::new(Stalled { sent: false }, 1024);
+        let frame = tokio::time::timeout(Duration::from_secs(1), body.frame()).await.unwrap();
+        assert_eq!(frame.unwrap().unwrap().into_data().unwrap(), "partial");
+    }
+
+    #[tokio::test]
+    async fn overdrawn_throttle_delays_next_frame() {
+        let throttle = Throttle::new(&BandwidthLimit { burst: 10, per_second: 1000 });
+        let chunks = [Bytes::from("0123456789"), Bytes::from(vec![b'x'; 50]), Bytes::from("y")];
+        let mut body = Throttled::new(Ready(chunks.into_iter().collect()), Some(throttle));
+        let start = Instant::now();
+        body.frame().await.unwrap().unwrap();
+        body.frame().await.unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(40));
+        // The 50 bytes overdrew the bucket by 50ms worth of bandwidth.
+        body.frame().await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn buffered_keeps_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("digest", "sha-256=abc".parse().unwrap());
+        let frames = vec![Frame::data(Bytes::from_static(b"hello")), Frame::trailers(trailers)];
+        let buffered = Buffered::read(Frames(frames.into())).await.unwrap();
+        assert_eq!(buffered.size_hint().exact(), None);
+        let collected = buffered.clone().collect().await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["digest"], "sha-256=abc");
+        assert_eq!(collected.to_bytes(), "hello");
+
+        let buffered = Buffered::read(Full::new(Bytes::from_static(b"hello"))).await.unwrap();
+        assert_eq!(buffered.size_hint().exact(), Some(5));
+        assert!(buffered.collect().await.unwrap().trailers().is_none());
+    }
+
+    /// Has every chunk ready at once.
+    struct Ready(std::collections::VecDeque<Bytes>);
+
+    impl Body for Ready {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(|chunk| Ok(Frame::data(chunk))))
+        }
+    }
+
+    /// Has every frame ready at once.
+    struct Frames(std::collections::VecDeque<Frame<Bytes>>);
+
+    impl Body for Frames {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    /// Sends a single frame and then never makes progress again.
+    struct Stalled {
+        sent: bool,
+    }
+
+    impl Body for Stalled {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            if self.sent {
+                return Poll::Pending;
+            }
+            self.sent = true;
+            Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(b"partial")))))
+        }
+    }
+}
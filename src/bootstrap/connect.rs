@@ -1,70 +1,76 @@
 use std::fmt::Debug;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::time::Duration;
 
 use http_body_util::combinators::BoxBody;
-use hyper::body::{Bytes, Incoming};
+use hyper::body::Bytes;
 use hyper::upgrade::Upgraded;
-use hyper::{Method, Request, Response};
+use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use tokio::net::TcpStream;
 use tracing::{debug, error, instrument};
 
+use crate::connector::connect_any;
 use crate::error::Error;
-use crate::{empty, uri_to_addr, GatewayUri};
+use crate::gateway_uri::Gateways;
+use crate::resolve::{resolve_uri, Resolver};
+use crate::{empty, GatewayUri};
 
-pub(crate) fn is_connect_request(req: &Request<Incoming>) -> bool {
-    Method::CONNECT == req.method()
-}
+pub(crate) fn is_connect_request<B>(req: &Request<B>) -> bool { Method::CONNECT == req.method() }
 
-#[instrument]
-pub(crate) async fn try_upgrade(
-    req: Request<Incoming>,
-    gateway_origin: Arc<GatewayUri>,
+#[instrument(skip_all)]
+pub(crate) async fn try_upgrade<B: Debug + Send + 'static>(
+    req: Request<B>,
+    gateways: &Gateways,
+    resolver: &dyn Resolver,
+    attempt_delay: Duration,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
-    if let Some(addr) = find_allowable_gateway(&req, &gateway_origin) {
-        tokio::task::spawn(async move {
-            match hyper::upgrade::on(req).await {
-                Ok(upgraded) => {
-                    if let Err(e) = tunnel(upgraded, addr).await {
-                        error!("server io error: {}", e);
-                    };
-                }
-                Err(e) => error!("upgrade error: {}", e),
+    let gateway = find_allowable_gateway(&req, gateways).ok_or_else(|| {
+        error!("CONNECT target is not an allowed gateway: {:?}", req.uri());
+        Error::Denied(StatusCode::FORBIDDEN)
+    })?;
+    let addrs = resolve_uri(gateway, resolver).await.map_err(|e| {
+        error!("Failed to resolve gateway: {}", e);
+        Error::BadGateway
+    })?;
+    tokio::task::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                if let Err(e) = tunnel(upgraded, addrs, attempt_delay).await {
+                    error!("server io error: {}", e);
+                };
             }
-        });
-        Ok(Response::new(empty()))
-    } else {
-        error!("CONNECT host is not socket addr: {:?}", req.uri());
-        Err(Error::BadRequest("CONNECT Host must be a known gateway socket address".to_string()))
-    }
+            Err(e) => error!("upgrade error: {}", e),
+        }
+    });
+    Ok(Response::new(empty()))
 }
 
-/// Create a TCP connection to host:port, build a tunnel between the connection and
-/// the upgraded connection
+/// Create a TCP connection to the first reachable address, build a tunnel between the
+/// connection and the upgraded connection
 #[instrument]
-async fn tunnel(upgraded: Upgraded, addr: SocketAddr) -> std::io::Result<()> {
-    let mut server = TcpStream::connect(addr).await?;
-    let mut upgraded = TokioIo::new(upgraded);
-    let (_, _) = tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
-    Ok(())
+async fn tunnel(
+    upgraded: Upgraded,
+    addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+) -> std::io::Result<()> {
+    let server = connect_any(&addrs, attempt_delay).await?;
+    super::bridge("connect", TokioIo::new(upgraded), server).await
 }
 
-/// Only allow CONNECT requests to the configured OHTTP gateway authority.
-/// This prevents the relay from being used as an arbitrary proxy
+/// Only allow CONNECT requests to the default gateway's authority or one of the configured
+/// connect targets. This prevents the relay from being used as an arbitrary proxy
 /// to any host on the internet.
-#[instrument]
-fn find_allowable_gateway<B>(req: &Request<B>, gateway_origin: &GatewayUri) -> Option<SocketAddr>
+#[instrument(skip_all)]
+fn find_allowable_gateway<'a, B>(req: &Request<B>, gateways: &'a Gateways) -> Option<&'a GatewayUri>
 where
     B: Debug,
 {
-    debug!("req: {:?}, gateway_origin: {:?}", req, gateway_origin);
-    if req.uri().authority() != gateway_origin.authority() {
+    debug!("req: {:?}, gateways: {:?}", req, gateways);
+    let target = req.uri().authority().and_then(|authority| gateways.connect_target(authority));
+    if target.is_none() {
         debug!("CONNECT request to non-gateway authority: {:?}", req.uri());
-        return None;
     }
-
-    uri_to_addr(gateway_origin)
+    target
 }
 
 #[cfg(test)]
@@ -74,9 +80,16 @@ mod test {
     use tracing_subscriber::{self, EnvFilter, FmtSubscriber};
 
     use super::*;
+    use crate::Config;
 
-    static GATEWAY_ORIGIN: Lazy<GatewayUri> =
-        Lazy::new(|| GatewayUri::new(Uri::from_static("https://0.0.0.0")).unwrap());
+    static GATEWAYS: Lazy<Gateways> = Lazy::new(|| {
+        let config = Config {
+            connect_targets: vec![Uri::from_static("https://gateway-2.example:8443")],
+            ..Config::default()
+        };
+        let default = GatewayUri::new(Uri::from_static("https://0.0.0.0"), true).unwrap();
+        Gateways::new(default, &config).unwrap()
+    });
     static INIT: OnceCell<()> = OnceCell::new();
 
     #[test]
@@ -84,7 +97,7 @@ mod test {
         init_tracing();
         let not_gateway_origin = "https://0.0.0.0:4433";
         let req = hyper::Request::builder().uri(not_gateway_origin).body(()).unwrap();
-        let allowable_gateway = find_allowable_gateway(&req, &*GATEWAY_ORIGIN);
+        let allowable_gateway = find_allowable_gateway(&req, &GATEWAYS);
         assert!(allowable_gateway.is_none());
     }
 
@@ -93,7 +106,16 @@ mod test {
         init_tracing();
         // ensure GatewayUri port is defined automatically
         let req = Request::builder().uri("https://0.0.0.0:443").body(()).unwrap();
-        assert!(find_allowable_gateway(&req, &*GATEWAY_ORIGIN).is_some());
+        assert!(find_allowable_gateway(&req, &GATEWAYS).is_some());
+    }
+
+    #[test]
+    fn listed_connect_targets_allowed() {
+        init_tracing();
+        let req = Request::builder().uri("gateway-2.example:8443").body(()).unwrap();
+        assert!(find_allowable_gateway(&req, &GATEWAYS).is_some());
+        let req = Request::builder().uri("gateway-2.example:443").body(()).unwrap();
+        assert!(find_allowable_gateway(&req, &GATEWAYS).is_none());
     }
 
     fn init_tracing() {
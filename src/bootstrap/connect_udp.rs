@@ -0,0 +1,259 @@
+use std::fmt::Debug;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Instant;
+
+use http::uri::Authority;
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONNECTION, UPGRADE};
+use hyper::upgrade::Upgraded;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tracing::{debug, error, info, instrument};
+
+use crate::empty;
+use crate::error::Error;
+use crate::gateway_uri::Gateways;
+use crate::resolve::{resolve_uri, Resolver};
+
+/// The upgrade token and `:protocol` of UDP proxying requests.
+const PROTOCOL: &str = "connect-udp";
+/// The default URI template path, `/.well-known/masque/udp/{target_host}/{target_port}/`.
+const PATH_PREFIX: &str = "/.well-known/masque/udp/";
+const CAPSULE_PROTOCOL: &str = "capsule-protocol";
+/// Capsules of this type carry a datagram.
+const DATAGRAM_CAPSULE: u64 = 0x00;
+/// The largest UDP payload over IPv4.
+const MAX_DATAGRAM: usize = 65_527;
+
+/// Whether `req` asks to proxy UDP, as an HTTP/1.1 upgrade or an HTTP/2 extended `CONNECT`.
+pub(crate) fn is_connect_udp_request<B>(req: &Request<B>) -> bool {
+    match *req.method() {
+        Method::GET => req
+            .headers()
+            .get(UPGRADE)
+            .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(PROTOCOL.as_bytes())),
+        Method::CONNECT => req
+            .extensions()
+            .get::<hyper::ext::Protocol>()
+            .is_some_and(|protocol| protocol.as_str() == PROTOCOL),
+        _ => false,
+    }
+}
+
+/// Proxy UDP to the target named in the request path, if it is the default gateway or one of
+/// the configured connect targets.
+#[instrument(skip_all)]
+pub(crate) async fn try_upgrade<B: Debug + Send + 'static>(
+    req: Request<B>,
+    gateways: &Gateways,
+    resolver: &dyn Resolver,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    let target = target_authority(req.uri().path())
+        .ok_or_else(|| Error::BadRequest("Invalid CONNECT-UDP target".to_string()))?;
+    let gateway = gateways.connect_target(&target).ok_or_else(|| {
+        error!("CONNECT-UDP target is not an allowed gateway: {}", target);
+        Error::Denied(StatusCode::FORBIDDEN)
+    })?;
+    let addrs = resolve_uri(gateway, resolver).await.map_err(|e| {
+        error!("Failed to resolve gateway: {}", e);
+        Error::BadGateway
+    })?;
+    let socket = connect(&addrs).await.map_err(|e| {
+        error!("Failed to open UDP socket to gateway: {}", e);
+        Error::BadGateway
+    })?;
+
+    let mut res = Response::new(empty());
+    if req.method() == Method::GET {
+        *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        res.headers_mut().insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        res.headers_mut().insert(UPGRADE, HeaderValue::from_static(PROTOCOL));
+    }
+    res.headers_mut().insert(CAPSULE_PROTOCOL, HeaderValue::from_static("?1"));
+    tokio::task::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) =>
+                if let Err(e) = tunnel(upgraded, socket).await {
+                    error!("server io error: {}", e);
+                },
+            Err(e) => error!("upgrade error: {}", e),
+        }
+    });
+    Ok(res)
+}
+
+/// The `host:port` a path following the default URI template names. IPv6 hosts have their
+/// colons percent-encoded.
+fn target_authority(path: &str) -> Option<Authority> {
+    let mut segments = path.strip_prefix(PATH_PREFIX)?.split('/');
+    let (host, port) = (segments.next()?, segments.next()?);
+    if !matches!((segments.next(), segments.next()), (None, None) | (Some(""), None)) {
+        return None;
+    }
+    let host = host.replace("%3A", ":").replace("%3a", ":");
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        return None;
+    }
+    let authority = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    Authority::from_str(&authority).ok()
+}
+
+/// A UDP socket connected to the first of `addrs`.
+async fn connect(addrs: &[SocketAddr]) -> io::Result<UdpSocket> {
+    let addr = *addrs.first().ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Forward datagrams between the client's capsules and the gateway until the client closes the
+/// stream.
+#[instrument(skip_all)]
+async fn tunnel(upgraded: Upgraded, socket: UdpSocket) -> io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+    let (mut bytes_client_to_gateway, mut bytes_gateway_to_client) = (0u64, 0u64);
+    let start = Instant::now();
+    info!(tunnel = "connect-udp", "bootstrap tunnel opened");
+    let to_gateway = async {
+        while let Some(datagram) = read_datagram(&mut reader).await? {
+            socket.send(&datagram).await?;
+            bytes_client_to_gateway += datagram.len() as u64;
+        }
+        Ok(())
+    };
+    let to_client = async {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            write_datagram(&mut writer, &buf[..len]).await?;
+            bytes_gateway_to_client += len as u64;
+        }
+    };
+    let result: io::Result<()> = tokio::select! {
+        result = to_gateway => result,
+        result = to_client => result,
+    };
+    let reason = match &result {
+        Ok(_) => "eof".to_string(),
+        Err(e) => e.to_string(),
+    };
+    info!(
+        tunnel = "connect-udp",
+        bytes_client_to_gateway,
+        bytes_gateway_to_client,
+        duration_ms = start.elapsed().as_millis() as u64,
+        reason = %reason,
+        "bootstrap tunnel closed"
+    );
+    result
+}
+
+/// Read capsules until one carries a UDP payload, skipping capsules of other types and
+/// datagrams with other context IDs. `None` once the stream ends between capsules.
+async fn read_datagram<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    loop {
+        let capsule_type = match read_varint(reader).await {
+            Ok(capsule_type) => capsule_type,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let len = read_varint(reader).await?;
+        if capsule_type != DATAGRAM_CAPSULE {
+            debug!("Skipping capsule of type {}", capsule_type);
+            tokio::io::copy(&mut (&mut *reader).take(len), &mut tokio::io::sink()).await?;
+            continue;
+        }
+        if len > (MAX_DATAGRAM + 8) as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "datagram capsule too large"));
+        }
+        let mut value = vec![0; len as usize];
+        reader.read_exact(&mut value).await?;
+        let mut payload = &value[..];
+        let context_id = read_varint(&mut payload).await?;
+        if context_id == 0 {
+            return Ok(Some(payload.to_vec()));
+        }
+    }
+}
+
+/// Write `payload` as a datagram capsule with context ID 0.
+async fn write_datagram<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let mut capsule = Vec::with_capacity(payload.len() + 6);
+    put_varint(&mut capsule, DATAGRAM_CAPSULE);
+    put_varint(&mut capsule, payload.len() as u64 + 1);
+    put_varint(&mut capsule, 0);
+    capsule.extend_from_slice(payload);
+    writer.write_all(&capsule).await?;
+    writer.flush().await
+}
+
+/// Read a QUIC variable-length integer.
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<u64> {
+    let first = reader.read_u8().await?;
+    let mut value = u64::from(first & 0x3f);
+    for _ in 1..1 << (first >> 6) {
+        value = value << 8 | u64::from(reader.read_u8().await?);
+    }
+    Ok(value)
+}
+
+/// Append `value` as a QUIC variable-length integer.
+fn put_varint(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn targets_follow_the_default_template() {
+        let target = |path| target_authority(path).map(|authority| authority.to_string());
+        assert_eq!(
+            target("/.well-known/masque/udp/gateway.example/443/").unwrap(),
+            "gateway.example:443"
+        );
+        assert_eq!(target("/.well-known/masque/udp/192.0.2.6/443").unwrap(), "192.0.2.6:443");
+        assert_eq!(
+            target("/.well-known/masque/udp/2001%3Adb8%3A%3A1/443/").unwrap(),
+            "[2001:db8::1]:443"
+        );
+        assert!(target("/.well-known/masque/udp/gateway.example/").is_none());
+        assert!(target("/.well-known/masque/udp/gateway.example/https/").is_none());
+        assert!(target("/.well-known/masque/udp/gateway.example/443/extra").is_none());
+        assert!(target("/gateway.example/443/").is_none());
+    }
+
+    #[tokio::test]
+    async fn datagram_capsules_round_trip() {
+        let (mut client, mut relay) = tokio::io::duplex(1 << 17);
+        // A capsule of an unknown type and a datagram for another context are skipped.
+        client.write_all(&[0x17, 0x02, 0xff, 0xff, 0x00, 0x02, 0x01, 0xaa]).await.unwrap();
+        let large = vec![7; 20_000];
+        for payload in [&b"key request"[..], &large] {
+            write_datagram(&mut client, payload).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+        assert_eq!(read_datagram(&mut relay).await.unwrap().unwrap(), b"key request");
+        assert_eq!(read_datagram(&mut relay).await.unwrap().unwrap(), large);
+        assert!(read_datagram(&mut relay).await.unwrap().is_none());
+    }
+}
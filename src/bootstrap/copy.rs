@@ -0,0 +1,234 @@
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Bytes buffered in each direction of a tunnel, allocated once when it opens.
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Copy between `a` and `b` in both directions until both have reached EOF, shutting down the
+/// write side of each once the other has nothing more to send.
+///
+/// Unlike [`tokio::io::copy_bidirectional`], each direction keeps reading into a larger ring
+/// buffer while the writer catches up, and writes data that wraps around its end in one
+/// vectored write.
+pub(crate) fn copy_bidirectional<'a, A, B>(
+    a: &'a mut A,
+    b: &'a mut B,
+) -> CopyBidirectional<'a, A, B>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    CopyBidirectional { a, b, a_to_b: Some(RingBuf::new()), b_to_a: Some(RingBuf::new()) }
+}
+
+pub(crate) struct CopyBidirectional<'a, A, B> {
+    a: &'a mut A,
+    b: &'a mut B,
+    /// Dropped once the direction is done, freeing its buffer.
+    a_to_b: Option<RingBuf>,
+    b_to_a: Option<RingBuf>,
+}
+
+impl<A, B> Future for CopyBidirectional<'_, A, B>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(buf) = &mut this.a_to_b {
+            if buf.poll_copy(cx, Pin::new(&mut *this.a), Pin::new(&mut *this.b))?.is_ready() {
+                this.a_to_b = None;
+            }
+        }
+        if let Some(buf) = &mut this.b_to_a {
+            if buf.poll_copy(cx, Pin::new(&mut *this.b), Pin::new(&mut *this.a))?.is_ready() {
+                this.b_to_a = None;
+            }
+        }
+        match this.a_to_b.is_none() && this.b_to_a.is_none() {
+            true => Poll::Ready(Ok(())),
+            false => Poll::Pending,
+        }
+    }
+}
+
+struct RingBuf {
+    buf: Box<[u8]>,
+    start: usize,
+    len: usize,
+    read_done: bool,
+    need_flush: bool,
+}
+
+impl RingBuf {
+    fn new() -> Self {
+        Self {
+            buf: vec![0; BUF_SIZE].into(),
+            start: 0,
+            len: 0,
+            read_done: false,
+            need_flush: false,
+        }
+    }
+
+    /// Copy from `reader` to `writer` until `reader` reaches EOF and everything read is written,
+    /// then shut `writer` down.
+    fn poll_copy<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut reader: Pin<&mut R>,
+        mut writer: Pin<&mut W>,
+    ) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+    {
+        loop {
+            // Keep reading while there is room, so a slow writer doesn't hold up the reader.
+            while !self.read_done && self.len < self.buf.len() {
+                if self.poll_fill(cx, reader.as_mut())?.is_pending() {
+                    break;
+                }
+            }
+            if self.len == 0 {
+                if self.need_flush {
+                    if writer.as_mut().poll_flush(cx)?.is_pending() {
+                        return Poll::Pending;
+                    }
+                    self.need_flush = false;
+                }
+                if self.read_done {
+                    return writer.as_mut().poll_shutdown(cx);
+                }
+                // The reader is pending and has registered for wakeup.
+                return Poll::Pending;
+            }
+            if self.poll_drain(cx, writer.as_mut())?.is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn poll_fill<R: AsyncRead + ?Sized>(
+        &mut self,
+        cx: &mut Context<'_>,
+        reader: Pin<&mut R>,
+    ) -> Poll<io::Result<()>> {
+        let tail = (self.start + self.len) % self.buf.len();
+        let end = if tail >= self.start { self.buf.len() } else { self.start };
+        let mut read_buf = ReadBuf::new(&mut self.buf[tail..end]);
+        if reader.poll_read(cx, &mut read_buf)?.is_pending() {
+            return Poll::Pending;
+        }
+        match read_buf.filled().len() {
+            0 => self.read_done = true,
+            n => self.len += n,
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_drain<W: AsyncWrite + ?Sized>(
+        &mut self,
+        cx: &mut Context<'_>,
+        writer: Pin<&mut W>,
+    ) -> Poll<io::Result<()>> {
+        let end = self.start + self.len;
+        let (head, wrapped) = match end > self.buf.len() {
+            true => (&self.buf[self.start..], &self.buf[..end - self.buf.len()]),
+            false => (&self.buf[self.start..end], &self.buf[..0]),
+        };
+        let written = match !wrapped.is_empty() && writer.is_write_vectored() {
+            true => writer.poll_write_vectored(cx, &[IoSlice::new(head), IoSlice::new(wrapped)]),
+            false => writer.poll_write(cx, head),
+        }?;
+        let written = match written {
+            Poll::Ready(written) => written,
+            Poll::Pending => return Poll::Pending,
+        };
+        if written == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        self.need_flush = true;
+        self.len -= written;
+        self.start = match self.len {
+            // Start over at the front so the next read gets the whole buffer.
+            0 => 0,
+            _ => (self.start + written) % self.buf.len(),
+        };
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn copies_more_than_a_buffer_each_way() {
+        let upload: Vec<u8> = (0..3 * BUF_SIZE + 123).map(|i| i as u8).collect();
+        let download: Vec<u8> = upload.iter().rev().copied().collect();
+        // Small pipes, so the ring buffers fill up and wrap around.
+        let (client, mut client_io) = tokio::io::duplex(1000);
+        let (mut gateway, mut gateway_io) = tokio::io::duplex(7000);
+        let tunnel =
+            tokio::spawn(async move { copy_bidirectional(&mut client_io, &mut gateway_io).await });
+
+        let client_side = {
+            let upload = upload.clone();
+            tokio::spawn(async move {
+                let (mut read, mut write) = tokio::io::split(client);
+                let send = async {
+                    write.write_all(&upload).await.unwrap();
+                    write.shutdown().await.unwrap();
+                };
+                let mut received = Vec::new();
+                tokio::join!(send, read.read_to_end(&mut received)).1.unwrap();
+                received
+            })
+        };
+        let (mut read, mut write) = tokio::io::split(&mut gateway);
+        let send = async {
+            write.write_all(&download).await.unwrap();
+            write.shutdown().await.unwrap();
+        };
+        let mut received = Vec::new();
+        tokio::join!(send, read.read_to_end(&mut received)).1.unwrap();
+
+        assert_eq!(received, upload);
+        assert_eq!(client_side.await.unwrap(), download);
+        tunnel.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn wrapped_data_is_written_vectored() {
+        let mut buf = RingBuf::new();
+        buf.start = BUF_SIZE - 3;
+        buf.len = 5;
+        buf.buf[BUF_SIZE - 3..].copy_from_slice(b"abc");
+        buf.buf[..2].copy_from_slice(b"de");
+        let mut written = Vec::new();
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        assert!(buf.poll_drain(&mut cx, Pin::new(&mut written)).is_ready());
+        assert_eq!(written, b"abcde");
+        assert_eq!((buf.start, buf.len), (0, 0));
+    }
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+}
@@ -1,33 +1,242 @@
-use std::sync::Arc;
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+use std::fmt::Debug;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
 
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
 use http_body_util::combinators::BoxBody;
-use hyper::body::{Bytes, Incoming};
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+use hyper::body::Bytes;
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
 use hyper::{Request, Response};
-use tracing::instrument;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{info, instrument};
 
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
 use crate::error::Error;
-use crate::GatewayUri;
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+use crate::gateway_uri::Gateways;
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+use crate::Config;
 
 #[cfg(feature = "connect-bootstrap")]
 pub mod connect;
 
+mod copy;
+
+#[cfg(feature = "connect-udp-bootstrap")]
+pub(crate) mod connect_udp;
+
 #[cfg(feature = "ws-bootstrap")]
 pub mod ws;
 
-#[instrument]
-pub(crate) async fn handle_ohttp_keys(
-    mut req: Request<Incoming>,
-    gateway_origin: Arc<GatewayUri>,
+#[cfg(feature = "wt-bootstrap")]
+pub(crate) mod wt;
+
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+#[instrument(skip_all)]
+pub(crate) async fn handle_ohttp_keys<B: Debug + Send + 'static>(
+    mut req: Request<B>,
+    gateways: &Gateways,
+    config: &Config,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    let resolver = config.resolver.as_ref();
+    let attempt_delay = config.connection_attempt_delay;
+    #[cfg(feature = "connect-udp-bootstrap")]
+    if connect_udp::is_connect_udp_request(&req) {
+        return connect_udp::try_upgrade(req, gateways, resolver).await;
+    }
+
+    #[cfg(feature = "ws-bootstrap")]
+    if ws::is_extended_connect_request(&req) {
+        let gateway = gateways.default_gateway();
+        return ws::try_extended_connect(
+            req,
+            gateway,
+            resolver,
+            attempt_delay,
+            &config.ws_bootstrap,
+        )
+        .await;
+    }
+
     #[cfg(feature = "connect-bootstrap")]
     if connect::is_connect_request(&req) {
-        return connect::try_upgrade(req, gateway_origin).await;
+        return connect::try_upgrade(req, gateways, resolver, attempt_delay).await;
     }
 
     #[cfg(feature = "ws-bootstrap")]
     if ws::is_websocket_request(&req) {
-        return ws::try_upgrade(&mut req, gateway_origin).await;
+        let gateway = gateways.default_gateway();
+        return ws::try_upgrade(&mut req, gateway, resolver, attempt_delay, &config.ws_bootstrap)
+            .await;
     }
 
     Err(Error::BadRequest("Not a supported proxy upgrade request".to_string()))
 }
+
+/// Copy bytes between a bootstrap client and the gateway until both sides are closed.
+///
+/// Structured events are emitted when the tunnel opens and closes. The close event carries
+/// byte counts in each direction, the duration, and the close reason, but never any payload.
+#[instrument(skip(client, gateway))]
+pub(crate) async fn bridge<C, G>(kind: &'static str, client: C, gateway: G) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    G: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client = CountingIo::new(client);
+    let mut gateway = CountingIo::new(gateway);
+    let start = Instant::now();
+    info!(tunnel = kind, "bootstrap tunnel opened");
+    let result = copy::copy_bidirectional(&mut client, &mut gateway).await;
+    let reason = match &result {
+        Ok(_) => "eof".to_string(),
+        Err(e) => e.to_string(),
+    };
+    info!(
+        tunnel = kind,
+        bytes_client_to_gateway = client.bytes_read,
+        bytes_gateway_to_client = gateway.bytes_read,
+        duration_ms = start.elapsed().as_millis() as u64,
+        reason = %reason,
+        "bootstrap tunnel closed"
+    );
+    result
+}
+
+/// Counts the bytes read from the wrapped stream so tunnel statistics survive I/O errors.
+struct CountingIo<S> {
+    inner: S,
+    bytes_read: u64,
+}
+
+impl<S> CountingIo<S> {
+    fn new(inner: S) -> Self { Self { inner, bytes_read: 0 } }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingIo<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let self_mut = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self_mut.inner).poll_read(cx, buf);
+        self_mut.bytes_read += (buf.filled().len() - before) as u64;
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, data)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool { self.inner.is_write_vectored() }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    use super::*;
+
+    type Events = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    /// Records the fields of every event so tests can assert on them.
+    struct CaptureLayer(Events);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for CaptureLayer {
+        fn on_event(&self, event: &tracing::Event<'_>, _: LayerContext<'_, S>) {
+            let mut fields = HashMap::new();
+            event.record(&mut FieldVisitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    #[tokio::test]
+    async fn tunnel_lifecycle_events() {
+        let events = Events::default();
+        let subscriber = tracing_subscriber::registry().with(CaptureLayer(events.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (mut client, client_io) = tokio::io::duplex(1024);
+        let (mut gateway, gateway_io) = tokio::io::duplex(1024);
+        let tunnel = tokio::spawn(bridge("test", client_io, gateway_io));
+
+        client.write_all(b"client hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        gateway.write_all(b"gateway hi").await.unwrap();
+        gateway.shutdown().await.unwrap();
+
+        let mut from_client = Vec::new();
+        gateway.read_to_end(&mut from_client).await.unwrap();
+        let mut from_gateway = Vec::new();
+        client.read_to_end(&mut from_gateway).await.unwrap();
+        tunnel.await.unwrap().unwrap();
+        assert_eq!(from_client, b"client hello");
+        assert_eq!(from_gateway, b"gateway hi");
+
+        let events = events.lock().unwrap();
+        let opened = events
+            .iter()
+            .find(|e| e["message"] == "bootstrap tunnel opened")
+            .expect("missing open event");
+        assert_eq!(opened["tunnel"], "test");
+        let closed = events
+            .iter()
+            .find(|e| e["message"] == "bootstrap tunnel closed")
+            .expect("missing close event");
+        assert_eq!(closed["tunnel"], "test");
+        assert_eq!(closed["bytes_client_to_gateway"], "12");
+        assert_eq!(closed["bytes_gateway_to_client"], "10");
+        assert_eq!(closed["reason"], "eof");
+        assert!(closed.contains_key("duration_ms"));
+    }
+}
@@ -1,55 +1,170 @@
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use futures::{Sink, SinkExt, StreamExt};
 use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
-use hyper::body::{Bytes, Incoming};
-use hyper::{Request, Response};
-use hyper_tungstenite::HyperWebsocket;
+use hyper::body::Bytes;
+use hyper::ext::Protocol;
+use hyper::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use hyper::{Method, Request, Response};
+use hyper_util::rt::TokioIo;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio::time::{Instant, Sleep};
+use tokio_tungstenite::tungstenite::protocol::{Message, Role, WebSocketConfig};
 use tokio_tungstenite::{tungstenite, WebSocketStream};
 use tracing::{error, instrument};
 
+use crate::connector::connect_any;
 use crate::error::Error;
 use crate::gateway_uri::GatewayUri;
-use crate::uri_to_addr;
+use crate::resolve::{resolve_uri, Resolver};
+use crate::{empty, WsBootstrap};
 
-pub(crate) fn is_websocket_request(req: &Request<Incoming>) -> bool {
+pub(crate) fn is_websocket_request<B>(req: &Request<B>) -> bool {
     hyper_tungstenite::is_upgrade_request(req)
 }
 
-#[instrument]
-pub(crate) async fn try_upgrade(
-    req: &mut Request<Incoming>,
+#[instrument(skip_all)]
+pub(crate) async fn try_upgrade<B>(
+    req: &mut Request<B>,
     gateway_origin: Arc<GatewayUri>,
+    resolver: &dyn Resolver,
+    attempt_delay: Duration,
+    settings: &WsBootstrap,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
-    let (res, websocket) = hyper_tungstenite::upgrade(req, None)
+    let subprotocol = negotiate_subprotocol(req, settings.subprotocol.as_deref())?;
+    let (res, websocket) = hyper_tungstenite::upgrade(req, Some(websocket_config(settings)))
         .map_err(|e| Error::BadRequest(format!("Error upgrading to websocket: {}", e)))?;
-    let gateway_addr = uri_to_addr(&gateway_origin).ok_or(Error::InternalServerError)?;
+    let gateway_addrs = resolve_gateway(&gateway_origin, resolver).await?;
+    spawn_tunnel(websocket, gateway_addrs, attempt_delay, settings.idle_timeout);
+    let (mut parts, body) = res.into_parts();
+    if let Some(subprotocol) = subprotocol {
+        parts.headers.insert(SEC_WEBSOCKET_PROTOCOL, subprotocol);
+    }
+    let boxbody = body.map_err(|never| match never {}).boxed();
+    Ok(Response::from_parts(parts, boxbody))
+}
+
+/// Whether `req` opens a WebSocket, or another protocol, over an HTTP/2 stream as described in
+/// RFC 8441.
+pub(crate) fn is_extended_connect_request<B>(req: &Request<B>) -> bool {
+    req.method() == Method::CONNECT && req.extensions().get::<Protocol>().is_some()
+}
+
+/// Accept a WebSocket opened with HTTP/2 extended CONNECT and tunnel it to the gateway like an
+/// HTTP/1.1 upgrade.
+#[instrument(skip_all)]
+pub(crate) async fn try_extended_connect<B: Send + 'static>(
+    req: Request<B>,
+    gateway_origin: Arc<GatewayUri>,
+    resolver: &dyn Resolver,
+    attempt_delay: Duration,
+    settings: &WsBootstrap,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    if req.extensions().get::<Protocol>().map(Protocol::as_str) != Some("websocket") {
+        return Err(Error::BadRequest("Unsupported extended CONNECT protocol".to_owned()));
+    }
+    let subprotocol = negotiate_subprotocol(&req, settings.subprotocol.as_deref())?;
+    let gateway_addrs = resolve_gateway(&gateway_origin, resolver).await?;
+    let config = websocket_config(settings);
+    let websocket = async move {
+        let upgraded = TokioIo::new(hyper::upgrade::on(req).await?);
+        Ok::<_, hyper::Error>(
+            WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config)).await,
+        )
+    };
+    spawn_tunnel(websocket, gateway_addrs, attempt_delay, settings.idle_timeout);
+    let mut res = Response::new(empty());
+    if let Some(subprotocol) = subprotocol {
+        res.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, subprotocol);
+    }
+    Ok(res)
+}
+
+fn websocket_config(settings: &WsBootstrap) -> WebSocketConfig {
+    let mut config = WebSocketConfig::default();
+    config.max_frame_size = settings.max_frame_size.or(config.max_frame_size);
+    config.max_message_size = settings.max_message_size.or(config.max_message_size);
+    config
+}
+
+async fn resolve_gateway(
+    gateway_origin: &GatewayUri,
+    resolver: &dyn Resolver,
+) -> Result<Vec<SocketAddr>, Error> {
+    resolve_uri(gateway_origin, resolver).await.map_err(|e| {
+        error!("Failed to resolve gateway: {}", e);
+        Error::BadGateway
+    })
+}
+
+/// Tunnel the WebSocket `websocket` resolves to once the client has it, see [`serve_websocket`].
+fn spawn_tunnel<F, S, E>(
+    websocket: F,
+    gateway_addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    idle: Option<Duration>,
+) where
+    F: Future<Output = Result<WebSocketStream<S>, E>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
     tokio::spawn(async move {
-        if let Err(e) = serve_websocket(websocket, gateway_addr).await {
+        if let Err(e) = serve_websocket(websocket, gateway_addrs, attempt_delay, idle).await {
             error!("Error in websocket connection: {e}");
         }
     });
-    let (parts, body) = res.into_parts();
-    let boxbody = body.map_err(|never| match never {}).boxed();
-    Ok(Response::from_parts(parts, boxbody))
+}
+
+/// The subprotocol to accept `req` with, refusing it if it does not offer `required`.
+fn negotiate_subprotocol<B>(
+    req: &Request<B>,
+    required: Option<&str>,
+) -> Result<Option<HeaderValue>, Error> {
+    let required = match required {
+        Some(required) => required,
+        None => return Ok(None),
+    };
+    let offered = req
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == required);
+    if !offered {
+        return Err(Error::BadRequest(format!("WebSocket subprotocol {} required", required)));
+    }
+    HeaderValue::from_str(required)
+        .map(Some)
+        .map_err(|_| Error::BadRequest("Invalid WebSocket subprotocol".to_owned()))
 }
 
 /// Stream WebSocket frames from the client to the gateway server's TCP socket and vice versa.
-#[instrument]
-async fn serve_websocket(
-    websocket: HyperWebsocket,
-    gateway_addr: SocketAddr,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let mut tcp_stream = tokio::net::TcpStream::connect(gateway_addr).await?;
+#[instrument(skip(websocket))]
+async fn serve_websocket<F, S, E>(
+    websocket: F,
+    gateway_addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    idle_timeout: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+where
+    F: Future<Output = Result<WebSocketStream<S>, E>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let tcp_stream = connect_any(&gateway_addrs, attempt_delay).await?;
     let mut ws_io = WsIo::new(websocket.await?);
-    let (_, _) = tokio::io::copy_bidirectional(&mut ws_io, &mut tcp_stream).await?;
+    if let Some(timeout) = idle_timeout {
+        ws_io = ws_io.idle_timeout(timeout);
+    }
+    super::bridge("ws", ws_io, tcp_stream).await?;
     Ok(())
 }
 
@@ -59,6 +174,17 @@ where
 {
     ws_stream: WebSocketStream<S>,
     read_buffer: Vec<u8>,
+    idle: Option<Idle>,
+}
+
+/// When a tunnel is closed for lack of data.
+struct Idle {
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl Idle {
+    fn reset(&mut self) { self.deadline.as_mut().reset(Instant::now() + self.timeout) }
 }
 
 impl<S> WsIo<S>
@@ -66,7 +192,14 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     pub fn new(ws_stream: WebSocketStream<S>) -> Self {
-        WsIo { ws_stream, read_buffer: Vec::new() }
+        WsIo { ws_stream, read_buffer: Vec::new(), idle: None }
+    }
+
+    /// Fail reads with [`io::ErrorKind::TimedOut`] once no data was read or written for
+    /// `timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle = Some(Idle { timeout, deadline: Box::pin(tokio::time::sleep(timeout)) });
+        self
     }
 }
 
@@ -80,6 +213,11 @@ where
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
         let self_mut = self.get_mut();
+        if let Some(idle) = &mut self_mut.idle {
+            if idle.deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "Tunnel idle")));
+            }
+        }
 
         // If the read buffer has data, use it first.
         if !self_mut.read_buffer.is_empty() {
@@ -92,6 +230,9 @@ where
         match self_mut.ws_stream.poll_next_unpin(cx) {
             Poll::Ready(Some(Ok(message))) => match message {
                 Message::Binary(data) => {
+                    if let Some(idle) = &mut self_mut.idle {
+                        idle.reset();
+                    }
                     self_mut.read_buffer.extend_from_slice(&data);
                     let len = std::cmp::min(buf.remaining(), self_mut.read_buffer.len());
                     buf.put_slice(&self_mut.read_buffer[..len]);
@@ -138,9 +279,13 @@ where
     ) -> Poll<Result<usize, io::Error>> {
         let self_mut = self.get_mut();
         match Pin::new(&mut self_mut.ws_stream).poll_ready(cx) {
-            Poll::Ready(Ok(())) =>
+            Poll::Ready(Ok(())) => {
+                if let Some(idle) = &mut self_mut.idle {
+                    idle.reset();
+                }
                 start_send(&mut self_mut.ws_stream, Message::Binary(data.to_vec()))
-                    .map(|r| r.map(|_| data.len())),
+                    .map(|r| r.map(|_| data.len()))
+            }
             Poll::Ready(Err(e)) => Poll::Ready(Err(map_ws_error(e))),
             Poll::Pending => Poll::Pending,
         }
@@ -165,3 +310,33 @@ fn start_send(
 fn map_ws_error(e: tungstenite::Error) -> io::Error {
     io::Error::new(io::ErrorKind::BrokenPipe, format!("Tungstenite error: {}", e))
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn upgrade_request(protocols: &'static str) -> Request<()> {
+        Request::builder().header(SEC_WEBSOCKET_PROTOCOL, protocols).body(()).unwrap()
+    }
+
+    #[test]
+    fn subprotocol_required() {
+        let required = Some(WsBootstrap::SUBPROTOCOL);
+        let accepted = negotiate_subprotocol(&upgrade_request("chat, ohttp-bootstrap"), required);
+        assert_eq!(accepted.unwrap().unwrap(), WsBootstrap::SUBPROTOCOL);
+        assert!(negotiate_subprotocol(&upgrade_request("chat"), required).is_err());
+        assert!(negotiate_subprotocol(&Request::new(()), required).is_err());
+        assert_eq!(negotiate_subprotocol(&Request::new(()), None).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn idle_tunnel_times_out() {
+        let (_client, server) = tokio::io::duplex(64);
+        let ws_stream = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut ws_io = WsIo::new(ws_stream).idle_timeout(Duration::from_millis(50));
+        let err = ws_io.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
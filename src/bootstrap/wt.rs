@@ -0,0 +1,107 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use h3::ext::Protocol;
+use h3::server::{Connection, RequestStream};
+use h3_webtransport::server::{AcceptedBi, WebTransportSession};
+use hyper::body::Bytes;
+use hyper::{Method, Request};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{error, instrument};
+
+use crate::body::BoxError;
+use crate::connector::connect_any;
+use crate::error::Error;
+use crate::resolve::resolve_uri;
+use crate::{authorize, quic, rate_limit, Relay};
+
+pub(crate) fn is_webtransport_request<B>(req: &Request<B>) -> bool {
+    req.method() == Method::CONNECT
+        && req.extensions().get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT)
+}
+
+/// Accept the WebTransport session `req` asks for and tunnel each bidirectional stream the
+/// client opens in it to the default gateway. Requests sent within the session are relayed as
+/// usual.
+#[instrument(skip_all)]
+pub(crate) async fn serve_session(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    conn: Connection<h3_quinn::Connection, Bytes>,
+    peer_addr: Option<SocketAddr>,
+    relay: Arc<Relay>,
+) -> Result<(), BoxError> {
+    let reloadable = relay.reloadable();
+    let gateway_addrs = async {
+        rate_limit(reloadable.rate_limiter.as_deref(), peer_addr)?;
+        authorize(&req, peer_addr, relay.config.authorizer.as_ref()).await?;
+        let gateway = reloadable.gateways.default_gateway();
+        resolve_uri(&gateway, relay.config.resolver.as_ref()).await.map_err(|e| {
+            error!("Failed to resolve gateway: {}", e);
+            Error::BadGateway
+        })
+    }
+    .await;
+    let gateway_addrs = match gateway_addrs {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            let res = match relay.config.problem_details {
+                true => e.to_problem_response(None),
+                false => e.to_response(),
+            };
+            return quic::respond(&mut stream, res).await;
+        }
+    };
+    let session = WebTransportSession::accept(req, stream, conn).await?;
+    let shutdown = relay.config.shutdown.clone();
+    loop {
+        let accepted = tokio::select! {
+            accepted = session.accept_bi() => accepted?,
+            _ = shutdown.cancelled() => break,
+        };
+        match accepted {
+            Some(AcceptedBi::BidiStream(_, stream)) => {
+                let gateway_addrs = gateway_addrs.clone();
+                let attempt_delay = relay.config.connection_attempt_delay;
+                tokio::spawn(async move {
+                    if let Err(e) = tunnel(stream, gateway_addrs, attempt_delay).await {
+                        error!("server io error: {}", e);
+                    }
+                });
+            }
+            Some(AcceptedBi::Request(req, stream)) => {
+                tokio::spawn(quic::serve_request(req, stream, peer_addr, relay.clone()));
+            }
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Create a TCP connection to the first reachable gateway address and bridge it with `stream`.
+async fn tunnel<S>(stream: S, addrs: Vec<SocketAddr>, attempt_delay: Duration) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let gateway = connect_any(&addrs, attempt_delay).await?;
+    super::bridge("wt", stream, gateway).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_webtransport_connect_is_a_session() {
+        let mut req = Request::builder().method(Method::CONNECT).body(()).unwrap();
+        assert!(!is_webtransport_request(&req));
+        req.extensions_mut().insert(Protocol::CONNECT_UDP);
+        assert!(!is_webtransport_request(&req));
+        req.extensions_mut().insert(Protocol::WEB_TRANSPORT);
+        assert!(is_webtransport_request(&req));
+        *req.method_mut() = Method::GET;
+        assert!(!is_webtransport_request(&req));
+    }
+}
@@ -0,0 +1,662 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::{HeaderName, Uri};
+use rustls::ServerConfig;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::{Authorizer, StaticToken};
+use crate::hook::RelayHook;
+use crate::resolve::Resolver;
+use crate::select::GatewaySelector;
+#[cfg(feature = "acme")]
+use crate::Acme;
+#[cfg(feature = "tor-listener")]
+use crate::OnionService;
+#[cfg(feature = "tor-client")]
+use crate::TorDirs;
+#[cfg(feature = "ws-bootstrap")]
+use crate::WsBootstrap;
+use crate::{
+    AccessLog, Admin, BandwidthLimit, CircuitBreaker, ClientIdentity, Config, ContentEncoding,
+    Cors, DeniedGateway, DnsCache, GatewayConfig, GatewayPool, HealthCheck, Http1Server, Jitter,
+    Listen, Listening, OhttpKeys, OnBound, Padding, PathRewrite, RateLimit, RedirectPolicy,
+    RelayError, RelayEvent, Reload, Retry, Roots, SocketFile, SpkiPin, Streaming, TcpOptions,
+    UpstreamTls, DEFAULT_PORT,
+};
+
+/// Configures and runs a relay.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// ohttp_relay::Builder::new("https://payjo.in".parse()?)
+///     .port(3000)
+///     .body_read_timeout(std::time::Duration::from_secs(10))
+///     .serve()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Builder {
+    gateway_origin: Uri,
+    /// The first is the one [`Builder::port`] and its siblings replace.
+    /// Each with the bandwidth limit it overrides [`Config::bandwidth_limit`] with, if any.
+    listeners: Vec<(Listen, Option<BandwidthLimit>)>,
+    tls: Option<Arc<ServerConfig>>,
+    #[cfg(feature = "acme")]
+    acme: Option<Acme>,
+    config: Config,
+}
+
+impl Builder {
+    /// A relay to `gateway_origin` listening on TCP port [`DEFAULT_PORT`] of all IPv4
+    /// interfaces with the default [`Config`].
+    pub fn new(gateway_origin: Uri) -> Self {
+        Self {
+            gateway_origin,
+            listeners: vec![(Listen::Tcp(SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))), None)],
+            tls: None,
+            #[cfg(feature = "acme")]
+            acme: None,
+            config: Config::default(),
+        }
+    }
+
+    /// Listen on all IPv4 interfaces at TCP `port`.
+    pub fn port(self, port: u16) -> Self { self.bind_addr(SocketAddr::from(([0, 0, 0, 0], port))) }
+
+    /// Listen on TCP `addr`, e.g. loopback only, a specific interface, or an IPv6 address.
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.listeners[0].0 = Listen::Tcp(addr);
+        self
+    }
+
+    /// Listen on all IPv4 and IPv6 interfaces at TCP `port`, with one dual-stack socket or, where
+    /// the OS has none, a socket for each.
+    pub fn dual_stack(mut self, port: u16) -> Self {
+        self.listeners[0].0 = Listen::DualStack(port);
+        self
+    }
+
+    /// Listen on a unix socket at `path` instead of a TCP port.
+    #[cfg(unix)]
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.listeners[0].0 = Listen::Socket(path.into());
+        self
+    }
+
+    /// Serve on the socket passed by systemd socket activation instead of binding one.
+    /// See [`crate::listen_activated`].
+    #[cfg(unix)]
+    pub fn socket_activated(mut self) -> Self {
+        self.listeners[0].0 = Listen::Activated;
+        self
+    }
+
+    /// Listen on the Windows named pipe `name` instead of a TCP port.
+    /// See [`crate::listen_named_pipe`].
+    #[cfg(windows)]
+    pub fn named_pipe(mut self, name: impl Into<String>) -> Self {
+        self.listeners[0].0 = Listen::NamedPipe(name.into());
+        self
+    }
+
+    /// Publish the relay as a Tor onion service instead of listening on a port.
+    /// See [`crate::listen_onion`].
+    #[cfg(feature = "tor-listener")]
+    pub fn onion_service(mut self, service: OnionService) -> Self {
+        self.listeners[0].0 = Listen::Onion(service);
+        self
+    }
+
+    /// Also listen on `listen`, serving it alongside the others against the same gateways,
+    /// limits and metrics. All listeners shut down together, and once one fails the others
+    /// stop too. With [`Builder::tls`], TLS is terminated on the TCP listeners only.
+    pub fn add_listener(mut self, listen: Listen) -> Self {
+        self.listeners.push((listen, None));
+        self
+    }
+
+    /// Like [`Builder::add_listener`], but throttle the connections `listen` accepts to `limit`
+    /// instead of [`Config::bandwidth_limit`].
+    pub fn add_listener_with_bandwidth_limit(
+        mut self,
+        listen: Listen,
+        limit: BandwidthLimit,
+    ) -> Self {
+        self.listeners.push((listen, Some(limit)));
+        self
+    }
+
+    /// Terminate TLS on accepted TCP connections.
+    pub fn tls(mut self, tls_config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(tls_config);
+        self
+    }
+
+    /// Terminate TLS on accepted TCP connections with certificates obtained and renewed over
+    /// ACME, instead of those of [`Builder::tls`].
+    #[cfg(feature = "acme")]
+    pub fn acme(mut self, acme: Acme) -> Self {
+        self.acme = Some(acme);
+        self
+    }
+
+    /// Replace every option at once.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// See [`Config::enforce_content_length`].
+    pub fn enforce_content_length(mut self, enforce: bool) -> Self {
+        self.config.enforce_content_length = enforce;
+        self
+    }
+
+    /// See [`Config::roots`].
+    pub fn roots(mut self, roots: Roots) -> Self {
+        self.config.roots = roots;
+        self
+    }
+
+    /// See [`Config::extra_root_certs`].
+    pub fn extra_root_cert(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.extra_root_certs.push(path.into());
+        self
+    }
+
+    /// See [`Config::danger_allow_insecure_gateway`].
+    pub fn danger_allow_insecure_gateway(mut self, allow: bool) -> Self {
+        self.config.danger_allow_insecure_gateway = allow;
+        self
+    }
+
+    /// See [`Config::pinned_spki`].
+    pub fn pin_spki(mut self, pin: SpkiPin) -> Self {
+        self.config.pinned_spki.push(pin);
+        self
+    }
+
+    /// See [`Config::client_identity`].
+    pub fn client_identity(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.config.client_identity = Some(ClientIdentity { cert: cert.into(), key: key.into() });
+        self
+    }
+
+    /// See [`Config::upstream_tls`].
+    pub fn upstream_tls(mut self, upstream_tls: UpstreamTls) -> Self {
+        self.config.upstream_tls = upstream_tls;
+        self
+    }
+
+    /// See [`Config::path_rewrite`].
+    pub fn path_rewrite(mut self, rewrite: PathRewrite) -> Self {
+        self.config.path_rewrite = rewrite;
+        self
+    }
+
+    /// See [`Config::content_encoding`].
+    pub fn content_encoding(mut self, policy: ContentEncoding) -> Self {
+        self.config.content_encoding = policy;
+        self
+    }
+
+    /// See [`Config::redirect_policy`].
+    pub fn redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.config.redirect_policy = policy;
+        self
+    }
+
+    /// See [`Config::validate_gateway_responses`].
+    pub fn validate_gateway_responses(mut self, enable: bool) -> Self {
+        self.config.validate_gateway_responses = enable;
+        self
+    }
+
+    /// See [`Config::scrubbed_response_headers`].
+    pub fn scrubbed_response_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.config.scrubbed_response_headers = headers;
+        self
+    }
+
+    /// See [`Config::retry`].
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.config.retry = Some(retry);
+        self
+    }
+
+    /// See [`Config::circuit_breaker`].
+    pub fn circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.config.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// See [`Config::body_read_timeout`].
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.body_read_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`Config::max_body_size`].
+    pub fn max_body_size(mut self, limit: Option<u64>) -> Self {
+        self.config.max_body_size = limit;
+        self
+    }
+
+    /// See [`Config::max_response_body_size`].
+    pub fn max_response_body_size(mut self, limit: Option<u64>) -> Self {
+        self.config.max_response_body_size = limit;
+        self
+    }
+
+    /// See [`Config::max_header_bytes`].
+    pub fn max_header_bytes(mut self, limit: usize) -> Self {
+        self.config.max_header_bytes = Some(limit);
+        self
+    }
+
+    /// See [`Config::max_header_count`].
+    pub fn max_header_count(mut self, limit: usize) -> Self {
+        self.config.max_header_count = Some(limit);
+        self
+    }
+
+    /// See [`Config::resolver`].
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.config.resolver = resolver;
+        self
+    }
+
+    /// See [`Config::dns_pin_interval`].
+    pub fn dns_pin_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.dns_pin_interval = interval;
+        self
+    }
+
+    /// See [`Config::dns_cache`].
+    pub fn dns_cache(mut self, dns_cache: DnsCache) -> Self {
+        self.config.dns_cache = Some(dns_cache);
+        self
+    }
+
+    /// See [`Config::gateway_discovery`].
+    pub fn gateway_discovery(mut self, enabled: bool) -> Self {
+        self.config.gateway_discovery = enabled;
+        self
+    }
+
+    /// See [`Config::socks5_proxy`].
+    pub fn socks5_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.config.socks5_proxy = Some(proxy);
+        self
+    }
+
+    /// See [`Config::tor`].
+    #[cfg(feature = "tor-client")]
+    pub fn tor(mut self, dirs: TorDirs) -> Self {
+        self.config.tor = Some(dirs);
+        self
+    }
+
+    /// See [`Config::connect_timeout`].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`Config::connection_attempt_delay`].
+    pub fn connection_attempt_delay(mut self, delay: Duration) -> Self {
+        self.config.connection_attempt_delay = delay;
+        self
+    }
+
+    /// See [`Config::response_timeout`].
+    pub fn response_timeout(mut self, timeout: Duration) -> Self {
+        self.config.response_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`Config::request_deadline`].
+    pub fn request_deadline(mut self, deadline: Duration) -> Self {
+        self.config.request_deadline = Some(deadline);
+        self
+    }
+
+    /// See [`Config::rate_limit`].
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.config.rate_limit = Some(limit);
+        self
+    }
+
+    /// See [`Config::bandwidth_limit`].
+    pub fn bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.config.bandwidth_limit = Some(limit);
+        self
+    }
+
+    /// See [`Config::authorizer`].
+    pub fn authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.config.authorizer = authorizer;
+        self
+    }
+
+    /// Add a hook to run after those added before. See [`Config::hooks`].
+    pub fn hook(mut self, hook: Arc<dyn RelayHook>) -> Self {
+        self.config.hooks.push(hook);
+        self
+    }
+
+    /// See [`Config::compress_error_bodies`].
+    pub fn compress_error_bodies(mut self, compress: bool) -> Self {
+        self.config.compress_error_bodies = compress;
+        self
+    }
+
+    /// See [`Config::problem_details`].
+    pub fn problem_details(mut self, enabled: bool) -> Self {
+        self.config.problem_details = enabled;
+        self
+    }
+
+    /// See [`Config::bind_retry`].
+    pub fn bind_retry(mut self, retry_for: Duration) -> Self {
+        self.config.bind_retry = Some(retry_for);
+        self
+    }
+
+    /// See [`Config::admin`].
+    pub fn admin(mut self, admin: Admin) -> Self {
+        self.config.admin = Some(admin);
+        self
+    }
+
+    /// See [`Config::admin_token`].
+    pub fn admin_token(mut self, token: StaticToken) -> Self {
+        self.config.admin_token = Some(token);
+        self
+    }
+
+    /// See [`Config::force_http1`].
+    pub fn force_http1(mut self, force: bool) -> Self {
+        self.config.force_http1 = force;
+        self
+    }
+
+    /// See [`Config::max_connections`].
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    /// See [`Config::max_inflight_requests`].
+    pub fn max_inflight_requests(mut self, max: usize) -> Self {
+        self.config.max_inflight_requests = Some(max);
+        self
+    }
+
+    /// See [`Config::max_concurrent_requests_per_connection`].
+    pub fn max_concurrent_requests_per_connection(mut self, max: u32) -> Self {
+        self.config.max_concurrent_requests_per_connection = Some(max);
+        self
+    }
+
+    /// See [`Config::max_requests_per_connection`].
+    pub fn max_requests_per_connection(mut self, max: u64) -> Self {
+        self.config.max_requests_per_connection = Some(max);
+        self
+    }
+
+    /// See [`Config::header_read_timeout`].
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.header_read_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`Config::http1`].
+    pub fn http1(mut self, http1: Http1Server) -> Self {
+        self.config.http1 = http1;
+        self
+    }
+
+    /// See [`Config::streaming`].
+    pub fn streaming(mut self, streaming: Streaming) -> Self {
+        self.config.streaming = streaming;
+        self
+    }
+
+    /// See [`Config::gateway_pool`].
+    pub fn gateway_pool(mut self, gateway_pool: GatewayPool) -> Self {
+        self.config.gateway_pool = gateway_pool;
+        self
+    }
+
+    /// See [`Config::client_tcp`].
+    pub fn client_tcp(mut self, options: TcpOptions) -> Self {
+        self.config.client_tcp = options;
+        self
+    }
+
+    /// See [`Config::gateway_tcp`].
+    pub fn gateway_tcp(mut self, options: TcpOptions) -> Self {
+        self.config.gateway_tcp = options;
+        self
+    }
+
+    /// See [`Config::listen_backlog`].
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.config.listen_backlog = backlog;
+        self
+    }
+
+    /// See [`Config::acceptors`].
+    pub fn acceptors(mut self, acceptors: usize) -> Self {
+        self.config.acceptors = acceptors;
+        self
+    }
+
+    /// See [`Config::idle_timeout`].
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`Config::max_connection_age`].
+    pub fn max_connection_age(mut self, age: Duration) -> Self {
+        self.config.max_connection_age = Some(age);
+        self
+    }
+
+    /// See [`Config::proxy_protocol`].
+    pub fn proxy_protocol(mut self, enable: bool) -> Self {
+        self.config.proxy_protocol = enable;
+        self
+    }
+
+    /// See [`Config::shutdown`].
+    pub fn shutdown(mut self, token: CancellationToken) -> Self {
+        self.config.shutdown = token;
+        self
+    }
+
+    /// See [`Config::on_listening`].
+    pub fn on_listening(mut self, listening: UnboundedSender<Listening>) -> Self {
+        self.config.on_listening = Some(listening);
+        self
+    }
+
+    /// See [`Config::on_bound`].
+    pub fn on_bound(mut self, on_bound: OnBound) -> Self {
+        self.config.on_bound = Some(on_bound);
+        self
+    }
+
+    /// See [`Config::events`].
+    pub fn events(mut self, events: broadcast::Sender<RelayEvent>) -> Self {
+        self.config.events = Some(events);
+        self
+    }
+
+    /// See [`Config::socket_file`].
+    pub fn socket_file(mut self, socket_file: SocketFile) -> Self {
+        self.config.socket_file = socket_file;
+        self
+    }
+
+    /// Drain and return from [`Builder::serve`] on SIGTERM or SIGINT, see
+    /// [`Config::signal_handling`].
+    pub fn with_signal_handling(mut self) -> Self {
+        self.config.signal_handling = true;
+        self
+    }
+
+    /// See [`Config::shutdown_timeout`].
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`Config::drain_on_reload`].
+    pub fn drain_on_reload(mut self, enable: bool) -> Self {
+        self.config.drain_on_reload = enable;
+        self
+    }
+
+    /// See [`Config::reload`].
+    pub fn reload(mut self, reload: Reload) -> Self {
+        self.config.reload = reload;
+        self
+    }
+
+    /// See [`Config::allowed_gateways`].
+    pub fn allow_gateway(mut self, gateway_origin: Uri) -> Self {
+        self.config.allowed_gateways.push(gateway_origin);
+        self
+    }
+
+    /// See [`Config::denied_gateways`].
+    pub fn deny_gateway(mut self, denied: DeniedGateway) -> Self {
+        self.config.denied_gateways.push(denied);
+        self
+    }
+
+    /// See [`Config::named_gateways`].
+    pub fn named_gateway(mut self, name: impl Into<String>, gateway_origin: Uri) -> Self {
+        self.config.named_gateways.insert(name.into(), gateway_origin);
+        self
+    }
+
+    /// See [`Config::gateway_replicas`].
+    pub fn gateway_replica(mut self, replica: Uri) -> Self {
+        self.config.gateway_replicas.push(replica);
+        self
+    }
+
+    /// See [`Config::gateway_configs`].
+    pub fn gateway_config(mut self, gateway_origin: Uri, gateway_config: GatewayConfig) -> Self {
+        self.config.gateway_configs.insert(gateway_origin, gateway_config);
+        self
+    }
+
+    /// See [`Config::gateway_selector`].
+    pub fn gateway_selector(mut self, selector: Arc<dyn GatewaySelector>) -> Self {
+        self.config.gateway_selector = Some(selector);
+        self
+    }
+
+    /// See [`Config::health_check`].
+    pub fn health_check(mut self, health_check: HealthCheck) -> Self {
+        self.config.health_check = Some(health_check);
+        self
+    }
+
+    /// See [`Config::health_endpoints`].
+    pub fn health_endpoints(mut self, enable: bool) -> Self {
+        self.config.health_endpoints = enable;
+        self
+    }
+
+    /// See [`Config::ohttp_keys`].
+    pub fn ohttp_keys(mut self, ohttp_keys: OhttpKeys) -> Self {
+        self.config.ohttp_keys = Some(ohttp_keys);
+        self
+    }
+
+    /// See [`Config::capabilities`].
+    pub fn capabilities(mut self, enable: bool) -> Self {
+        self.config.capabilities = enable;
+        self
+    }
+
+    /// See [`Config::cors`].
+    pub fn cors(mut self, cors: Cors) -> Self {
+        self.config.cors = cors;
+        self
+    }
+
+    /// See [`Config::bootstrap`].
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap", feature = "wt-bootstrap"))]
+    pub fn bootstrap(mut self, enable: bool) -> Self {
+        self.config.bootstrap = enable;
+        self
+    }
+
+    /// See [`Config::ws_bootstrap`].
+    #[cfg(feature = "ws-bootstrap")]
+    pub fn ws_bootstrap(mut self, ws_bootstrap: WsBootstrap) -> Self {
+        self.config.ws_bootstrap = ws_bootstrap;
+        self
+    }
+
+    /// See [`Config::connect_targets`].
+    #[cfg(feature = "connect-bootstrap")]
+    pub fn connect_target(mut self, target: Uri) -> Self {
+        self.config.connect_targets.push(target);
+        self
+    }
+
+    /// See [`Config::padding`].
+    pub fn padding(mut self, padding: Padding) -> Self {
+        self.config.padding = Some(padding);
+        self
+    }
+
+    /// See [`Config::jitter`].
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.config.jitter = Some(jitter);
+        self
+    }
+
+    /// See [`Config::access_log`].
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.config.access_log = Some(access_log);
+        self
+    }
+
+    /// See [`Config::metrics_addr`].
+    #[cfg(feature = "metrics")]
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Run the relay until it is shut down or a listener fails.
+    pub async fn serve(self) -> Result<(), RelayError> {
+        #[cfg(feature = "acme")]
+        if let Some(acme) = &self.acme {
+            let (tls, renew) = crate::acme::start(acme);
+            let serve =
+                crate::serve_listeners(self.listeners, self.gateway_origin, Some(tls), self.config);
+            return tokio::select! {
+                result = serve => result,
+                never = renew => match never {},
+            };
+        }
+        crate::serve_listeners(self.listeners, self.gateway_origin, self.tls, self.config).await
+    }
+}
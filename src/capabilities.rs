@@ -0,0 +1,83 @@
+use crate::access_log::push_json_str;
+use crate::gateway_uri::Gateways;
+use crate::Config;
+
+/// The JSON document served at `GET /.well-known/ohttp-relay`, describing what the relay
+/// supports so clients can configure themselves against it.
+pub(crate) fn document(config: &Config, gateways: &Gateways, max_body_size: Option<u64>) -> String {
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap", feature = "wt-bootstrap"))]
+    let bootstrap = config.bootstrap;
+    #[cfg(not(any(
+        feature = "connect-bootstrap",
+        feature = "ws-bootstrap",
+        feature = "wt-bootstrap"
+    )))]
+    let bootstrap = false;
+    let methods = [
+        ("connect", cfg!(feature = "connect-bootstrap")),
+        ("connect-udp", cfg!(feature = "connect-udp-bootstrap")),
+        ("websocket", cfg!(feature = "ws-bootstrap")),
+        ("webtransport", cfg!(feature = "wt-bootstrap")),
+    ];
+    let limit = |limit: Option<u64>| limit.map_or("null".to_owned(), |limit| limit.to_string());
+
+    let mut doc = String::from(r#"{"chunked":true,"bootstrap":["#);
+    let enabled = methods.iter().filter(|(_, built)| bootstrap && *built);
+    for (i, (method, _)) in enabled.enumerate() {
+        if i > 0 {
+            doc.push(',');
+        }
+        push_json_str(&mut doc, method);
+    }
+    doc.push_str(r#"],"max_body_size":"#);
+    doc.push_str(&limit(max_body_size));
+    doc.push_str(r#","max_response_body_size":"#);
+    doc.push_str(&limit(config.max_response_body_size));
+    doc.push_str(r#","ohttp_keys":"#);
+    match &config.ohttp_keys {
+        Some(keys) => push_json_str(&mut doc, &keys.path),
+        None => doc.push_str("null"),
+    }
+    doc.push_str(r#","gateway_paths":["/""#);
+    for name in gateways.names() {
+        doc.push(',');
+        push_json_str(&mut doc, &format!("/gw/{}/", name));
+    }
+    for gateway in gateways.allowed() {
+        doc.push(',');
+        push_json_str(&mut doc, &format!("/{}/", gateway.origin()));
+    }
+    doc.push_str("]}");
+    doc
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::gateway_uri::GatewayUri;
+
+    #[test]
+    fn document_lists_limits_and_gateway_paths() {
+        let config = Config {
+            allowed_gateways: vec!["https://other.example".parse().unwrap()],
+            named_gateways: BTreeMap::from([(
+                "alice".to_owned(),
+                "https://alice.example".parse().unwrap(),
+            )]),
+            ..Config::default()
+        };
+        let default = GatewayUri::new("https://gateway.example".parse().unwrap(), false).unwrap();
+        let gateways = Gateways::new(default, &config).unwrap();
+        let doc = document(&config, &gateways, Some(1024));
+        assert!(doc.starts_with(r#"{"chunked":true,"bootstrap":["#), "{}", doc);
+        assert!(doc.contains(r#""max_body_size":1024,"max_response_body_size":null"#), "{}", doc);
+        assert!(doc.contains(r#""ohttp_keys":null"#), "{}", doc);
+        assert!(
+            doc.ends_with(r#""gateway_paths":["/","/gw/alice/","/https://other.example:443/"]}"#),
+            "{}",
+            doc
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::error::Error;
+use crate::gateway_uri::GatewayUri;
+use crate::CircuitBreaker;
+
+/// A circuit breaker for each gateway origin requests were forwarded to.
+#[derive(Debug)]
+pub(crate) struct CircuitBreakers {
+    settings: CircuitBreaker,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+/// A closed circuit has no entry, so only failing origins take up space.
+#[derive(Debug, Default)]
+struct Circuit {
+    failures: u32,
+    opened_at: Option<Instant>,
+    probing_since: Option<Instant>,
+}
+
+impl CircuitBreakers {
+    pub(crate) fn new(settings: CircuitBreaker) -> Self {
+        Self { settings, circuits: Mutex::default() }
+    }
+
+    /// Let a request through to `gateway` unless its circuit is open. Once open long enough,
+    /// a single request at a time is let through to probe whether the gateway recovered.
+    pub(crate) fn admit(&self, gateway: &GatewayUri) -> Result<(), Error> {
+        let now = Instant::now();
+        let open_duration = self.settings.open_duration;
+        let mut circuits = self.circuits.lock().expect("circuit breakers poisoned");
+        let circuit = match circuits.get_mut(&gateway.origin()) {
+            Some(circuit) => circuit,
+            None => return Ok(()),
+        };
+        let opened_at = match circuit.opened_at {
+            Some(opened_at) => opened_at,
+            None => return Ok(()),
+        };
+        let closes_at = match circuit.probing_since {
+            Some(probing_since) => probing_since + open_duration,
+            None => opened_at + open_duration,
+        };
+        if now < closes_at {
+            return Err(Error::ServiceUnavailable { retry_after: round_up(closes_at - now) });
+        }
+        circuit.probing_since = Some(now);
+        Ok(())
+    }
+
+    /// Whether requests to `gateway` are being refused, or only let through as probes.
+    pub(crate) fn is_open(&self, gateway: &GatewayUri) -> bool {
+        let circuits = self.circuits.lock().expect("circuit breakers poisoned");
+        circuits.get(&gateway.origin()).is_some_and(|circuit| circuit.opened_at.is_some())
+    }
+
+    /// Record whether a request forwarded to `gateway` reached it.
+    pub(crate) fn record(&self, gateway: &GatewayUri, ok: bool) {
+        let mut circuits = self.circuits.lock().expect("circuit breakers poisoned");
+        if ok {
+            if let Some(circuit) = circuits.remove(&gateway.origin()) {
+                if circuit.opened_at.is_some() {
+                    info!("Circuit to {} closed", gateway.origin());
+                }
+            }
+            return;
+        }
+        let circuit = circuits.entry(gateway.origin()).or_default();
+        circuit.failures += 1;
+        if circuit.probing_since.is_some() || circuit.failures >= self.settings.failure_threshold {
+            if circuit.opened_at.is_none() {
+                warn!("Circuit to {} opened after {} failures", gateway.origin(), circuit.failures);
+            }
+            circuit.opened_at = Some(Instant::now());
+            circuit.probing_since = None;
+        }
+    }
+}
+
+/// Whole seconds, as `Retry-After` needs, never rounding down to zero.
+fn round_up(duration: Duration) -> Duration {
+    Duration::from_secs(duration.as_secs() + u64::from(duration.subsec_nanos() > 0))
+}
+
+#[cfg(test)]
+mod test {
+    use http::Uri;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn opens_after_threshold_and_probes_once_open_duration_passed() {
+        let breakers = CircuitBreakers::new(CircuitBreaker {
+            failure_threshold: 2,
+            open_duration: Duration::from_millis(100),
+        });
+        let gateway = GatewayUri::new(Uri::from_static("https://gateway.example"), false).unwrap();
+        let other = GatewayUri::new(Uri::from_static("https://other.example"), false).unwrap();
+
+        breakers.record(&gateway, false);
+        assert!(breakers.admit(&gateway).is_ok());
+        breakers.record(&gateway, false);
+        assert!(breakers.is_open(&gateway));
+        assert!(matches!(
+            breakers.admit(&gateway),
+            Err(Error::ServiceUnavailable { retry_after }) if retry_after == Duration::from_secs(1)
+        ));
+        assert!(breakers.admit(&other).is_ok());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(breakers.admit(&gateway).is_ok(), "probe let through");
+        assert!(breakers.admit(&gateway).is_err(), "one probe at a time");
+        breakers.record(&gateway, false);
+        assert!(breakers.admit(&gateway).is_err(), "failed probe reopens");
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(breakers.admit(&gateway).is_ok());
+        breakers.record(&gateway, true);
+        assert!(!breakers.is_open(&gateway));
+        assert!(breakers.admit(&gateway).is_ok());
+        assert!(breakers.admit(&gateway).is_ok(), "closed circuits admit everything");
+    }
+}
@@ -0,0 +1,950 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use http::uri::PathAndQuery;
+use http::{HeaderName, HeaderValue, Method, Uri};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+use crate::admin::Admin;
+use crate::auth::{AllowAll, Authorizer, StaticToken};
+use crate::events::RelayEvent;
+use crate::gateway_uri::DeniedGateway;
+use crate::hook::RelayHook;
+use crate::listen::{Listen, Listening, OnBound};
+use crate::pinning::SpkiPin;
+use crate::reload::Reload;
+use crate::resolve::{Resolver, SystemResolver};
+use crate::select::GatewaySelector;
+use crate::tls::{ClientIdentity, Roots, UpstreamTls};
+
+/// The default [`Config::max_body_size`], and a fitting [`Config::max_response_body_size`].
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// The default [`Config::scrubbed_response_headers`]: gateway response headers that can
+/// identify the gateway's software or let it track clients across requests.
+pub const DEFAULT_SCRUBBED_RESPONSE_HEADERS: &[&str] = &[
+    "set-cookie",
+    "set-cookie2",
+    "server",
+    "via",
+    "alt-svc",
+    "x-powered-by",
+    "server-timing",
+    "report-to",
+    "nel",
+    "x-request-id",
+];
+
+/// The default [`Config::listen_backlog`].
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// Options controlling how the relay handles requests.
+///
+/// [`crate::listen_tcp`] and [`crate::listen_socket`] use the default configuration, which
+/// relays less than earlier releases did:
+///
+/// - Request bodies over [`DEFAULT_MAX_BODY_SIZE`] are refused, see [`Config::max_body_size`].
+/// - The headers in [`DEFAULT_SCRUBBED_RESPONSE_HEADERS`] are removed from gateway responses,
+///   see [`Config::scrubbed_response_headers`].
+/// - Plain `http` gateways and gateways at internal addresses are refused, where earlier
+///   releases forwarded to them, see [`Config::danger_allow_insecure_gateway`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Reject requests with 400 Bad Request when the body received is shorter or longer
+    /// than the declared `Content-Length`.
+    pub enforce_content_length: bool,
+    /// The base trust anchors for verifying gateway certificates.
+    pub roots: Roots,
+    /// PEM files with additional CA certificates trusted alongside [`Config::roots`].
+    pub extra_root_certs: Vec<PathBuf>,
+    /// Forward to `http://` gateway origins and those on loopback, link-local or private
+    /// addresses, such as a gateway run for local testing. Plaintext exposes OHTTP messages and
+    /// the relay's metadata to the network between relay and gateway, and internal addresses let
+    /// a misconfigured gateway reach services such as cloud metadata endpoints. All are refused
+    /// when `false`, the default.
+    pub danger_allow_insecure_gateway: bool,
+    /// Public keys the gateway's certificate must carry one of, on top of chaining to the trust
+    /// anchors. Any key is accepted when empty.
+    pub pinned_spki: Vec<SpkiPin>,
+    /// The client certificate to authenticate the relay to gateways with. None by default.
+    pub client_identity: Option<ClientIdentity>,
+    /// The TLS versions, cipher suites, session resumption and ALPN order offered to gateways.
+    pub upstream_tls: UpstreamTls,
+    /// How the path and query the client sent are translated into the gateway's.
+    pub path_rewrite: PathRewrite,
+    /// What to do when the gateway answers with a 3xx redirect.
+    pub redirect_policy: RedirectPolicy,
+    /// Whether `Accept-Encoding` and `Content-Encoding` are forwarded between client and
+    /// gateway.
+    pub content_encoding: ContentEncoding,
+    /// Answer 502 Bad Gateway instead of forwarding a gateway response that cannot be a valid
+    /// OHTTP response: a 200 OK without `Content-Type: message/ohttp-res`, or any other 2xx or
+    /// 1xx status. Chunked requests expect `message/ohttp-chunked-res` instead. Error statuses
+    /// are forwarded as they are.
+    pub validate_gateway_responses: bool,
+    /// Headers removed from gateway responses before they reach the client, so the gateway
+    /// cannot set cookies or reveal itself through them. [`DEFAULT_SCRUBBED_RESPONSE_HEADERS`]
+    /// by default; empty to forward every header.
+    pub scrubbed_response_headers: Vec<HeaderName>,
+    /// Re-send requests whose connection to the gateway could not be established, e.g. when
+    /// it was refused or reset, so the gateway never saw them. Bodies are buffered to be sent
+    /// again, so chunked requests, which are streamed, are never retried. Disabled when `None`.
+    pub retry: Option<Retry>,
+    /// Answer 503 Service Unavailable right away for a gateway origin that keeps failing,
+    /// instead of attempting to connect to it for every request. Disabled when `None`.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Abort forwarding with 408 Request Timeout when the client makes no progress sending
+    /// its request body for this long. Disabled when `None`.
+    pub body_read_timeout: Option<Duration>,
+    /// Reject request bodies larger than this many bytes with 413 Payload Too Large.
+    /// Encapsulated OHTTP requests are small, so the default is 64 KiB. Unlimited when `None`.
+    pub max_body_size: Option<u64>,
+    /// Answer 502 Bad Gateway when the gateway's response body is larger than this many bytes.
+    /// Responses are buffered to enforce the limit, so they are no longer streamed or coalesced
+    /// by [`Streaming::flush_threshold`]; [`DEFAULT_MAX_BODY_SIZE`] suits encapsulated OHTTP
+    /// responses. Unlimited and streamed when `None`, the default. Responses to chunked requests
+    /// are always streamed as the gateway produces them.
+    pub max_response_body_size: Option<u64>,
+    /// Reject requests whose header names and values add up to more than this many bytes with
+    /// 431 Request Header Fields Too Large. Only hyper's own limit applies when `None`.
+    pub max_header_bytes: Option<usize>,
+    /// Reject requests with more than this many headers with 431 Request Header Fields Too
+    /// Large. Only hyper's own limit applies when `None`.
+    pub max_header_count: Option<usize>,
+    /// Pad forwarded requests and responses to clients to uniform sizes, so observers of the
+    /// encrypted connections learn less from message lengths. Disables
+    /// [`Config::compress_error_bodies`]. Disabled when `None`.
+    pub padding: Option<Padding>,
+    /// Hold relayed requests and responses for a random time, so observers on both sides of the
+    /// relay have a harder time matching them by timing. Disabled when `None`.
+    pub jitter: Option<Jitter>,
+    /// Looks up gateway addresses for forwarded requests and bootstrap tunnels, except that
+    /// forwarded requests leave resolution to [`Config::socks5_proxy`] or Tor when set. Uses the
+    /// system resolver by default.
+    pub resolver: Arc<dyn Resolver>,
+    /// Reuse the addresses a gateway host resolved to for this long before looking it up again,
+    /// so a rebinding DNS name cannot move the relay's connections between requests. Resolved
+    /// loopback, link-local and private addresses are refused unless
+    /// [`Config::danger_allow_insecure_gateway`] is set. Resolves on every connection when `None`.
+    pub dns_pin_interval: Option<Duration>,
+    /// Cache what gateway hosts resolve to for as long as [`Resolver::resolve_with_ttl`] says,
+    /// and failed lookups briefly, for forwarded requests and bootstrap tunnels alike. Flushed
+    /// by `POST /dns/flush` on the [`Config::admin`] listener. Disabled when `None`.
+    pub dns_cache: Option<DnsCache>,
+    /// Look up gateways' DNS HTTPS records (RFC 9460) and dial the endpoint they advertise,
+    /// following aliases and taking its host and port, so gateway operators can move endpoints
+    /// without relay changes. Certificates are still verified for the gateway origin's name.
+    /// Endpoints are looked up again once their records expire, and origins without records
+    /// are dialed as is. Ignored with [`Config::socks5_proxy`] or Tor. Disabled by default.
+    pub gateway_discovery: bool,
+    /// Connect to gateways through the SOCKS5 proxy at this address, such as Tor at
+    /// `127.0.0.1:9050`, hiding the relay's own address from them. The proxy resolves gateway
+    /// hostnames. Bootstrap tunnels still connect directly.
+    pub socks5_proxy: Option<SocketAddr>,
+    /// Connect to gateways over Tor with an embedded arti client, without running a separate
+    /// Tor daemon, hiding the relay's network position from them. `.onion` gateway origins are
+    /// reachable too. Takes precedence over [`Config::socks5_proxy`]. Bootstrap tunnels still
+    /// connect directly.
+    #[cfg(feature = "tor-client")]
+    pub tor: Option<TorDirs>,
+    /// Answer 504 Gateway Timeout when connecting to the gateway takes longer than this.
+    /// Waits for the operating system to give up when `None`.
+    pub connect_timeout: Option<Duration>,
+    /// When a gateway resolves to several addresses, start connecting to the next one if the
+    /// previous attempt has neither failed nor succeeded within this long, racing them as RFC
+    /// 8305 describes, so a dead address or broken IPv6 path does not fail the request. 250
+    /// milliseconds by default. Applies to bootstrap tunnels too.
+    pub connection_attempt_delay: Duration,
+    /// Answer 504 Gateway Timeout when the gateway's response headers have not arrived this
+    /// long after forwarding started, including any time spent connecting. Waits indefinitely
+    /// when `None`.
+    pub response_timeout: Option<Duration>,
+    /// Bound a relayed exchange from receiving the client's request until its response has
+    /// been fully streamed back. Answers 504 Gateway Timeout if the gateway's response headers
+    /// have not arrived by then, and aborts the connection if streaming the response body is
+    /// still under way. Over HTTP/3 only the wait for the response headers is bounded.
+    /// Unbounded when `None`.
+    pub request_deadline: Option<Duration>,
+    /// Limit how often each client may send requests, answering 429 Too Many Requests with a
+    /// `Retry-After` when it is exceeded. Only applies to TCP listeners. Disabled when `None`.
+    pub rate_limit: Option<RateLimit>,
+    /// Limit how fast each client connection may stream request and response bodies, both
+    /// directions together. Listeners added with
+    /// [`crate::Builder::add_listener_with_bandwidth_limit`] set their own. Not applied to
+    /// HTTP/3. Unlimited when `None`.
+    pub bandwidth_limit: Option<BandwidthLimit>,
+    /// Decides which requests may be forwarded. Allows everything by default.
+    pub authorizer: Arc<dyn Authorizer>,
+    /// Run, in order, before each relayed OHTTP request is forwarded and once the gateway
+    /// responds. None by default.
+    pub hooks: Vec<Arc<dyn RelayHook>>,
+    /// Gzip the bodies of relay-generated error responses for clients that accept it.
+    /// Forwarded gateway bodies are opaque ciphertext and are never compressed.
+    pub compress_error_bodies: bool,
+    /// Describe relay-generated errors with RFC 9457 `application/problem+json` bodies that
+    /// only restate the status. Takes precedence over [`Config::compress_error_bodies`].
+    pub problem_details: bool,
+    /// Keep retrying a failed listener bind with backoff for this long before giving up,
+    /// e.g. while a previous process still holds the port. Disabled when `None`.
+    pub bind_retry: Option<Duration>,
+    /// Serve a JSON snapshot of in-flight forwards at `GET /admin/inflight`, and metrics at
+    /// `GET /admin/metrics` with the `metrics` feature, to loopback clients presenting this
+    /// bearer token. Disabled when `None`.
+    pub admin_token: Option<StaticToken>,
+    /// Serve the health, stats, metrics and reload endpoints on a listener of their own
+    /// instead, see [`Admin`]. Disabled when `None`.
+    pub admin: Option<Admin>,
+    /// Only offer HTTP/1.1 to the gateway. Otherwise HTTP/2 is used whenever the gateway
+    /// negotiates it with ALPN, multiplexing concurrent requests over one TLS connection.
+    pub force_http1: bool,
+    /// Serve at most this many connections at once, across all listeners. Further clients wait
+    /// in the listener's backlog until a connection closes. Unlimited when `None`.
+    pub max_connections: Option<usize>,
+    /// Forward at most this many requests at once, across all listeners and gateways, and
+    /// answer further ones with 503 Service Unavailable and `Retry-After: 1` rather than pile
+    /// more load onto a struggling gateway. Unlimited when `None`.
+    pub max_inflight_requests: Option<usize>,
+    /// Let each client connection have at most this many requests in progress at once, by
+    /// advertising it as the HTTP/2 and QUIC stream limit. HTTP/1 connections serve one at a
+    /// time anyway. Unlimited when `None`.
+    pub max_concurrent_requests_per_connection: Option<u32>,
+    /// Close client connections once they have sent this many requests, after answering them,
+    /// so one keep-alive client cannot send an endless series of forwards over one connection.
+    /// Unlimited when `None`.
+    pub max_requests_per_connection: Option<u64>,
+    /// Close connections whose TLS handshake or HTTP/1 request headers take longer than this
+    /// to arrive. Disabled when `None`.
+    pub header_read_timeout: Option<Duration>,
+    /// Socket options of TCP connections from clients.
+    pub client_tcp: TcpOptions,
+    /// Socket options of TCP connections to gateways, or to the SOCKS5 proxy reaching them.
+    pub gateway_tcp: TcpOptions,
+    /// The address the `listen_tcp*` and `listen_quic*` functions listen on at their port.
+    /// Every IPv4 interface by default. [`crate::Builder`] listens where it is told instead.
+    pub bind_ip: IpAddr,
+    /// Have the `listen_tcp*` functions listen on every IPv4 and IPv6 interface instead of
+    /// [`Config::bind_ip`], as [`crate::Builder::dual_stack`] does. `false` by default.
+    pub dual_stack: bool,
+    /// How many connections TCP listeners queue for the relay to accept. The OS may cap it,
+    /// e.g. at `net.core.somaxconn` on Linux.
+    pub listen_backlog: u32,
+    /// How many sockets a TCP listener on an address binds with `SO_REUSEPORT`, each with its
+    /// own accept loop, so the kernel spreads connections across them on multi-core hosts. 1
+    /// binds a single socket. Binding fails where `SO_REUSEPORT` is unsupported, e.g. Windows.
+    pub acceptors: usize,
+    /// How HTTP/1 client connections are served.
+    pub http1: Http1Server,
+    /// How request and response bodies stream between clients and gateways.
+    pub streaming: Streaming,
+    /// How many connections to gateways are kept open between requests.
+    pub gateway_pool: GatewayPool,
+    /// Close connections that have had no request in progress for this long.
+    /// Kept open until the client closes them when `None`.
+    pub idle_timeout: Option<Duration>,
+    /// Close connections this long after they were accepted, once their in-flight requests
+    /// have finished. Unlimited when `None`.
+    pub max_connection_age: Option<Duration>,
+    /// Expect a PROXY protocol v1 or v2 header, as sent by HAProxy or an AWS NLB, at the start
+    /// of every connection and rate limit, authorize and log by the client address it names.
+    /// Connections without one are closed. Only enable this behind such a proxy, since any
+    /// client reaching the listener directly could otherwise claim any address.
+    pub proxy_protocol: bool,
+    /// Cancel to stop accepting connections and let open ones finish their in-flight requests,
+    /// after which the listener future resolves.
+    pub shutdown: CancellationToken,
+    /// Sent where each listener accepts connections, once it is bound and the relay is ready to
+    /// serve it, so embedders and tests can wait for the relay without polling its port.
+    /// Listeners passed to [`crate::serve_listener`] are not reported. `None` by default.
+    pub on_listening: Option<UnboundedSender<Listening>>,
+    /// Called once every listener, including the admin and metrics ones, is bound and before
+    /// any connection is accepted, e.g. to drop the privileges needed to bind. If it fails, the
+    /// relay stops without serving. `None` by default.
+    pub on_bound: Option<OnBound>,
+    /// Sent a [`RelayEvent`] for connections, forwarded requests, gateway errors, circuit
+    /// changes and key refreshes, so embedders can drive dashboards and alerting without
+    /// parsing logs. Events are dropped while nothing is subscribed, and a subscriber that
+    /// falls behind misses the oldest. `None` by default.
+    pub events: Option<broadcast::Sender<RelayEvent>>,
+    /// Cancel [`Config::shutdown`] on the first SIGTERM or SIGINT, or Ctrl-C on Windows, so the
+    /// relay drains and its listener future resolves with `Ok(())`. Signals are left alone
+    /// when `false`, the default.
+    pub signal_handling: bool,
+    /// How a unix socket listener's file is created and cleaned up.
+    pub socket_file: SocketFile,
+    /// How long open connections may take to finish their in-flight requests once draining,
+    /// after [`Config::shutdown`] is cancelled or with [`Config::drain_on_reload`], before
+    /// they are closed. Waits for all of them when `None`.
+    pub shutdown_timeout: Option<Duration>,
+    /// Drain open connections whenever settings are reloaded: in-flight requests finish and are
+    /// answered with `Connection: close`, so keep-alive clients reconnect, e.g. to be spread
+    /// anew by a load balancer in front of the relay.
+    pub drain_on_reload: bool,
+    /// Replaces the gateway list and limits of the running relay. See [`Reload`].
+    pub reload: Reload,
+    /// Gateway origins besides the default that clients may select per request by prefixing
+    /// the request path with the origin, e.g. `POST /https://gateway.example/`. Requests naming
+    /// any other origin are rejected with 403 Forbidden.
+    pub allowed_gateways: Vec<Uri>,
+    /// Gateway origins, networks and domains never relayed to, whichever way they are selected.
+    /// The deny-list takes precedence over every allow, and matching requests are rejected with
+    /// 403 Forbidden. Networks only match gateways addressed by IP.
+    pub denied_gateways: Vec<DeniedGateway>,
+    /// Gateways of tenants sharing the relay, by name. Requests under `/gw/<name>/` go to the
+    /// gateway named `<name>` with that prefix stripped, and names not listed here are answered
+    /// with 404 Not Found.
+    pub named_gateways: BTreeMap<String, Uri>,
+    /// Replicas of the default gateway. Requests for the default gateway are spread across it
+    /// and its replicas in round-robin order.
+    pub gateway_replicas: Vec<Uri>,
+    /// Chooses the gateway of each relayed request instead of the default gateway, its replicas,
+    /// [`Config::allowed_gateways`] and [`Config::named_gateways`]. Health checks, readiness, served keys and bootstrap
+    /// tunnels still use the default gateway. `None` by default.
+    pub gateway_selector: Option<Arc<dyn GatewaySelector>>,
+    /// Settings overriding the relay-wide ones for requests forwarded to these gateway origins,
+    /// e.g. for a gateway behind a private CA or one that answers slowly. Health probes use the
+    /// relay-wide settings. Fixed at startup.
+    pub gateway_configs: HashMap<Uri, GatewayConfig>,
+    /// Probe the default gateway and its replicas in the background, leaving unhealthy ones
+    /// out of rotation. Disabled when `None`.
+    pub health_check: Option<HealthCheck>,
+    /// Answer `GET /health` while the process is up and `GET /ready` while the default gateway
+    /// or one of its replicas is healthy, for orchestrator probes. Disable on public-facing
+    /// listeners.
+    pub health_endpoints: bool,
+    /// Serve the default gateway's key configuration at `GET /ohttp-keys` and
+    /// `GET /.well-known/ohttp-gateway`, so browser clients can fetch it cross-origin.
+    /// Clients using these keys trust the relay not to substitute its own, so prefer the
+    /// bootstrap tunnels where that matters. Disabled when `None`.
+    pub ohttp_keys: Option<OhttpKeys>,
+    /// Describe the relay at `GET /.well-known/ohttp-relay` as JSON: the bootstrap tunnels,
+    /// body size limits, key path and gateway paths it offers, so clients can configure
+    /// themselves. Disabled by default.
+    pub capabilities: bool,
+    /// Which web origins may call the relay from a browser, and how preflight requests are
+    /// answered.
+    pub cors: Cors,
+    /// Tunnel OHTTP key bootstrap requests to the default gateway over `CONNECT` and
+    /// WebSocket, or WebTransport when served over HTTP/3.
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap", feature = "wt-bootstrap"))]
+    pub bootstrap: bool,
+    /// Origins such as `https://gateway-2.example:8443` that `CONNECT` and CONNECT-UDP bootstrap
+    /// requests may tunnel to besides the default gateway. Every other target is refused with 403
+    /// Forbidden, so the relay never acts as an open forward proxy.
+    #[cfg(feature = "connect-bootstrap")]
+    pub connect_targets: Vec<Uri>,
+    /// The subprotocol and limits WebSocket bootstrap tunnels are held to.
+    #[cfg(feature = "ws-bootstrap")]
+    pub ws_bootstrap: WsBootstrap,
+    /// Log every request for operators to audit the relay. Disabled when `None`.
+    pub access_log: Option<AccessLog>,
+    /// Serve Prometheus metrics at `GET /metrics`, and a JSON summary of them at `GET /stats`,
+    /// on a separate listener at this address. Metrics are also served at `GET /admin/metrics`
+    /// and the summary at `GET /admin/stats` when [`Config::admin_token`] is set.
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enforce_content_length: false,
+            roots: Roots::default(),
+            extra_root_certs: Vec::new(),
+            danger_allow_insecure_gateway: false,
+            pinned_spki: Vec::new(),
+            client_identity: None,
+            upstream_tls: UpstreamTls::default(),
+            path_rewrite: PathRewrite::default(),
+            redirect_policy: RedirectPolicy::default(),
+            content_encoding: ContentEncoding::default(),
+            validate_gateway_responses: false,
+            scrubbed_response_headers: DEFAULT_SCRUBBED_RESPONSE_HEADERS
+                .iter()
+                .map(|name| HeaderName::from_static(name))
+                .collect(),
+            retry: None,
+            circuit_breaker: None,
+            body_read_timeout: None,
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            max_response_body_size: None,
+            max_header_bytes: None,
+            max_header_count: None,
+            padding: None,
+            jitter: None,
+            resolver: Arc::new(SystemResolver::default()),
+            dns_pin_interval: Some(Duration::from_secs(60)),
+            dns_cache: None,
+            gateway_discovery: false,
+            socks5_proxy: None,
+            #[cfg(feature = "tor-client")]
+            tor: None,
+            connect_timeout: None,
+            connection_attempt_delay: Duration::from_millis(250),
+            response_timeout: None,
+            request_deadline: None,
+            rate_limit: None,
+            bandwidth_limit: None,
+            authorizer: Arc::new(AllowAll),
+            hooks: Vec::new(),
+            compress_error_bodies: false,
+            problem_details: false,
+            bind_retry: None,
+            admin_token: None,
+            admin: None,
+            force_http1: false,
+            max_connections: None,
+            max_inflight_requests: None,
+            max_concurrent_requests_per_connection: None,
+            max_requests_per_connection: None,
+            header_read_timeout: None,
+            client_tcp: TcpOptions::default(),
+            gateway_tcp: TcpOptions::default(),
+            bind_ip: Ipv4Addr::UNSPECIFIED.into(),
+            dual_stack: false,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            acceptors: 1,
+            http1: Http1Server::default(),
+            streaming: Streaming::default(),
+            gateway_pool: GatewayPool::default(),
+            idle_timeout: None,
+            max_connection_age: None,
+            proxy_protocol: false,
+            shutdown: CancellationToken::new(),
+            on_listening: None,
+            on_bound: None,
+            events: None,
+            signal_handling: false,
+            socket_file: SocketFile::default(),
+            shutdown_timeout: None,
+            drain_on_reload: false,
+            reload: Reload::default(),
+            allowed_gateways: Vec::new(),
+            denied_gateways: Vec::new(),
+            named_gateways: BTreeMap::new(),
+            gateway_replicas: Vec::new(),
+            gateway_selector: None,
+            gateway_configs: HashMap::new(),
+            health_check: None,
+            health_endpoints: true,
+            ohttp_keys: None,
+            capabilities: false,
+            cors: Cors::default(),
+            #[cfg(any(
+                feature = "connect-bootstrap",
+                feature = "ws-bootstrap",
+                feature = "wt-bootstrap"
+            ))]
+            bootstrap: true,
+            #[cfg(feature = "connect-bootstrap")]
+            connect_targets: Vec::new(),
+            #[cfg(feature = "ws-bootstrap")]
+            ws_bootstrap: WsBootstrap::default(),
+            access_log: None,
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+        }
+    }
+}
+
+impl Config {
+    /// Where the `listen_tcp*` functions listen at `port`.
+    pub(crate) fn tcp_listen(&self, port: u16) -> Listen {
+        match self.dual_stack {
+            true => Listen::DualStack(port),
+            false => Listen::Tcp(SocketAddr::new(self.bind_ip, port)),
+        }
+    }
+}
+
+/// How the file of a unix socket listener is managed. By default it is created with the
+/// process umask and binding fails if the file already exists.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketFile {
+    /// Permission bits set on the socket once bound, e.g. `0o660`.
+    pub mode: Option<u32>,
+    /// Group ID the socket is changed to once bound, to share it with e.g. a reverse proxy.
+    pub group: Option<u32>,
+    /// Remove a leftover socket at the path that no process is listening on before binding.
+    pub remove_stale: bool,
+    /// Remove the socket once the relay stops serving.
+    pub unlink_on_shutdown: bool,
+}
+
+/// Where the embedded Tor client of [`Config::tor`] keeps its state and directory cache.
+#[cfg(feature = "tor-client")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorDirs {
+    /// Where arti keeps its guards and other state across restarts.
+    pub state_dir: PathBuf,
+    /// Where arti caches Tor directory information.
+    pub cache_dir: PathBuf,
+}
+
+#[cfg(feature = "tor-client")]
+impl TorDirs {
+    /// Keep the state and cache under `dir`.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        Self { state_dir: dir.join("state"), cache_dir: dir.join("cache") }
+    }
+}
+
+/// How the target of a relayed request is translated into a path on the gateway, after any
+/// gateway the client selected in the path was taken off.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PathRewrite {
+    /// Forward the path and query as the client sent them.
+    #[default]
+    Unchanged,
+    /// Forward every request to this path, e.g. `/gateway`, dropping the client's path and
+    /// query so identifiers a client puts in them never reach the gateway or its logs.
+    Fixed(PathAndQuery),
+    /// Take `strip` off the start of the path and put `add` in its place, keeping the query.
+    /// Paths not starting with `strip` as whole segments, e.g. `/relayx` for a `strip` of
+    /// `/relay`, are answered with 404 Not Found.
+    Prefix { strip: String, add: String },
+}
+
+/// What happens to content-coding headers on relayed requests and their responses.
+///
+/// Encapsulated messages are ciphertext that compression cannot shrink, and a negotiated
+/// compression of anything else would make message sizes depend on their contents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContentEncoding {
+    /// Drop `Accept-Encoding` and `Content-Encoding` in both directions, so client and gateway
+    /// never negotiate a compression.
+    #[default]
+    Strip,
+    /// Forward `Accept-Encoding` and `Content-Encoding` untouched in both directions.
+    PassThrough,
+}
+
+/// How gateway redirects are handled.
+///
+/// Redirects make little sense for OHTTP, where the gateway origin is fixed by configuration,
+/// so operators may prefer to surface them as errors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    /// Forward 3xx responses to the client unchanged.
+    #[default]
+    PassThrough,
+    /// Answer 502 Bad Gateway instead of forwarding a 3xx response.
+    Refuse,
+    /// Re-send the request, body and all, to a `Location` on the gateway's own origin at most
+    /// `max_redirects` times. Cross-origin redirects and redirects beyond the limit are answered
+    /// with 502 Bad Gateway, as are redirects of chunked requests, whose bodies are streamed.
+    FollowSameOrigin { max_redirects: usize },
+}
+
+/// How connect-level gateway failures are retried.
+///
+/// Attempts are spaced by exponential backoff from `initial_backoff`, doubling up to
+/// `max_backoff`, with each delay jittered down by up to half so clients retry out of step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retry {
+    /// Attempts in total, including the first.
+    pub max_attempts: u32,
+    /// The delay before the first retry.
+    pub initial_backoff: Duration,
+    /// The longest delay between attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Settings for forwarding to one gateway, overriding the relay-wide ones. Fields left `None`
+/// keep the [`Config`] setting.
+#[derive(Debug, Clone, Default)]
+pub struct GatewayConfig {
+    /// See [`Config::connect_timeout`].
+    pub connect_timeout: Option<Duration>,
+    /// See [`Config::response_timeout`].
+    pub response_timeout: Option<Duration>,
+    /// See [`Config::retry`].
+    pub retry: Option<Retry>,
+    /// See [`Config::max_body_size`].
+    pub max_body_size: Option<u64>,
+    /// See [`Config::max_response_body_size`].
+    pub max_response_body_size: Option<u64>,
+    /// See [`Config::roots`].
+    pub roots: Option<Roots>,
+    /// See [`Config::pinned_spki`].
+    pub pinned_spki: Option<Vec<SpkiPin>>,
+    /// Limit each client's requests to this gateway with buckets of their own, once the request
+    /// is routed here. Applies on top of [`Config::rate_limit`], which alone limits gateways
+    /// without their own.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl GatewayConfig {
+    /// `config` with these settings applied on top.
+    pub(crate) fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        config.connect_timeout = self.connect_timeout.or(config.connect_timeout);
+        config.response_timeout = self.response_timeout.or(config.response_timeout);
+        config.retry = self.retry.clone().or(config.retry);
+        config.max_body_size = self.max_body_size.or(config.max_body_size);
+        config.max_response_body_size =
+            self.max_response_body_size.or(config.max_response_body_size);
+        if let Some(roots) = &self.roots {
+            config.roots = roots.clone();
+        }
+        if let Some(pins) = &self.pinned_spki {
+            config.pinned_spki = pins.clone();
+        }
+        config
+    }
+}
+
+/// Size buckets that relayed messages are padded up to.
+///
+/// Encapsulated messages are ciphertext the relay cannot change, so a `Padding` header is added
+/// instead, filling each message up to the smallest bucket that holds its body, or a multiple of
+/// the largest bucket. Only messages whose length is known up front are padded: not chunked
+/// requests, nor responses the gateway streams without a `Content-Length`. Responses from the
+/// gateway to the relay are sized by the gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Padding {
+    /// Body sizes in bytes, in any order.
+    pub buckets: Vec<u64>,
+}
+
+impl Default for Padding {
+    fn default() -> Self { Self { buckets: vec![256, 1024, 4096, 16384, 65536] } }
+}
+
+/// The longest random delays added to relayed requests, each drawn uniformly from zero up to
+/// the maximum. A zero maximum adds no delay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Jitter {
+    /// Before a request is forwarded to the gateway.
+    pub max_forward_delay: Duration,
+    /// Before the gateway's response is returned to the client.
+    pub max_response_delay: Duration,
+}
+
+impl Default for Jitter {
+    fn default() -> Self {
+        Self {
+            max_forward_delay: Duration::from_millis(50),
+            max_response_delay: Duration::from_millis(50),
+        }
+    }
+}
+
+/// When requests to a gateway origin stop being forwarded.
+///
+/// Requests that fail with 502 Bad Gateway or 504 Gateway Timeout count as failures. After
+/// `failure_threshold` consecutive failures the circuit opens, and requests are answered with
+/// 503 Service Unavailable and a `Retry-After` for `open_duration`. A single request is then
+/// let through as a probe: its success closes the circuit, its failure opens it again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    /// Consecutive failures that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a probe request is let through.
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self { Self { failure_threshold: 5, open_duration: Duration::from_secs(30) } }
+}
+
+/// How long [`Config::dns_cache`] keeps lookups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsCache {
+    /// How long addresses are kept if the resolver does not know their TTL, as the system
+    /// resolver does not.
+    pub default_ttl: Duration,
+    /// The longest addresses are kept, whatever their TTL.
+    pub max_ttl: Duration,
+    /// How long a failed lookup is answered from the cache before it is tried again.
+    pub negative_ttl: Duration,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self {
+            default_ttl: Duration::from_secs(30),
+            max_ttl: Duration::from_secs(300),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Socket options of TCP connections, e.g. to send small responses to high-latency mobile
+/// clients right away, or to notice gateway connections that died silently.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Send small writes right away instead of waiting to coalesce them (`TCP_NODELAY`).
+    pub nodelay: bool,
+    /// Probe idle connections, so dead peers are noticed and NATs keep the connection open
+    /// (`SO_KEEPALIVE`). Disabled when `None`.
+    pub keepalive: Option<Keepalive>,
+}
+
+/// When idle TCP connections are probed, see [`TcpOptions::keepalive`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long a connection is idle before the first probe.
+    pub idle: Duration,
+    /// The time between unanswered probes. The OS default when `None`, and on systems where it
+    /// cannot be set.
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped. The OS default when `None`, and on
+    /// systems where it cannot be set.
+    pub retries: Option<u32>,
+}
+
+/// Tuning of HTTP/1 client connections, e.g. to save memory on a small machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Http1Server {
+    /// Serve further requests on a connection once one is answered.
+    pub keep_alive: bool,
+    /// The most bytes buffered per connection, at least [`Http1Server::MIN_BUF_SIZE`]. About
+    /// 400 KB when `None`.
+    pub max_buf_size: Option<usize>,
+    /// Keep answering clients that shut down their write side after sending a request.
+    pub half_close: bool,
+    /// Collect the responses to pipelined requests into fewer writes.
+    pub pipeline_flush: bool,
+}
+
+impl Http1Server {
+    /// The smallest [`Http1Server::max_buf_size`] hyper accepts.
+    pub const MIN_BUF_SIZE: usize = 8192;
+}
+
+impl Default for Http1Server {
+    fn default() -> Self {
+        Self { keep_alive: true, max_buf_size: None, half_close: false, pipeline_flush: false }
+    }
+}
+
+/// How bodies stream between clients and gateways. Bodies are forwarded frame by frame, reading
+/// from one side only as fast as the other takes the data, so these bound how much a fast sender
+/// can pile up in the relay for a slow receiver.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Streaming {
+    /// The most body bytes in flight per HTTP/2 stream and connection, to clients and gateways
+    /// alike, and buffered per HTTP/1 gateway connection. At least
+    /// [`Http1Server::MIN_BUF_SIZE`]. Hyper's defaults when `None`. HTTP/1 client connections are
+    /// bounded by [`Http1Server::max_buf_size`].
+    pub max_buffered_bytes: Option<u32>,
+    /// Merge body chunks that are ready together into writes of about this many bytes, so a
+    /// sender of many small chunks does not cost a write each. Data is never held back waiting
+    /// for more. Each chunk is written on its own when `None`.
+    pub flush_threshold: Option<usize>,
+}
+
+/// How idle connections to gateways are pooled for reuse. Keeping more open longer saves
+/// forwards a TCP connect and TLS handshake, at the cost of connections held open to the gateway.
+/// HTTP/2 connections carry every concurrent request to a gateway, up to the stream limit the
+/// gateway advertises.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayPool {
+    /// The most idle connections kept per gateway. Unlimited when `None`.
+    pub max_idle_per_host: Option<usize>,
+    /// Close connections that have been idle for this long. Kept until the gateway closes them
+    /// when `None`.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl GatewayPool {
+    /// The default [`GatewayPool::idle_timeout`].
+    pub const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+}
+
+impl Default for GatewayPool {
+    fn default() -> Self {
+        Self { max_idle_per_host: None, idle_timeout: Some(Self::IDLE_TIMEOUT) }
+    }
+}
+
+/// What WebSocket bootstrap tunnels must ask for and may send.
+#[cfg(feature = "ws-bootstrap")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsBootstrap {
+    /// The subprotocol clients must request with `Sec-WebSocket-Protocol`, `ohttp-bootstrap` by
+    /// default. Upgrades that do not are refused with 400 Bad Request. Any upgrade is accepted
+    /// when `None`.
+    pub subprotocol: Option<String>,
+    /// The largest frame accepted from the client, in bytes. 16 MiB when `None`.
+    pub max_frame_size: Option<usize>,
+    /// The largest message accepted from the client, in bytes. 64 MiB when `None`.
+    pub max_message_size: Option<usize>,
+    /// Close a tunnel once no data has passed in either direction for this long.
+    pub idle_timeout: Option<Duration>,
+}
+
+#[cfg(feature = "ws-bootstrap")]
+impl WsBootstrap {
+    /// The subprotocol required by default.
+    pub const SUBPROTOCOL: &'static str = "ohttp-bootstrap";
+}
+
+#[cfg(feature = "ws-bootstrap")]
+impl Default for WsBootstrap {
+    fn default() -> Self {
+        Self {
+            subprotocol: Some(Self::SUBPROTOCOL.to_owned()),
+            max_frame_size: None,
+            max_message_size: None,
+            idle_timeout: None,
+        }
+    }
+}
+
+/// What is logged about each request.
+///
+/// By default only the method, a coarse class of the path such as `relay` or `health`, the
+/// response status and the latency are logged, never client addresses or headers. Each optional
+/// field makes clients easier to tell apart, so enable them only where that is acceptable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessLog {
+    /// Where entries are written.
+    pub sink: AccessLogSink,
+    /// Log the client's address.
+    pub client_addr: bool,
+    /// Log the `User-Agent` header.
+    pub user_agent: bool,
+    /// Log the request path instead of its class. Reveals which gateway a client selected.
+    pub full_path: bool,
+}
+
+/// Where access log entries are written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AccessLogSink {
+    /// `tracing` events at info level with the `ohttp_relay::access` target.
+    #[default]
+    Tracing,
+    /// A JSON object per line appended to this file.
+    JsonFile(PathBuf),
+}
+
+/// How gateways are probed for health.
+///
+/// When every replica of the default gateway is unhealthy, requests for it are answered with
+/// 503 Service Unavailable and a `Retry-After` of one probe interval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// The path requested with GET on each gateway, such as its key configuration endpoint.
+    pub path: String,
+    /// How often each gateway is probed.
+    pub interval: Duration,
+    /// Consecutive failed probes before a gateway is taken out of rotation. A single
+    /// successful probe puts it back.
+    pub failure_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: "/ohttp-keys".to_owned(),
+            interval: Duration::from_secs(10),
+            failure_threshold: 3,
+        }
+    }
+}
+
+/// How key configurations are fetched from the gateway and cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OhttpKeys {
+    /// The path requested with GET on the default gateway.
+    pub path: String,
+    /// The longest a fetched key configuration is served from cache. Shortened by the
+    /// gateway's own `Cache-Control: max-age`, or its `Expires` without one, and not cached at
+    /// all for `no-store`.
+    pub max_age: Duration,
+    /// The longest a request with `If-None-Match` and `Prefer: wait` is held until the key
+    /// configuration changes. Zero to answer such requests at once.
+    pub max_wait: Duration,
+}
+
+impl Default for OhttpKeys {
+    fn default() -> Self {
+        Self {
+            path: "/ohttp-keys".to_owned(),
+            max_age: Duration::from_secs(5 * 60),
+            max_wait: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Cross-origin resource sharing for browser clients.
+///
+/// `OPTIONS` preflight requests on any path are answered with 204 No Content and the allowed
+/// methods and headers. Every response allows the requesting origin to read it if listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cors {
+    /// Origins such as `https://wallet.example` allowed to read responses, or `*` for any.
+    /// Empty to allow none.
+    pub allowed_origins: Vec<HeaderValue>,
+    /// Methods preflight requests are told they may use.
+    pub allowed_methods: Vec<Method>,
+    /// Request headers preflight requests are told they may send.
+    pub allowed_headers: Vec<HeaderName>,
+    /// How long browsers may cache a preflight response. Left to the browser when `None`.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![HeaderValue::from_static("*")],
+            allowed_methods: vec![Method::CONNECT, Method::GET, Method::OPTIONS, Method::POST],
+            allowed_headers: vec![CONTENT_TYPE, CONTENT_LENGTH],
+            max_age: None,
+        }
+    }
+}
+
+/// A token bucket rate limit per client address.
+///
+/// Clients are told apart by a salted hash of their address, never the address itself, and
+/// IPv6 clients by their /64 prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Requests a client may send at once before being limited.
+    pub burst: u32,
+    /// Requests per second a client may sustain.
+    pub per_second: u32,
+    /// How often the salt is replaced and every client's bucket forgotten.
+    pub salt_rotation: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self { burst: 20, per_second: 5, salt_rotation: Duration::from_secs(60 * 60) }
+    }
+}
+
+/// A token bucket limit on the body bytes one client connection streams.
+///
+/// Each chunk is passed on as it arrives, and the connection's next chunk waits until the
+/// bucket has refilled what it overdrew.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    /// Bytes a connection may stream at once before being slowed down.
+    pub burst: u32,
+    /// Bytes per second a connection may sustain. Must not be zero.
+    pub per_second: u32,
+}
+
+impl Default for BandwidthLimit {
+    fn default() -> Self { Self { burst: 256 * 1024, per_second: 1024 * 1024 } }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use http::uri::PathAndQuery;
+use http::Uri;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::body::BoxError;
+use crate::{Config, DeniedGateway, PathRewrite, RateLimit, Reload};
+
+/// Relay settings read from a TOML file, e.g.
+///
+/// ```toml
+/// gateway_origin = "https://gateway.example"
+/// bind_addr = "0.0.0.0:3000"
+/// allowed_gateways = ["https://other-gateway.example"]
+/// gateway_replicas = ["https://gateway-2.example"]
+/// denied_gateways = ["https://blocked.example", "203.0.113.0/24", "example"]
+/// gateway_path = "/gateway"
+/// max_body_size = 65536
+/// connect_timeout = 5
+///
+/// [named_gateways]
+/// acme = "https://gateway.acme.example"
+///
+/// [rate_limit]
+/// burst = 20
+/// per_second = 5
+///
+/// [tls]
+/// cert = "/etc/ohttp-relay/cert.pem"
+/// key = "/etc/ohttp-relay/key.pem"
+/// ```
+///
+/// Every setting is optional. Timeouts are in seconds.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default, deserialize_with = "uri")]
+    pub gateway_origin: Option<Uri>,
+    pub bind_addr: Option<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub tls: Option<TlsFiles>,
+    #[serde(default, deserialize_with = "uris")]
+    pub allowed_gateways: Vec<Uri>,
+    #[serde(default, deserialize_with = "uris")]
+    pub gateway_replicas: Vec<Uri>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub denied_gateways: Vec<DeniedGateway>,
+    #[serde(default, deserialize_with = "named_uris")]
+    pub named_gateways: BTreeMap<String, Uri>,
+    #[serde(default)]
+    pub danger_allow_insecure_gateway: bool,
+    #[serde(default, deserialize_with = "path")]
+    pub gateway_path: Option<PathAndQuery>,
+    pub strip_path_prefix: Option<String>,
+    pub add_path_prefix: Option<String>,
+    pub max_body_size: Option<u64>,
+    pub max_response_body_size: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub max_concurrent_requests_per_connection: Option<u32>,
+    pub max_requests_per_connection: Option<u64>,
+    #[serde(default, deserialize_with = "rate_limit")]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default, deserialize_with = "secs")]
+    pub connect_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "secs")]
+    pub response_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "secs")]
+    pub request_deadline: Option<Duration>,
+    #[serde(default, deserialize_with = "secs")]
+    pub header_read_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "secs")]
+    pub body_read_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "secs")]
+    pub idle_timeout: Option<Duration>,
+}
+
+/// PEM files to terminate TLS with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA certificates client certificates must be signed by, to require them.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, BoxError> {
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// `config` with the settings in this file applied on top. Listener and TLS settings are
+    /// left to the caller.
+    pub fn apply(&self, mut config: Config) -> Config {
+        config.allowed_gateways = self.allowed_gateways.clone();
+        config.gateway_replicas = self.gateway_replicas.clone();
+        config.denied_gateways = self.denied_gateways.clone();
+        config.named_gateways = self.named_gateways.clone();
+        config.danger_allow_insecure_gateway |= self.danger_allow_insecure_gateway;
+        if let Some(path) = &self.gateway_path {
+            config.path_rewrite = PathRewrite::Fixed(path.clone());
+        } else if self.strip_path_prefix.is_some() || self.add_path_prefix.is_some() {
+            config.path_rewrite = PathRewrite::Prefix {
+                strip: self.strip_path_prefix.clone().unwrap_or_default(),
+                add: self.add_path_prefix.clone().unwrap_or_default(),
+            };
+        }
+        config.rate_limit = self.rate_limit.clone().or(config.rate_limit);
+        config.max_body_size = self.max_body_size.or(config.max_body_size);
+        config.max_response_body_size =
+            self.max_response_body_size.or(config.max_response_body_size);
+        config.max_connections = self.max_connections.or(config.max_connections);
+        config.max_concurrent_requests_per_connection = self
+            .max_concurrent_requests_per_connection
+            .or(config.max_concurrent_requests_per_connection);
+        config.max_requests_per_connection =
+            self.max_requests_per_connection.or(config.max_requests_per_connection);
+        config.connect_timeout = self.connect_timeout.or(config.connect_timeout);
+        config.response_timeout = self.response_timeout.or(config.response_timeout);
+        config.request_deadline = self.request_deadline.or(config.request_deadline);
+        config.header_read_timeout = self.header_read_timeout.or(config.header_read_timeout);
+        config.body_read_timeout = self.body_read_timeout.or(config.body_read_timeout);
+        config.idle_timeout = self.idle_timeout.or(config.idle_timeout);
+        config
+    }
+}
+
+/// Reloads a relay whenever its configuration file changes, until dropped.
+///
+/// The file is checked for a new modification time every `interval`. Each change is turned
+/// into a [`Config`] by `build`, e.g. [`ConfigFile::apply`] with overrides layered on top, and
+/// sent through `reload`, so only the settings listed at [`Reload`] take effect without a
+/// restart. A file that fails to load or build is logged and the running settings kept.
+#[derive(Debug)]
+pub struct ConfigWatcher(JoinHandle<()>);
+
+impl ConfigWatcher {
+    pub fn spawn<F>(path: PathBuf, reload: Reload, interval: Duration, build: F) -> Self
+    where
+        F: Fn(&ConfigFile) -> Result<Config, BoxError> + Send + 'static,
+    {
+        Self(tokio::spawn(watch(path, reload, interval, build)))
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) { self.0.abort(); }
+}
+
+async fn watch<F>(path: PathBuf, reload: Reload, interval: Duration, build: F)
+where
+    F: Fn(&ConfigFile) -> Result<Config, BoxError>,
+{
+    let mut modified = modified_at(&path);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let now = modified_at(&path);
+        if now == modified {
+            continue;
+        }
+        modified = now;
+        match ConfigFile::load(&path).and_then(|file| build(&file)) {
+            Ok(config) => {
+                info!("Reloading {}", path.display());
+                reload.reload(config);
+            }
+            Err(e) => warn!("Keeping previous settings: {}", e),
+        }
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn uri<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Uri>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|uri| uri.parse().map_err(D::Error::custom))
+        .transpose()
+}
+
+fn path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathAndQuery>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|path| path.parse().map_err(D::Error::custom))
+        .transpose()
+}
+
+fn uris<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Uri>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|uri| uri.parse().map_err(D::Error::custom))
+        .collect()
+}
+
+fn parsed<'de, D: Deserializer<'de>, T: FromStr<Err = String>>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| value.parse().map_err(D::Error::custom))
+        .collect()
+}
+
+fn named_uris<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, Uri>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, uri)| Ok((name, uri.parse().map_err(D::Error::custom)?)))
+        .collect()
+}
+
+fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(secs) if secs.is_finite() && secs >= 0.0 => Ok(Some(Duration::from_secs_f64(secs))),
+        Some(secs) => Err(D::Error::custom(format!("Invalid number of seconds: {}", secs))),
+        None => Ok(None),
+    }
+}
+
+fn rate_limit<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<RateLimit>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Table {
+        burst: Option<u32>,
+        per_second: Option<u32>,
+        #[serde(default, deserialize_with = "secs")]
+        salt_rotation: Option<Duration>,
+    }
+
+    Ok(Option::<Table>::deserialize(deserializer)?.map(|table| {
+        let default = RateLimit::default();
+        RateLimit {
+            burst: table.burst.unwrap_or(default.burst),
+            per_second: table.per_second.unwrap_or(default.per_second),
+            salt_rotation: table.salt_rotation.unwrap_or(default.salt_rotation),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn settings_applied_on_top_of_config() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            gateway_origin = "https://gateway.example"
+            gateway_replicas = ["https://gateway-2.example"]
+            gateway_path = "/gateway"
+            connect_timeout = 1.5
+
+            [named_gateways]
+            acme = "https://gateway.acme.example"
+
+            [rate_limit]
+            burst = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.gateway_origin, Some(Uri::from_static("https://gateway.example")));
+
+        let config =
+            file.apply(Config { idle_timeout: Some(Duration::from_secs(9)), ..Config::default() });
+        assert_eq!(config.gateway_replicas, [Uri::from_static("https://gateway-2.example")]);
+        assert_eq!(config.named_gateways["acme"], "https://gateway.acme.example");
+        let gateway_path = PathAndQuery::from_static("/gateway");
+        assert_eq!(config.path_rewrite, PathRewrite::Fixed(gateway_path));
+        assert_eq!(config.connect_timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(9)));
+        assert_eq!(config.rate_limit, Some(RateLimit { burst: 2, ..RateLimit::default() }));
+    }
+
+    #[test]
+    fn unknown_settings_rejected() {
+        assert!(toml::from_str::<ConfigFile>("gateway = \"https://gateway.example\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("connect_timeout = -1").is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http::Uri;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tower_service::Service;
+
+use crate::body::BoxError;
+use crate::error::ResolveError;
+use crate::resolve::{resolve_uri, Resolver};
+use crate::svcb::Discovery;
+use crate::{tcp, TcpOptions};
+
+/// Opens connections to the gateway, either directly, through a SOCKS5 proxy or over Tor.
+#[derive(Debug, Clone)]
+pub(crate) enum GatewayConnector {
+    /// Racing every address the gateway resolves to, see [`connect_any`].
+    Direct {
+        resolver: Arc<dyn Resolver>,
+        /// Dials the endpoints gateways advertise, see [`Config::gateway_discovery`].
+        ///
+        /// [`Config::gateway_discovery`]: crate::Config::gateway_discovery
+        discovery: Option<Arc<Discovery>>,
+        connect_timeout: Option<Duration>,
+        attempt_delay: Duration,
+        tcp: TcpOptions,
+    },
+    Socks5 {
+        proxy: SocketAddr,
+        connect_timeout: Option<Duration>,
+        tcp: TcpOptions,
+    },
+    #[cfg(feature = "tor-client")]
+    Tor(Box<TorDialer>),
+}
+
+impl Service<Uri> for GatewayConnector {
+    type Response = GatewayStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        match self {
+            Self::Direct { resolver, discovery, connect_timeout, attempt_delay, tcp } => {
+                let (resolver, discovery, options) =
+                    (resolver.clone(), discovery.clone(), tcp.clone());
+                let (connect_timeout, attempt_delay) = (*connect_timeout, *attempt_delay);
+                Box::pin(async move {
+                    let addrs = match discovery {
+                        Some(discovery) => discovery.resolve(&dst, resolver.as_ref()).await,
+                        None => resolve_uri(&dst, resolver.as_ref()).await,
+                    }
+                    .map_err(ResolveError)?;
+                    let stream =
+                        with_timeout(connect_timeout, connect_any(&addrs, attempt_delay)).await?;
+                    tcp::apply(&stream, &options)?;
+                    Ok(GatewayStream::Tcp(TokioIo::new(stream)))
+                })
+            }
+            Self::Socks5 { proxy, connect_timeout, tcp } => {
+                let (proxy, connect_timeout, options) = (*proxy, *connect_timeout, tcp.clone());
+                Box::pin(async move {
+                    let (host, port) = host_port(&dst)?;
+                    let stream =
+                        with_timeout(connect_timeout, socks5_connect(proxy, host, port)).await?;
+                    tcp::apply(&stream, &options)?;
+                    Ok(GatewayStream::Tcp(TokioIo::new(stream)))
+                })
+            }
+            #[cfg(feature = "tor-client")]
+            Self::Tor(tor) => {
+                let tor = tor.as_ref().clone();
+                Box::pin(async move {
+                    let (host, port) = host_port(&dst)?;
+                    let connecting = tor.client.connect_with_prefs((host, port), &tor.prefs);
+                    let stream = with_timeout(tor.connect_timeout, async {
+                        connecting.await.map_err(std::io::Error::other)
+                    })
+                    .await?;
+                    Ok(GatewayStream::Tor(TokioIo::new(stream)))
+                })
+            }
+        }
+    }
+}
+
+/// The host and port to dial for `dst`, defaulting the port by scheme.
+fn host_port(dst: &Uri) -> Result<(&str, u16), BoxError> {
+    let host = dst.host().ok_or("Gateway uri has no host")?;
+    let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+        Some("http") => 80,
+        _ => 443,
+    });
+    Ok((host, port))
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    connecting: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connecting)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::TimedOut, e))?,
+        None => connecting.await,
+    }
+}
+
+/// Connect to the first of `addrs` to accept, racing them as RFC 8305 describes: addresses
+/// are tried alternating between IPv6 and IPv4, starting with the family of the first, and
+/// each attempt starts once the previous one failed or has not succeeded within
+/// `attempt_delay`. Fails with the last error if none succeeds.
+pub(crate) async fn connect_any(
+    addrs: &[SocketAddr],
+    attempt_delay: Duration,
+) -> std::io::Result<TcpStream> {
+    let mut pending = interleave_families(addrs).into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+    if let Some(addr) = pending.next() {
+        attempts.spawn(TcpStream::connect(addr));
+    }
+    loop {
+        tokio::select! {
+            Some(attempt) = attempts.join_next() => match attempt {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    last_err = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.spawn(TcpStream::connect(addr));
+                    }
+                }
+                Err(e) => last_err = Some(std::io::Error::other(e)),
+            },
+            _ = tokio::time::sleep(attempt_delay), if pending.peek().is_some() => {
+                let addr = pending.next().expect("checked by the guard");
+                attempts.spawn(TcpStream::connect(addr));
+            }
+            else => break,
+        }
+    }
+    Err(last_err.unwrap_or_else(|| std::io::ErrorKind::AddrNotAvailable.into()))
+}
+
+/// `addrs` reordered to alternate between address families, keeping the order within each.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == first_v6);
+    preferred.reverse();
+    other.reverse();
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        interleaved.extend(preferred.pop());
+        interleaved.extend(other.pop());
+    }
+    interleaved
+}
+
+/// Dials gateways, including `.onion` ones, over Tor with an embedded arti client. The client
+/// bootstraps on the first connection.
+#[cfg(feature = "tor-client")]
+#[derive(Clone)]
+pub(crate) struct TorDialer {
+    client: arti_client::TorClient<tor_rtcompat::PreferredRuntime>,
+    prefs: arti_client::StreamPrefs,
+    connect_timeout: Option<Duration>,
+}
+
+#[cfg(feature = "tor-client")]
+impl TorDialer {
+    pub(crate) fn new(
+        dirs: &crate::TorDirs,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Self, BoxError> {
+        let config = arti_client::config::TorClientConfigBuilder::from_directories(
+            &dirs.state_dir,
+            &dirs.cache_dir,
+        )
+        .build()?;
+        let client = arti_client::TorClient::builder().config(config).create_unbootstrapped()?;
+        let mut prefs = arti_client::StreamPrefs::new();
+        prefs.connect_to_onion_services(arti_client::config::BoolOrAuto::Explicit(true));
+        Ok(Self { client, prefs, connect_timeout })
+    }
+}
+
+#[cfg(feature = "tor-client")]
+impl std::fmt::Debug for TorDialer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TorDialer").field("connect_timeout", &self.connect_timeout).finish()
+    }
+}
+
+/// A connection to the gateway opened by [`GatewayConnector`].
+pub(crate) enum GatewayStream {
+    Tcp(TokioIo<TcpStream>),
+    #[cfg(feature = "tor-client")]
+    Tor(TokioIo<arti_client::DataStream>),
+}
+
+impl Connection for GatewayStream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Tcp(stream) => stream.connected(),
+            #[cfg(feature = "tor-client")]
+            Self::Tor(_) => Connected::new(),
+        }
+    }
+}
+
+impl Read for GatewayStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tor-client")]
+            Self::Tor(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for GatewayStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tor-client")]
+            Self::Tor(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tor-client")]
+            Self::Tor(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tor-client")]
+            Self::Tor(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Open a tunnel to `host:port` through the SOCKS5 `proxy` without authentication.
+///
+/// Hostnames are resolved by the proxy, so a Tor proxy does not leak the gateway's name to
+/// the local resolver.
+async fn socks5_connect(proxy: SocketAddr, host: &str, port: u16) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        return Err(socks_error("SOCKS5 proxy requires authentication"));
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| socks_error("Hostname too long"))?;
+            request.extend_from_slice(&[3, len]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(socks_error(&format!("SOCKS5 proxy refused to connect: code {}", reply[1])));
+    }
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => usize::from(stream.read_u8().await?),
+        _ => return Err(socks_error("Invalid SOCKS5 reply")),
+    };
+    // Skip the address and port the proxy bound for us.
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+fn socks_error(message: &str) -> std::io::Error { std::io::Error::other(message.to_owned()) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn port_defaults_by_scheme() {
+        let port = |uri: &str| host_port(&uri.parse().unwrap()).unwrap().1;
+        assert_eq!(port("https://gateway.example"), 443);
+        assert_eq!(port("http://gateway.example"), 80);
+        assert_eq!(port("https://gateway.onion:8443"), 8443);
+    }
+
+    #[test]
+    fn families_interleaved() {
+        let addrs: Vec<SocketAddr> =
+            ["[::1]:1", "[::2]:1", "[::3]:1", "127.0.0.1:1", "127.0.0.2:1"]
+                .iter()
+                .map(|addr| addr.parse().unwrap())
+                .collect();
+        let interleaved: Vec<String> =
+            interleave_families(&addrs).iter().map(ToString::to_string).collect();
+        assert_eq!(interleaved, ["[::1]:1", "127.0.0.1:1", "[::2]:1", "127.0.0.2:1", "[::3]:1"]);
+    }
+
+    #[tokio::test]
+    async fn unreachable_addresses_skipped() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused = closed.local_addr().unwrap();
+        drop(closed);
+        // The delay is long enough that only the refusal moves on to the next address.
+        let addrs = [refused, live];
+        let connecting = connect_any(&addrs, Duration::from_secs(10));
+        let stream = tokio::time::timeout(Duration::from_secs(2), connecting).await.unwrap();
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), live);
+    }
+}
@@ -0,0 +1,91 @@
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, VARY,
+};
+use hyper::{Response, StatusCode};
+
+use crate::{empty, Cors};
+
+/// Answer a preflight request from `origin`.
+pub(crate) fn preflight(
+    cors: &Cors,
+    origin: Option<&HeaderValue>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = Response::new(empty());
+    *res.status_mut() = StatusCode::NO_CONTENT;
+    insert_allow_origin(&mut res, cors, origin);
+    let headers = res.headers_mut();
+    let methods: Vec<_> = cors.allowed_methods.iter().map(|method| method.as_str()).collect();
+    if let Ok(methods) = HeaderValue::from_str(&methods.join(", ")) {
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+    }
+    let allowed: Vec<_> = cors.allowed_headers.iter().map(|name| name.as_str()).collect();
+    if let Ok(allowed) = HeaderValue::from_str(&allowed.join(", ")) {
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+    }
+    if let Some(max_age) = cors.max_age {
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+    }
+    res
+}
+
+/// Allow `origin` to read `res` if it is one of the allowed origins. Caches must tell origins
+/// apart unless every origin is allowed.
+pub(crate) fn insert_allow_origin<B>(
+    res: &mut Response<B>,
+    cors: &Cors,
+    origin: Option<&HeaderValue>,
+) {
+    let headers = res.headers_mut();
+    if cors.allowed_origins.iter().any(|allowed| allowed == "*") {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        return;
+    }
+    if cors.allowed_origins.is_empty() {
+        return;
+    }
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+    if let Some(origin) = origin.filter(|origin| cors.allowed_origins.contains(origin)) {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn only_listed_origins_allowed() {
+        let cors = Cors {
+            allowed_origins: vec![HeaderValue::from_static("https://wallet.example")],
+            max_age: Some(Duration::from_secs(600)),
+            ..Cors::default()
+        };
+        let allowed = HeaderValue::from_static("https://wallet.example");
+        let res = preflight(&cors, Some(&allowed));
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], allowed);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_METHODS], "CONNECT, GET, OPTIONS, POST");
+        assert_eq!(res.headers()[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(res.headers()[VARY], "Origin");
+
+        let other = HeaderValue::from_static("https://evil.example");
+        let res = preflight(&cors, Some(&other));
+        assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        let res = preflight(&cors, None);
+        assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[test]
+    fn wildcard_allows_any_origin() {
+        let origin = HeaderValue::from_static("https://wallet.example");
+        let res = preflight(&Cors::default(), Some(&origin));
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!res.headers().contains_key(VARY));
+        assert!(!res.headers().contains_key(ACCESS_CONTROL_MAX_AGE));
+    }
+}
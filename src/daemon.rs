@@ -0,0 +1,324 @@
+//! Backgrounding the relay binary and dropping its privileges, for hosts without a service
+//! manager to do either. Part of the `cli` feature.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use crate::body::BoxError;
+
+/// The background half of [`daemonize`], whose foreground half waits until it is
+/// [`Daemon::ready`].
+#[derive(Debug)]
+pub struct Daemon {
+    ready: File,
+}
+
+/// Fork into the background and start a new session, so the relay outlives the shell it was
+/// started from. The foreground process exits once the background one is [`Daemon::ready`],
+/// with status 0, or with status 1 if it exits before then, so a failure to start is still
+/// reported to whoever started the relay.
+///
+/// The background process moves to `/` so it does not keep the directory it was started from
+/// in use, and sets its umask to `027`, so files it creates are not world-readable whatever
+/// the shell's umask was.
+///
+/// Call this before any other thread is started, e.g. before the async runtime.
+pub fn daemonize() -> io::Result<Daemon> {
+    let mut fds = [0; 2];
+    // Safety: `fds` has room for the two descriptors `pipe` writes.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: `pipe` just opened both descriptors and nothing else owns them.
+    let (mut waiting, ready) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // Safety: the process is still single-threaded, so the child inherits a consistent state.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(waiting);
+            // Safety: `setsid` has no memory-safety preconditions.
+            if unsafe { libc::setsid() } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            std::env::set_current_dir("/")?;
+            // Safety: `umask` has no memory-safety preconditions.
+            unsafe { libc::umask(0o027) };
+            redirect(libc::STDIN_FILENO)?;
+            Ok(Daemon { ready })
+        }
+        _ => {
+            drop(ready);
+            let mut byte = [0];
+            let started = matches!(waiting.read(&mut byte), Ok(1));
+            std::process::exit(if started { 0 } else { 1 })
+        }
+    }
+}
+
+impl Daemon {
+    /// Let the foreground process exit successfully, and discard further output, since nothing
+    /// reads the terminal the relay was started from anymore.
+    pub fn ready(mut self) -> io::Result<()> {
+        self.ready.write_all(&[1])?;
+        redirect(libc::STDOUT_FILENO)?;
+        redirect(libc::STDERR_FILENO)
+    }
+}
+
+/// Point `fd` at `/dev/null`.
+fn redirect(fd: RawFd) -> io::Result<()> {
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    // Safety: both descriptors are open, and `dup2` replaces `fd` atomically.
+    if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A file holding the relay's process ID, removed when dropped.
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Write the current process ID to `path`, replacing any file left there, owned by the
+    /// user and group of `owner` so the relay still controls it after
+    /// [`Credentials::drop_privileges`].
+    ///
+    /// Removing it on exit also needs write access to its directory, so put it in one `owner`
+    /// may write to, e.g. `/run/ohttp-relay/`.
+    pub fn create(path: &Path, owner: &Credentials) -> io::Result<Self> {
+        // Replace rather than write through what is there, which may be a symlink planted to
+        // have the relay overwrite another file.
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut file = OpenOptions::new().write(true).create_new(true).mode(0o644).open(path)?;
+        let pid_file = Self(path.to_owned());
+        if owner.uid.is_some() || owner.gid.is_some() {
+            // `-1` leaves the user or group unchanged.
+            let uid = owner.uid.unwrap_or(libc::uid_t::MAX);
+            let gid = owner.gid.unwrap_or(libc::gid_t::MAX);
+            // Safety: `file` is open for as long as the call.
+            if unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        writeln!(file, "{}", std::process::id())?;
+        Ok(pid_file)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if std::fs::remove_file(&self.0).is_err() {
+            // Without write access to the directory, at least leave no stale process ID.
+            let _ = OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&self.0);
+        }
+    }
+}
+
+/// The user and group to run as once the relay's listeners are bound and its keys read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Credentials {
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+}
+
+impl Credentials {
+    /// Look up `user` and `group` by name or numeric ID. Without `group`, the user's primary
+    /// group is taken, if the user has an entry in the user database.
+    ///
+    /// Call this before any other thread is started, since the lookups are not thread-safe.
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> Result<Self, BoxError> {
+        let (uid, primary_gid) = match user {
+            Some(user) => {
+                let (uid, gid) = lookup_user(user)?;
+                (Some(uid), gid)
+            }
+            None => (None, None),
+        };
+        let gid = match group {
+            Some(group) => Some(lookup_group(group)?),
+            None => primary_gid,
+        };
+        Ok(Self { uid, gid })
+    }
+
+    /// Switch to the group and then the user, dropping supplementary groups, for every thread
+    /// of the process. Without either, nothing changes.
+    pub fn drop_privileges(&self) -> io::Result<()> {
+        if let Some(gid) = self.gid {
+            // Safety: an empty list is never read.
+            if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safety: `setgid` has no memory-safety preconditions.
+            if unsafe { libc::setgid(gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(uid) = self.uid {
+            // Safety: `setuid` has no memory-safety preconditions.
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>), BoxError> {
+    let name = CString::new(user)?;
+    // Safety: `name` is a valid C string, and the entry is copied out before the next lookup.
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !entry.is_null() {
+        // Safety: checked not to be null.
+        let entry = unsafe { &*entry };
+        return Ok((entry.pw_uid, Some(entry.pw_gid)));
+    }
+    let uid: libc::uid_t = user.parse().map_err(|_| format!("Unknown user {}", user))?;
+    // Safety: as above.
+    let entry = unsafe { libc::getpwuid(uid) };
+    match entry.is_null() {
+        // Safety: checked not to be null.
+        false => Ok((uid, Some(unsafe { (*entry).pw_gid }))),
+        true => Ok((uid, None)),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t, BoxError> {
+    let name = CString::new(group)?;
+    // Safety: `name` is a valid C string, and the entry is read before the next lookup.
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    match entry.is_null() {
+        // Safety: checked not to be null.
+        false => Ok(unsafe { (*entry).gr_gid }),
+        true => group.parse().map_err(|_| format!("Unknown group {}", group).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    #[test]
+    fn credentials_looked_up_by_name_and_id() {
+        let root = Credentials::lookup(Some("root"), None).unwrap();
+        assert_eq!(root, Credentials { uid: Some(0), gid: Some(0) });
+        let numeric = Credentials::lookup(Some("0"), Some("0")).unwrap();
+        assert_eq!(numeric, root);
+        assert!(Credentials::lookup(Some("no-such-user-for-ohttp-relay"), None).is_err());
+        assert_eq!(Credentials::lookup(None, None).unwrap(), Credentials { uid: None, gid: None });
+    }
+
+    #[test]
+    fn pid_file_removed_when_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.pid");
+        let pid_file = PidFile::create(&path, &Credentials::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn pid_file_replaces_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        std::fs::write(&target, "kept").unwrap();
+        let path = dir.path().join("relay.pid");
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        let _pid_file = PidFile::create(&path, &Credentials::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "kept");
+        assert!(std::fs::symlink_metadata(&path).unwrap().is_file());
+    }
+
+    #[test]
+    #[ignore = "needs root, run with --ignored"]
+    fn pid_file_owned_by_credentials() {
+        assert!(is_root());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.pid");
+        let nobody = Credentials { uid: Some(NOBODY), gid: Some(NOBODY) };
+        let _pid_file = PidFile::create(&path, &nobody).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (NOBODY, NOBODY));
+    }
+
+    #[test]
+    #[ignore = "needs root, run with --ignored"]
+    fn privileges_dropped_for_good() {
+        assert!(is_root());
+        // In a child, since the test process itself would lose root.
+        let status = in_child(|| {
+            let nobody = Credentials { uid: Some(NOBODY), gid: Some(NOBODY) };
+            nobody.drop_privileges().is_ok()
+                // Safety: these calls have no memory-safety preconditions.
+                && unsafe { (libc::getuid(), libc::getgid(), libc::getgroups(0, std::ptr::null_mut())) }
+                    == (NOBODY, NOBODY, 0)
+                && unsafe { libc::setuid(0) } != 0
+        });
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn foreground_exits_with_background_status() {
+        // The foreground half exits, so it runs in a child of the test process.
+        let ready = in_child(|| match daemonize() {
+            // Safety: `umask` has no memory-safety preconditions.
+            Ok(daemon)
+                if std::env::current_dir().unwrap() == Path::new("/")
+                    && unsafe { libc::umask(0o027) } == 0o027 =>
+                daemon.ready().is_ok(),
+            _ => false,
+        });
+        assert_eq!(ready, 0);
+        let failed = in_child(|| match daemonize() {
+            Ok(daemon) => {
+                drop(daemon);
+                true
+            }
+            Err(_) => false,
+        });
+        assert_eq!(failed, 1);
+    }
+
+    const NOBODY: u32 = 65534;
+
+    fn is_root() -> bool {
+        // Safety: `geteuid` has no memory-safety preconditions.
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    /// Run `f` in a forked child that exits with status 0 if it returns `true` and 1 otherwise,
+    /// returning that status.
+    fn in_child(f: impl FnOnce() -> bool) -> i32 {
+        // Safety: the child only runs `f` and exits without unwinding into the test harness.
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+            0 => {
+                let ok = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(false);
+                // Safety: `_exit` skips the test harness's exit handlers, as a child should.
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            pid => {
+                let mut status = 0;
+                // Safety: `status` is a valid place for `waitpid` to write to.
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                libc::WEXITSTATUS(status)
+            }
+        }
+    }
+}
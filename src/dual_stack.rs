@@ -0,0 +1,104 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::task::{Context, Poll};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::net::Listener;
+use tracing::{debug, warn};
+
+use crate::tcp;
+
+/// Accepts TCP connections over IPv4 and IPv6 on one port: on one dual-stack socket where the
+/// OS supports them, on one socket per family where it does not, and over IPv4 alone where
+/// IPv6 is unavailable.
+#[derive(Debug)]
+pub(crate) enum DualStackListener {
+    One(TcpListener),
+    Two {
+        v6: TcpListener,
+        v4: TcpListener,
+        /// Which socket to poll first, alternated so neither family starves the other.
+        v4_first: bool,
+    },
+}
+
+impl DualStackListener {
+    /// Bind TCP `port` on every IPv4 and IPv6 interface, queueing up to `backlog` connections
+    /// on each socket. Port 0 binds the same free port for both families.
+    pub(crate) fn bind(port: u16, backlog: u32) -> io::Result<Self> {
+        match bind_v6(port, false, backlog) {
+            Ok(listener) => return Ok(Self::One(listener)),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => return Err(e),
+            Err(e) => debug!("No dual-stack socket, binding each family on its own: {}", e),
+        }
+        match bind_v6(port, true, backlog) {
+            Ok(v6) => {
+                let v4 = bind_v4(v6.local_addr()?.port(), backlog)?;
+                Ok(Self::Two { v6, v4, v4_first: false })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => Err(e),
+            Err(e) => {
+                warn!("IPv6 is unavailable, listening on IPv4 only: {}", e);
+                Ok(Self::One(bind_v4(port, backlog)?))
+            }
+        }
+    }
+}
+
+fn bind_v6(port: u16, only_v6: bool, backlog: u32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(only_v6)?;
+    tcp::listen(socket, SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), backlog)
+}
+
+fn bind_v4(port: u16, backlog: u32) -> io::Result<TcpListener> {
+    tcp::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), backlog)
+}
+
+impl Listener for DualStackListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Io, Self::Addr)>> {
+        match self {
+            Self::One(listener) => listener.poll_accept(cx),
+            Self::Two { v6, v4, v4_first } => {
+                *v4_first = !*v4_first;
+                let (first, second) = if *v4_first { (v4, v6) } else { (v6, v4) };
+                match first.poll_accept(cx) {
+                    Poll::Pending => second.poll_accept(cx),
+                    ready => ready,
+                }
+            }
+        }
+    }
+
+    /// The IPv6 address where there is one, as IPv4 connections arrive on the same port.
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        match self {
+            Self::One(listener) | Self::Two { v6: listener, .. } => listener.local_addr(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn accepts_both_families() {
+        let mut listener = DualStackListener::bind(0, 1024).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for ip in [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()] {
+            let mut client = TcpStream::connect(SocketAddr::new(ip, port)).await.unwrap();
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            client.write_all(b"hi").await.unwrap();
+            let mut buf = [0; 2];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hi");
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::body::BoxError;
+use crate::resolve::SystemResolver;
+use crate::{BandwidthLimit, Config, Keepalive, PathRewrite, RateLimit, TcpOptions};
+
+/// The prefix of every variable read by [`Config::from_env`].
+const PREFIX: &str = "OHTTP_RELAY_";
+
+impl Config {
+    /// The default configuration with [`Config::with_env`] applied.
+    pub fn from_env() -> Result<Self, BoxError> { Self::default().with_env() }
+
+    /// Override settings with the `OHTTP_RELAY_*` environment variables that are set:
+    ///
+    /// - `CONNECT_TIMEOUT`, `RESPONSE_TIMEOUT`, `REQUEST_DEADLINE`, `HEADER_READ_TIMEOUT`,
+    ///   `BODY_READ_TIMEOUT`, `IDLE_TIMEOUT`, `MAX_CONNECTION_AGE`, `SHUTDOWN_TIMEOUT`, `DNS_PIN_INTERVAL`
+    ///   and `POOL_IDLE_TIMEOUT` in seconds
+    /// - `MAX_BODY_SIZE`, `MAX_RESPONSE_BODY_SIZE` and `MAX_HEADER_BYTES` in bytes, and
+    ///   `MAX_HEADER_COUNT`, `MAX_CONNECTIONS`, `MAX_INFLIGHT_REQUESTS`,
+    ///   `MAX_CONCURRENT_REQUESTS_PER_CONNECTION`, `MAX_REQUESTS_PER_CONNECTION`,
+    ///   `POOL_MAX_IDLE_PER_HOST`, `LISTEN_BACKLOG` and `ACCEPTORS`
+    /// - `CLIENT_TCP_KEEPALIVE` and `GATEWAY_TCP_KEEPALIVE` as the seconds a TCP connection is idle
+    ///   before keepalive probes start, and `CLIENT_TCP_NODELAY` and `GATEWAY_TCP_NODELAY` as `true`
+    ///   or `false`
+    /// - `RATE_LIMIT_BURST` and `RATE_LIMIT_PER_SECOND`, either of which enables rate limiting
+    /// - `BANDWIDTH_LIMIT_BURST` and `BANDWIDTH_LIMIT_PER_SECOND` in bytes, either of which
+    ///   enables bandwidth limiting
+    /// - `ALLOWED_GATEWAYS` and `GATEWAY_REPLICAS` as comma-separated origins, and
+    ///   `DENIED_GATEWAYS` as comma-separated origins, networks and domains
+    /// - `GATEWAY_PATH` as the path every request is forwarded to, e.g. `/gateway`, or else
+    ///   `STRIP_PATH_PREFIX` and `ADD_PATH_PREFIX` to replace one prefix of the path with another
+    /// - `SCRUBBED_RESPONSE_HEADERS` as comma-separated header names removed from gateway
+    ///   responses, replacing the default ones
+    /// - `PINNED_SPKI` as comma-separated base64 SHA-256 hashes of gateway public keys
+    /// - `TLS_VERSIONS` as comma-separated versions such as `1.3`, `TLS_CIPHER_SUITES` as
+    ///   comma-separated IANA names and `TLS_SESSION_CACHE_SIZE`, for connections to gateways
+    /// - `BIND_ADDR` as the IP address the `listen_tcp*` functions listen on, and `DUAL_STACK` as
+    ///   `true` or `false`
+    /// - `SOCKS5_PROXY` as a socket address, and `DISCOVERY_NAMESERVER` as the DNS server HTTPS
+    ///   records are queried from
+    /// - `DANGER_ALLOW_INSECURE_GATEWAY`, `GATEWAY_DISCOVERY`, `PROXY_PROTOCOL`, `DRAIN_ON_RELOAD`
+    ///   and `BOOTSTRAP` as `true` or `false`
+    /// - `METRICS_ADDR` as a socket address, with the `metrics` feature
+    /// - `TOR_DIR` as the directory of the embedded Tor client, with the `tor-client` feature
+    ///
+    /// Settings from a configuration file should be applied first, so the environment
+    /// overrides them.
+    pub fn with_env(self) -> Result<Self, BoxError> {
+        self.with_vars(|name| std::env::var(format!("{}{}", PREFIX, name)).ok())
+    }
+
+    fn with_vars(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self, BoxError> {
+        let vars = Vars(var);
+        self.connect_timeout = vars.secs("CONNECT_TIMEOUT")?.or(self.connect_timeout);
+        self.response_timeout = vars.secs("RESPONSE_TIMEOUT")?.or(self.response_timeout);
+        self.request_deadline = vars.secs("REQUEST_DEADLINE")?.or(self.request_deadline);
+        self.header_read_timeout = vars.secs("HEADER_READ_TIMEOUT")?.or(self.header_read_timeout);
+        self.body_read_timeout = vars.secs("BODY_READ_TIMEOUT")?.or(self.body_read_timeout);
+        self.idle_timeout = vars.secs("IDLE_TIMEOUT")?.or(self.idle_timeout);
+        self.max_connection_age = vars.secs("MAX_CONNECTION_AGE")?.or(self.max_connection_age);
+        self.shutdown_timeout = vars.secs("SHUTDOWN_TIMEOUT")?.or(self.shutdown_timeout);
+        self.dns_pin_interval = vars.secs("DNS_PIN_INTERVAL")?.or(self.dns_pin_interval);
+        self.gateway_pool.idle_timeout =
+            vars.secs("POOL_IDLE_TIMEOUT")?.or(self.gateway_pool.idle_timeout);
+        self.gateway_pool.max_idle_per_host =
+            vars.parse("POOL_MAX_IDLE_PER_HOST")?.or(self.gateway_pool.max_idle_per_host);
+        self.max_body_size = vars.parse("MAX_BODY_SIZE")?.or(self.max_body_size);
+        self.max_response_body_size =
+            vars.parse("MAX_RESPONSE_BODY_SIZE")?.or(self.max_response_body_size);
+        self.max_header_bytes = vars.parse("MAX_HEADER_BYTES")?.or(self.max_header_bytes);
+        self.max_header_count = vars.parse("MAX_HEADER_COUNT")?.or(self.max_header_count);
+        self.max_connections = vars.parse("MAX_CONNECTIONS")?.or(self.max_connections);
+        self.max_inflight_requests =
+            vars.parse("MAX_INFLIGHT_REQUESTS")?.or(self.max_inflight_requests);
+        self.max_concurrent_requests_per_connection = vars
+            .parse("MAX_CONCURRENT_REQUESTS_PER_CONNECTION")?
+            .or(self.max_concurrent_requests_per_connection);
+        self.max_requests_per_connection =
+            vars.parse("MAX_REQUESTS_PER_CONNECTION")?.or(self.max_requests_per_connection);
+        self.bind_ip = vars.parse("BIND_ADDR")?.unwrap_or(self.bind_ip);
+        self.dual_stack = vars.parse("DUAL_STACK")?.unwrap_or(self.dual_stack);
+        self.listen_backlog = vars.parse("LISTEN_BACKLOG")?.unwrap_or(self.listen_backlog);
+        self.acceptors = vars.parse("ACCEPTORS")?.unwrap_or(self.acceptors);
+        vars.tcp("CLIENT", &mut self.client_tcp)?;
+        vars.tcp("GATEWAY", &mut self.gateway_tcp)?;
+        let (burst, per_second) =
+            (vars.parse("RATE_LIMIT_BURST")?, vars.parse("RATE_LIMIT_PER_SECOND")?);
+        if burst.is_some() || per_second.is_some() {
+            let limit = self.rate_limit.take().unwrap_or_default();
+            self.rate_limit = Some(RateLimit {
+                burst: burst.unwrap_or(limit.burst),
+                per_second: per_second.unwrap_or(limit.per_second),
+                ..limit
+            });
+        }
+        let (burst, per_second) =
+            (vars.parse("BANDWIDTH_LIMIT_BURST")?, vars.parse("BANDWIDTH_LIMIT_PER_SECOND")?);
+        if burst.is_some() || per_second.is_some() {
+            let limit = self.bandwidth_limit.take().unwrap_or_default();
+            self.bandwidth_limit = Some(BandwidthLimit {
+                burst: burst.unwrap_or(limit.burst),
+                per_second: per_second.unwrap_or(limit.per_second),
+            });
+        }
+        if let Some(gateways) = vars.list("ALLOWED_GATEWAYS")? {
+            self.allowed_gateways = gateways;
+        }
+        if let Some(denied) = vars.list("DENIED_GATEWAYS")? {
+            self.denied_gateways = denied;
+        }
+        if let Some(replicas) = vars.list("GATEWAY_REPLICAS")? {
+            self.gateway_replicas = replicas;
+        }
+        let (strip, add) = (vars.0("STRIP_PATH_PREFIX"), vars.0("ADD_PATH_PREFIX"));
+        if let Some(path) = vars.parse("GATEWAY_PATH")? {
+            self.path_rewrite = PathRewrite::Fixed(path);
+        } else if strip.is_some() || add.is_some() {
+            let (strip, add) = (strip.unwrap_or_default(), add.unwrap_or_default());
+            self.path_rewrite = PathRewrite::Prefix { strip, add };
+        }
+        if let Some(pins) = vars.list("PINNED_SPKI")? {
+            self.pinned_spki = pins;
+        }
+        if let Some(versions) = vars.list("TLS_VERSIONS")? {
+            self.upstream_tls.versions = versions;
+        }
+        if let Some(headers) = vars.list("SCRUBBED_RESPONSE_HEADERS")? {
+            self.scrubbed_response_headers = headers;
+        }
+        if let Some(suites) = vars.list("TLS_CIPHER_SUITES")? {
+            self.upstream_tls.cipher_suites = suites;
+        }
+        self.upstream_tls.session_cache_size =
+            vars.parse("TLS_SESSION_CACHE_SIZE")?.unwrap_or(self.upstream_tls.session_cache_size);
+        self.socks5_proxy = vars.parse("SOCKS5_PROXY")?.or(self.socks5_proxy);
+        #[cfg(feature = "tor-client")]
+        if let Some(dir) = vars.0("TOR_DIR") {
+            self.tor = Some(crate::TorDirs::new(dir));
+        }
+        self.danger_allow_insecure_gateway = vars
+            .parse("DANGER_ALLOW_INSECURE_GATEWAY")?
+            .unwrap_or(self.danger_allow_insecure_gateway);
+        self.gateway_discovery = vars.parse("GATEWAY_DISCOVERY")?.unwrap_or(self.gateway_discovery);
+        if let Some(nameserver) = vars.parse("DISCOVERY_NAMESERVER")? {
+            self.resolver = Arc::new(SystemResolver { nameserver: Some(nameserver) });
+        }
+        self.proxy_protocol = vars.parse("PROXY_PROTOCOL")?.unwrap_or(self.proxy_protocol);
+        self.drain_on_reload = vars.parse("DRAIN_ON_RELOAD")?.unwrap_or(self.drain_on_reload);
+        #[cfg(any(
+            feature = "connect-bootstrap",
+            feature = "ws-bootstrap",
+            feature = "wt-bootstrap"
+        ))]
+        {
+            self.bootstrap = vars.parse("BOOTSTRAP")?.unwrap_or(self.bootstrap);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics_addr = vars.parse("METRICS_ADDR")?.or(self.metrics_addr);
+        }
+        Ok(self)
+    }
+}
+
+/// Variables looked up by their name without [`PREFIX`].
+struct Vars<F>(F);
+
+impl<F: Fn(&str) -> Option<String>> Vars<F> {
+    fn parse<T: FromStr>(&self, name: &str) -> Result<Option<T>, BoxError> {
+        self.0(name).map(|value| parse(name, &value)).transpose()
+    }
+
+    fn secs(&self, name: &str) -> Result<Option<Duration>, BoxError> {
+        match self.parse::<f64>(name)? {
+            Some(secs) if secs.is_finite() && secs >= 0.0 =>
+                Ok(Some(Duration::from_secs_f64(secs))),
+            Some(secs) => Err(format!("Invalid {}{}: {}", PREFIX, name, secs).into()),
+            None => Ok(None),
+        }
+    }
+
+    /// `<SIDE>_TCP_NODELAY` and `<SIDE>_TCP_KEEPALIVE`, keeping the keepalive's other settings.
+    fn tcp(&self, side: &str, options: &mut TcpOptions) -> Result<(), BoxError> {
+        let nodelay = self.parse(&format!("{}_TCP_NODELAY", side))?;
+        options.nodelay = nodelay.unwrap_or(options.nodelay);
+        if let Some(idle) = self.secs(&format!("{}_TCP_KEEPALIVE", side))? {
+            let keepalive = options.keepalive.take();
+            let (interval, retries) = keepalive.map_or((None, None), |k| (k.interval, k.retries));
+            options.keepalive = Some(Keepalive { idle, interval, retries });
+        }
+        Ok(())
+    }
+
+    /// A comma-separated list.
+    fn list<T: FromStr>(&self, name: &str) -> Result<Option<Vec<T>>, BoxError> {
+        self.0(name)
+            .map(|value| value.split(',').map(|uri| parse(name, uri.trim())).collect())
+            .transpose()
+    }
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, BoxError> {
+    value.parse().map_err(|_| format!("Invalid {}{}: {}", PREFIX, name, value).into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn with_vars(config: Config, vars: &[(&str, &str)]) -> Result<Config, BoxError> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        config.with_vars(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn variables_override_config() {
+        let config = Config { idle_timeout: Some(Duration::from_secs(9)), ..Config::default() };
+        let config = with_vars(
+            config,
+            &[
+                ("CONNECT_TIMEOUT", "2.5"),
+                ("RATE_LIMIT_BURST", "3"),
+                ("BANDWIDTH_LIMIT_PER_SECOND", "65536"),
+                ("GATEWAY_REPLICAS", "https://a.example, https://b.example"),
+                ("DENIED_GATEWAYS", "https://c.example, 192.0.2.0/24, onion"),
+                ("PROXY_PROTOCOL", "true"),
+                ("TLS_VERSIONS", "1.3"),
+                ("POOL_MAX_IDLE_PER_HOST", "4"),
+                ("GATEWAY_TCP_KEEPALIVE", "30"),
+                ("BIND_ADDR", "::1"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.connect_timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(9)));
+        assert_eq!(config.rate_limit, Some(RateLimit { burst: 3, ..RateLimit::default() }));
+        let bandwidth_limit = BandwidthLimit { per_second: 65536, ..BandwidthLimit::default() };
+        assert_eq!(config.bandwidth_limit, Some(bandwidth_limit));
+        assert_eq!(config.gateway_replicas.len(), 2);
+        assert_eq!(config.denied_gateways.len(), 3);
+        assert!(config.proxy_protocol);
+        assert_eq!(config.bind_ip, std::net::Ipv6Addr::LOCALHOST);
+        assert_eq!(config.upstream_tls.versions, [crate::TlsVersion::Tls13]);
+        assert_eq!(config.gateway_pool.max_idle_per_host, Some(4));
+        let keepalive = Keepalive { idle: Duration::from_secs(30), interval: None, retries: None };
+        assert_eq!(config.gateway_tcp.keepalive, Some(keepalive));
+        assert_eq!(config.client_tcp, TcpOptions::default());
+    }
+
+    #[test]
+    fn invalid_variable_named() {
+        let err = with_vars(Config::default(), &[("MAX_CONNECTIONS", "many")]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid OHTTP_RELAY_MAX_CONNECTIONS: many");
+    }
+}
@@ -1,19 +1,44 @@
+use std::io::Write;
+use std::time::Duration;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
 use hyper::body::Bytes;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, ALLOW, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER,
+    VARY, WWW_AUTHENTICATE,
+};
 use hyper::{Response, StatusCode};
 
+use crate::body::BoxError;
 use crate::{empty, full};
 
 #[derive(Debug)]
 #[allow(clippy::enum_variant_names)]
 pub(crate) enum Error {
     BadGateway,
-    MethodNotAllowed,
+    GatewayTimeout,
+    /// With the methods the target allows.
+    MethodNotAllowed(HeaderValue),
     UnsupportedMediaType,
     BadRequest(String),
     NotFound,
-    InternalServerError,
+    RequestTimeout,
+    PayloadTooLarge,
+    HeadersTooLarge,
+    TooManyRequests {
+        retry_after: Duration,
+    },
+    Denied(StatusCode),
+    /// With the `WWW-Authenticate` challenge to answer.
+    Unauthorized(HeaderValue),
+    ServiceUnavailable {
+        retry_after: Duration,
+    },
+    /// Forwarding to the gateway failed, answered as [`UpstreamFailure::status`] says.
+    Upstream(UpstreamFailure),
 }
 
 impl Error {
@@ -22,16 +47,78 @@ impl Error {
         match self {
             Self::UnsupportedMediaType => *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE,
             Self::BadGateway => *res.status_mut() = StatusCode::BAD_GATEWAY,
-            Self::MethodNotAllowed => *res.status_mut() = StatusCode::METHOD_NOT_ALLOWED,
+            Self::GatewayTimeout => *res.status_mut() = StatusCode::GATEWAY_TIMEOUT,
+            Self::MethodNotAllowed(allow) => {
+                *res.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+                res.headers_mut().insert(ALLOW, allow.clone());
+            }
             Self::BadRequest(e) => {
                 *res.status_mut() = StatusCode::BAD_REQUEST;
                 *res.body_mut() = full(e.to_string()).boxed();
             }
             Self::NotFound => *res.status_mut() = StatusCode::NOT_FOUND,
-            Self::InternalServerError => *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR,
+            Self::RequestTimeout => *res.status_mut() = StatusCode::REQUEST_TIMEOUT,
+            Self::PayloadTooLarge => *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE,
+            Self::HeadersTooLarge =>
+                *res.status_mut() = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::TooManyRequests { retry_after } => {
+                *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                res.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+            }
+            Self::Denied(status) => *res.status_mut() = *status,
+            Self::Unauthorized(challenge) => {
+                *res.status_mut() = StatusCode::UNAUTHORIZED;
+                res.headers_mut().insert(WWW_AUTHENTICATE, challenge.clone());
+            }
+            Self::ServiceUnavailable { retry_after } => {
+                *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                res.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+            }
+            Self::Upstream(failure) => *res.status_mut() = failure.status(),
         };
         res
     }
+
+    /// Like [`Error::to_response`], but with an RFC 9457 `application/problem+json` body. The
+    /// problem only restates the status and the ID of the request, if any, so it tells clients
+    /// nothing about the relay's internals.
+    pub fn to_problem_response(
+        &self,
+        request_id: Option<&HeaderValue>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut res = self.to_response();
+        let status = res.status();
+        let request_id = request_id
+            .and_then(|id| id.to_str().ok())
+            .map(|id| format!(r#","request_id":"{}""#, id))
+            .unwrap_or_default();
+        let problem = format!(
+            r#"{{"type":"about:blank","title":"{}","status":{}{}}}"#,
+            status.canonical_reason().unwrap_or_default(),
+            status.as_u16(),
+            request_id
+        );
+        *res.body_mut() = full(problem).boxed();
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+        res
+    }
+
+    /// Like [`Error::to_response`], but with any body gzip-compressed.
+    pub fn to_gzip_response(&self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut res = self.to_response();
+        if let Self::BadRequest(e) = self {
+            match gzip(e.as_bytes()) {
+                Ok(compressed) => {
+                    *res.body_mut() = full(compressed).boxed();
+                    res.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                    res.headers_mut().insert(VARY, HeaderValue::from_static("accept-encoding"));
+                }
+                Err(e) => tracing::warn!("Failed to compress error body: {}", e),
+            }
+        }
+        res
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -39,12 +126,277 @@ impl std::fmt::Display for Error {
         match self {
             Self::UnsupportedMediaType => write!(f, "Unsupported media type"),
             Self::BadGateway => write!(f, "Bad gateway"),
-            Self::MethodNotAllowed => write!(f, "Method not allowed"),
+            Self::GatewayTimeout => write!(f, "Gateway timeout"),
+            Self::MethodNotAllowed(_) => write!(f, "Method not allowed"),
             Self::BadRequest(e) => write!(f, "Bad request: {}", e),
             Self::NotFound => write!(f, "Not found"),
-            Self::InternalServerError => write!(f, "Internal server error"),
+            Self::RequestTimeout => write!(f, "Request timeout"),
+            Self::PayloadTooLarge => write!(f, "Payload too large"),
+            Self::HeadersTooLarge => write!(f, "Request header fields too large"),
+            Self::TooManyRequests { .. } => write!(f, "Too many requests"),
+            Self::Denied(status) => write!(f, "Request denied: {}", status),
+            Self::Unauthorized(_) => write!(f, "Unauthorized"),
+            Self::ServiceUnavailable { .. } => write!(f, "Service unavailable"),
+            Self::Upstream(failure) => write!(f, "Gateway unreachable: {}", failure.as_str()),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+/// Why a request could not be forwarded to the gateway, for metrics and logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum UpstreamFailure {
+    /// The gateway's name did not resolve.
+    Dns,
+    /// The gateway refused the connection.
+    Refused,
+    /// Connecting took longer than [`crate::Config::connect_timeout`].
+    ConnectTimeout,
+    /// Connecting failed otherwise, e.g. in the TLS handshake.
+    Connect,
+    /// The response headers took longer than [`crate::Config::response_timeout`].
+    ResponseTimeout,
+    /// The connection failed once established.
+    Connection,
+    /// Too many requests were in flight to the gateway.
+    Overloaded,
+    /// The gateway's circuit breaker is open.
+    CircuitOpen,
+}
+
+impl UpstreamFailure {
+    /// Classify an error of the upstream client by the errors that caused it.
+    pub(crate) fn of(err: &hyper_util::client::legacy::Error) -> Self {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+        while let Some(e) = source {
+            if e.is::<ResolveError>() {
+                return Self::Dns;
+            }
+            match e.downcast_ref::<std::io::Error>().map(std::io::Error::kind) {
+                Some(std::io::ErrorKind::TimedOut) if err.is_connect() =>
+                    return Self::ConnectTimeout,
+                Some(std::io::ErrorKind::ConnectionRefused) => return Self::Refused,
+                _ => {}
+            }
+            source = e.source();
+        }
+        match err.is_connect() {
+            true => Self::Connect,
+            false => Self::Connection,
+        }
+    }
+
+    /// 504 Gateway Timeout for timeouts, 503 Service Unavailable while the relay holds requests
+    /// back, and 502 Bad Gateway otherwise.
+    pub(crate) fn status(self) -> StatusCode {
+        match self {
+            Self::ConnectTimeout | Self::ResponseTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::Overloaded | Self::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// The label of the failure in metrics and logs.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Refused => "refused",
+            Self::ConnectTimeout => "connect_timeout",
+            Self::Connect => "connect",
+            Self::ResponseTimeout => "response_timeout",
+            Self::Connection => "connection",
+            Self::Overloaded => "overloaded",
+            Self::CircuitOpen => "circuit_open",
+        }
+    }
+}
+
+/// A failure to resolve the gateway's name, told apart from failures to reach its addresses.
+#[derive(Debug)]
+pub(crate) struct ResolveError(pub(crate) std::io::Error);
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Failed to resolve gateway: {}", self.0)
+    }
+}
+
+impl std::error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> { Some(&self.0) }
+}
+
+/// Why a relay could not start or stopped serving, as returned by the `listen_*` functions.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RelayError {
+    /// The listener could not be bound or set up, e.g. because its address is in use.
+    Bind(std::io::Error),
+    /// A gateway origin is invalid, e.g. plain `http` without
+    /// [`crate::Config::danger_allow_insecure_gateway`].
+    InvalidGateway(BoxError),
+    /// TLS could not be set up for clients or gateways.
+    Tls(BoxError),
+    /// Another setting in the [`crate::Config`] is invalid.
+    Config(BoxError),
+    /// The onion service could not be launched.
+    #[cfg(feature = "tor-listener")]
+    Tor(BoxError),
+    /// [`crate::Config::on_bound`] failed, so no connection was accepted.
+    OnBound(BoxError),
+    /// The task serving the relay panicked or was cancelled.
+    Task(tokio::task::JoinError),
+}
+
+impl std::fmt::Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Bind(e) => write!(f, "Failed to bind listener: {}", e),
+            Self::InvalidGateway(e) => write!(f, "Invalid gateway: {}", e),
+            Self::Tls(e) => write!(f, "Failed to set up TLS: {}", e),
+            Self::Config(e) => write!(f, "Invalid configuration: {}", e),
+            #[cfg(feature = "tor-listener")]
+            Self::Tor(e) => write!(f, "Failed to launch onion service: {}", e),
+            Self::OnBound(e) => write!(f, "Failed to finish starting: {}", e),
+            Self::Task(e) => write!(f, "Relay task failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Bind(e) => Some(e),
+            Self::InvalidGateway(e) | Self::Tls(e) | Self::Config(e) | Self::OnBound(e) =>
+                Some(e.as_ref()),
+            #[cfg(feature = "tor-listener")]
+            Self::Tor(e) => Some(e.as_ref()),
+            Self::Task(e) => Some(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for RelayError {
+    fn from(e: std::io::Error) -> Self { Self::Bind(e) }
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Whether `Accept-Encoding` lists gzip without ruling it out with `q=0`.
+pub(crate) fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let rejected = params.any(|param| {
+                param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip")) && !rejected
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::io::Read;
+    use std::net::IpAddr;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    use flate2::read::GzDecoder;
+    use http_body_util::Empty;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    use super::*;
+    use crate::connector::GatewayConnector;
+    use crate::resolve::Resolver;
+    use crate::TcpOptions;
+
+    #[derive(Debug)]
+    struct Unresolvable;
+
+    impl Resolver for Unresolvable {
+        fn resolve<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<IpAddr>>> + Send + 'a>> {
+            Box::pin(async { Err(std::io::ErrorKind::NotFound.into()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_failures_classified() {
+        let connector = GatewayConnector::Direct {
+            resolver: Arc::new(Unresolvable),
+            discovery: None,
+            connect_timeout: None,
+            attempt_delay: Duration::from_millis(250),
+            tcp: TcpOptions::default(),
+        };
+        let client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(connector);
+        let err = client.get("http://gateway.example/".parse().unwrap()).await.unwrap_err();
+        assert_eq!(UpstreamFailure::of(&err), UpstreamFailure::Dns);
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let err = client.get(format!("http://{}/", closed).parse().unwrap()).await.unwrap_err();
+        assert_eq!(UpstreamFailure::of(&err), UpstreamFailure::Refused);
+
+        let res = Error::Upstream(UpstreamFailure::Refused).to_response();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        let res = Error::Upstream(UpstreamFailure::ResponseTimeout).to_response();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn gzip_response_decompresses_to_message() {
+        let res = Error::BadRequest("Invalid target uri".to_owned()).to_gzip_response();
+        assert_eq!(res.headers().get(CONTENT_ENCODING), Some(&HeaderValue::from_static("gzip")));
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let mut decompressed = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, "Invalid target uri");
+    }
+
+    #[tokio::test]
+    async fn problem_response_hides_details() {
+        let res = Error::BadRequest("Invalid target uri".to_owned()).to_problem_response(None);
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/problem+json");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, r#"{"type":"about:blank","title":"Bad Request","status":400}"#);
+
+        let res = Error::NotFound.to_problem_response(Some(&HeaderValue::from_static("00ff")));
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            body,
+            r#"{"type":"about:blank","title":"Not Found","status":404,"request_id":"00ff"}"#
+        );
+
+        let res = Error::TooManyRequests { retry_after: Duration::from_secs(3) }
+            .to_problem_response(None);
+        assert_eq!(res.headers()[RETRY_AFTER], "3");
+    }
+
+    #[test]
+    fn accept_encoding_negotiation() {
+        let accepts = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("br, GZIP;q=0.5"));
+        assert!(!accepts("br, deflate"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+}
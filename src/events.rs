@@ -0,0 +1,85 @@
+//! Typed events for applications embedding the relay, see [`crate::Config::events`].
+
+use std::time::Duration;
+
+use http::StatusCode;
+use tokio::sync::broadcast::Sender;
+
+use crate::error::UpstreamFailure;
+use crate::gateway_uri::GatewayUri;
+
+/// Something that happened in the relay, sent to the subscribers of [`crate::Config::events`].
+///
+/// Like the metrics, events say nothing about the client, so subscribers cannot link requests
+/// to who sent them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RelayEvent {
+    /// A listener accepted a connection.
+    ConnectionAccepted,
+    /// A connection was closed, by either side.
+    ConnectionClosed,
+    /// A gateway answered a forwarded request with `status` after `latency`.
+    RequestForwarded { gateway: GatewayUri, status: StatusCode, latency: Duration },
+    /// A request could not be forwarded to `gateway`, for the same `reason` the
+    /// `ohttp_relay_gateway_failures_total` metric is labelled with, e.g. `refused`.
+    GatewayError { gateway: GatewayUri, reason: &'static str },
+    /// The circuit breaker for `gateway` opened or closed again.
+    CircuitChanged { gateway: GatewayUri, open: bool },
+    /// The key configuration served for `gateway` was fetched from it again, and `changed` from
+    /// the one cached before if there was one.
+    KeysRefreshed { gateway: GatewayUri, changed: bool },
+}
+
+/// Send the event `event` builds to the subscribers of `events`, if there are any.
+pub(crate) fn emit(events: &Option<Sender<RelayEvent>>, event: impl FnOnce() -> RelayEvent) {
+    if let Some(events) = events.as_ref().filter(|events| events.receiver_count() > 0) {
+        // Fails only if every subscriber went away since.
+        let _ = events.send(event());
+    }
+}
+
+/// Report that a request could not be forwarded to `gateway` because of `failure`.
+pub(crate) fn gateway_error(
+    events: &Option<Sender<RelayEvent>>,
+    gateway: &GatewayUri,
+    failure: UpstreamFailure,
+) {
+    emit(events, || RelayEvent::GatewayError {
+        gateway: gateway.clone(),
+        reason: failure.as_str(),
+    });
+}
+
+/// Reports a connection as accepted, and as closed once dropped.
+pub(crate) struct Connection(Option<Sender<RelayEvent>>);
+
+impl Connection {
+    pub(crate) fn accepted(events: &Option<Sender<RelayEvent>>) -> Self {
+        emit(events, || RelayEvent::ConnectionAccepted);
+        Self(events.clone())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) { emit(&self.0, || RelayEvent::ConnectionClosed); }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    #[test]
+    fn connection_reported_until_dropped() {
+        let (events, mut subscriber) = broadcast::channel(4);
+        let connection = Connection::accepted(&Some(events));
+        assert_eq!(subscriber.try_recv().unwrap(), RelayEvent::ConnectionAccepted);
+        assert!(subscriber.try_recv().is_err());
+        drop(connection);
+        assert_eq!(subscriber.try_recv().unwrap(), RelayEvent::ConnectionClosed);
+        // Nothing is built or sent without an events channel.
+        drop(Connection::accepted(&None));
+    }
+}
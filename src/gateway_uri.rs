@@ -1,16 +1,40 @@
-use http::Uri;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::uri::PathAndQuery;
+use http::{StatusCode, Uri};
+
+use crate::circuit_breaker::CircuitBreakers;
+use crate::error::Error;
+use crate::select::{GatewaySelector, SelectMeta};
+use crate::{Config, PathRewrite};
 
 /// A normalized gateway origin URI with a default port if none is specified.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GatewayUri(Uri);
 
 impl GatewayUri {
-    pub fn new(mut gateway_origin: Uri) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+    /// Normalize `gateway_origin`, refusing `http://` origins and those on loopback, link-local
+    /// or private addresses unless `allow_insecure` is set.
+    pub fn new(
+        mut gateway_origin: Uri,
+        allow_insecure: bool,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let (scheme, default_port) = match gateway_origin.scheme_str() {
-            Some("http") => ("http", 80),
+            Some("http") if allow_insecure => ("http", 80),
+            Some("http") => return Err(insecure("plaintext", &gateway_origin)),
             Some("https") | None => ("https", 443),
             _ => return Err("Unsupported URI scheme".into()),
         };
+        if let Some(kind) = gateway_origin.host().and_then(internal_kind) {
+            if !allow_insecure {
+                return Err(insecure(kind, &gateway_origin));
+            }
+        }
 
         if gateway_origin.authority().map(|a| a.port().is_none()).unwrap_or(true) {
             let authority = if let Some(auth) = gateway_origin.authority() {
@@ -32,6 +56,414 @@ impl GatewayUri {
 
         Ok(Self(gateway_origin))
     }
+
+    /// Whether `other` has the same scheme and authority.
+    pub fn same_origin(&self, other: &GatewayUri) -> bool {
+        self.scheme() == other.scheme() && self.authority() == other.authority()
+    }
+
+    /// The gateway's scheme and authority, e.g. `https://gateway.example:443`.
+    pub(crate) fn origin(&self) -> String {
+        format!(
+            "{}://{}",
+            self.scheme_str().unwrap_or("https"),
+            self.authority().map_or("", |a| a.as_str())
+        )
+    }
+
+    /// The URI of `path_and_query` on this gateway's origin.
+    pub(crate) fn with_path(&self, path_and_query: &str) -> Result<Uri, http::Error> {
+        Uri::builder()
+            .scheme(self.scheme_str().unwrap_or("https"))
+            .authority(self.authority().map_or("", |a| a.as_str()))
+            .path_and_query(path_and_query)
+            .build()
+    }
+}
+
+fn insecure(kind: &str, gateway_origin: &Uri) -> Box<dyn std::error::Error + Send + Sync> {
+    format!(
+        "Refusing {} gateway {}, only meant for development. Set \
+         danger_allow_insecure_gateway to relay to it anyway.",
+        kind, gateway_origin
+    )
+    .into()
+}
+
+/// Gateways never relayed to, even when allowed, named or chosen by a [`GatewaySelector`]. See
+/// [`Config::denied_gateways`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeniedGateway {
+    /// One origin, e.g. `https://gateway.example`.
+    Origin(Uri),
+    /// Gateways addressed by an IP in this network, e.g. `203.0.113.0/24`.
+    Network { addr: IpAddr, prefix_len: u8 },
+    /// Gateways with this host or a subdomain of it, e.g. `example` for a whole TLD.
+    Domain(String),
+}
+
+impl DeniedGateway {
+    fn matches(&self, gateway: &GatewayUri) -> bool {
+        let host = gateway.host().unwrap_or("").trim_start_matches('[').trim_end_matches(']');
+        match self {
+            Self::Origin(origin) => GatewayUri::new(origin.clone(), true)
+                .is_ok_and(|origin| origin.same_origin(gateway)),
+            Self::Network { addr, prefix_len } =>
+                host.parse().is_ok_and(|ip| in_network(ip, *addr, *prefix_len)),
+            Self::Domain(domain) => {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                host == *domain
+                    || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+            }
+        }
+    }
+}
+
+/// Parses an origin with its scheme, a network in CIDR notation or a single IP, or a domain.
+impl FromStr for DeniedGateway {
+    type Err = String;
+
+    fn from_str(denied: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid denied gateway: {}", denied);
+        if denied.contains("://") {
+            return denied.parse().map(Self::Origin).map_err(|_| invalid());
+        }
+        let (addr, prefix_len) = match denied.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (denied, None),
+        };
+        match addr.parse::<IpAddr>() {
+            Ok(addr) => {
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix_len = prefix_len.unwrap_or(max);
+                match prefix_len <= max {
+                    true => Ok(Self::Network { addr: canonical(addr), prefix_len }),
+                    false => Err(invalid()),
+                }
+            }
+            Err(_) if prefix_len.is_some() => Err(invalid()),
+            Err(_) => {
+                let domain = denied.trim_matches('.').to_ascii_lowercase();
+                match domain.is_empty() {
+                    true => Err(invalid()),
+                    false => Ok(Self::Domain(domain)),
+                }
+            }
+        }
+    }
+}
+
+/// `ip`, with IPv4-mapped IPv6 addresses as the IPv4 address they map.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+fn in_network(ip: IpAddr, addr: IpAddr, prefix_len: u8) -> bool {
+    let (ip, addr, bits) = match (canonical(ip), addr) {
+        (IpAddr::V4(ip), IpAddr::V4(addr)) => (u32::from(ip).into(), u32::from(addr).into(), 32),
+        (IpAddr::V6(ip), IpAddr::V6(addr)) => (u128::from(ip), u128::from(addr), 128),
+        _ => return false,
+    };
+    // Only the leading `prefix_len` bits must match.
+    (ip ^ addr).checked_shr(bits - u32::from(prefix_len)).unwrap_or(0) == 0
+}
+
+/// Which kind of internal target `host` is, if it names this machine or an address on a
+/// private network, such as a cloud metadata service, which a production gateway never is.
+fn internal_kind(host: &str) -> Option<&'static str> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => internal_ip_kind(ip),
+        Err(_) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            (host == "localhost" || host.ends_with(".localhost")).then_some("loopback")
+        }
+    }
+}
+
+/// Which kind of internal address `ip` is: loopback, link-local or private.
+pub(crate) fn internal_ip_kind(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => ipv4_kind(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ipv4_kind(ip),
+            None => ipv6_kind(ip),
+        },
+    }
+}
+
+fn ipv4_kind(ip: Ipv4Addr) -> Option<&'static str> {
+    if ip.is_loopback() || ip.is_unspecified() {
+        Some("loopback")
+    } else if ip.is_link_local() {
+        Some("link-local")
+    } else if ip.is_private() {
+        Some("private")
+    } else {
+        None
+    }
+}
+
+fn ipv6_kind(ip: Ipv6Addr) -> Option<&'static str> {
+    let first = ip.segments()[0];
+    if ip.is_loopback() || ip.is_unspecified() {
+        Some("loopback")
+    } else if first & 0xffc0 == 0xfe80 {
+        Some("link-local")
+    } else if first & 0xfe00 == 0xfc00 {
+        // Unique local addresses, the IPv6 counterpart of RFC 1918.
+        Some("private")
+    } else {
+        None
+    }
+}
+
+/// The gateways a relay forwards to: a default with its replicas, plus an allowlist of
+/// origins that clients may select per request by prefixing the request path with the origin,
+/// unless a [`GatewaySelector`] chooses instead.
+#[derive(Debug)]
+pub(crate) struct Gateways {
+    default: Arc<GatewayUri>,
+    /// The default gateway followed by its replicas.
+    replicas: Vec<Replica>,
+    next_replica: AtomicUsize,
+    allowed: Vec<GatewayUri>,
+    denied: Vec<DeniedGateway>,
+    named: BTreeMap<String, GatewayUri>,
+    circuit_breakers: Option<CircuitBreakers>,
+    selector: Option<Arc<dyn GatewaySelector>>,
+    #[cfg(feature = "connect-bootstrap")]
+    connect_targets: Vec<GatewayUri>,
+    /// How soon to suggest retrying when no replica is healthy.
+    retry_after: Duration,
+}
+
+#[derive(Debug)]
+struct Replica {
+    uri: GatewayUri,
+    healthy: AtomicBool,
+    failed_probes: AtomicU32,
+}
+
+impl Gateways {
+    pub(crate) fn new(
+        default: GatewayUri,
+        config: &Config,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let normalize = |uris: &[Uri]| {
+            uris.iter()
+                .cloned()
+                .map(|uri| GatewayUri::new(uri, config.danger_allow_insecure_gateway))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let replicas = std::iter::once(default.clone())
+            .chain(normalize(&config.gateway_replicas)?)
+            .map(|uri| Replica {
+                uri,
+                healthy: AtomicBool::new(true),
+                failed_probes: AtomicU32::new(0),
+            })
+            .collect();
+        Ok(Self {
+            default: Arc::new(default),
+            replicas,
+            next_replica: AtomicUsize::new(0),
+            allowed: normalize(&config.allowed_gateways)?,
+            denied: config.denied_gateways.clone(),
+            named: config
+                .named_gateways
+                .iter()
+                .map(|(name, uri)| {
+                    let gateway =
+                        GatewayUri::new(uri.clone(), config.danger_allow_insecure_gateway);
+                    Ok((name.clone(), gateway?))
+                })
+                .collect::<Result<_, Box<dyn std::error::Error + Send + Sync>>>()?,
+            circuit_breakers: config.circuit_breaker.clone().map(CircuitBreakers::new),
+            selector: config.gateway_selector.clone(),
+            #[cfg(feature = "connect-bootstrap")]
+            connect_targets: normalize(&config.connect_targets)?,
+            retry_after: config.health_check.as_ref().map_or(Duration::ZERO, |hc| hc.interval),
+        })
+    }
+
+    pub(crate) fn default_gateway(&self) -> Arc<GatewayUri> { self.default.clone() }
+
+    /// The gateways listed in [`Config::allowed_gateways`].
+    pub(crate) fn allowed(&self) -> &[GatewayUri] { &self.allowed }
+
+    /// The names of the gateways in [`Config::named_gateways`].
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.named.keys().map(String::as_str)
+    }
+
+    pub(crate) fn circuit_breakers(&self) -> Option<&CircuitBreakers> {
+        self.circuit_breakers.as_ref()
+    }
+
+    /// The default gateway or listed [`Config::connect_targets`] entry with `authority`.
+    #[cfg(feature = "connect-bootstrap")]
+    pub(crate) fn connect_target(&self, authority: &http::uri::Authority) -> Option<&GatewayUri> {
+        std::iter::once(&*self.default)
+            .chain(&self.connect_targets)
+            .find(|target| target.authority() == Some(authority))
+    }
+
+    /// The default gateway and its replicas, in the order expected by [`Gateways::record_probe`].
+    pub(crate) fn replicas(&self) -> impl Iterator<Item = &GatewayUri> {
+        self.replicas.iter().map(|replica| &replica.uri)
+    }
+
+    /// Record the outcome of a health probe of the `index`th replica, returning its new health
+    /// if it changed.
+    pub(crate) fn record_probe(
+        &self,
+        index: usize,
+        ok: bool,
+        failure_threshold: u32,
+    ) -> Option<bool> {
+        let replica = &self.replicas[index];
+        let healthy = if ok {
+            replica.failed_probes.store(0, Ordering::Relaxed);
+            true
+        } else {
+            replica.failed_probes.fetch_add(1, Ordering::Relaxed) + 1 < failure_threshold
+                && replica.healthy.load(Ordering::Relaxed)
+        };
+        let was_healthy = replica.healthy.swap(healthy, Ordering::Relaxed);
+        if was_healthy != healthy {
+            Some(healthy)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the default gateway or any of its replicas is healthy.
+    pub(crate) fn default_available(&self) -> bool {
+        self.replicas.iter().any(|replica| replica.healthy.load(Ordering::Relaxed))
+    }
+
+    /// The next healthy replica of the default gateway, in round-robin order.
+    fn next_default(&self) -> Result<&GatewayUri, Error> {
+        let start = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        (0..self.replicas.len())
+            .map(|offset| &self.replicas[(start + offset) % self.replicas.len()])
+            .find(|replica| replica.healthy.load(Ordering::Relaxed))
+            .map(|replica| &replica.uri)
+            .ok_or(Error::ServiceUnavailable { retry_after: self.retry_after })
+    }
+
+    /// Choose the gateway for a request and the path and query to request on it, with the
+    /// configured [`GatewaySelector`] if there is one, rejecting it with 403 Forbidden if it is
+    /// on the deny-list.
+    ///
+    /// Otherwise a target under `/gw/<name>/` selects the gateway named `<name>`, if any, with
+    /// the prefix stripped. A target of the form `/https://gateway.example/path` selects
+    /// `https://gateway.example` if it is on the allowlist, and is rejected with 403 Forbidden
+    /// otherwise. Any other target, or one naming the default gateway or a replica, is requested
+    /// unchanged from the next healthy replica of the default gateway.
+    pub(crate) fn select(&self, req: &SelectMeta<'_>) -> Result<(GatewayUri, PathAndQuery), Error> {
+        let (gateway, path_and_query) = self.choose(req)?;
+        if self.denied.iter().any(|denied| denied.matches(&gateway)) {
+            return Err(Error::Denied(StatusCode::FORBIDDEN));
+        }
+        Ok((gateway, path_and_query))
+    }
+
+    fn choose(&self, req: &SelectMeta<'_>) -> Result<(GatewayUri, PathAndQuery), Error> {
+        if let Some(selector) = &self.selector {
+            let selection = selector.select(req).map_err(Error::Denied)?;
+            return Ok((selection.gateway, selection.path_and_query));
+        }
+        if let Some((name, path_and_query)) = tenant_in_path(req.target)? {
+            let gateway = self.named.get(name).ok_or(Error::NotFound)?;
+            return Ok((gateway.clone(), path_and_query));
+        }
+        let (origin, path_and_query) = match gateway_in_path(req.target)? {
+            Some(named) => named,
+            None => return Ok((self.next_default()?.clone(), req.target.clone())),
+        };
+        if self.replicas().any(|replica| replica.same_origin(&origin)) {
+            return Ok((self.next_default()?.clone(), path_and_query));
+        }
+        let gateway = self
+            .allowed
+            .iter()
+            .find(|allowed| allowed.same_origin(&origin))
+            .ok_or(Error::Denied(StatusCode::FORBIDDEN))?;
+        Ok((gateway.clone(), path_and_query))
+    }
+}
+
+/// The tenant a target under `/gw/<name>/` names, and the rest of the target. `None` for any
+/// other target.
+fn tenant_in_path(target: &PathAndQuery) -> Result<Option<(&str, PathAndQuery)>, Error> {
+    let rest = match target.path().strip_prefix("/gw/") {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    let (name, path) = match rest.find('/') {
+        Some(end) => rest.split_at(end),
+        None => (rest, "/"),
+    };
+    let path_and_query = match target.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_owned(),
+    };
+    let path_and_query =
+        path_and_query.parse().map_err(|_| Error::BadRequest("Invalid target uri".to_owned()))?;
+    Ok(Some((name, path_and_query)))
+}
+
+/// The origin a target of the form `/https://gateway.example/path` names, and the path and
+/// query to request on it. `None` for any other target.
+pub(crate) fn gateway_in_path(
+    target: &PathAndQuery,
+) -> Result<Option<(GatewayUri, PathAndQuery)>, Error> {
+    let selected = target
+        .as_str()
+        .strip_prefix('/')
+        .filter(|rest| rest.starts_with("https://") || rest.starts_with("http://"));
+    let selected = match selected {
+        Some(selected) => selected,
+        None => return Ok(None),
+    };
+    let invalid = || Error::BadRequest("Invalid gateway in request path".to_owned());
+    let uri: Uri = selected.parse().map_err(|_| invalid())?;
+    let path_and_query =
+        uri.path_and_query().cloned().unwrap_or_else(|| PathAndQuery::from_static("/"));
+    // Only ever matched against configured origins, which were checked already.
+    let origin = GatewayUri::new(uri, true).map_err(|_| invalid())?;
+    Ok(Some((origin, path_and_query)))
+}
+
+impl PathRewrite {
+    /// The path and query to request from the gateway for a client's `target`.
+    pub(crate) fn apply(&self, target: PathAndQuery) -> Result<PathAndQuery, Error> {
+        let (strip, add) = match self {
+            Self::Unchanged => return Ok(target),
+            Self::Fixed(path) => return Ok(path.clone()),
+            Self::Prefix { strip, add } => (strip, add),
+        };
+        // `/relay` is a prefix of `/relay/ohttp` but not of `/relayx/ohttp`.
+        let rest = target
+            .path()
+            .strip_prefix(strip.as_str())
+            .filter(|rest| rest.is_empty() || rest.starts_with('/') || strip.ends_with('/'))
+            .ok_or(Error::NotFound)?;
+        let mut rewritten = format!("{}{}", add, rest);
+        if !rewritten.starts_with('/') {
+            rewritten.insert(0, '/');
+        }
+        if let Some(query) = target.query() {
+            rewritten.push('?');
+            rewritten.push_str(query);
+        }
+        rewritten.parse().map_err(|_| Error::BadRequest("Invalid target uri".to_owned()))
+    }
 }
 
 impl std::ops::Deref for GatewayUri {
@@ -43,3 +475,241 @@ impl std::ops::Deref for GatewayUri {
 impl From<GatewayUri> for Uri {
     fn from(val: GatewayUri) -> Self { val.0 }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::HealthCheck;
+
+    fn gateways() -> Gateways {
+        let default = GatewayUri::new(Uri::from_static("https://default.example"), false).unwrap();
+        let config = Config {
+            allowed_gateways: vec![Uri::from_static("http://other.example:8080")],
+            danger_allow_insecure_gateway: true,
+            ..Config::default()
+        };
+        Gateways::new(default, &config).unwrap()
+    }
+
+    fn select(target: &'static str) -> Result<(Uri, String), Error> {
+        let (gateway, path) = select_target(&gateways(), target)?;
+        Ok((Uri::from(gateway), path.to_string()))
+    }
+
+    fn select_target(
+        gateways: &Gateways,
+        target: &'static str,
+    ) -> Result<(GatewayUri, PathAndQuery), Error> {
+        let (target, headers) = (PathAndQuery::from_static(target), http::HeaderMap::new());
+        gateways.select(&SelectMeta { peer_addr: None, target: &target, headers: &headers })
+    }
+
+    #[test]
+    fn paths_rewritten() {
+        let rewrite = |rewrite: PathRewrite, target: &'static str| {
+            rewrite.apply(PathAndQuery::from_static(target)).map(|path| path.to_string())
+        };
+        let fixed = PathRewrite::Fixed(PathAndQuery::from_static("/gateway"));
+        assert_eq!(rewrite(fixed, "/client/1?id=2").unwrap(), "/gateway");
+        let prefix = PathRewrite::Prefix { strip: "/".to_owned(), add: "/ohttp/v1/".to_owned() };
+        assert_eq!(rewrite(prefix.clone(), "/").unwrap(), "/ohttp/v1/");
+        assert_eq!(rewrite(prefix, "/request?x=1").unwrap(), "/ohttp/v1/request?x=1");
+        let prefix = PathRewrite::Prefix { strip: "/relay".to_owned(), add: String::new() };
+        assert_eq!(rewrite(prefix.clone(), "/relay/ohttp").unwrap(), "/ohttp");
+        assert_eq!(rewrite(prefix.clone(), "/relay").unwrap(), "/");
+        assert!(matches!(rewrite(prefix.clone(), "/relayx/ohttp"), Err(Error::NotFound)));
+        assert!(matches!(rewrite(prefix, "/other"), Err(Error::NotFound)));
+        assert_eq!(rewrite(PathRewrite::Unchanged, "/a?b").unwrap(), "/a?b");
+    }
+
+    #[test]
+    fn insecure_gateways_refused_unless_allowed() {
+        for origin in [
+            "http://gateway.example",
+            "https://127.0.0.1:8443",
+            "https://[::1]",
+            "https://0.0.0.0",
+            "https://LOCALHOST",
+            "https://gateway.localhost.",
+            "https://169.254.169.254",
+            "https://10.0.0.1",
+            "https://172.16.5.4:8443",
+            "https://192.168.1.1",
+            "https://[fe80::1]",
+            "https://[fd00::1]",
+            "https://[::ffff:10.0.0.1]",
+        ] {
+            let err = GatewayUri::new(Uri::from_static(origin), false).unwrap_err();
+            assert!(err.to_string().contains("danger_allow_insecure_gateway"), "{}", err);
+            assert!(GatewayUri::new(Uri::from_static(origin), true).is_ok());
+        }
+        assert!(GatewayUri::new(Uri::from_static("https://localhost.example"), false).is_ok());
+        for public in ["https://172.32.0.1", "https://192.0.2.1", "https://[2001:db8::1]"] {
+            assert!(GatewayUri::new(Uri::from_static(public), false).is_ok(), "{}", public);
+        }
+    }
+
+    #[test]
+    fn plain_path_uses_default_gateway() {
+        let (gateway, path) = select("/ohttp?x=1").unwrap();
+        assert_eq!(gateway.authority().unwrap(), "default.example:443");
+        assert_eq!(path, "/ohttp?x=1");
+    }
+
+    #[test]
+    fn allowed_gateway_selected_from_path() {
+        let (gateway, path) = select("/http://other.example:8080/ohttp").unwrap();
+        assert_eq!(gateway.authority().unwrap(), "other.example:8080");
+        assert_eq!(path, "/ohttp");
+
+        let (gateway, path) = select("/https://default.example").unwrap();
+        assert_eq!(gateway.authority().unwrap(), "default.example:443");
+        assert_eq!(path, "/");
+    }
+
+    #[test]
+    fn named_gateway_selected_by_tenant_prefix() {
+        let config = Config {
+            named_gateways: [("acme".to_owned(), Uri::from_static("https://acme.example"))].into(),
+            ..Config::default()
+        };
+        let gateways =
+            Gateways::new(gateways().default_gateway().as_ref().clone(), &config).unwrap();
+        let (gateway, path) = select_target(&gateways, "/gw/acme/ohttp?x=1").unwrap();
+        assert_eq!(gateway.authority().unwrap(), "acme.example:443");
+        assert_eq!(path, "/ohttp?x=1");
+        assert_eq!(select_target(&gateways, "/gw/acme").unwrap().1, "/");
+        assert!(matches!(select_target(&gateways, "/gw/other/ohttp"), Err(Error::NotFound)));
+        assert_eq!(select_target(&gateways, "/gwx/acme").unwrap().1, "/gwx/acme");
+    }
+
+    #[test]
+    fn unlisted_gateway_rejected() {
+        assert!(matches!(
+            select("/https://evil.example/"),
+            Err(Error::Denied(StatusCode::FORBIDDEN))
+        ));
+        // Same host, different scheme and port.
+        assert!(matches!(
+            select("/https://other.example:8080/"),
+            Err(Error::Denied(StatusCode::FORBIDDEN))
+        ));
+    }
+
+    #[test]
+    fn denied_gateways_override_allowlist() {
+        let config = Config {
+            allowed_gateways: vec![
+                Uri::from_static("https://other.example"),
+                Uri::from_static("https://gateway.test"),
+                Uri::from_static("https://203.0.113.7"),
+                Uri::from_static("https://[2001:db8::1]"),
+            ],
+            denied_gateways: ["https://other.example", "203.0.113.0/24", "2001:db8::/32", "TEST."]
+                .iter()
+                .map(|denied| denied.parse().unwrap())
+                .collect(),
+            ..Config::default()
+        };
+        let gateways =
+            Gateways::new(gateways().default_gateway().as_ref().clone(), &config).unwrap();
+        for target in [
+            "/https://other.example/",
+            "/https://gateway.test/",
+            "/https://203.0.113.7/",
+            "/https://[2001:db8::1]/",
+        ] {
+            assert!(
+                matches!(
+                    select_target(&gateways, target),
+                    Err(Error::Denied(StatusCode::FORBIDDEN))
+                ),
+                "{}",
+                target
+            );
+        }
+        assert!(select_target(&gateways, "/ohttp").is_ok());
+    }
+
+    #[test]
+    fn denied_gateways_parsed() {
+        let parse = |denied: &str| denied.parse::<DeniedGateway>();
+        assert_eq!(
+            parse("https://blocked.example").unwrap(),
+            DeniedGateway::Origin(Uri::from_static("https://blocked.example"))
+        );
+        let addr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        assert_eq!(parse("198.51.100.1").unwrap(), DeniedGateway::Network { addr, prefix_len: 32 });
+        assert_eq!(
+            parse("::ffff:198.51.100.1/16").unwrap(),
+            DeniedGateway::Network { addr, prefix_len: 16 }
+        );
+        assert_eq!(parse(".Onion").unwrap(), DeniedGateway::Domain("onion".to_owned()));
+        for invalid in ["10.0.0.0/33", "example/8", "", "."] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+        let gateway = |origin| GatewayUri::new(Uri::from_static(origin), true).unwrap();
+        let tld = parse("example").unwrap();
+        assert!(tld.matches(&gateway("https://a.b.example.")));
+        assert!(!tld.matches(&gateway("https://notexample")));
+        assert!(parse("0.0.0.0/0").unwrap().matches(&gateway("https://192.0.2.1")));
+        assert!(!parse("0.0.0.0/0").unwrap().matches(&gateway("https://[2001:db8::1]")));
+    }
+
+    #[test]
+    fn selector_replaces_default_choice() {
+        let other = GatewayUri::new(Uri::from_static("https://other.example"), false).unwrap();
+        let config = Config {
+            gateway_selector: Some(Arc::new(crate::select::SingleGateway(other.clone()))),
+            ..Config::default()
+        };
+        let gateways =
+            Gateways::new(gateways().default_gateway().as_ref().clone(), &config).unwrap();
+        let (gateway, path) = select_target(&gateways, "/https://default.example/ohttp").unwrap();
+        assert_eq!(gateway, other);
+        assert_eq!(path, "/https://default.example/ohttp");
+    }
+
+    #[test]
+    fn replicas_used_round_robin() {
+        let default = GatewayUri::new(Uri::from_static("https://a.example"), false).unwrap();
+        let replicas =
+            vec![Uri::from_static("https://b.example"), Uri::from_static("https://c.example")];
+        let config = Config { gateway_replicas: replicas, ..Config::default() };
+        let gateways = Gateways::new(default, &config).unwrap();
+        assert_eq!(picks(&gateways, 4), ["a.example", "b.example", "c.example", "a.example"]);
+    }
+
+    #[test]
+    fn unhealthy_replicas_skipped() {
+        let default = GatewayUri::new(Uri::from_static("https://a.example"), false).unwrap();
+        let config = Config {
+            gateway_replicas: vec![Uri::from_static("https://b.example")],
+            health_check: Some(HealthCheck {
+                interval: Duration::from_secs(5),
+                failure_threshold: 2,
+                ..HealthCheck::default()
+            }),
+            ..Config::default()
+        };
+        let gateways = Gateways::new(default, &config).unwrap();
+        assert_eq!(gateways.record_probe(0, false, 2), None);
+        assert_eq!(picks(&gateways, 2), ["a.example", "b.example"]);
+        assert_eq!(gateways.record_probe(0, false, 2), Some(false));
+        assert_eq!(picks(&gateways, 2), ["b.example", "b.example"]);
+
+        gateways.record_probe(1, false, 2);
+        gateways.record_probe(1, false, 2);
+        assert!(matches!(
+            select_target(&gateways, "/"),
+            Err(Error::ServiceUnavailable { retry_after }) if retry_after == Duration::from_secs(5)
+        ));
+
+        assert_eq!(gateways.record_probe(0, true, 2), Some(true));
+        assert_eq!(picks(&gateways, 2), ["a.example", "a.example"]);
+    }
+
+    fn picks(gateways: &Gateways, n: usize) -> Vec<String> {
+        (0..n).map(|_| select_target(gateways, "/").unwrap().0.host().unwrap().to_owned()).collect()
+    }
+}
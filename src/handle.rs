@@ -0,0 +1,44 @@
+use std::net::SocketAddr;
+
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::RelayError;
+
+type ServeResult = Result<(), RelayError>;
+
+/// A relay serving in the background, as returned by [`crate::spawn_tcp`].
+#[derive(Debug)]
+pub struct RelayHandle {
+    local_addr: SocketAddr,
+    shutdown: CancellationToken,
+    task: JoinHandle<ServeResult>,
+}
+
+impl RelayHandle {
+    pub(crate) fn new(
+        local_addr: SocketAddr,
+        shutdown: CancellationToken,
+        task: JoinHandle<ServeResult>,
+    ) -> Self {
+        Self { local_addr, shutdown, task }
+    }
+
+    /// The address actually bound, with the port chosen by the OS when port 0 was requested.
+    pub fn local_addr(&self) -> SocketAddr { self.local_addr }
+
+    /// Stop accepting connections and drain open ones, as cancelling [`crate::Config::shutdown`]
+    /// does, then wait for the relay to finish.
+    pub async fn shutdown(self) -> ServeResult {
+        self.shutdown.cancel();
+        self.join().await
+    }
+
+    /// Wait for the relay to stop, either after a shutdown or an error.
+    pub async fn join(self) -> ServeResult {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => Err(RelayError::Task(e)),
+        }
+    }
+}
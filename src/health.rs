@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use http::Uri;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::Request;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+use crate::gateway_uri::Gateways;
+use crate::metrics::Metrics;
+use crate::{HealthCheck, UpstreamClient};
+
+/// Probes gateways in the background until dropped.
+#[derive(Debug)]
+pub(crate) struct ProbeTask(JoinHandle<()>);
+
+impl ProbeTask {
+    pub(crate) fn spawn(
+        gateways: Arc<Gateways>,
+        client: UpstreamClient,
+        health_check: HealthCheck,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self(tokio::spawn(probe_gateways(gateways, client, health_check, metrics)))
+    }
+}
+
+impl Drop for ProbeTask {
+    fn drop(&mut self) { self.0.abort(); }
+}
+
+/// Probe every replica of the default gateway once per interval, forever.
+async fn probe_gateways(
+    gateways: Arc<Gateways>,
+    client: UpstreamClient,
+    health_check: HealthCheck,
+    metrics: Arc<Metrics>,
+) {
+    let mut interval = tokio::time::interval(health_check.interval);
+    loop {
+        interval.tick().await;
+        for (index, gateway) in gateways.replicas().enumerate() {
+            let ok = probe(gateway, &client, &health_check).await;
+            if let Some(healthy) = gateways.record_probe(index, ok, health_check.failure_threshold)
+            {
+                let state = if healthy { "healthy" } else { "unhealthy" };
+                info!("Gateway {} is now {}", **gateway, state);
+                metrics.record_health(gateway, healthy);
+            }
+        }
+    }
+}
+
+/// Whether a GET of the probe path succeeds within one interval.
+async fn probe(gateway: &Uri, client: &UpstreamClient, health_check: &HealthCheck) -> bool {
+    let uri = Uri::builder()
+        .scheme(gateway.scheme_str().unwrap_or("https"))
+        .authority(gateway.authority().map_or("", |a| a.as_str()))
+        .path_and_query(health_check.path.as_str())
+        .build();
+    let uri = match uri {
+        Ok(uri) => uri,
+        Err(e) => {
+            debug!("Invalid health probe uri: {}", e);
+            return false;
+        }
+    };
+    let mut req = Request::new(empty());
+    *req.uri_mut() = uri;
+    match tokio::time::timeout(health_check.interval, client.request(req)).await {
+        Ok(Ok(res)) => res.status().is_success(),
+        Ok(Err(e)) => {
+            debug!("Health probe error: {}", e);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+fn empty() -> BoxBody<Bytes, crate::body::BoxError> {
+    Empty::<Bytes>::new().map_err(|never| match never {}).boxed()
+}
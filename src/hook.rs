@@ -0,0 +1,60 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use http::Uri;
+use hyper::{HeaderMap, StatusCode};
+
+use crate::auth::Authorization;
+
+/// What a [`RelayHook`] sees of a request about to be forwarded: where it comes from and goes
+/// and its headers, but never the encapsulated body.
+#[derive(Debug)]
+pub struct ForwardMeta<'a> {
+    /// The address of the connected peer, or `None` when the relay is not serving TCP.
+    pub peer_addr: Option<SocketAddr>,
+    /// The headers the client sent, none of which reach the gateway on their own.
+    pub client_headers: &'a HeaderMap,
+    /// The gateway URI the request is forwarded to.
+    pub uri: &'a Uri,
+    /// The headers forwarded to the gateway. Hooks may add, change or remove them.
+    pub headers: &'a mut HeaderMap,
+}
+
+/// What a [`RelayHook`] sees of the gateway's response to a forwarded request, but never the
+/// encapsulated body.
+#[derive(Debug)]
+pub struct ResponseMeta<'a> {
+    /// The address of the connected peer, or `None` when the relay is not serving TCP.
+    pub peer_addr: Option<SocketAddr>,
+    /// The gateway URI the request was forwarded to.
+    pub uri: &'a Uri,
+    pub status: StatusCode,
+    /// The headers sent back to the client. Hooks may add, change or remove them.
+    pub headers: &'a mut HeaderMap,
+}
+
+/// Custom policy applied to relayed OHTTP requests, such as quotas or header scrubbing.
+///
+/// Hooks run after the [`crate::auth::Authorizer`] and the relay's own checks, and only for
+/// requests forwarded to a gateway: bootstrap tunnels, health checks and errors the relay
+/// answers itself are never hooked.
+pub trait RelayHook: Debug + Send + Sync {
+    /// Called before the request is forwarded. Denying answers the client without forwarding,
+    /// with the given status or 403 Forbidden. Allows every request by default.
+    fn before_forward<'a>(
+        &'a self,
+        _req: ForwardMeta<'a>,
+    ) -> Pin<Box<dyn Future<Output = Authorization> + Send + 'a>> {
+        Box::pin(async { Authorization::Allow })
+    }
+
+    /// Called once the gateway responded, before the response is sent to the client.
+    fn after_response<'a>(
+        &'a self,
+        _res: ResponseMeta<'a>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async {})
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use http::Uri;
+
+use crate::access_log::push_json_str;
+
+/// A registry of the requests currently being forwarded to the gateway.
+///
+/// Only the gateway, path and timing of each forward are recorded, never its query, headers or
+/// body.
+#[derive(Debug, Default)]
+pub(crate) struct Inflight {
+    next_id: AtomicU64,
+    /// Forwards admitted at once, see [`crate::Config::max_inflight_requests`].
+    max: Option<usize>,
+    active: Mutex<HashMap<u64, Forward>>,
+}
+
+#[derive(Debug)]
+struct Forward {
+    target: String,
+    started_at: SystemTime,
+    started: Instant,
+}
+
+impl Inflight {
+    pub(crate) fn new(max: Option<usize>) -> Self { Self { max, ..Self::default() } }
+
+    /// Record a forward to `target` until the returned guard is dropped, or `None` if the
+    /// maximum number of forwards is already in flight.
+    pub(crate) fn track(self: &Arc<Self>, target: &Uri) -> Option<Tracked> {
+        let mut active = self.active.lock().expect("inflight registry poisoned");
+        if self.max.is_some_and(|max| active.len() >= max) {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let forward = Forward {
+            target: format!(
+                "{}://{}{}",
+                target.scheme_str().unwrap_or("https"),
+                target.authority().map_or("", |authority| authority.as_str()),
+                target.path()
+            ),
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+        };
+        active.insert(id, forward);
+        Some(Tracked { registry: self.clone(), id })
+    }
+
+    /// A JSON snapshot of the active forwards, oldest first.
+    pub(crate) fn to_json(&self) -> String {
+        let active = self.active.lock().expect("inflight registry poisoned");
+        let mut forwards: Vec<&Forward> = active.values().collect();
+        forwards.sort_by_key(|forward| forward.started);
+        let mut json = String::from(r#"{"inflight":["#);
+        for (i, forward) in forwards.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let started_at_ms =
+                forward.started_at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis());
+            json.push_str(r#"{"target":"#);
+            push_json_str(&mut json, &forward.target);
+            json.push_str(&format!(
+                r#","started_at_ms":{},"elapsed_ms":{}}}"#,
+                started_at_ms,
+                forward.started.elapsed().as_millis()
+            ));
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Removes its forward from the [`Inflight`] registry when dropped.
+#[derive(Debug)]
+pub(crate) struct Tracked {
+    registry: Arc<Inflight>,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.registry.active.lock() {
+            active.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn forwards_listed_until_dropped() {
+        let inflight = Arc::new(Inflight::default());
+        assert_eq!(inflight.to_json(), r#"{"inflight":[]}"#);
+
+        let target = Uri::from_static("https://gateway.example/ohttp?client=alice");
+        let tracked = inflight.track(&target).unwrap();
+        let snapshot = inflight.to_json();
+        assert!(snapshot.contains(r#""target":"https://gateway.example/ohttp""#), "{}", snapshot);
+        assert!(!snapshot.contains("alice"), "{}", snapshot);
+        assert!(snapshot.contains(r#""elapsed_ms":"#), "{}", snapshot);
+
+        drop(tracked);
+        assert_eq!(inflight.to_json(), r#"{"inflight":[]}"#);
+    }
+
+    #[test]
+    fn forwards_beyond_max_refused() {
+        let inflight = Arc::new(Inflight::new(Some(1)));
+        let target = Uri::from_static("https://gateway.example/");
+        let tracked = inflight.track(&target).unwrap();
+        assert!(inflight.track(&target).is_none());
+        drop(tracked);
+        assert!(inflight.track(&target).is_some());
+    }
+}
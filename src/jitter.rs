@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Sleep for a random time of at most `max`, so the moments a relayed message is seen on
+/// either side of the relay are harder to match up.
+pub(crate) async fn delay(max: Duration) {
+    if !max.is_zero() {
+        tokio::time::sleep(max.mul_f64(random())).await;
+    }
+}
+
+/// A random number in `[0, 1)`, drawn from the system's randomness so observers cannot
+/// predict one delay from another.
+pub(crate) fn random() -> f64 {
+    let mut bytes = [0; 8];
+    SystemRandom::new().fill(&mut bytes).expect("system randomness is available");
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn random_in_unit_interval() {
+        let samples: Vec<f64> = (0..100).map(|_| random()).collect();
+        assert!(samples.iter().all(|&r| (0.0..1.0).contains(&r)));
+        assert!(samples.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+}
@@ -0,0 +1,281 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_TYPE, DATE, ETAG, EXPIRES,
+    IF_NONE_MATCH,
+};
+use hyper::{Request, Response, StatusCode};
+use tokio::sync::broadcast::Sender;
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::body::BoxError;
+use crate::error::Error;
+use crate::events::{self, RelayEvent};
+use crate::gateway_uri::GatewayUri;
+use crate::{forward_request, full, OhttpKeys, UpstreamClient};
+
+/// Asks, as in RFC 7240, to hold a conditional request until the key configuration changes.
+static PREFER: HeaderName = HeaderName::from_static("prefer");
+
+/// The largest key configuration accepted from a gateway. Real ones are a few hundred bytes.
+const MAX_KEYS_SIZE: usize = 64 * 1024;
+
+/// The default gateway's key configuration, cached for browser clients. Fetched again as soon as
+/// the default gateway is replaced.
+#[derive(Debug, Default)]
+pub(crate) struct KeyCache {
+    cached: Mutex<Option<Cached>>,
+    /// Held while fetching, so clients waiting on an expired configuration share one fetch.
+    refresh: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug, Clone)]
+struct Cached {
+    gateway: GatewayUri,
+    body: Bytes,
+    content_type: Option<HeaderValue>,
+    etag: HeaderValue,
+    expires: Instant,
+}
+
+impl KeyCache {
+    /// Answer with the cached key configuration, fetching it from `gateway` once expired.
+    ///
+    /// A request whose `If-None-Match` names the cached configuration is answered with 304 Not
+    /// Modified. With `Prefer: wait=<seconds>` it is held until the configuration changes, for
+    /// at most that long and [`OhttpKeys::max_wait`], so clients learn of key rotation without
+    /// polling. The gateway is still only asked again once the cached configuration expires.
+    pub(crate) async fn respond(
+        &self,
+        headers: &HeaderMap,
+        gateway: &GatewayUri,
+        settings: &OhttpKeys,
+        client: &UpstreamClient,
+        response_timeout: Option<Duration>,
+        events: &Option<Sender<RelayEvent>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+        let deadline = Instant::now() + requested_wait(headers).min(settings.max_wait);
+        let (cached, unchanged) = loop {
+            let cached = self.current(gateway, settings, client, response_timeout, events).await?;
+            let unchanged =
+                headers.get(IF_NONE_MATCH).is_some_and(|tags| matches(tags, &cached.etag));
+            // An uncacheable configuration would be fetched again on every wakeup.
+            let now = Instant::now();
+            if !unchanged || now >= deadline || cached.expires <= now {
+                break (cached, unchanged);
+            }
+            tokio::time::sleep_until(cached.expires.min(deadline)).await;
+        };
+        let mut res = match unchanged {
+            true => {
+                let mut res = Response::new(full(Bytes::new()));
+                *res.status_mut() = StatusCode::NOT_MODIFIED;
+                res
+            }
+            false => Response::new(full(cached.body)),
+        };
+        if let (Some(content_type), StatusCode::OK) = (cached.content_type, res.status()) {
+            res.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        let max_age = cached.expires.saturating_duration_since(Instant::now()).as_secs();
+        let cache_control =
+            HeaderValue::from_str(&format!("max-age={}", max_age)).expect("Invalid HeaderValue");
+        res.headers_mut().insert(CACHE_CONTROL, cache_control);
+        res.headers_mut().insert(ETAG, cached.etag);
+        Ok(res)
+    }
+
+    /// The cached key configuration, fetched again once expired.
+    async fn current(
+        &self,
+        gateway: &GatewayUri,
+        settings: &OhttpKeys,
+        client: &UpstreamClient,
+        response_timeout: Option<Duration>,
+        events: &Option<Sender<RelayEvent>>,
+    ) -> Result<Cached, Error> {
+        if let Some(cached) = self.fresh(gateway) {
+            return Ok(cached);
+        }
+        let _refreshing = self.refresh.lock().await;
+        // Another request may have fetched it while this one waited.
+        if let Some(cached) = self.fresh(gateway) {
+            return Ok(cached);
+        }
+        let fetched = fetch(gateway, settings, client, response_timeout).await?;
+        let previous = self.cached.lock().expect("key cache poisoned").replace(fetched.clone());
+        let changed = previous.is_some_and(|previous| previous.etag != fetched.etag);
+        events::emit(events, || RelayEvent::KeysRefreshed { gateway: gateway.clone(), changed });
+        Ok(fetched)
+    }
+
+    fn fresh(&self, gateway: &GatewayUri) -> Option<Cached> {
+        let cached = self.cached.lock().expect("key cache poisoned");
+        cached
+            .as_ref()
+            .filter(|cached| cached.gateway == *gateway && cached.expires > Instant::now())
+            .cloned()
+    }
+}
+
+/// Whether the `If-None-Match` list `tags` names `etag`, comparing weakly.
+fn matches(tags: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (tags, etag) = match (tags.to_str(), etag.to_str()) {
+        (Ok(tags), Ok(etag)) => (tags, etag),
+        _ => return false,
+    };
+    tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// How long `Prefer: wait=<seconds>` asks to hold the request, zero if not at all.
+fn requested_wait(headers: &HeaderMap) -> Duration {
+    headers
+        .get_all(&PREFER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|preference| preference.trim().strip_prefix("wait=")?.parse().ok())
+        .map_or(Duration::ZERO, Duration::from_secs)
+}
+
+async fn fetch(
+    gateway: &GatewayUri,
+    settings: &OhttpKeys,
+    client: &UpstreamClient,
+    response_timeout: Option<Duration>,
+) -> Result<Cached, Error> {
+    let uri = gateway.with_path(&settings.path).map_err(|e| {
+        error!("Invalid key configuration uri: {}", e);
+        Error::BadGateway
+    })?;
+    let mut req = Request::new(Empty::<Bytes>::new().map_err(BoxError::from).boxed());
+    *req.uri_mut() = uri;
+    let res = forward_request(req, client, response_timeout).await?;
+    if !res.status().is_success() {
+        error!("Gateway answered key configuration request with {}", res.status());
+        return Err(Error::BadGateway);
+    }
+    let ttl = gateway_max_age(res.headers().get(CACHE_CONTROL))
+        .or_else(|| gateway_expires(res.headers()))
+        .map_or(settings.max_age, |max_age| max_age.min(settings.max_age));
+    let content_type = res.headers().get(CONTENT_TYPE).cloned();
+    let body = Limited::new(res.into_body(), MAX_KEYS_SIZE).collect().await.map_err(|e| {
+        error!("Failed to read key configuration: {}", e);
+        Error::BadGateway
+    })?;
+    let body = body.to_bytes();
+    Ok(Cached {
+        gateway: gateway.clone(),
+        etag: etag(&body),
+        body,
+        content_type,
+        expires: Instant::now() + ttl,
+    })
+}
+
+/// A strong entity tag for `body`, so it changes exactly when the key configuration does.
+fn etag(body: &[u8]) -> HeaderValue {
+    let digest = ring::digest::digest(&ring::digest::SHA256, body);
+    let hex: String = digest.as_ref()[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    HeaderValue::try_from(format!("\"{}\"", hex)).expect("hex digits are a valid header value")
+}
+
+/// How long the gateway's `Cache-Control` lets its response be cached, zero if not at all.
+fn gateway_max_age(cache_control: Option<&HeaderValue>) -> Option<Duration> {
+    let directives = cache_control?.to_str().ok()?;
+    let mut max_age = None;
+    for directive in directives.split(',').map(str::trim) {
+        let directive = directive.to_ascii_lowercase();
+        if directive == "no-store" || directive == "no-cache" {
+            return Some(Duration::ZERO);
+        }
+        if let Some(secs) = directive.strip_prefix("max-age=") {
+            max_age = secs.trim_matches('"').parse().ok().map(Duration::from_secs);
+        }
+    }
+    max_age
+}
+
+/// How long until the gateway's `Expires`, measured from its `Date`, zero if already passed or
+/// not a valid date, as RFC 9111 asks. Only consulted without a `Cache-Control` max-age.
+fn gateway_expires(headers: &HeaderMap) -> Option<Duration> {
+    let date = |value: &HeaderValue| httpdate::parse_http_date(value.to_str().ok()?).ok();
+    let expires = match date(headers.get(EXPIRES)?) {
+        Some(expires) => expires,
+        None => return Some(Duration::ZERO),
+    };
+    let now = headers.get(DATE).and_then(date).unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Uri;
+
+    use super::*;
+
+    #[test]
+    fn gateway_max_age_parsed() {
+        let max_age = |value| gateway_max_age(Some(&HeaderValue::from_static(value)));
+        assert_eq!(max_age("public, max-age=60"), Some(Duration::from_secs(60)));
+        assert_eq!(max_age("max-age=60, no-store"), Some(Duration::ZERO));
+        assert_eq!(max_age("public"), None);
+        assert_eq!(gateway_max_age(None), None);
+    }
+
+    #[test]
+    fn gateway_expires_parsed() {
+        let expires = |pairs: &[(HeaderName, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name, HeaderValue::from_static(value));
+            }
+            gateway_expires(&headers)
+        };
+        let date = (DATE, "Thu, 15 Oct 2026 12:00:00 GMT");
+        let later = (EXPIRES, "Thu, 15 Oct 2026 12:01:30 GMT");
+        assert_eq!(expires(&[date.clone(), later]), Some(Duration::from_secs(90)));
+        let earlier = (EXPIRES, "Thu, 15 Oct 2026 11:00:00 GMT");
+        assert_eq!(expires(&[date.clone(), earlier]), Some(Duration::ZERO));
+        assert_eq!(expires(&[date.clone(), (EXPIRES, "0")]), Some(Duration::ZERO));
+        assert_eq!(expires(&[date]), None);
+    }
+
+    #[test]
+    fn cache_keyed_by_gateway() {
+        let gateway = |origin| GatewayUri::new(Uri::from_static(origin), false).unwrap();
+        let (old, new) = (gateway("https://old.example"), gateway("https://new.example"));
+        let cache = KeyCache::default();
+        *cache.cached.lock().unwrap() = Some(Cached {
+            gateway: old.clone(),
+            body: Bytes::from_static(b"keys"),
+            content_type: None,
+            etag: etag(b"keys"),
+            expires: Instant::now() + Duration::from_secs(60),
+        });
+        assert!(cache.fresh(&old).is_some());
+        // A replaced default gateway's keys are never served for the new one.
+        assert!(cache.fresh(&new).is_none());
+    }
+
+    #[test]
+    fn etags_and_waits_parsed() {
+        let tag = etag(b"keys");
+        assert_eq!(tag, etag(b"keys"));
+        assert_ne!(tag, etag(b"rotated"));
+        let list = format!("\"other\", W/{}", tag.to_str().unwrap());
+        assert!(matches(&HeaderValue::try_from(list).unwrap(), &tag));
+        assert!(matches(&HeaderValue::from_static("*"), &tag));
+        assert!(!matches(&HeaderValue::from_static("\"other\""), &tag));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(requested_wait(&headers), Duration::ZERO);
+        headers.insert(&PREFER, HeaderValue::from_static("respond-async, wait=30"));
+        assert_eq!(requested_wait(&headers), Duration::from_secs(30));
+    }
+}
@@ -1,33 +1,157 @@
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Arc;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use gateway_uri::GatewayUri;
+use gateway_uri::Gateways;
+pub use gateway_uri::{DeniedGateway, GatewayUri};
 use http::uri::PathAndQuery;
 use http::Uri;
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty, Full};
-use hyper::body::{Bytes, Incoming};
+use hyper::body::{Body, Bytes, Incoming};
 use hyper::header::{
-    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
-    ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_LENGTH, CONTENT_TYPE, HOST,
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, ALLOW, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_TYPE, EXPECT, HOST, LOCATION, ORIGIN, TE, TRAILER,
 };
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response};
-use hyper_rustls::HttpsConnectorBuilder;
+use hyper::{Method, Request, Response, StatusCode, Version};
+use hyper_rustls::HttpsConnector;
 use hyper_util::client::legacy::Client;
-use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
 use once_cell::sync::Lazy;
+use rustls::{ClientConfig, ServerConfig};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{TcpListener, UnixListener};
+use tokio::net::TcpListener;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
 use tokio_util::net::Listener;
+use tokio_util::task::TaskTracker;
+use tower_service::Service;
 use tracing::{debug, error, info, instrument};
 
+mod access_log;
+#[cfg(feature = "acme")]
+mod acme;
+#[cfg(unix)]
+mod activation;
+mod activity;
+mod admin;
+pub mod auth;
+#[cfg(feature = "cli")]
+pub mod bench;
+mod body;
+mod builder;
+mod capabilities;
+mod circuit_breaker;
+mod config;
+pub mod config_file;
+mod connector;
+mod cors;
+#[cfg(all(unix, feature = "cli"))]
+pub mod daemon;
+mod dual_stack;
+mod env;
 pub mod error;
+mod events;
 mod gateway_uri;
-use crate::error::Error;
+mod handle;
+mod health;
+pub mod hook;
+mod inflight;
+mod jitter;
+mod keys;
+mod listen;
+mod metrics;
+#[cfg(windows)]
+mod named_pipe;
+#[cfg(feature = "tor-listener")]
+mod onion;
+#[cfg(feature = "otel")]
+pub mod otel;
+mod padding;
+mod pinning;
+mod proxy_protocol;
+#[cfg(feature = "h3")]
+mod quic;
+mod rate_limit;
+mod reload;
+mod request_id;
+pub mod resolve;
+mod retry;
+pub mod select;
+mod service;
+mod signal;
+#[cfg(unix)]
+mod socket_file;
+mod svcb;
+mod tcp;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod tls;
+#[cfg(all(feature = "vsock", target_os = "linux"))]
+mod vsock;
+use crate::access_log::AccessLogger;
+#[cfg(feature = "acme")]
+pub use crate::acme::Acme;
+#[cfg(unix)]
+use crate::activation::Inherited;
+use crate::activity::Activity;
+use crate::admin::AdminServer;
+pub use crate::admin::{Admin, AdminReload};
+use crate::auth::{Authorization, Authorizer, RequestMeta};
+use crate::body::{
+    request_body_error, BoxError, Buffered, Coalesce, ExactLength, IdleTimeout, LengthLimit,
+    ReadAhead, Throttle, Throttled,
+};
+pub use crate::builder::Builder;
+#[cfg(feature = "tor-client")]
+pub use crate::config::TorDirs;
+#[cfg(feature = "ws-bootstrap")]
+pub use crate::config::WsBootstrap;
+pub use crate::config::{
+    AccessLog, AccessLogSink, BandwidthLimit, CircuitBreaker, Config, ContentEncoding, Cors,
+    DnsCache, GatewayConfig, GatewayPool, HealthCheck, Http1Server, Jitter, Keepalive, OhttpKeys,
+    Padding, PathRewrite, RateLimit, RedirectPolicy, Retry, SocketFile, Streaming, TcpOptions,
+    DEFAULT_LISTEN_BACKLOG, DEFAULT_MAX_BODY_SIZE, DEFAULT_SCRUBBED_RESPONSE_HEADERS,
+};
+use crate::connector::GatewayConnector;
+pub use crate::error::RelayError;
+use crate::error::{accepts_gzip, Error, UpstreamFailure};
+pub use crate::events::RelayEvent;
+pub use crate::handle::RelayHandle;
+use crate::health::ProbeTask;
+use crate::hook::{ForwardMeta, ResponseMeta};
+use crate::inflight::Inflight;
+use crate::keys::KeyCache;
+use crate::listen::Bound;
+pub use crate::listen::{Listen, Listening, OnBound};
+use crate::metrics::Metrics;
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsServer;
+#[cfg(feature = "tor-listener")]
+pub use crate::onion::OnionService;
+pub use crate::pinning::SpkiPin;
+use crate::rate_limit::RateLimiter;
+pub use crate::reload::Reload;
+use crate::request_id::REQUEST_ID;
+use crate::resolve::{CachingResolver, PinnedResolver, Resolver};
+use crate::select::SelectMeta;
+pub use crate::service::{make_service, OhttpRelayService};
+use crate::signal::SignalHandler;
+#[cfg(feature = "h3")]
+pub use crate::tls::quic_server_config_from_pem;
+#[cfg(feature = "dev-tls")]
+pub use crate::tls::self_signed_server_config;
+pub use crate::tls::{
+    mtls_server_config_from_pem, server_config_from_pem, ClientIdentity, Roots, TlsVersion,
+    UpstreamTls,
+};
 
-#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap", feature = "wt-bootstrap"))]
 pub mod bootstrap;
 
 pub const DEFAULT_PORT: u16 = 3000;
@@ -35,132 +159,1624 @@ pub static OHTTP_RELAY_HOST: Lazy<HeaderValue> =
     Lazy::new(|| HeaderValue::from_str("0.0.0.0").expect("Invalid HeaderValue"));
 pub static EXPECTED_MEDIA_TYPE: Lazy<HeaderValue> =
     Lazy::new(|| HeaderValue::from_str("message/ohttp-req").expect("Invalid HeaderValue"));
+/// The media type of a chunked OHTTP request, streamed to the gateway as it arrives.
+pub static CHUNKED_MEDIA_TYPE: Lazy<HeaderValue> =
+    Lazy::new(|| HeaderValue::from_str("message/ohttp-chunked-req").expect("Invalid HeaderValue"));
+static GATEWAY_RESPONSE_MEDIA_TYPE: Lazy<HeaderValue> =
+    Lazy::new(|| HeaderValue::from_str("message/ohttp-res").expect("Invalid HeaderValue"));
+static CHUNKED_GATEWAY_RESPONSE_MEDIA_TYPE: Lazy<HeaderValue> =
+    Lazy::new(|| HeaderValue::from_str("message/ohttp-chunked-res").expect("Invalid HeaderValue"));
+
+/// Relay from TCP `port` on every IPv4 interface to `gateway_origin` with the default
+/// [`Config`].
+///
+/// Unlike earlier releases, a plain `http` gateway origin is refused with
+/// [`RelayError::InvalidGateway`], and gateways at internal addresses are never connected to;
+/// use [`listen_tcp_with_config`] with [`Config::danger_allow_insecure_gateway`] to relay to
+/// them.
+#[instrument]
+pub async fn listen_tcp(port: u16, gateway_origin: Uri) -> Result<(), RelayError> {
+    listen_tcp_with_config(port, gateway_origin, Config::default()).await
+}
 
 #[instrument]
-pub async fn listen_tcp(
+pub async fn listen_tcp_with_config(
+    port: u16,
+    gateway_origin: Uri,
+    config: Config,
+) -> Result<(), RelayError> {
+    serve_listeners(vec![(config.tcp_listen(port), None)], gateway_origin, None, config).await
+}
+
+/// Like [`listen_tcp`], but terminate TLS with `tls_config` so the relay can run without a
+/// reverse proxy in front of it. Set `alpn_protocols` on `tls_config` to offer HTTP/2.
+#[instrument(skip(tls_config))]
+pub async fn listen_tcp_tls(
     port: u16,
     gateway_origin: Uri,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await?;
-    println!("OHTTP relay listening on tcp://{}", addr);
-    ohttp_relay(listener, gateway_origin).await
+    tls_config: Arc<ServerConfig>,
+) -> Result<(), RelayError> {
+    listen_tcp_tls_with_config(port, gateway_origin, tls_config, Config::default()).await
+}
+
+#[instrument(skip(tls_config))]
+pub async fn listen_tcp_tls_with_config(
+    port: u16,
+    gateway_origin: Uri,
+    tls_config: Arc<ServerConfig>,
+    config: Config,
+) -> Result<(), RelayError> {
+    let listen = config.tcp_listen(port);
+    serve_listeners(vec![(listen, None)], gateway_origin, Some(tls_config), config).await
+}
+
+/// Bind `addr` and serve in the background, returning once bound. Bind port 0 to have the OS
+/// pick a free port and read it from [`RelayHandle::local_addr`].
+#[instrument]
+pub async fn spawn_tcp(
+    addr: SocketAddr,
+    gateway_origin: Uri,
+    mut config: Config,
+) -> Result<RelayHandle, RelayError> {
+    let backlog = config.listen_backlog;
+    let listener =
+        bind_with_retry(config.bind_retry, || async { tcp::bind(addr, backlog) }).await?;
+    let local_addr = listener.local_addr()?;
+    let shutdown = config.shutdown.child_token();
+    config.shutdown = shutdown.clone();
+    let task =
+        tokio::spawn(serve_bound(vec![(Bound::Tcp(listener), None)], gateway_origin, None, config));
+    Ok(RelayHandle::new(local_addr, shutdown, task))
+}
+
+/// Relay from the unix socket at `socket_path` to `gateway_origin` with the default [`Config`].
+/// Gateways are refused as by [`listen_tcp`].
+#[cfg(unix)]
+#[instrument]
+pub async fn listen_socket(socket_path: &str, gateway_origin: Uri) -> Result<(), RelayError> {
+    listen_socket_with_config(socket_path, gateway_origin, Config::default()).await
 }
 
+#[cfg(unix)]
 #[instrument]
-pub async fn listen_socket(
+pub async fn listen_socket_with_config(
     socket_path: &str,
     gateway_origin: Uri,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let listener = UnixListener::bind(socket_path)?;
-    info!("OHTTP relay listening on socket: {}", socket_path);
-    ohttp_relay(listener, gateway_origin).await
+    config: Config,
+) -> Result<(), RelayError> {
+    serve_listeners(vec![(Listen::Socket(socket_path.into()), None)], gateway_origin, None, config)
+        .await
+}
+
+/// Serve on the Windows named pipe `name`, e.g. `\\.\pipe\ohttp-relay`, so local clients
+/// can reach the relay without a TCP port.
+#[cfg(windows)]
+#[instrument]
+pub async fn listen_named_pipe(name: &str, gateway_origin: Uri) -> Result<(), RelayError> {
+    listen_named_pipe_with_config(name, gateway_origin, Config::default()).await
+}
+
+#[cfg(windows)]
+#[instrument]
+pub async fn listen_named_pipe_with_config(
+    name: &str,
+    gateway_origin: Uri,
+    config: Config,
+) -> Result<(), RelayError> {
+    serve_listeners(vec![(Listen::NamedPipe(name.to_owned()), None)], gateway_origin, None, config)
+        .await
+}
+
+/// Serve on vsock `port` of context ID `cid`, so a relay in a VM or enclave can take
+/// connections from its host without any TCP exposure. Pass `libc::VMADDR_CID_ANY` as `cid`
+/// to accept on every context ID of the VM.
+#[cfg(all(feature = "vsock", target_os = "linux"))]
+#[instrument]
+pub async fn listen_vsock(cid: u32, port: u32, gateway_origin: Uri) -> Result<(), RelayError> {
+    listen_vsock_with_config(cid, port, gateway_origin, Config::default()).await
+}
+
+#[cfg(all(feature = "vsock", target_os = "linux"))]
+#[instrument]
+pub async fn listen_vsock_with_config(
+    cid: u32,
+    port: u32,
+    gateway_origin: Uri,
+    config: Config,
+) -> Result<(), RelayError> {
+    serve_listeners(vec![(Listen::Vsock { cid, port }, None)], gateway_origin, None, config).await
+}
+
+/// Publish the relay as the Tor onion service `service`, so clients can reach it without the
+/// operator exposing an IP address. Bootstraps a Tor client first, which can take a while.
+#[cfg(feature = "tor-listener")]
+#[instrument]
+pub async fn listen_onion(
+    service: OnionService,
+    gateway_origin: Uri,
+    config: Config,
+) -> Result<(), RelayError> {
+    serve_listeners(vec![(Listen::Onion(service), None)], gateway_origin, None, config).await
+}
+
+/// Serve HTTP/3 over QUIC on UDP `port`, which holds up better than TCP for mobile clients on
+/// lossy networks. `tls_config` is a rustls 0.23 configuration, see
+/// [`quic_server_config_from_pem`], offering `h3` with ALPN. Bootstrap tunnels are only served
+/// over WebTransport, with the `wt-bootstrap` feature.
+#[cfg(feature = "h3")]
+#[instrument(skip(tls_config))]
+pub async fn listen_quic(
+    port: u16,
+    gateway_origin: Uri,
+    tls_config: Arc<quinn::rustls::ServerConfig>,
+) -> Result<(), RelayError> {
+    listen_quic_with_config(port, gateway_origin, tls_config, Config::default()).await
+}
+
+#[cfg(feature = "h3")]
+#[instrument(skip(tls_config))]
+pub async fn listen_quic_with_config(
+    port: u16,
+    gateway_origin: Uri,
+    tls_config: Arc<quinn::rustls::ServerConfig>,
+    config: Config,
+) -> Result<(), RelayError> {
+    #[cfg(feature = "wt-bootstrap")]
+    let webtransport = config.bootstrap;
+    #[cfg(not(feature = "wt-bootstrap"))]
+    let webtransport = false;
+    // Upgrades to CONNECT and WebSocket tunnels need HTTP/1.1.
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+    let config = Config { bootstrap: false, ..config };
+    let running = RunningRelay::start(gateway_origin, config).await?;
+    let max_streams = running.relay.config.max_concurrent_requests_per_connection;
+    let addr = SocketAddr::new(running.relay.config.bind_ip, port);
+    let endpoint = quic::bind(addr, tls_config, max_streams)?;
+    let local_addr = endpoint.local_addr()?;
+    if let Some(on_bound) = &running.relay.config.on_bound {
+        on_bound.call()?;
+    }
+    info!("OHTTP relay listening on quic://{}", local_addr);
+    if let Some(on_listening) = &running.relay.config.on_listening {
+        let _ = on_listening.send(Listening::Quic(local_addr));
+    }
+    quic::serve(endpoint, running.relay.clone(), webtransport).await;
+    Ok(())
+}
+
+/// Serve on the TCP or unix socket passed by systemd socket activation, as described in
+/// `sd_listen_fds(3)`, instead of binding one. Only the first passed socket is used.
+#[cfg(unix)]
+#[instrument]
+pub async fn listen_activated(gateway_origin: Uri, config: Config) -> Result<(), RelayError> {
+    serve_listeners(vec![(Listen::Activated, None)], gateway_origin, None, config).await
+}
+
+/// Serve on the already bound and listening TCP or unix socket `fd`, e.g. one inherited from
+/// a supervisor.
+///
+/// # Safety
+///
+/// `fd` must be an open listening socket that the relay takes ownership of: nothing else in
+/// the process may use or close it.
+#[cfg(unix)]
+pub async unsafe fn listen_from_fd(
+    fd: std::os::unix::io::RawFd,
+    gateway_origin: Uri,
+    config: Config,
+) -> Result<(), RelayError> {
+    let bound = Bound::inherit(Inherited::from_fd(fd)?)?;
+    serve_bound(vec![(bound, None)], gateway_origin, None, config).await
 }
 
+/// Serve on the already bound and listening `listener`, e.g. one set up with `SO_REUSEPORT` or
+/// bound before dropping privileges, instead of binding one.
 #[instrument(skip(listener))]
-async fn ohttp_relay<L>(
-    mut listener: L,
+pub async fn serve_tcp_listener(
+    listener: std::net::TcpListener,
+    gateway_origin: Uri,
+    config: Config,
+) -> Result<(), RelayError> {
+    listener.set_nonblocking(true)?;
+    let bound = Bound::Tcp(TcpListener::from_std(listener)?);
+    serve_bound(vec![(bound, None)], gateway_origin, None, config).await
+}
+
+/// Serve on the already bound and listening unix socket `listener`, see
+/// [`serve_tcp_listener`]. Its file is left in place on shutdown.
+#[cfg(unix)]
+#[instrument(skip(listener))]
+pub async fn serve_unix_listener(
+    listener: std::os::unix::net::UnixListener,
+    gateway_origin: Uri,
+    config: Config,
+) -> Result<(), RelayError> {
+    listener.set_nonblocking(true)?;
+    let bound = Bound::inherit(Inherited::Unix(listener))?;
+    serve_bound(vec![(bound, None)], gateway_origin, None, config).await
+}
+
+/// Serve on every connection accepted by `listener`, for transports the other `listen_*`
+/// functions don't cover, e.g. vsock or an in-memory listener in tests. Wrap the accepted
+/// streams in the listener itself to terminate TLS.
+#[instrument(skip(listener))]
+pub async fn serve_listener<L>(
+    listener: L,
     gateway_origin: Uri,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    config: Config,
+) -> Result<(), RelayError>
 where
     L: Listener + Unpin,
     L::Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    L::Addr: 'static,
 {
-    let gateway_origin = GatewayUri::new(gateway_origin)?;
-    let gateway_origin: Arc<GatewayUri> = Arc::new(gateway_origin);
-
-    while let Ok((stream, _)) = listener.accept().await {
-        let gateway_origin = gateway_origin.clone();
-        let io = TokioIo::new(stream);
-        tokio::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(move |req| serve_ohttp_relay(req, gateway_origin.clone())),
-                )
-                .with_upgrades()
-                .await
-            {
+    ohttp_relay(listener, gateway_origin, config).await
+}
+
+/// Bind each of `listeners` and serve them all against one relay, see [`Builder::add_listener`].
+pub(crate) async fn serve_listeners(
+    listeners: Vec<(Listen, Option<BandwidthLimit>)>,
+    gateway_origin: Uri,
+    tls_config: Option<Arc<ServerConfig>>,
+    config: Config,
+) -> Result<(), RelayError> {
+    let mut bound = Vec::with_capacity(listeners.len());
+    for (listen, bandwidth_limit) in listeners {
+        bound.push((Bound::bind(listen, &config).await?, bandwidth_limit));
+    }
+    serve_bound(bound, gateway_origin, tls_config, config).await
+}
+
+/// Serve every bound listener against one relay, terminating TLS first on TCP listeners if
+/// `tls_config` is given. Once one listener fails or the relay shuts down, they all stop.
+/// Listeners given a bandwidth limit use it instead of [`Config::bandwidth_limit`].
+async fn serve_bound(
+    bound: Vec<(Bound, Option<BandwidthLimit>)>,
+    gateway_origin: Uri,
+    tls_config: Option<Arc<ServerConfig>>,
+    mut config: Config,
+) -> Result<(), RelayError> {
+    if tls_config.is_some() && !bound.iter().any(|(bound, _)| bound.is_tcp()) {
+        return Err(RelayError::Tls("TLS is only supported on TCP listeners".into()));
+    }
+    for limit in bound.iter().filter_map(|(_, limit)| limit.as_ref()) {
+        check_bandwidth_limit(limit)?;
+    }
+    // Stopping one listener stops the others without cancelling the caller's token.
+    config.shutdown = config.shutdown.child_token();
+    let running = RunningRelay::start(gateway_origin, config).await?;
+    let relay = running.relay.clone();
+    if let Some(on_bound) = &relay.config.on_bound {
+        on_bound.call()?;
+    }
+    if let Some(on_listening) = &relay.config.on_listening {
+        for (bound, _) in &bound {
+            // The receiver may be gone if the embedder stopped waiting.
+            let _ = on_listening.send(bound.listening()?);
+        }
+    }
+    let connections = TaskTracker::new();
+    let accepting = bound.into_iter().map(|(bound, bandwidth_limit)| {
+        let bandwidth_limit = bandwidth_limit.or_else(|| relay.config.bandwidth_limit.clone());
+        let accept =
+            bound.accept(tls_config.clone(), bandwidth_limit, relay.clone(), connections.clone());
+        let shutdown = relay.config.shutdown.clone();
+        Box::pin(async move {
+            let result = accept.await;
+            shutdown.cancel();
+            result
+        }) as Pin<Box<dyn Future<Output = Result<(), RelayError>> + Send>>
+    });
+    let result = listen::AcceptAll::new(accepting.collect()).await;
+    drop(running);
+    drain_connections(connections, &relay.config).await;
+    result
+}
+
+fn check_bandwidth_limit(limit: &BandwidthLimit) -> Result<(), RelayError> {
+    match limit.per_second {
+        0 =>
+            Err(RelayError::Config("Bandwidth limits must allow at least 1 byte per second".into())),
+        _ => Ok(()),
+    }
+}
+
+fn check_rate_limit(limit: &RateLimit) -> Result<(), RelayError> {
+    match (limit.burst, limit.per_second) {
+        (0, _) | (_, 0) => Err(RelayError::Config(
+            "Rate limits must allow at least 1 request at once and 1 per second".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Call `bind` until it succeeds, retrying with exponential backoff for at most `retry_for`.
+async fn bind_with_retry<T, F, Fut>(retry_for: Option<Duration>, mut bind: F) -> std::io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let deadline = retry_for.map(|retry_for| tokio::time::Instant::now() + retry_for);
+    let mut backoff = Duration::from_millis(50);
+    loop {
+        match bind().await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => {
+                let now = tokio::time::Instant::now();
+                match deadline {
+                    Some(deadline) if now < deadline => {
+                        debug!("Bind failed, retrying in {:?}: {}", backoff, e);
+                        tokio::time::sleep(backoff.min(deadline - now)).await;
+                        backoff = (backoff * 2).min(Duration::from_secs(1));
+                    }
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+}
+
+#[instrument(skip(listener))]
+async fn ohttp_relay<L>(listener: L, gateway_origin: Uri, config: Config) -> Result<(), RelayError>
+where
+    L: Listener + Unpin,
+    L::Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    L::Addr: 'static,
+{
+    ohttp_relay_with(listener, gateway_origin, config, |stream| std::future::ready(Ok(stream)))
+        .await
+}
+
+/// Serve the relay on every connection accepted by `listener` once `handshake` has wrapped it,
+/// e.g. in TLS.
+#[instrument(skip(listener, handshake))]
+async fn ohttp_relay_with<L, H, F, S>(
+    listener: L,
+    gateway_origin: Uri,
+    config: Config,
+    handshake: H,
+) -> Result<(), RelayError>
+where
+    L: Listener + Unpin,
+    L::Io: AsyncRead + Unpin + Send + 'static,
+    L::Addr: 'static,
+    H: Fn(L::Io) -> F + Send + Sync + 'static,
+    F: Future<Output = std::io::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let running = RunningRelay::start(gateway_origin, config).await?;
+    let relay = running.relay.clone();
+    if let Some(on_bound) = &relay.config.on_bound {
+        on_bound.call()?;
+    }
+    let connections = TaskTracker::new();
+    let bandwidth_limit = relay.config.bandwidth_limit.clone();
+    accept_connections(listener, relay.clone(), connections.clone(), bandwidth_limit, handshake)
+        .await;
+    drop(running);
+    drain_connections(connections, &relay.config).await;
+    Ok(())
+}
+
+/// Accept connections from `listener` into `connections` until shutdown or the listener fails,
+/// serving each once `handshake` has wrapped it. Handshakes run on each connection's own task
+/// so a slow client cannot stall the accept loop. Each connection's bodies are throttled to
+/// `bandwidth_limit`.
+async fn accept_connections<L, H, F, S>(
+    mut listener: L,
+    relay: Arc<Relay>,
+    connections: TaskTracker,
+    bandwidth_limit: Option<BandwidthLimit>,
+    handshake: H,
+) where
+    L: Listener + Unpin,
+    L::Io: AsyncRead + Unpin + Send + 'static,
+    L::Addr: 'static,
+    H: Fn(L::Io) -> F + Send + Sync + 'static,
+    F: Future<Output = std::io::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let handshake = Arc::new(handshake);
+    let shutdown = relay.config.shutdown.clone();
+
+    loop {
+        let permit = match &relay.connection_slots {
+            Some(slots) => tokio::select! {
+                permit = acquire_slot(slots, &relay.metrics) => Some(permit),
+                _ = shutdown.cancelled() => break,
+            },
+            None => None,
+        };
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        let peer_addr = peer_socket_addr(&addr);
+        let relay = relay.clone();
+        let shutdown = shutdown.clone();
+        let handshake = handshake.clone();
+        let throttle = bandwidth_limit.as_ref().map(Throttle::new);
+        connections.spawn(async move {
+            let _permit = permit;
+            let _open = relay.metrics.connection_opened();
+            let _connection = events::Connection::accepted(&relay.config.events);
+            let config = &relay.config;
+            let max_age = config.max_connection_age.map(|age| tokio::time::Instant::now() + age);
+            let handshake = async {
+                let mut stream = stream;
+                let client_addr = match config.proxy_protocol {
+                    true => proxy_protocol::read_header(&mut stream).await?,
+                    false => None,
+                };
+                Ok::<_, std::io::Error>((handshake(stream).await?, client_addr.or(peer_addr)))
+            };
+            let handshake = async {
+                match config.header_read_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, handshake)
+                        .await
+                        .unwrap_or_else(|e| Err(e.into())),
+                    None => handshake.await,
+                }
+            };
+            let (io, peer_addr) = match handshake.await {
+                Ok((stream, peer_addr)) => (TokioIo::new(stream), peer_addr),
+                Err(e) => {
+                    debug!("Connection handshake failed: {}", e);
+                    return;
+                }
+            };
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder
+                .http1()
+                .keep_alive(config.http1.keep_alive)
+                .half_close(config.http1.half_close)
+                .pipeline_flush(config.http1.pipeline_flush);
+            if let Some(max) = config.http1.max_buf_size {
+                builder.http1().max_buf_size(max);
+            }
+            if let Some(timeout) = config.header_read_timeout {
+                builder.http1().timer(TokioTimer::new()).header_read_timeout(timeout);
+            }
+            if let Some(max) = config.max_concurrent_requests_per_connection {
+                builder.http2().max_concurrent_streams(max);
+            }
+            if let Some(max) = config.streaming.max_buffered_bytes {
+                builder
+                    .http2()
+                    .initial_stream_window_size(max)
+                    .initial_connection_window_size(max)
+                    .max_send_buf_size(max as usize);
+            }
+            // Browsers open WebSockets over HTTP/2 with extended CONNECT, see RFC 8441, as do
+            // CONNECT-UDP clients.
+            #[cfg(any(feature = "ws-bootstrap", feature = "connect-udp-bootstrap"))]
+            if config.bootstrap {
+                builder.http2().enable_connect_protocol();
+            }
+            let mut drain = relay.drain.subscribe();
+            let activity = Arc::new(Activity::default());
+            let idle_timeout = config.idle_timeout;
+            let conn = builder.serve_connection_with_upgrades(io, {
+                let activity = activity.clone();
+                let relay = relay.clone();
+                let service = OhttpRelayService::for_connection(relay.clone(), peer_addr);
+                service_fn(move |req: Request<Incoming>| {
+                    let busy = activity.busy();
+                    activity.count_request(relay.config.max_requests_per_connection);
+                    let keep_alive =
+                        req.version() < Version::HTTP_2 && req.method() != Method::CONNECT;
+                    let req = req.map(|body| Throttled::new(body, throttle.clone()));
+                    let res = service.clone().call(req);
+                    let activity = activity.clone();
+                    let throttle = throttle.clone();
+                    async move {
+                        let mut res = match throttle {
+                            Some(throttle) => res.await.map(|res| {
+                                res.map(|body| Throttled::new(body, Some(throttle)).boxed())
+                            }),
+                            None => res.await,
+                        };
+                        drop(busy);
+                        // Tell HTTP/1 clients the connection closes after this response.
+                        if let Ok(res) = &mut res {
+                            if keep_alive
+                                && activity.is_draining()
+                                && !res.status().is_informational()
+                            {
+                                res.headers_mut()
+                                    .insert(CONNECTION, HeaderValue::from_static("close"));
+                            }
+                        }
+                        res
+                    }
+                })
+            });
+            let idle = async {
+                match idle_timeout {
+                    Some(timeout) => activity.idle_for(timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                // Finish in-flight requests but accept no new ones on this connection.
+                _ = shutdown.cancelled() => {
+                    activity.drain();
+                    conn.as_mut().graceful_shutdown();
+                    finish_within(conn, config.shutdown_timeout).await
+                }
+                _ = drain.changed() => {
+                    debug!("Draining connection after reload");
+                    activity.drain();
+                    conn.as_mut().graceful_shutdown();
+                    finish_within(conn, config.shutdown_timeout).await
+                }
+                _ = idle => {
+                    debug!("Closing idle connection");
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+                _ = activity.exhausted() => {
+                    debug!("Closing connection that reached its maximum number of requests");
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+                _ = sleep_until(max_age) => {
+                    debug!("Closing connection that reached its maximum age");
+                    activity.drain();
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(err) = result {
                 error!("Error serving connection: {:?}", err);
             }
         });
     }
+}
 
-    Ok(())
+/// Wait for open connections to drain, for at most [`Config::shutdown_timeout`].
+async fn drain_connections(connections: TaskTracker, config: &Config) {
+    connections.close();
+    match config.shutdown_timeout {
+        Some(timeout) =>
+            if tokio::time::timeout(timeout, connections.wait()).await.is_err() {
+                info!("Shutdown timed out with {} connections still open", connections.len());
+            },
+        None => connections.wait().await,
+    }
 }
 
-#[instrument]
-async fn serve_ohttp_relay(
-    req: Request<Incoming>,
-    gateway_origin: Arc<GatewayUri>,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+/// A relay's shared state along with the tasks serving it, which stop once it is dropped.
+struct RunningRelay {
+    relay: Arc<Relay>,
+    reloads: JoinHandle<()>,
+    _admin_server: Option<AdminServer>,
+    _signal_handler: Option<SignalHandler>,
+    #[cfg(feature = "otel")]
+    _observed_metrics: Option<otel::ObservedMetrics>,
+    #[cfg(feature = "metrics")]
+    _metrics_server: Option<MetricsServer>,
+}
+
+impl RunningRelay {
+    /// Set up a relay forwarding to `gateway_origin`, for any listener to serve.
+    async fn start(gateway_origin: Uri, mut config: Config) -> Result<Self, RelayError> {
+        if config.http1.max_buf_size.is_some_and(|size| size < Http1Server::MIN_BUF_SIZE) {
+            return Err(RelayError::Config(
+                format!("HTTP/1 buffers must be at least {} bytes", Http1Server::MIN_BUF_SIZE)
+                    .into(),
+            ));
+        }
+        if let Some(limit) = &config.bandwidth_limit {
+            check_bandwidth_limit(limit)?;
+        }
+        if let Some(max) = config.streaming.max_buffered_bytes {
+            // HTTP/2 flow-control windows are at most 2^31 - 1 bytes.
+            if (max as usize) < Http1Server::MIN_BUF_SIZE || max > i32::MAX as u32 {
+                return Err(RelayError::Config(
+                    format!(
+                        "Buffered bytes must be between {} and {}",
+                        Http1Server::MIN_BUF_SIZE,
+                        i32::MAX
+                    )
+                    .into(),
+                ));
+            }
+        }
+        let default_gateway = GatewayUri::new(gateway_origin, config.danger_allow_insecure_gateway)
+            .map_err(RelayError::InvalidGateway)?;
+        // Every gateway connection, forwarded or tunnelled, resolves through the pins.
+        let resolver: Arc<dyn Resolver> = match &config.dns_cache {
+            Some(cache) => Arc::new(CachingResolver::new(config.resolver.clone(), cache.clone())),
+            None => config.resolver.clone(),
+        };
+        config.resolver = Arc::new(PinnedResolver::new(
+            resolver,
+            config.dns_pin_interval,
+            config.danger_allow_insecure_gateway,
+        ));
+        let client =
+            upstream_client(tls::client_config(&config).map_err(RelayError::Tls)?, &config)
+                .map_err(RelayError::Config)?;
+        let profiles = profiles(&config)?;
+        let metrics = Arc::new(Metrics::default());
+        let reloadable = Reloadable::new(&default_gateway, &config, &client, &metrics, None)?;
+        let access_log = config
+            .access_log
+            .clone()
+            .map(AccessLogger::open)
+            .transpose()
+            .map_err(|e| RelayError::Config(e.into()))?;
+        #[cfg(feature = "otel")]
+        let _observed_metrics = otel::ObservedMetrics::register(metrics.clone());
+        #[cfg(feature = "metrics")]
+        let _metrics_server = match config.metrics_addr {
+            Some(addr) => Some(MetricsServer::bind(addr, metrics.clone()).await?),
+            None => None,
+        };
+        let relay = Arc::new(Relay {
+            default_gateway: RwLock::new(default_gateway),
+            client,
+            profiles,
+            inflight: Arc::new(Inflight::new(config.max_inflight_requests)),
+            metrics,
+            keys: KeyCache::default(),
+            access_log,
+            reloadable: RwLock::new(Arc::new(reloadable)),
+            drain: watch::channel(()).0,
+            connection_slots: config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            config,
+        });
+        let _admin_server = match &relay.config.admin {
+            Some(admin) => Some(AdminServer::bind(admin, relay.clone()).await?),
+            None => None,
+        };
+        let _signal_handler = match relay.config.signal_handling {
+            true => Some(
+                SignalHandler::install(relay.config.shutdown.clone())
+                    .map_err(|e| RelayError::Config(e.into()))?,
+            ),
+            false => None,
+        };
+        let reloads = tokio::spawn(apply_reloads(relay.clone(), relay.config.reload.subscribe()));
+        Ok(Self {
+            relay,
+            reloads,
+            _admin_server,
+            _signal_handler,
+            #[cfg(feature = "otel")]
+            _observed_metrics,
+            #[cfg(feature = "metrics")]
+            _metrics_server,
+        })
+    }
+}
+
+impl Drop for RunningRelay {
+    fn drop(&mut self) { self.reloads.abort() }
+}
+
+/// Wait for a draining connection to finish its in-flight requests, closing it once `deadline`
+/// passes.
+async fn finish_within<F, E>(conn: F, deadline: Option<Duration>) -> Result<(), E>
+where
+    F: Future<Output = Result<(), E>>,
+{
+    match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, conn).await.unwrap_or_else(|_| {
+            info!("Closing connection with requests still in flight after draining");
+            Ok(())
+        }),
+        None => conn.await,
+    }
+}
+
+/// Wait for a free connection slot, counting the accept as queued if there is none.
+async fn acquire_slot(slots: &Arc<Semaphore>, metrics: &Metrics) -> OwnedSemaphorePermit {
+    match slots.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            metrics.accept_queued();
+            slots.clone().acquire_owned().await.expect("connection slots are never closed")
+        }
+    }
+}
+
+/// Sleep until `deadline`, or forever when `None`.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The peer's socket address if the listener is a TCP listener.
+fn peer_socket_addr<A: 'static>(addr: &A) -> Option<SocketAddr> {
+    (addr as &dyn std::any::Any).downcast_ref::<SocketAddr>().copied()
+}
+
+/// State shared by every connection to a relay.
+#[derive(Debug)]
+struct Relay {
+    /// Replaced by [`Relay::replace_gateways`].
+    default_gateway: RwLock<GatewayUri>,
+    config: Config,
+    client: UpstreamClient,
+    profiles: Vec<(GatewayUri, Profile)>,
+    inflight: Arc<Inflight>,
+    metrics: Arc<Metrics>,
+    keys: KeyCache,
+    access_log: Option<AccessLogger>,
+    reloadable: RwLock<Arc<Reloadable>>,
+    /// Tells open connections to drain, see [`Config::drain_on_reload`].
+    drain: watch::Sender<()>,
+    /// Limits open connections across every listener, see [`Config::max_connections`].
+    connection_slots: Option<Arc<Semaphore>>,
+}
+
+impl Relay {
+    fn reloadable(&self) -> Arc<Reloadable> {
+        self.reloadable.read().expect("reloadable settings poisoned").clone()
+    }
+
+    fn default_gateway(&self) -> GatewayUri {
+        self.default_gateway.read().expect("default gateway poisoned").clone()
+    }
+
+    /// Replace the default gateway and [`Config::allowed_gateways`] for new requests, keeping
+    /// the other reloadable settings as last applied. Either is left as is when `None`.
+    fn replace_gateways(
+        &self,
+        origin: Option<Uri>,
+        allowed: Option<Vec<Uri>>,
+    ) -> Result<(), RelayError> {
+        let mut config = self.config.reload.current().unwrap_or_else(|| self.config.clone());
+        if let Some(allowed) = allowed {
+            config.allowed_gateways = allowed;
+        }
+        let default_gateway = match origin {
+            Some(origin) => GatewayUri::new(origin, self.config.danger_allow_insecure_gateway)
+                .map_err(RelayError::InvalidGateway)?,
+            None => self.default_gateway(),
+        };
+        // Refuse an invalid allowlist now rather than once the reload is applied.
+        Gateways::new(default_gateway.clone(), &config).map_err(RelayError::InvalidGateway)?;
+        *self.default_gateway.write().expect("default gateway poisoned") = default_gateway;
+        self.config.reload.reload(config);
+        Ok(())
+    }
+
+    /// The settings for forwarding to `gateway`, if it has its own [`GatewayConfig`].
+    fn profile(&self, gateway: &GatewayUri) -> Option<&Profile> {
+        self.profiles.iter().find(|(origin, _)| origin.same_origin(gateway)).map(|(_, p)| p)
+    }
+}
+
+/// How requests to a gateway with its own [`GatewayConfig`] are forwarded.
+#[derive(Debug)]
+struct Profile {
+    /// The relay's settings with the gateway's applied on top.
+    config: Config,
+    client: UpstreamClient,
+    /// Replaces the reloadable limit when set.
+    max_body_size: Option<u64>,
+    /// Applied on top of the reloadable limit, to requests routed to this gateway only.
+    rate_limiter: Option<RateLimiter>,
+}
+
+/// Build a [`Profile`] for every gateway in [`Config::gateway_configs`].
+fn profiles(config: &Config) -> Result<Vec<(GatewayUri, Profile)>, RelayError> {
+    config
+        .gateway_configs
+        .iter()
+        .map(|(origin, gateway_config)| {
+            let origin = GatewayUri::new(origin.clone(), config.danger_allow_insecure_gateway)
+                .map_err(RelayError::InvalidGateway)?;
+            let profile_config = gateway_config.apply(config);
+            let tls_config = tls::client_config(&profile_config).map_err(RelayError::Tls)?;
+            let client =
+                upstream_client(tls_config, &profile_config).map_err(RelayError::Config)?;
+            let max_body_size = gateway_config.max_body_size;
+            if let Some(limit) = &gateway_config.rate_limit {
+                check_rate_limit(limit)?;
+            }
+            let rate_limiter = gateway_config.rate_limit.clone().map(RateLimiter::new);
+            let profile = Profile { config: profile_config, client, max_body_size, rate_limiter };
+            Ok((origin, profile))
+        })
+        .collect()
+}
+
+/// The settings [`Reload`] can replace while the relay runs.
+#[derive(Debug)]
+struct Reloadable {
+    gateways: Arc<Gateways>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    max_body_size: Option<u64>,
+    _probes: Option<ProbeTask>,
+}
+
+impl Reloadable {
+    /// Build the settings from `config`, keeping the rate limiter's buckets from `previous`
+    /// if the limit is unchanged.
+    fn new(
+        default_gateway: &GatewayUri,
+        config: &Config,
+        client: &UpstreamClient,
+        metrics: &Arc<Metrics>,
+        previous: Option<&Reloadable>,
+    ) -> Result<Self, RelayError> {
+        if let Some(limit) = &config.rate_limit {
+            check_rate_limit(limit)?;
+        }
+        let gateways = Arc::new(
+            Gateways::new(default_gateway.clone(), config).map_err(RelayError::InvalidGateway)?,
+        );
+        let probes = config.health_check.clone().map(|health_check| {
+            ProbeTask::spawn(gateways.clone(), client.clone(), health_check, metrics.clone())
+        });
+        let previous_limiter = previous.and_then(|previous| previous.rate_limiter.as_ref());
+        let rate_limiter = match (&config.rate_limit, previous_limiter) {
+            (Some(limit), Some(limiter)) if limiter.limit() == limit => Some(limiter.clone()),
+            (limit, _) => limit.clone().map(|limit| Arc::new(RateLimiter::new(limit))),
+        };
+        Ok(Self { gateways, rate_limiter, max_body_size: config.max_body_size, _probes: probes })
+    }
+}
+
+/// Replace the relay's reloadable settings with each [`Config`] sent through [`Reload`].
+async fn apply_reloads(relay: Arc<Relay>, mut reloads: watch::Receiver<Option<Config>>) {
+    while reloads.changed().await.is_ok() {
+        let config = match reloads.borrow_and_update().clone() {
+            Some(config) => config,
+            None => continue,
+        };
+        let previous = relay.reloadable();
+        let default_gateway = relay.default_gateway();
+        let (client, metrics) = (&relay.client, &relay.metrics);
+        match Reloadable::new(&default_gateway, &config, client, metrics, Some(&previous)) {
+            Ok(reloadable) => {
+                *relay.reloadable.write().expect("reloadable settings poisoned") =
+                    Arc::new(reloadable);
+                info!("Reloaded settings");
+                if relay.config.drain_on_reload {
+                    relay.drain.send_replace(());
+                }
+            }
+            Err(e) => error!("Keeping previous settings, reload failed: {}", e),
+        }
+    }
+}
+
+#[instrument(
+    name = "relay",
+    skip_all,
+    fields(
+        method = %req.method(),
+        path = access_log::path_class(&req),
+        request_id = tracing::field::Empty,
+        status
+    )
+)]
+async fn serve_ohttp_relay<B>(
+    req: Request<B>,
+    peer_addr: Option<SocketAddr>,
+    deadline: Option<tokio::time::Instant>,
+    relay: Arc<Relay>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible>
+where
+    B: Body<Data = Bytes> + std::fmt::Debug + Send + Sync + Unpin + 'static,
+    B::Error: Into<BoxError> + Send + Sync + Unpin + 'static,
+{
+    let Relay { config, client, inflight, metrics, keys, access_log, .. } = &*relay;
+    let request_id = request_id::generate();
+    tracing::Span::current().record("request_id", request_id.to_str().unwrap_or_default());
+    let reloadable = relay.reloadable();
+    let Reloadable { gateways, rate_limiter, .. } = &*reloadable;
     let path = req.uri().path();
+    // Compressed error bodies would vary in size with their contents.
+    let compress_errors =
+        config.compress_error_bodies && config.padding.is_none() && accepts_gzip(req.headers());
+    let origin = req.headers().get(ORIGIN).cloned();
+    let logged = access_log.as_ref().map(|access_log| access_log.start(&req, peer_addr));
+    let head = req.method() == Method::HEAD;
+    let allow = allowed_methods(config, path);
     let mut res = match (req.method(), path) {
-        (&Method::OPTIONS, _) => Ok(handle_preflight()),
-        (&Method::GET, "/health") => Ok(health_check().await),
-        (&Method::POST, _) => handle_ohttp_relay(req, &gateway_origin).await,
+        _ if !headers_within_limits(req.headers(), config) => {
+            metrics.headers_rejected();
+            Err(Error::HeadersTooLarge)
+        }
+        (&Method::OPTIONS, _) => Ok(options(&config.cors, origin.as_ref(), allow)),
+        (&Method::GET | &Method::HEAD, "/health") if public_health_endpoints(config) =>
+            Ok(health_check().await),
+        (&Method::GET | &Method::HEAD, "/ready") if public_health_endpoints(config) =>
+            Ok(readiness_check(gateways)),
+        (&Method::GET, "/admin/inflight") if public_admin_endpoints(config) =>
+            handle_admin_inflight(&req, peer_addr, config, inflight).await,
+        #[cfg(feature = "metrics")]
+        (&Method::GET, "/admin/metrics") if public_admin_endpoints(config) =>
+            handle_admin_metrics(&req, peer_addr, config, metrics).await,
+        #[cfg(feature = "metrics")]
+        (&Method::GET, "/admin/gateways") if public_admin_endpoints(config) =>
+            handle_admin_gateways(&req, peer_addr, config, metrics).await,
+        #[cfg(feature = "metrics")]
+        (&Method::GET, "/admin/stats") if public_admin_endpoints(config) =>
+            handle_admin_stats(&req, peer_addr, config, metrics).await,
+        (&Method::GET | &Method::HEAD, "/ohttp-keys" | "/.well-known/ohttp-gateway")
+            if config.ohttp_keys.is_some() =>
+            async {
+                rate_limit(rate_limiter.as_deref(), peer_addr)?;
+                let settings = config.ohttp_keys.as_ref().expect("checked by the guard");
+                let gateway = gateways.default_gateway();
+                let (client, config) = match relay.profile(&gateway) {
+                    Some(profile) => (&profile.client, &profile.config),
+                    None => (client, config),
+                };
+                let timeout = config.response_timeout;
+                keys.respond(
+                    req.headers(),
+                    &gateway,
+                    settings,
+                    client,
+                    timeout,
+                    &relay.config.events,
+                )
+                .await
+            }
+            .await,
+        (&Method::GET | &Method::HEAD, "/.well-known/ohttp-relay") if config.capabilities => {
+            let Reloadable { max_body_size, .. } = &*reloadable;
+            let mut res =
+                Response::new(full(capabilities::document(config, gateways, *max_body_size)));
+            res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            Ok(res)
+        }
+        (&Method::POST, _) =>
+            async {
+                rate_limit(rate_limiter.as_deref(), peer_addr)?;
+                authorize(&req, peer_addr, config.authorizer.as_ref()).await?;
+                let forward = handle_ohttp_relay(req, peer_addr, &relay, &reloadable);
+                match deadline {
+                    Some(deadline) =>
+                        tokio::time::timeout_at(deadline, forward).await.unwrap_or_else(|_| {
+                            metrics.deadline_exceeded();
+                            Err(Error::GatewayTimeout)
+                        }),
+                    None => forward.await,
+                }
+            }
+            .await,
         #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
-        (&Method::CONNECT, _) | (&Method::GET, _) =>
-            crate::bootstrap::handle_ohttp_keys(req, gateway_origin).await,
-        _ => Err(Error::NotFound),
+        (&Method::CONNECT, _) | (&Method::GET, _) if config.bootstrap =>
+            async {
+                rate_limit(rate_limiter.as_deref(), peer_addr)?;
+                authorize(&req, peer_addr, config.authorizer.as_ref()).await?;
+                crate::bootstrap::handle_ohttp_keys(req, gateways, config).await
+            }
+            .await,
+        _ => Err(Error::MethodNotAllowed(allow)),
+    }
+    .unwrap_or_else(|e| {
+        let mut res = match (config.problem_details, compress_errors) {
+            (true, _) => e.to_problem_response(Some(&request_id)),
+            (false, true) => e.to_gzip_response(),
+            (false, false) => e.to_response(),
+        };
+        res.headers_mut().insert(REQUEST_ID.clone(), request_id);
+        res
+    });
+    cors::insert_allow_origin(&mut res, &config.cors, origin.as_ref());
+    if let (Some(padding), Some(len)) = (&config.padding, res.body().size_hint().exact()) {
+        padding::pad(res.headers_mut(), len, padding);
+    }
+    if head {
+        res = without_body(res);
+    }
+    metrics.record_response(res.status());
+    tracing::Span::current().record("status", res.status().as_u16());
+    if let (Some(access_log), Some(logged)) = (access_log, logged) {
+        access_log.finish(logged, res.status());
     }
-    .unwrap_or_else(|e| e.to_response());
-    res.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
     Ok(res)
 }
 
-fn handle_preflight() -> Response<BoxBody<Bytes, hyper::Error>> {
-    let mut res = Response::new(empty());
-    *res.status_mut() = hyper::StatusCode::NO_CONTENT;
-    res.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
-    res.headers_mut().insert(
-        ACCESS_CONTROL_ALLOW_METHODS,
-        HeaderValue::from_static("CONNECT, GET, OPTIONS, POST"),
-    );
-    res.headers_mut().insert(
-        ACCESS_CONTROL_ALLOW_HEADERS,
-        HeaderValue::from_static("Content-Type, Content-Length"),
-    );
+/// Whether `headers` fit [`Config::max_header_count`] and [`Config::max_header_bytes`].
+fn headers_within_limits(headers: &HeaderMap, config: &Config) -> bool {
+    if config.max_header_count.is_some_and(|max| headers.len() > max) {
+        return false;
+    }
+    let bytes =
+        || headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum::<usize>();
+    config.max_header_bytes.is_none_or(|max| bytes() <= max)
+}
+
+/// The methods `path` can be requested with besides `OPTIONS`: `GET` and `HEAD` on the health,
+/// key and capability endpoints that are enabled, `CONNECT` and `GET` with bootstrapping, and
+/// always `POST`.
+fn allowed_methods(config: &Config, path: &str) -> HeaderValue {
+    let resource = match path {
+        "/health" | "/ready" => public_health_endpoints(config),
+        "/ohttp-keys" | "/.well-known/ohttp-gateway" => config.ohttp_keys.is_some(),
+        "/.well-known/ohttp-relay" => config.capabilities,
+        _ => false,
+    };
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+    let bootstrap = config.bootstrap;
+    #[cfg(not(any(feature = "connect-bootstrap", feature = "ws-bootstrap")))]
+    let bootstrap = false;
+    HeaderValue::from_static(match (resource, bootstrap) {
+        (false, false) => "POST",
+        (true, false) => "GET, HEAD, POST",
+        (false, true) => "CONNECT, GET, POST",
+        (true, true) => "CONNECT, GET, HEAD, POST",
+    })
+}
+
+/// Answer an `OPTIONS` request with the methods `path` allows, as a CORS preflight if any
+/// origin is allowed.
+fn options(
+    cors: &Cors,
+    origin: Option<&HeaderValue>,
+    allow: HeaderValue,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = match cors.allowed_origins.is_empty() {
+        true => {
+            let mut res = Response::new(empty());
+            *res.status_mut() = StatusCode::NO_CONTENT;
+            res
+        }
+        false => cors::preflight(cors, origin),
+    };
+    res.headers_mut().insert(ALLOW, allow);
     res
 }
 
+/// The response to a `HEAD` request: `res`'s headers, with the length of the body left out.
+fn without_body(
+    res: Response<BoxBody<Bytes, hyper::Error>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let (mut parts, body) = res.into_parts();
+    if let Some(len) = body.size_hint().exact() {
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    }
+    Response::from_parts(parts, empty())
+}
+
+/// Apply the rate limit, if any, to TCP clients.
+fn rate_limit(limiter: Option<&RateLimiter>, peer_addr: Option<SocketAddr>) -> Result<(), Error> {
+    match (limiter, peer_addr) {
+        (Some(limiter), Some(addr)) => limiter.check(addr.ip()),
+        _ => Ok(()),
+    }
+}
+
+async fn authorize<B>(
+    req: &Request<B>,
+    peer_addr: Option<SocketAddr>,
+    authorizer: &dyn Authorizer,
+) -> Result<(), Error> {
+    let meta = RequestMeta {
+        peer_addr,
+        method: req.method(),
+        path: req.uri().path(),
+        headers: req.headers(),
+    };
+    allowed(authorizer.authorize(&meta).await)
+}
+
+fn allowed(authorization: Authorization) -> Result<(), Error> {
+    match authorization {
+        Authorization::Allow => Ok(()),
+        Authorization::Deny(status) => Err(Error::Denied(status.unwrap_or(StatusCode::FORBIDDEN))),
+        Authorization::Challenge(challenge) => Err(Error::Unauthorized(challenge)),
+    }
+}
+
+/// Whether the relay's own listeners serve `/health` and `/ready`, which move to the admin
+/// listener when [`Config::admin`] is set.
+fn public_health_endpoints(config: &Config) -> bool {
+    config.health_endpoints && config.admin.is_none()
+}
+
+/// Whether the relay's own listeners serve the `/admin` endpoints of [`Config::admin_token`].
+fn public_admin_endpoints(config: &Config) -> bool {
+    config.admin_token.is_some() && config.admin.is_none()
+}
+
 async fn health_check() -> Response<BoxBody<Bytes, hyper::Error>> { Response::new(empty()) }
 
-#[instrument]
-async fn handle_ohttp_relay(
-    req: Request<Incoming>,
-    gateway_origin: &GatewayUri,
+/// Ready once the listener is bound, for as long as the default gateway is reachable as far as
+/// the background health checks can tell.
+fn readiness_check(gateways: &Gateways) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = Response::new(empty());
+    if !gateways.default_available() {
+        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    res
+}
+
+/// Only loopback clients holding the admin token may use admin endpoints; they do not exist
+/// for anyone else.
+async fn authorize_admin<B>(
+    req: &Request<B>,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Result<(), Error> {
+    let admin_token = config.admin_token.as_ref().ok_or(Error::NotFound)?;
+    if !peer_addr.is_some_and(|addr| addr.ip().is_loopback()) {
+        return Err(Error::NotFound);
+    }
+    authorize(req, peer_addr, admin_token).await
+}
+
+/// List the forwards currently in flight.
+async fn handle_admin_inflight<B>(
+    req: &Request<B>,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+    inflight: &Inflight,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    authorize_admin(req, peer_addr, config).await?;
+    let mut res = Response::new(full(inflight.to_json()));
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(res)
+}
+
+/// Render the relay's metrics in the Prometheus text format.
+#[cfg(feature = "metrics")]
+async fn handle_admin_metrics<B>(
+    req: &Request<B>,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+    metrics: &Metrics,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    authorize_admin(req, peer_addr, config).await?;
+    let mut res = Response::new(full(metrics.render()));
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(metrics::CONTENT_TYPE_TEXT));
+    Ok(res)
+}
+
+/// Summarize the relay's metrics and the health of its gateways.
+#[cfg(feature = "metrics")]
+async fn handle_admin_stats<B>(
+    req: &Request<B>,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+    metrics: &Metrics,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    authorize_admin(req, peer_addr, config).await?;
+    let mut res = Response::new(full(metrics.stats_json()));
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(res)
+}
+
+/// Summarize how forwards to each gateway went.
+#[cfg(feature = "metrics")]
+async fn handle_admin_gateways<B>(
+    req: &Request<B>,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+    metrics: &Metrics,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    authorize_admin(req, peer_addr, config).await?;
+    let mut res = Response::new(full(metrics.gateways_json()));
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(res)
+}
+
+/// Relay `req` to its gateway, recording how long that took by gateway and status class.
+#[instrument(skip_all)]
+async fn handle_ohttp_relay<B>(
+    req: Request<B>,
+    peer_addr: Option<SocketAddr>,
+    relay: &Relay,
+    reloadable: &Reloadable,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error>
+where
+    B: Body<Data = Bytes> + Send + Sync + Unpin + 'static,
+    B::Error: Into<BoxError> + Send + Sync + Unpin + 'static,
+{
+    let received = Instant::now();
+    let mut gateway = None;
+    let res = relay_to_gateway(req, peer_addr, relay, reloadable, &mut gateway).await;
+    if let Some(gateway) = gateway {
+        let status = res.as_ref().map(|res| res.status());
+        relay.metrics.observe_request_duration(&gateway, status, received.elapsed());
+    }
+    res
+}
+
+/// Forward `req` to the gateway it selects, which is stored in `gateway` once known.
+async fn relay_to_gateway<B>(
+    req: Request<B>,
+    peer_addr: Option<SocketAddr>,
+    relay: &Relay,
+    reloadable: &Reloadable,
+    gateway: &mut Option<GatewayUri>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error>
+where
+    B: Body<Data = Bytes> + Send + Sync + Unpin + 'static,
+    B::Error: Into<BoxError> + Send + Sync + Unpin + 'static,
+{
+    let Relay { config, client, inflight, metrics, .. } = relay;
+    let Reloadable { gateways, max_body_size, .. } = reloadable;
+    let expects_continue = req
+        .headers()
+        .get(EXPECT)
+        .is_some_and(|expect| expect.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    let (mut fwd_req, gateway_origin, client_headers) =
+        into_forward_req(req, peer_addr, gateways, &config.path_rewrite, config.content_encoding)?;
+    *gateway = Some(gateway_origin.clone());
+    let (config, client, max_body_size) = match relay.profile(&gateway_origin) {
+        Some(profile) => {
+            rate_limit(profile.rate_limiter.as_ref(), peer_addr)?;
+            (&profile.config, &profile.client, profile.max_body_size.or(*max_body_size))
+        }
+        None => (config, client, *max_body_size),
+    };
+    let fwd_uri = fwd_req.uri().clone();
+    let chunked = fwd_req.headers().get(CONTENT_TYPE) == Some(&*CHUNKED_MEDIA_TYPE);
+    let _tracked = match inflight.track(fwd_req.uri()) {
+        Some(tracked) => tracked,
+        None => {
+            let failure = UpstreamFailure::Overloaded;
+            debug!(failure = failure.as_str(), "Too many requests in flight to {}", fwd_uri);
+            metrics.inflight_rejected();
+            metrics.record_failure(&gateway_origin, failure);
+            events::gateway_error(&relay.config.events, &gateway_origin, failure);
+            return Err(Error::ServiceUnavailable { retry_after: Duration::from_secs(1) });
+        }
+    };
+    let _forwarding = metrics.forward_started();
+    let declared_length = declared_content_length(&fwd_req);
+    if let (Ok(Some(declared)), Some(limit)) = (&declared_length, max_body_size) {
+        if *declared > limit {
+            return Err(Error::PayloadTooLarge);
+        }
+    }
+    if let (Some(padding), Ok(Some(declared))) = (&config.padding, &declared_length) {
+        padding::pad(fwd_req.headers_mut(), *declared, padding);
+    }
+    let expected_length = if config.enforce_content_length { declared_length? } else { None };
+    for hook in &config.hooks {
+        let headers = fwd_req.headers_mut();
+        let meta =
+            ForwardMeta { peer_addr, client_headers: &client_headers, uri: &fwd_uri, headers };
+        allowed(hook.before_forward(meta).await)?;
+    }
+    if let Some(breakers) = gateways.circuit_breakers() {
+        breakers.admit(&gateway_origin).inspect_err(|_e| {
+            let failure = UpstreamFailure::CircuitOpen;
+            debug!(failure = failure.as_str(), "Circuit to {} is open", gateway_origin.origin());
+            metrics.record_failure(&gateway_origin, failure);
+            events::gateway_error(&relay.config.events, &gateway_origin, failure);
+        })?;
+    }
+    // The request is accepted, so let the client send its body while the gateway is reached.
+    let mut fwd_req = fwd_req.map(ReadAhead::new);
+    if expects_continue {
+        fwd_req.body_mut().start().await;
+    }
+    let fwd_req = fwd_req.map(|body| match config.body_read_timeout {
+        Some(timeout) => IdleTimeout::new(body, timeout).boxed(),
+        None => body.map_err(Into::into).boxed(),
+    });
+    let fwd_req = match expected_length {
+        Some(expected) => fwd_req.map(|body| ExactLength::new(body, expected).boxed()),
+        None => fwd_req,
+    };
+    let fwd_req = match max_body_size {
+        Some(limit) => fwd_req.map(|body| LengthLimit::new(body, limit).boxed()),
+        None => fwd_req,
+    };
+    let fwd_req = match config.streaming.flush_threshold {
+        Some(threshold) => fwd_req.map(|body| Coalesce::new(body, threshold).boxed()),
+        None => fwd_req,
+    };
+    if let Some(jitter) = &config.jitter {
+        jitter::delay(jitter.max_forward_delay).await;
+    }
+    let started = Instant::now();
+    // A chunked request is streamed to the gateway, so there is no body to send again.
+    let retry = config.retry.as_ref().filter(|_| !chunked);
+    let redirect_policy = match config.redirect_policy {
+        RedirectPolicy::FollowSameOrigin { .. } if chunked => RedirectPolicy::Refuse,
+        policy => policy,
+    };
+    let res = match redirect_policy {
+        RedirectPolicy::PassThrough =>
+            forward_with_retries(fwd_req, client, config.response_timeout, retry).await,
+        RedirectPolicy::Refuse =>
+            forward_with_retries(fwd_req, client, config.response_timeout, retry).await.and_then(
+                |res| {
+                    if res.status().is_redirection() {
+                        Err(Error::BadGateway)
+                    } else {
+                        Ok(res)
+                    }
+                },
+            ),
+        RedirectPolicy::FollowSameOrigin { max_redirects } =>
+            follow_redirects(
+                fwd_req,
+                &gateway_origin,
+                max_redirects,
+                client,
+                config.response_timeout,
+                retry,
+            )
+            .await,
+    };
+    let upstream_latency = started.elapsed();
+    let status = res.as_ref().map(|res| res.status());
+    metrics.observe_upstream_latency(upstream_latency);
+    metrics.observe_gateway_latency(&gateway_origin, status, upstream_latency);
+    metrics.record_forward(&gateway_origin, status);
+    match &res {
+        Ok(res) => events::emit(&relay.config.events, || RelayEvent::RequestForwarded {
+            gateway: gateway_origin.clone(),
+            status: res.status(),
+            latency: upstream_latency,
+        }),
+        Err(Error::Upstream(failure)) =>
+            events::gateway_error(&relay.config.events, &gateway_origin, *failure),
+        Err(_) => {}
+    }
+    if let Some(breakers) = gateways.circuit_breakers() {
+        let failed =
+            matches!(res, Err(Error::BadGateway | Error::GatewayTimeout | Error::Upstream(_)));
+        let was_open = breakers.is_open(&gateway_origin);
+        breakers.record(&gateway_origin, !failed);
+        let open = breakers.is_open(&gateway_origin);
+        metrics.record_circuit(&gateway_origin, open);
+        if open != was_open {
+            events::emit(&relay.config.events, || RelayEvent::CircuitChanged {
+                gateway: gateway_origin.clone(),
+                open,
+            });
+        }
+    }
+    let mut res = res?;
+    if config.validate_gateway_responses {
+        validate_gateway_response(&res, chunked)?;
+    }
+    if config.content_encoding == ContentEncoding::Strip {
+        res.headers_mut().remove(ACCEPT_ENCODING);
+        res.headers_mut().remove(CONTENT_ENCODING);
+    }
+    for name in &config.scrubbed_response_headers {
+        res.headers_mut().remove(name);
+    }
+    let mut res = match config.max_response_body_size {
+        Some(limit) if !chunked => buffer_response(res, limit).await?,
+        _ => {
+            let (parts, body) = res.into_parts();
+            let boxed_body = match config.streaming.flush_threshold {
+                Some(threshold) => BoxBody::new(Coalesce::new(body, threshold)),
+                None => BoxBody::new(body),
+            };
+            Response::from_parts(parts, boxed_body)
+        }
+    };
+    for hook in &config.hooks {
+        let status = res.status();
+        let meta = ResponseMeta { peer_addr, uri: &fwd_uri, status, headers: res.headers_mut() };
+        hook.after_response(meta).await;
+    }
+    if let Some(jitter) = &config.jitter {
+        jitter::delay(jitter.max_response_delay).await;
+    }
+    Ok(res)
+}
+
+/// Read the whole gateway response, failing with 502 Bad Gateway past `limit` bytes so an
+/// oversized response is never partially forwarded.
+async fn buffer_response(
+    res: Response<Incoming>,
+    limit: u64,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
-    let fwd_req = into_forward_req(req, gateway_origin)?;
-    forward_request(fwd_req).await.map(|res| {
-        let (parts, body) = res.into_parts();
-        let boxed_body = BoxBody::new(body);
-        Response::from_parts(parts, boxed_body)
+    let (parts, body) = res.into_parts();
+    let declared_length = parts.headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok());
+    if declared_length.and_then(|v| v.parse::<u64>().ok()).is_some_and(|len| len > limit) {
+        error!("Gateway response declares more than {} bytes", limit);
+        return Err(Error::BadGateway);
+    }
+    let body = Buffered::read(LengthLimit::new(body, limit)).await.map_err(|e| {
+        error!("Failed to read gateway response: {}", e);
+        Error::BadGateway
+    })?;
+    Ok(Response::from_parts(parts, body.map_err(|never| match never {}).boxed()))
+}
+
+/// Only a 200 OK carrying an encapsulated response, chunked if the request was, a redirect left
+/// to the redirect policy, or an error status make sense as an answer to a relayed OHTTP request.
+fn validate_gateway_response<B>(res: &Response<B>, chunked: bool) -> Result<(), Error> {
+    let status = res.status();
+    let media_type = match chunked {
+        true => &*CHUNKED_GATEWAY_RESPONSE_MEDIA_TYPE,
+        false => &*GATEWAY_RESPONSE_MEDIA_TYPE,
+    };
+    let valid = match status {
+        StatusCode::OK => res.headers().get(CONTENT_TYPE) == Some(media_type),
+        _ => !status.is_informational() && !status.is_success(),
+    };
+    if !valid {
+        error!(
+            "Gateway answered with {} and Content-Type {:?}",
+            status,
+            res.headers().get(CONTENT_TYPE)
+        );
+        return Err(Error::BadGateway);
+    }
+    Ok(())
+}
+
+/// Forward `req`, re-sending it to same-origin redirect locations at most `max_redirects` times.
+///
+/// The body is buffered so it can be sent again. Every redirect is followed with the original
+/// method, since a relayed OHTTP request means nothing as any other method.
+#[instrument(skip(req, client))]
+async fn follow_redirects(
+    req: Request<BoxBody<Bytes, BoxError>>,
+    gateway_origin: &GatewayUri,
+    max_redirects: usize,
+    client: &UpstreamClient,
+    response_timeout: Option<Duration>,
+    retry: Option<&Retry>,
+) -> Result<Response<Incoming>, Error> {
+    let (mut parts, body) = req.into_parts();
+    let body = buffer_request_body(body).await?;
+    for _ in 0..=max_redirects {
+        let res = send_buffered(&parts, &body, client, response_timeout, retry).await?;
+        if !res.status().is_redirection() {
+            return Ok(res);
+        }
+        parts.uri = same_origin_location(&res, gateway_origin).ok_or_else(|| {
+            debug!("Refusing gateway redirect: {:?}", res.headers().get(LOCATION));
+            Error::BadGateway
+        })?;
+    }
+    debug!("Gateway exceeded the maximum of {} redirects", max_redirects);
+    Err(Error::BadGateway)
+}
+
+/// Forward `req`, re-sending it after connect-level failures as `retry` allows.
+async fn forward_with_retries(
+    req: Request<BoxBody<Bytes, BoxError>>,
+    client: &UpstreamClient,
+    response_timeout: Option<Duration>,
+    retry: Option<&Retry>,
+) -> Result<Response<Incoming>, Error> {
+    if retry.is_none() {
+        return forward_request(req, client, response_timeout).await;
+    }
+    let (parts, body) = req.into_parts();
+    let body = buffer_request_body(body).await?;
+    send_buffered(&parts, &body, client, response_timeout, retry).await
+}
+
+/// Send a request with an already buffered body, re-sending it while the connection to the
+/// gateway fails to be established and attempts remain.
+async fn send_buffered(
+    parts: &http::request::Parts,
+    body: &Buffered,
+    client: &UpstreamClient,
+    response_timeout: Option<Duration>,
+    retry: Option<&Retry>,
+) -> Result<Response<Incoming>, Error> {
+    let max_attempts = retry.map_or(1, |retry| retry.max_attempts.max(1));
+    let mut attempt = 1;
+    loop {
+        let mut req = Request::new(body.clone().map_err(|never| match never {}).boxed());
+        *req.method_mut() = parts.method.clone();
+        *req.uri_mut() = parts.uri.clone();
+        *req.headers_mut() = parts.headers.clone();
+        match send_upstream(req, client, response_timeout).await? {
+            Ok(res) => return Ok(res),
+            Err(e) if e.is_connect() && attempt < max_attempts => {
+                let delay = retry::backoff(retry.expect("attempts beyond the first"), attempt);
+                info!("Retrying gateway in {:?} after connect failure: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(upstream_error(e)),
+        }
+    }
+}
+
+async fn buffer_request_body(body: BoxBody<Bytes, BoxError>) -> Result<Buffered, Error> {
+    Buffered::read(body).await.map_err(|e| {
+        request_body_error(e.as_ref())
+            .unwrap_or_else(|| Error::BadRequest("Failed to read request body".to_owned()))
     })
 }
 
-/// Convert an incoming request into a request to forward to the target gateway server.
-#[instrument]
-fn into_forward_req(
-    mut req: Request<Incoming>,
-    gateway_origin: &Uri,
-) -> Result<Request<Incoming>, Error> {
+/// The absolute target of a redirect response if it stays on the gateway's origin.
+fn same_origin_location<B>(res: &Response<B>, gateway_origin: &GatewayUri) -> Option<Uri> {
+    let location: Uri = res.headers().get(LOCATION)?.to_str().ok()?.parse().ok()?;
+    if location.authority().is_none() {
+        return Uri::builder()
+            .scheme(gateway_origin.scheme_str()?)
+            .authority(gateway_origin.authority()?.as_str())
+            .path_and_query(location.path_and_query()?.as_str())
+            .build()
+            .ok();
+    }
+    // Only followed to the gateway's own origin, so its scheme was allowed already.
+    let location = GatewayUri::new(location, true).ok()?;
+    if location.same_origin(gateway_origin) {
+        Some(location.into())
+    } else {
+        None
+    }
+}
+
+/// Whether `TE` lists `trailers`, e.g. `TE: trailers, deflate;q=0.5`.
+fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            coding.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("trailers")
+        })
+}
+
+/// Convert an incoming request into a request to forward to the gateway it selects, at the
+/// path `path_rewrite` translates its target to, with content-coding headers kept as
+/// `content_encoding` says.
+#[instrument(skip_all)]
+fn into_forward_req<B>(
+    mut req: Request<B>,
+    peer_addr: Option<SocketAddr>,
+    gateways: &Gateways,
+    path_rewrite: &PathRewrite,
+    content_encoding: ContentEncoding,
+) -> Result<(Request<B>, GatewayUri, HeaderMap), Error> {
     if req.method() != hyper::Method::POST {
-        return Err(Error::MethodNotAllowed);
+        return Err(Error::MethodNotAllowed(HeaderValue::from_static("POST")));
     }
-    let content_type_header = req.headers().get(CONTENT_TYPE).cloned();
-    let content_length_header = req.headers().get(CONTENT_LENGTH).cloned();
-    req.headers_mut().clear();
+    let client_headers = std::mem::take(req.headers_mut());
     req.headers_mut().insert(HOST, OHTTP_RELAY_HOST.to_owned());
-    if content_type_header != Some(EXPECTED_MEDIA_TYPE.to_owned()) {
-        return Err(Error::UnsupportedMediaType);
+    // The client may have spoken HTTP/2 to the relay. Leave the gateway's protocol to ALPN.
+    *req.version_mut() = hyper::Version::HTTP_11;
+    match client_headers.get(CONTENT_TYPE) {
+        Some(content_type)
+            if content_type == *EXPECTED_MEDIA_TYPE || content_type == *CHUNKED_MEDIA_TYPE =>
+            req.headers_mut().insert(CONTENT_TYPE, content_type.clone()),
+        _ => return Err(Error::UnsupportedMediaType),
+    };
+    // `Transfer-Encoding` is hop-by-hop and never forwarded: hyper frames a body of unknown
+    // length as chunked again on its way to the gateway, or with HTTP/2's own framing.
+    if let Some(content_length) = client_headers.get(CONTENT_LENGTH) {
+        req.headers_mut().insert(CONTENT_LENGTH, content_length.clone());
+    }
+    // HTTP/1 only carries trailers that are announced, and back to clients that accept them.
+    for value in client_headers.get_all(TRAILER) {
+        req.headers_mut().append(TRAILER, value.clone());
     }
-    if let Some(content_length) = content_length_header {
-        req.headers_mut().insert(CONTENT_LENGTH, content_length);
+    if accepts_trailers(&client_headers) {
+        req.headers_mut().insert(TE, HeaderValue::from_static("trailers"));
+    }
+    if content_encoding == ContentEncoding::PassThrough {
+        for name in [ACCEPT_ENCODING, CONTENT_ENCODING] {
+            for value in client_headers.get_all(&name) {
+                req.headers_mut().append(name.clone(), value.clone());
+            }
+        }
     }
 
     let req_path_and_query =
         req.uri().path_and_query().map_or_else(|| PathAndQuery::from_static("/"), |pq| pq.clone());
+    let meta = SelectMeta { peer_addr, target: &req_path_and_query, headers: &client_headers };
+    let (gateway_origin, req_path_and_query) = gateways.select(&meta)?;
+    let req_path_and_query = path_rewrite.apply(req_path_and_query)?;
 
     *req.uri_mut() = Uri::builder()
         .scheme(gateway_origin.scheme_str().unwrap_or("https"))
@@ -170,31 +1786,117 @@ fn into_forward_req(
         .path_and_query(req_path_and_query.as_str())
         .build()
         .map_err(|_| Error::BadRequest("Invalid target uri".to_owned()))?;
-    Ok(req)
+    Ok((req, gateway_origin, client_headers))
 }
 
-#[instrument]
-async fn forward_request(req: Request<Incoming>) -> Result<Response<Incoming>, Error> {
-    let https =
-        HttpsConnectorBuilder::new().with_webpki_roots().https_or_http().enable_http1().build();
-    let client = Client::builder(TokioExecutor::new()).build(https);
-    client.request(req).await.map_err(|_| Error::BadGateway)
+fn declared_content_length<B>(req: &Request<B>) -> Result<Option<u64>, Error> {
+    req.headers()
+        .get(CONTENT_LENGTH)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or_else(|| Error::BadRequest("Invalid Content-Length".to_owned()))
+        })
+        .transpose()
 }
 
-#[instrument]
-pub(crate) fn uri_to_addr(uri: &Uri) -> Option<SocketAddr> {
-    let authority = uri.authority()?;
+/// The client used to reach the gateway. Cloning it shares its connection pool.
+pub(crate) type UpstreamClient = Client<HttpsConnector<GatewayConnector>, BoxBody<Bytes, BoxError>>;
 
-    let host = authority.host();
-    let port = authority.port_u16().or_else(|| {
-        match uri.scheme_str() {
-            Some("https") => Some(443),
-            _ => Some(80), // Default to 80 if it's not https or if the scheme is not specified
-        }
-    })?;
-    let addr = (host, port).to_socket_addrs().ok()?.next()?;
-    debug!("Resolved address: {:?}", addr);
-    Some(addr)
+/// Build the client once so every forwarded request can reuse pooled gateway connections
+/// instead of paying for a fresh TCP connect and TLS handshake.
+fn upstream_client(tls_config: ClientConfig, config: &Config) -> Result<UpstreamClient, BoxError> {
+    #[cfg(feature = "tor-client")]
+    if let Some(dirs) = &config.tor {
+        let tor = connector::TorDialer::new(dirs, config.connect_timeout)?;
+        return Ok(https_client(GatewayConnector::Tor(Box::new(tor)), tls_config, config));
+    }
+    let tcp = match config.socks5_proxy {
+        Some(proxy) => GatewayConnector::Socks5 {
+            proxy,
+            connect_timeout: config.connect_timeout,
+            tcp: config.gateway_tcp.clone(),
+        },
+        None => GatewayConnector::Direct {
+            resolver: config.resolver.clone(),
+            discovery: config.gateway_discovery.then(Default::default),
+            connect_timeout: config.connect_timeout,
+            attempt_delay: config.connection_attempt_delay,
+            tcp: config.gateway_tcp.clone(),
+        },
+    };
+    Ok(https_client(tcp, tls_config, config))
+}
+
+fn https_client(
+    tcp: GatewayConnector,
+    tls_config: ClientConfig,
+    config: &Config,
+) -> UpstreamClient {
+    // ALPN is already set by `tls::client_config`, which the connector builder would overwrite.
+    let mut https = HttpsConnector::from((tcp, tls_config));
+    if !config.danger_allow_insecure_gateway {
+        https.enforce_https();
+    }
+    let mut client = Client::builder(TokioExecutor::new());
+    client
+        .pool_max_idle_per_host(config.gateway_pool.max_idle_per_host.unwrap_or(usize::MAX))
+        .pool_idle_timeout(config.gateway_pool.idle_timeout)
+        .pool_timer(TokioTimer::new());
+    if let Some(max) = config.streaming.max_buffered_bytes {
+        client
+            .http1_max_buf_size(max as usize)
+            .http2_initial_stream_window_size(max)
+            .http2_initial_connection_window_size(max)
+            .http2_max_send_buf_size(max as usize);
+    }
+    client.build(https)
+}
+
+/// Send `req` to the gateway, giving up with 504 Gateway Timeout if connecting takes longer
+/// than [`Config::connect_timeout`] or the response headers take longer than `response_timeout`.
+async fn forward_request(
+    req: Request<BoxBody<Bytes, BoxError>>,
+    client: &UpstreamClient,
+    response_timeout: Option<Duration>,
+) -> Result<Response<Incoming>, Error> {
+    send_upstream(req, client, response_timeout).await?.map_err(upstream_error)
+}
+
+/// Send `req` to the gateway, failing with 504 Gateway Timeout if no response arrives within
+/// `response_timeout` but leaving client errors to the caller.
+#[instrument(
+    name = "forward",
+    skip_all,
+    fields(gateway = %req.uri().authority().map_or("", |a| a.as_str()), status)
+)]
+async fn send_upstream(
+    req: Request<BoxBody<Bytes, BoxError>>,
+    client: &UpstreamClient,
+    response_timeout: Option<Duration>,
+) -> Result<Result<Response<Incoming>, hyper_util::client::legacy::Error>, Error> {
+    let res = match response_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, client.request(req)).await.map_err(|_| {
+            let failure = UpstreamFailure::ResponseTimeout;
+            error!(failure = failure.as_str(), "Gateway did not answer within {:?}", timeout);
+            Error::Upstream(failure)
+        }),
+        None => Ok(client.request(req).await),
+    };
+    if let Ok(Ok(res)) = &res {
+        tracing::Span::current().record("status", res.status().as_u16());
+    }
+    res
+}
+
+fn upstream_error(e: hyper_util::client::legacy::Error) -> Error {
+    request_body_error(&e).unwrap_or_else(|| {
+        let failure = UpstreamFailure::of(&e);
+        error!(failure = failure.as_str(), "Failed to forward to gateway: {}", e);
+        Error::Upstream(failure)
+    })
 }
 
 pub(crate) fn empty() -> BoxBody<Bytes, hyper::Error> {
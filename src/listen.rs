@@ -0,0 +1,370 @@
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use rustls::ServerConfig;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::net::Listener;
+use tokio_util::task::TaskTracker;
+use tracing::info;
+
+#[cfg(unix)]
+use crate::activation::{self, Inherited};
+use crate::body::BoxError;
+use crate::dual_stack::DualStackListener;
+#[cfg(windows)]
+use crate::named_pipe::NamedPipeListener;
+#[cfg(feature = "tor-listener")]
+use crate::onion::OnionListener;
+#[cfg(unix)]
+use crate::socket_file;
+#[cfg(all(feature = "vsock", target_os = "linux"))]
+use crate::vsock::VsockListener;
+#[cfg(feature = "tor-listener")]
+use crate::OnionService;
+use crate::{accept_connections, bind_with_retry, tcp, BandwidthLimit, Config, Relay, RelayError};
+
+/// Where the relay accepts connections, see [`crate::Builder::add_listener`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Listen {
+    /// TCP on this address.
+    Tcp(SocketAddr),
+    /// TCP on this port of every IPv4 and IPv6 interface, see [`crate::Builder::dual_stack`].
+    DualStack(u16),
+    /// A unix socket at this path, set up as [`Config::socket_file`] says. On Linux, a path
+    /// starting with `@`, e.g. `@ohttp-relay`, names an abstract socket that has no file.
+    #[cfg(unix)]
+    Socket(PathBuf),
+    /// The socket passed by systemd socket activation, see [`crate::listen_activated`].
+    #[cfg(unix)]
+    Activated,
+    /// A Windows named pipe, see [`crate::listen_named_pipe`].
+    #[cfg(windows)]
+    NamedPipe(String),
+    /// A Tor onion service, see [`crate::listen_onion`].
+    #[cfg(feature = "tor-listener")]
+    Onion(OnionService),
+    /// A vsock port of this context ID, see [`crate::listen_vsock`].
+    #[cfg(all(feature = "vsock", target_os = "linux"))]
+    Vsock { cid: u32, port: u32 },
+}
+
+/// Called once every listener is bound and before any connection is accepted, see
+/// [`Config::on_bound`].
+#[derive(Clone)]
+pub struct OnBound(Arc<dyn Fn() -> Result<(), BoxError> + Send + Sync>);
+
+impl OnBound {
+    pub fn new(on_bound: impl Fn() -> Result<(), BoxError> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(on_bound))
+    }
+
+    pub(crate) fn call(&self) -> Result<(), RelayError> { (self.0)().map_err(RelayError::OnBound) }
+}
+
+impl std::fmt::Debug for OnBound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("OnBound") }
+}
+
+/// Where a bound listener accepts connections, see [`Config::on_listening`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Listening {
+    /// TCP on this address, with the port the operating system picked if port 0 was asked for.
+    Tcp(SocketAddr),
+    /// A unix socket at this path, `@`-prefixed for an abstract one, or an unnamed one.
+    #[cfg(unix)]
+    Socket(Option<PathBuf>),
+    /// A Windows named pipe of this name.
+    #[cfg(windows)]
+    NamedPipe(String),
+    /// A Tor onion service at this `.onion` address.
+    #[cfg(feature = "tor-listener")]
+    Onion(String),
+    /// A vsock port of this context ID.
+    #[cfg(all(feature = "vsock", target_os = "linux"))]
+    Vsock { cid: u32, port: u32 },
+    /// HTTP/3 on this UDP address, see [`crate::listen_quic`].
+    #[cfg(feature = "h3")]
+    Quic(SocketAddr),
+}
+
+impl std::fmt::Display for Listening {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp://{}", addr),
+            #[cfg(unix)]
+            Self::Socket(Some(path)) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Self::Socket(None) => write!(f, "unnamed unix socket"),
+            #[cfg(windows)]
+            Self::NamedPipe(name) => write!(f, "named pipe {}", name),
+            #[cfg(feature = "tor-listener")]
+            Self::Onion(address) => write!(f, "onion service {}", address),
+            #[cfg(all(feature = "vsock", target_os = "linux"))]
+            Self::Vsock { cid, port } => write!(f, "vsock://{}:{}", cid, port),
+            #[cfg(feature = "h3")]
+            Self::Quic(addr) => write!(f, "quic://{}", addr),
+        }
+    }
+}
+
+/// A listener bound for [`Listen`], ready to accept connections.
+pub(crate) enum Bound {
+    Tcp(TcpListener),
+    /// Sockets sharing one address, see [`Config::acceptors`].
+    ReusePort(Vec<TcpListener>),
+    DualStack(DualStackListener),
+    /// Along with the guard removing the socket file, if it is to be removed on shutdown.
+    #[cfg(unix)]
+    Unix(UnixListener, Option<socket_file::Unlink>),
+    #[cfg(windows)]
+    NamedPipe(NamedPipeListener),
+    #[cfg(feature = "tor-listener")]
+    Onion(OnionListener),
+    #[cfg(all(feature = "vsock", target_os = "linux"))]
+    Vsock(VsockListener),
+}
+
+impl Bound {
+    /// Bind `listen`, retrying as [`Config::bind_retry`] says.
+    pub(crate) async fn bind(listen: Listen, config: &Config) -> Result<Self, RelayError> {
+        match listen {
+            Listen::Tcp(addr) if config.acceptors > 1 => Ok(Self::ReusePort(
+                bind_with_retry(config.bind_retry, || async {
+                    tcp::bind_reuse_port(addr, config.listen_backlog, config.acceptors)
+                })
+                .await?,
+            )),
+            Listen::Tcp(addr) => Ok(Self::Tcp(
+                bind_with_retry(config.bind_retry, || async {
+                    tcp::bind(addr, config.listen_backlog)
+                })
+                .await?,
+            )),
+            Listen::DualStack(port) => Ok(Self::DualStack(
+                bind_with_retry(config.bind_retry, || async {
+                    DualStackListener::bind(port, config.listen_backlog)
+                })
+                .await?,
+            )),
+            #[cfg(unix)]
+            Listen::Socket(path) => {
+                let settings = &config.socket_file;
+                let listener = bind_with_retry(config.bind_retry, || async {
+                    socket_file::bind(&path, settings)
+                })
+                .await?;
+                info!("OHTTP relay listening on socket: {}", path.display());
+                let unlink = (settings.unlink_on_shutdown
+                    && socket_file::abstract_name(&path).is_none())
+                .then(|| socket_file::Unlink(path));
+                Ok(Self::Unix(listener, unlink))
+            }
+            #[cfg(unix)]
+            Listen::Activated => {
+                let fd = activation::listen_fd().map_err(RelayError::Config)?;
+                // Safety: systemd hands the passed sockets to this process alone.
+                Self::inherit(unsafe { Inherited::from_fd(fd)? })
+            }
+            #[cfg(windows)]
+            Listen::NamedPipe(name) => {
+                let listener = NamedPipeListener::bind(&name)?;
+                info!("OHTTP relay listening on named pipe: {}", name);
+                Ok(Self::NamedPipe(listener))
+            }
+            #[cfg(feature = "tor-listener")]
+            Listen::Onion(service) => {
+                let listener = OnionListener::launch(&service).await.map_err(RelayError::Tor)?;
+                info!("OHTTP relay listening on onion service: {}", listener.address());
+                Ok(Self::Onion(listener))
+            }
+            #[cfg(all(feature = "vsock", target_os = "linux"))]
+            Listen::Vsock { cid, port } => {
+                let listener =
+                    bind_with_retry(config.bind_retry, || async { VsockListener::bind(cid, port) })
+                        .await?;
+                info!("OHTTP relay listening on vsock://{}:{}", cid, port);
+                Ok(Self::Vsock(listener))
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn inherit(inherited: Inherited) -> Result<Self, RelayError> {
+        match inherited {
+            Inherited::Tcp(listener) => Ok(Self::Tcp(TcpListener::from_std(listener)?)),
+            Inherited::Unix(listener) => {
+                let listener = UnixListener::from_std(listener)?;
+                info!("OHTTP relay listening on inherited socket: {:?}", listener.local_addr()?);
+                Ok(Self::Unix(listener, None))
+            }
+        }
+    }
+
+    /// Where this listener accepts connections.
+    pub(crate) fn listening(&self) -> std::io::Result<Listening> {
+        Ok(match self {
+            Self::Tcp(listener) => Listening::Tcp(listener.local_addr()?),
+            Self::ReusePort(listeners) => Listening::Tcp(listeners[0].local_addr()?),
+            Self::DualStack(listener) => Listening::Tcp(listener.local_addr()?),
+            #[cfg(unix)]
+            Self::Unix(listener, _) =>
+                Listening::Socket(match socket_file::local_abstract_name(listener)? {
+                    Some(name) => Some(name),
+                    None => listener.local_addr()?.as_pathname().map(Into::into),
+                }),
+            #[cfg(windows)]
+            Self::NamedPipe(listener) => Listening::NamedPipe(listener.local_addr()?),
+            #[cfg(feature = "tor-listener")]
+            Self::Onion(listener) => Listening::Onion(listener.address().to_owned()),
+            #[cfg(all(feature = "vsock", target_os = "linux"))]
+            Self::Vsock(listener) => {
+                let addr = listener.local_addr()?;
+                Listening::Vsock { cid: addr.cid, port: addr.port }
+            }
+        })
+    }
+
+    /// Whether TLS can be terminated on the accepted connections.
+    pub(crate) fn is_tcp(&self) -> bool {
+        matches!(self, Self::Tcp(_) | Self::ReusePort(_) | Self::DualStack(_))
+    }
+
+    /// Accept connections for `relay` into `connections` until shutdown or the listener fails,
+    /// terminating TLS first on TCP if `tls_config` is given and throttling each connection's
+    /// bodies to `bandwidth_limit`.
+    pub(crate) async fn accept(
+        self,
+        tls_config: Option<Arc<ServerConfig>>,
+        bandwidth_limit: Option<BandwidthLimit>,
+        relay: Arc<Relay>,
+        connections: TaskTracker,
+    ) -> Result<(), RelayError> {
+        match self {
+            Self::Tcp(listener) =>
+                accept_tcp(listener, tls_config, bandwidth_limit, relay, connections).await?,
+            Self::ReusePort(listeners) => {
+                let accepting = listeners.into_iter().map(|listener| {
+                    let accept = accept_tcp(
+                        listener,
+                        tls_config.clone(),
+                        bandwidth_limit.clone(),
+                        relay.clone(),
+                        connections.clone(),
+                    );
+                    Box::pin(accept) as Accepting
+                });
+                AcceptAll::new(accepting.collect()).await?
+            }
+            Self::DualStack(listener) =>
+                accept_tcp(listener, tls_config, bandwidth_limit, relay, connections).await?,
+            #[cfg(unix)]
+            Self::Unix(listener, _unlink) =>
+                accept_plain(listener, bandwidth_limit, relay, connections).await,
+            #[cfg(windows)]
+            Self::NamedPipe(listener) =>
+                accept_plain(listener, bandwidth_limit, relay, connections).await,
+            #[cfg(feature = "tor-listener")]
+            Self::Onion(listener) =>
+                accept_plain(listener, bandwidth_limit, relay, connections).await,
+            #[cfg(all(feature = "vsock", target_os = "linux"))]
+            Self::Vsock(listener) =>
+                accept_plain(listener, bandwidth_limit, relay, connections).await,
+        }
+        Ok(())
+    }
+}
+
+async fn accept_tcp<L>(
+    listener: L,
+    tls_config: Option<Arc<ServerConfig>>,
+    bandwidth_limit: Option<BandwidthLimit>,
+    relay: Arc<Relay>,
+    connections: TaskTracker,
+) -> Result<(), RelayError>
+where
+    L: Listener<Io = TcpStream, Addr = SocketAddr> + Unpin,
+{
+    let addr = listener.local_addr()?;
+    let options = relay.config.client_tcp.clone();
+    match tls_config {
+        Some(tls_config) => {
+            info!("OHTTP relay listening on tcp://{} with TLS", addr);
+            let acceptor = TlsAcceptor::from(tls_config);
+            let handshake = move |stream: TcpStream| {
+                let applied = tcp::apply(&stream, &options);
+                let accept = acceptor.accept(stream);
+                async move {
+                    applied?;
+                    accept.await
+                }
+            };
+            accept_connections(listener, relay, connections, bandwidth_limit, handshake).await
+        }
+        None => {
+            info!("OHTTP relay listening on tcp://{}", addr);
+            let handshake = move |stream: TcpStream| {
+                std::future::ready(tcp::apply(&stream, &options).map(|()| stream))
+            };
+            accept_connections(listener, relay, connections, bandwidth_limit, handshake).await
+        }
+    }
+    Ok(())
+}
+
+async fn accept_plain<L>(
+    listener: L,
+    bandwidth_limit: Option<BandwidthLimit>,
+    relay: Arc<Relay>,
+    connections: TaskTracker,
+) where
+    L: Listener + Unpin,
+    L::Io: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    L::Addr: 'static,
+{
+    let handshake = |stream| std::future::ready(Ok(stream));
+    accept_connections(listener, relay, connections, bandwidth_limit, handshake).await
+}
+
+type Accepting = Pin<Box<dyn Future<Output = Result<(), RelayError>> + Send>>;
+
+/// Runs accept loops on the task awaiting it, rather than spawning them, so dropping it closes
+/// every listener at once. Resolves to the first error once all have returned.
+pub(crate) struct AcceptAll {
+    accepting: Vec<Accepting>,
+    result: Result<(), RelayError>,
+}
+
+impl AcceptAll {
+    pub(crate) fn new(accepting: Vec<Accepting>) -> Self { Self { accepting, result: Ok(()) } }
+}
+
+impl Future for AcceptAll {
+    type Output = Result<(), RelayError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.accepting.retain_mut(|accept| match accept.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                if this.result.is_ok() {
+                    this.result = result;
+                }
+                false
+            }
+            Poll::Pending => true,
+        });
+        match this.accepting.is_empty() {
+            true => Poll::Ready(std::mem::replace(&mut this.result, Ok(()))),
+            false => Poll::Pending,
+        }
+    }
+}
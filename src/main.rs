@@ -1,38 +1,585 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use clap::{Parser, Subcommand};
 use http::Uri;
-use ohttp_relay::DEFAULT_PORT;
+use ohttp_relay::auth::{PrivacyPass, StaticToken};
+use ohttp_relay::bench::Bench;
+use ohttp_relay::config_file::{ConfigFile, ConfigWatcher};
+#[cfg(unix)]
+use ohttp_relay::daemon::{self, Credentials, PidFile};
+use ohttp_relay::resolve::SystemResolver;
+use ohttp_relay::{
+    AccessLog, AccessLogSink, Admin, AdminReload, ClientIdentity, Config, Jitter, Listen, OnBound,
+    SpkiPin, TlsVersion, DEFAULT_PORT,
+};
+use ring::rand::{SecureRandom, SystemRandom};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter};
 
+/// How often the configuration file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Relay Oblivious HTTP requests to a gateway, hiding clients' IP addresses from it.
+///
+/// Settings are taken from flags first, then `OHTTP_RELAY_*` environment variables, then the
+/// configuration file. Under systemd socket activation (`LISTEN_FDS` is set) the relay serves
+/// on the passed socket instead of binding one.
+#[derive(Debug, Clone, Parser)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML file to read settings from, watched for changes to the gateway list and limits.
+    #[arg(long, env = "OHTTP_RELAY_CONFIG")]
+    config: Option<PathBuf>,
+    /// OHTTP gateway origin to relay requests to.
+    #[arg(long, env = "OHTTP_RELAY_GATEWAY")]
+    gateway_origin: Option<Uri>,
+    /// TCP port to listen on [default: 3000].
+    #[arg(long, env = "OHTTP_RELAY_PORT", conflicts_with = "unix_socket")]
+    port: Option<u16>,
+    /// Address to listen on [default: 0.0.0.0].
+    #[arg(long, env = "OHTTP_RELAY_BIND_ADDR", conflicts_with = "unix_socket")]
+    bind_addr: Option<IpAddr>,
+    /// Listen on every IPv4 and IPv6 interface instead of `--bind-addr`.
+    #[arg(long, conflicts_with_all = ["bind_addr", "unix_socket"])]
+    dual_stack: bool,
+    /// Listen on a unix socket at this path instead of a TCP port, or `@name` for an abstract
+    /// socket on Linux.
+    #[arg(long, env = "OHTTP_RELAY_SOCKET")]
+    unix_socket: Option<PathBuf>,
+    /// Listen on this Windows named pipe, e.g. `\\.\pipe\ohttp-relay`, instead of a TCP port.
+    #[cfg(windows)]
+    #[arg(long, env = "OHTTP_RELAY_NAMED_PIPE", conflicts_with_all = ["port", "bind_addr"])]
+    named_pipe: Option<String>,
+    /// Publish the relay as a Tor onion service on port 80, keeping its keys and state in
+    /// this directory, instead of listening on a TCP port.
+    #[cfg(feature = "tor-listener")]
+    #[arg(
+        long,
+        env = "OHTTP_RELAY_ONION_DIR",
+        conflicts_with_all = ["port", "bind_addr", "unix_socket", "tls_cert"]
+    )]
+    onion_dir: Option<PathBuf>,
+    /// Octal permissions for the unix socket, e.g. `660`.
+    #[arg(long, value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+    /// Group ID to give the unix socket to.
+    #[arg(long)]
+    socket_group: Option<u32>,
+    /// Replace a leftover unix socket that nothing listens on anymore.
+    #[arg(long)]
+    remove_stale_socket: bool,
+    /// PEM certificate chain to terminate TLS with. Requires `--tls-key`.
+    #[arg(
+        long,
+        env = "OHTTP_RELAY_TLS_CERT",
+        requires = "tls_key",
+        conflicts_with = "unix_socket"
+    )]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key to terminate TLS with. Requires `--tls-cert`.
+    #[arg(long, env = "OHTTP_RELAY_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM CA certificates that clients must present a certificate signed by, rejecting
+    /// handshakes without one. Requires `--tls-cert`.
+    #[arg(long, env = "OHTTP_RELAY_TLS_CLIENT_CA", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// Terminate TLS with an ephemeral self-signed certificate for this hostname, printed on
+    /// startup, instead of `--tls-cert`. Repeatable. For local development only.
+    #[cfg(feature = "dev-tls")]
+    #[arg(long, conflicts_with_all = ["tls_cert", "unix_socket"])]
+    tls_self_signed: Vec<String>,
+    /// Obtain and renew a TLS certificate for this public hostname over ACME instead of
+    /// `--tls-cert`. Repeatable. Requires `--acme-dir`.
+    #[cfg(feature = "acme")]
+    #[arg(long, requires = "acme_dir", conflicts_with_all = ["tls_cert", "unix_socket"])]
+    acme_domain: Vec<String>,
+    /// Directory keeping the ACME account key and certificates.
+    #[cfg(feature = "acme")]
+    #[arg(long, env = "OHTTP_RELAY_ACME_DIR", requires = "acme_domain")]
+    acme_dir: Option<PathBuf>,
+    /// Contact for the ACME account, e.g. `mailto:admin@example.com`. Repeatable.
+    #[cfg(feature = "acme")]
+    #[arg(long)]
+    acme_contact: Vec<String>,
+    /// ACME directory URL [default: Let's Encrypt's production directory].
+    #[cfg(feature = "acme")]
+    #[arg(long)]
+    acme_directory: Option<String>,
+    /// PEM client certificate chain to present to the gateway. Requires `--gateway-client-key`.
+    #[arg(long, env = "OHTTP_RELAY_GATEWAY_CLIENT_CERT", requires = "gateway_client_key")]
+    gateway_client_cert: Option<PathBuf>,
+    /// PEM private key for the gateway client certificate. Requires `--gateway-client-cert`.
+    #[arg(long, env = "OHTTP_RELAY_GATEWAY_CLIENT_KEY", requires = "gateway_client_cert")]
+    gateway_client_key: Option<PathBuf>,
+    /// Relay only requests carrying `Authorization: Bearer <token>` for this token. Repeatable,
+    /// to accept any of several.
+    #[arg(long, env = "OHTTP_RELAY_BEARER_TOKENS", value_delimiter = ',', hide_env_values = true)]
+    bearer_token: Vec<String>,
+    /// Relay only requests redeeming a Privacy Pass token from this issuer, e.g.
+    /// `issuer.example`. Requires `--privacy-pass-key`.
+    #[arg(
+        long,
+        env = "OHTTP_RELAY_PRIVACY_PASS_ISSUER",
+        requires = "privacy_pass_key",
+        conflicts_with = "bearer_token"
+    )]
+    privacy_pass_issuer: Option<String>,
+    /// The Privacy Pass issuer's base64url public token key. Requires `--privacy-pass-issuer`.
+    #[arg(long, env = "OHTTP_RELAY_PRIVACY_PASS_KEY", requires = "privacy_pass_issuer")]
+    privacy_pass_key: Option<String>,
+    /// Allow `http://` gateway origins and loopback, link-local or private gateway addresses for
+    /// development. Never use in production.
+    #[arg(long)]
+    danger_allow_insecure_gateway: bool,
+    /// Base64 SHA-256 hash of a public key the gateway's certificate must carry. Repeatable.
+    #[arg(long)]
+    pinned_spki: Vec<SpkiPin>,
+    /// TLS version to offer the gateway, `1.2` or `1.3`. Repeatable. Both by default.
+    #[arg(long)]
+    tls_version: Vec<TlsVersion>,
+    /// IANA name of a cipher suite to offer the gateway, e.g. `TLS13_AES_256_GCM_SHA384`.
+    /// Repeatable, in order of preference. All supported suites by default.
+    #[arg(long)]
+    tls_cipher_suite: Vec<String>,
+    /// Gateway TLS sessions remembered for resumption, or 0 to disable resumption.
+    #[arg(long)]
+    tls_session_cache_size: Option<usize>,
+    /// Seconds to wait for a connection to the gateway.
+    #[arg(long, value_parser = parse_secs)]
+    connect_timeout: Option<Duration>,
+    /// Seconds to wait for the gateway's response headers.
+    #[arg(long, value_parser = parse_secs)]
+    response_timeout: Option<Duration>,
+    /// Seconds a relayed exchange may take until its response is fully streamed back.
+    #[arg(long, value_parser = parse_secs)]
+    request_deadline: Option<Duration>,
+    /// Seconds a client may take to send its request headers.
+    #[arg(long, value_parser = parse_secs)]
+    header_read_timeout: Option<Duration>,
+    /// Seconds a client may stall while sending a request body.
+    #[arg(long, value_parser = parse_secs)]
+    body_read_timeout: Option<Duration>,
+    /// Seconds before closing a client connection with no request in progress.
+    #[arg(long, value_parser = parse_secs)]
+    idle_timeout: Option<Duration>,
+    /// Reach the gateway through the SOCKS5 proxy at this address, e.g. 127.0.0.1:9050 for Tor.
+    #[arg(long)]
+    socks5_proxy: Option<SocketAddr>,
+    /// Reach the gateway over Tor with an embedded client keeping its state in this directory,
+    /// instead of a SOCKS5 proxy. Also reaches `.onion` gateways.
+    #[cfg(feature = "tor-client")]
+    #[arg(long)]
+    tor_dir: Option<PathBuf>,
+    /// Dial the endpoint each gateway's DNS HTTPS records advertise.
+    #[arg(long)]
+    gateway_discovery: bool,
+    /// Query HTTPS records from the DNS server at this address instead of the first one in
+    /// /etc/resolv.conf.
+    #[arg(long, requires = "gateway_discovery")]
+    discovery_nameserver: Option<SocketAddr>,
+    /// Read client addresses from PROXY protocol headers sent by a load balancer.
+    #[arg(long)]
+    proxy_protocol: bool,
+    /// Refuse OHTTP key bootstrap requests.
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap", feature = "wt-bootstrap"))]
+    #[arg(long)]
+    no_bootstrap: bool,
+    /// Serve Prometheus metrics at /metrics on this address.
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Serve health, stats, metrics and `POST /reload` on a unix socket at this path instead
+    /// of the relay's listeners.
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "admin_addr")]
+    admin_socket: Option<PathBuf>,
+    /// Serve health, stats, metrics and `POST /reload` on this loopback address instead of
+    /// the relay's listeners.
+    #[arg(long)]
+    admin_addr: Option<SocketAddr>,
+    /// Pad relayed messages to uniform size buckets.
+    #[arg(long)]
+    padding: bool,
+    /// Hold each relayed request and response for a random time of up to this many seconds.
+    #[arg(long, value_parser = parse_secs)]
+    max_jitter: Option<Duration>,
+    /// Log each request's method, path class, status and latency, but nothing identifying
+    /// clients.
+    #[arg(long)]
+    access_log: bool,
+    /// Append the access log to this file as JSON lines instead. Implies `--access-log`.
+    #[arg(long)]
+    access_log_file: Option<PathBuf>,
+    /// Export spans and metrics to this OTLP gRPC collector, e.g. `http://localhost:4317`.
+    #[cfg(feature = "otel")]
+    #[arg(long, env = "OHTTP_RELAY_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+    /// Log filter, e.g. `info` or `ohttp_relay=debug`. Defaults to `RUST_LOG`.
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Fork into the background once listening, discarding further output. The starting
+    /// process exits with an error if the relay fails to start.
+    #[cfg(unix)]
+    #[arg(long)]
+    daemonize: bool,
+    /// Write the relay's process ID to this file, removing it on exit.
+    #[cfg(unix)]
+    #[arg(long, env = "OHTTP_RELAY_PID_FILE")]
+    pid_file: Option<PathBuf>,
+    /// Run as this user, by name or ID, once listening and TLS keys are read.
+    #[cfg(unix)]
+    #[arg(long, env = "OHTTP_RELAY_USER")]
+    user: Option<String>,
+    /// Run as this group, by name or ID, once listening. Defaults to the primary group of
+    /// `--user`.
+    #[cfg(unix)]
+    #[arg(long, env = "OHTTP_RELAY_GROUP")]
+    group: Option<String>,
+}
+
+impl Args {
+    /// Layer the environment and then these flags over the settings in `file`.
+    fn config(&self, file: &ConfigFile) -> Result<Config, BoxError> {
+        let mut config = file.apply(Config::default()).with_env()?;
+        config.connect_timeout = self.connect_timeout.or(config.connect_timeout);
+        config.response_timeout = self.response_timeout.or(config.response_timeout);
+        config.request_deadline = self.request_deadline.or(config.request_deadline);
+        config.header_read_timeout = self.header_read_timeout.or(config.header_read_timeout);
+        config.body_read_timeout = self.body_read_timeout.or(config.body_read_timeout);
+        config.idle_timeout = self.idle_timeout.or(config.idle_timeout);
+        config.socks5_proxy = self.socks5_proxy.or(config.socks5_proxy);
+        config.gateway_discovery |= self.gateway_discovery;
+        if let Some(nameserver) = self.discovery_nameserver {
+            config.resolver = Arc::new(SystemResolver { nameserver: Some(nameserver) });
+        }
+        config.proxy_protocol |= self.proxy_protocol;
+        config.danger_allow_insecure_gateway |= self.danger_allow_insecure_gateway;
+        config.socket_file.mode = self.socket_mode.or(config.socket_file.mode);
+        config.socket_file.group = self.socket_group.or(config.socket_file.group);
+        config.socket_file.remove_stale |= self.remove_stale_socket;
+        #[cfg(any(
+            feature = "connect-bootstrap",
+            feature = "ws-bootstrap",
+            feature = "wt-bootstrap"
+        ))]
+        {
+            config.bootstrap &= !self.no_bootstrap;
+        }
+        #[cfg(feature = "metrics")]
+        {
+            config.metrics_addr = self.metrics_addr.or(config.metrics_addr);
+        }
+        #[cfg(feature = "tor-client")]
+        if let Some(dir) = &self.tor_dir {
+            config.tor = Some(ohttp_relay::TorDirs::new(dir));
+        }
+        if !self.pinned_spki.is_empty() {
+            config.pinned_spki = self.pinned_spki.clone();
+        }
+        if !self.tls_version.is_empty() {
+            config.upstream_tls.versions = self.tls_version.clone();
+        }
+        if !self.tls_cipher_suite.is_empty() {
+            config.upstream_tls.cipher_suites = self.tls_cipher_suite.clone();
+        }
+        if let Some(size) = self.tls_session_cache_size {
+            config.upstream_tls.session_cache_size = size;
+        }
+        if let (Some(cert), Some(key)) = (&self.gateway_client_cert, &self.gateway_client_key) {
+            config.client_identity = Some(ClientIdentity { cert: cert.clone(), key: key.clone() });
+        }
+        if !self.bearer_token.is_empty() {
+            config.authorizer = Arc::new(StaticToken::any(self.bearer_token.clone()));
+        }
+        if let (Some(issuer), Some(key)) = (&self.privacy_pass_issuer, &self.privacy_pass_key) {
+            config.authorizer = Arc::new(PrivacyPass::new(issuer, key)?);
+        }
+        if self.padding {
+            config.padding = Some(config.padding.unwrap_or_default());
+        }
+        if let Some(max) = self.max_jitter {
+            config.jitter = Some(Jitter { max_forward_delay: max, max_response_delay: max });
+        }
+        if let Some(path) = &self.access_log_file {
+            let sink = AccessLogSink::JsonFile(path.clone());
+            config.access_log = Some(AccessLog { sink, ..AccessLog::default() });
+        } else if self.access_log {
+            config.access_log = Some(AccessLog::default());
+        }
+        Ok(config)
+    }
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Load a running relay with synthetic OHTTP requests and report latency percentiles and
+    /// error rates.
+    Bench {
+        /// The relay URL to post requests to, e.g. `http://127.0.0.1:3000/`.
+        relay: Uri,
+        /// Requests in flight at once.
+        #[arg(long, default_value = "16")]
+        concurrency: usize,
+        /// Requests to send in total.
+        #[arg(long, default_value = "1000")]
+        requests: usize,
+        /// File with an encapsulated request to send. Random bytes of `--body-size` otherwise,
+        /// which the gateway answers with an error of its own.
+        #[arg(long)]
+        body_file: Option<PathBuf>,
+        /// Bytes of random body to send without `--body-file`.
+        #[arg(long, default_value = "128", conflicts_with = "body_file")]
+        body_size: usize,
+    },
+}
+
+/// Kept alive until exit so buffered spans and metrics are flushed.
+#[cfg(feature = "otel")]
+type Telemetry = Option<ohttp_relay::otel::Otel>;
+#[cfg(not(feature = "otel"))]
+type Telemetry = Option<std::convert::Infallible>;
+
+/// Background the process if asked, which must happen before the runtime starts any thread.
+#[cfg(unix)]
+fn main() -> Result<(), BoxError> {
+    let args = Args::parse();
+    let credentials = Credentials::lookup(args.user.as_deref(), args.group.as_deref())?;
+    let daemon = match args.daemonize {
+        true => Some(daemon::daemonize()?),
+        false => None,
+    };
+    let _pid_file =
+        args.pid_file.as_deref().map(|path| PidFile::create(path, &credentials)).transpose()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(run(args, Started { credentials, daemon }))
+}
+
+#[cfg(not(unix))]
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    init_tracing();
-    let port_env = std::env::var("PORT");
-    let unix_socket_env = std::env::var("UNIX_SOCKET");
-    let gateway_origin_str = std::env::var("GATEWAY_ORIGIN").expect("GATEWAY_ORIGIN is required");
-    let gateway_origin = Uri::from_str(&gateway_origin_str).expect("Invalid GATEWAY_ORIGIN URI");
-
-    match (port_env, unix_socket_env) {
-        (Ok(_), Ok(_)) => panic!(
-            "Both PORT and UNIX_SOCKET environment variables are set. Please specify only one."
-        ),
-        (Err(_), Ok(unix_socket_path)) =>
-            ohttp_relay::listen_socket(&unix_socket_path, gateway_origin).await?,
-        (Ok(port_str), Err(_)) => {
-            let port: u16 = port_str.parse().expect("Invalid PORT");
-            ohttp_relay::listen_tcp(port, gateway_origin).await?
-        }
-        (Err(_), Err(_)) => ohttp_relay::listen_tcp(DEFAULT_PORT, gateway_origin).await?,
+async fn main() -> Result<(), BoxError> { run(Args::parse(), Started {}).await }
+
+/// What to do once the relay's listeners are bound, before it accepts connections.
+struct Started {
+    /// Who to run as from then on.
+    #[cfg(unix)]
+    credentials: Credentials,
+    /// Tells the foreground process to exit.
+    #[cfg(unix)]
+    daemon: Option<daemon::Daemon>,
+}
+
+impl Started {
+    fn bound(self) -> Result<(), BoxError> {
+        #[cfg(unix)]
+        {
+            self.credentials.drop_privileges()?;
+            if let Some(daemon) = self.daemon {
+                daemon.ready()?;
+            }
+        }
+        Ok(())
     }
+}
+
+async fn run(args: Args, started: Started) -> Result<(), BoxError> {
+    let _telemetry = init_tracing(&args)?;
+    if let Some(Command::Bench { relay, concurrency, requests, body_file, body_size }) =
+        args.command.clone()
+    {
+        let body = match body_file {
+            Some(path) => std::fs::read(path)?,
+            None => {
+                let mut body = vec![0; body_size];
+                SystemRandom::new().fill(&mut body).map_err(|_| "Failed to generate a body")?;
+                body
+            }
+        };
+        let bench = Bench { relay, concurrency, requests, body: body.into() };
+        println!("{}", bench.run().await?);
+        return Ok(());
+    }
+
+    let file = match &args.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+    // Unprefixed variables from before the `OHTTP_RELAY_` prefix are still honored.
+    let gateway_origin = match args.gateway_origin.clone() {
+        Some(origin) => Some(origin),
+        None => legacy_var("GATEWAY_ORIGIN")?,
+    };
+    let gateway_origin = gateway_origin
+        .or_else(|| file.gateway_origin.clone())
+        .ok_or("A gateway origin is required")?;
+    let port = match args.port {
+        Some(port) => Some(port),
+        None => legacy_var("PORT")?,
+    };
+    let unix_socket =
+        args.unix_socket.clone().or_else(|| std::env::var_os("UNIX_SOCKET").map(PathBuf::from));
+
+    let mut config = args.config(&file)?;
+    #[cfg(unix)]
+    let admin_socket = args.admin_socket.clone().map(Listen::Socket);
+    #[cfg(not(unix))]
+    let admin_socket = None;
+    if let Some(listen) = admin_socket.or_else(|| args.admin_addr.map(Listen::Tcp)) {
+        let args = args.clone();
+        let reload: AdminReload = Arc::new(move || match &args.config {
+            Some(path) => args.config(&ConfigFile::load(path)?),
+            None => args.config(&ConfigFile::default()),
+        });
+        config.admin = Some(Admin { listen, reload: Some(reload) });
+    }
+    let _watcher = args.config.clone().map(|path| {
+        let args = args.clone();
+        ConfigWatcher::spawn(path, config.reload.clone(), RELOAD_INTERVAL, move |file| {
+            args.config(file)
+        })
+    });
+
+    let (listening, mut listening_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(listening) = listening_rx.recv().await {
+            println!("OHTTP relay listening on {}", listening);
+        }
+    });
+    let started = Mutex::new(Some(started));
+    let on_bound = OnBound::new(move || match started.lock().expect("poisoned").take() {
+        Some(started) => started.bound(),
+        None => Ok(()),
+    });
+    let relay = ohttp_relay::Builder::new(gateway_origin)
+        .config(config)
+        .on_listening(listening)
+        .on_bound(on_bound)
+        // Drain on SIGTERM and exit 0, so orchestrators can roll the relay without a wrapper.
+        .with_signal_handling();
+    let relay = match unix_socket.or_else(|| file.unix_socket.clone()) {
+        #[cfg(unix)]
+        _ if std::env::var("LISTEN_FDS").is_ok() => relay.socket_activated(),
+        #[cfg(unix)]
+        Some(path) if port.is_none() && args.bind_addr.is_none() => relay.unix_socket(path),
+        _ => {
+            let default = file
+                .bind_addr
+                .unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DEFAULT_PORT));
+            let port = port.unwrap_or_else(|| default.port());
+            match args.dual_stack {
+                true => relay.dual_stack(port),
+                false => relay.bind_addr(SocketAddr::new(
+                    args.bind_addr.unwrap_or_else(|| default.ip()),
+                    port,
+                )),
+            }
+        }
+    };
+    #[cfg(windows)]
+    let relay = match args.named_pipe.clone() {
+        Some(name) => relay.named_pipe(name),
+        None => relay,
+    };
+    #[cfg(feature = "tor-listener")]
+    let relay = match args.onion_dir.clone() {
+        Some(dir) => relay.onion_service(ohttp_relay::OnionService::new("ohttp-relay", dir)),
+        None => relay,
+    };
+    let tls_files = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => Some((cert, key, args.tls_client_ca)),
+        _ => file.tls.map(|tls| (tls.cert, tls.key, tls.client_ca)),
+    };
+    let relay = match tls_files {
+        Some((cert, key, None)) =>
+            relay.tls(Arc::new(ohttp_relay::server_config_from_pem(&cert, &key)?)),
+        Some((cert, key, Some(client_ca))) =>
+            relay.tls(Arc::new(ohttp_relay::mtls_server_config_from_pem(&cert, &key, &client_ca)?)),
+        None => relay,
+    };
+    #[cfg(feature = "dev-tls")]
+    let relay = match args.tls_self_signed.is_empty() {
+        true => relay,
+        false => {
+            let (tls, cert) = ohttp_relay::self_signed_server_config(args.tls_self_signed)?;
+            println!("Self-signed certificate for this run:\n{}", pem_certificate(&cert));
+            relay.tls(Arc::new(tls))
+        }
+    };
+    #[cfg(feature = "acme")]
+    let relay = match args.acme_dir {
+        Some(dir) => {
+            let mut acme = ohttp_relay::Acme::new(args.acme_domain, dir);
+            acme.contact = args.acme_contact;
+            if let Some(directory) = args.acme_directory {
+                acme.directory = directory;
+            }
+            relay.acme(acme)
+        }
+        None => relay,
+    };
+    relay.serve().await?;
 
     Ok(())
 }
 
-fn init_tracing() {
+#[cfg(feature = "dev-tls")]
+fn pem_certificate(der: &[u8]) -> String {
+    use base64::Engine;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        pem.push('\n');
+    }
+    pem.push_str("-----END CERTIFICATE-----");
+    pem
+}
+
+fn legacy_var<T: FromStr>(name: &str) -> Result<Option<T>, BoxError> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value.parse().map_err(|_| format!("Invalid {}: {}", name, value))?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_secs(secs: &str) -> Result<Duration, String> {
+    match secs.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("Invalid number of seconds: {}", secs)),
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("Invalid octal mode: {}", mode)),
+    }
+}
+
+fn init_tracing(args: &Args) -> Result<Telemetry, BoxError> {
+    let filter = match &args.log_level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::from_default_env(),
+    };
+    #[cfg(feature = "otel")]
+    let telemetry =
+        args.otlp_endpoint.as_deref().map(ohttp_relay::otel::Otel::install).transpose()?;
+    #[cfg(feature = "otel")]
+    let otel_layer = telemetry.as_ref().map(|otel| otel.layer());
+    #[cfg(not(feature = "otel"))]
+    let (telemetry, otel_layer) = (None, None::<tracing_subscriber::layer::Identity>);
     tracing_subscriber::registry()
-        .with(EnvFilter::from_default_env())
+        .with(filter)
         .with(fmt::layer().with_target(true)) // Log the target (usually the module path and function name)
+        .with(otel_layer)
         .init();
+    Ok(telemetry)
 }
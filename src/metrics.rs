@@ -0,0 +1,800 @@
+//! Counters for operators, rendered in the Prometheus text exposition format.
+//!
+//! Without the `metrics` feature every recording method is a no-op.
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) use disabled::*;
+#[cfg(feature = "metrics")]
+pub(crate) use enabled::*;
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use std::collections::BTreeMap;
+    use std::fmt::Write;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use hyper::header::{HeaderValue, CONTENT_TYPE};
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Method, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tracing::{debug, info};
+
+    use crate::error::{Error, UpstreamFailure};
+    use crate::gateway_uri::GatewayUri;
+    use crate::{empty, full};
+
+    /// Upper bounds of the upstream latency histogram buckets, in seconds.
+    const LATENCY_BUCKETS: [f64; 11] =
+        [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+    /// The content type of the Prometheus text exposition format.
+    pub(crate) const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4";
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Metrics {
+        requests: AtomicU64,
+        /// Responses by status class, 1xx through 5xx.
+        responses: [AtomicU64; 5],
+        /// Upstream latencies by histogram bucket, the last one being `+Inf`.
+        latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+        latency_sum_micros: AtomicU64,
+        active_connections: AtomicI64,
+        accepts_queued: AtomicU64,
+        headers_rejected: AtomicU64,
+        inflight_requests: AtomicI64,
+        inflight_rejected: AtomicU64,
+        deadlines_exceeded: AtomicU64,
+        /// Forwards by gateway origin.
+        gateways: Mutex<BTreeMap<String, GatewayStats>>,
+        /// Latencies by gateway origin and status class, never by anything about the client.
+        latencies: Mutex<BTreeMap<(String, u16), Latencies>>,
+        started: Started,
+    }
+
+    /// How long forwards to one gateway that ended in one status class took.
+    #[derive(Debug, Default)]
+    struct Latencies {
+        /// Until the gateway's response headers arrived.
+        upstream: Histogram,
+        /// From receiving the client's request until its response was ready to send.
+        total: Histogram,
+    }
+
+    /// Counts of observed durations by [`LATENCY_BUCKETS`] bucket, the last one being `+Inf`.
+    #[derive(Debug, Default)]
+    struct Histogram {
+        buckets: [u64; LATENCY_BUCKETS.len() + 1],
+        sum: Duration,
+    }
+
+    impl Histogram {
+        fn observe(&mut self, latency: Duration) {
+            let secs = latency.as_secs_f64();
+            let bucket = LATENCY_BUCKETS
+                .iter()
+                .position(|&upper| secs <= upper)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            self.buckets[bucket] += 1;
+            self.sum += latency;
+        }
+
+        /// Write the series of `name` with `labels`, e.g. `gateway="…",class="2xx"`.
+        fn render(&self, out: &mut String, name: &str, labels: &str) {
+            let mut cumulative = 0;
+            for (bucket, count) in self.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = LATENCY_BUCKETS
+                    .get(bucket)
+                    .map_or_else(|| "+Inf".to_owned(), |upper| upper.to_string());
+                let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+            }
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum.as_secs_f64());
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, cumulative);
+        }
+    }
+
+    /// When the relay started, for its uptime.
+    #[derive(Debug)]
+    struct Started(Instant);
+
+    impl Default for Started {
+        fn default() -> Self { Self(Instant::now()) }
+    }
+
+    /// The name, type, help text and value of a metric labelled by gateway.
+    type GatewayFamily = (&'static str, &'static str, &'static str, fn(&GatewayStats) -> u64);
+
+    /// The name, help text and histogram of a latency metric labelled by gateway and status class.
+    type LatencyFamily = (&'static str, &'static str, fn(&Latencies) -> &Histogram);
+
+    /// How forwards to one gateway went.
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
+    pub(crate) struct GatewayStats {
+        pub requests: u64,
+        /// Forwards answered with a 5xx status, by the gateway or by the relay on its behalf.
+        pub server_errors: u64,
+        pub timeouts: u64,
+        /// Forwards that failed or were held back before the gateway answered, by why.
+        pub failures: BTreeMap<UpstreamFailure, u64>,
+        pub circuit_open: bool,
+        /// Whether health probes found the gateway down, see [`crate::Config::health_check`].
+        pub unhealthy: bool,
+    }
+
+    impl Metrics {
+        /// Count a client connection as active until the returned guard is dropped.
+        pub(crate) fn connection_opened(self: &Arc<Self>) -> OpenConnection {
+            self.active_connections.fetch_add(1, Ordering::Relaxed);
+            OpenConnection(self.clone())
+        }
+
+        /// Count an accept that had to wait for a connection slot.
+        pub(crate) fn accept_queued(&self) { self.accepts_queued.fetch_add(1, Ordering::Relaxed); }
+
+        /// Count a request rejected for its headers' size or count.
+        pub(crate) fn headers_rejected(&self) {
+            self.headers_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Count a request as in flight to the gateway until the returned guard is dropped.
+        pub(crate) fn forward_started(self: &Arc<Self>) -> ActiveForward {
+            self.inflight_requests.fetch_add(1, Ordering::Relaxed);
+            ActiveForward(self.clone())
+        }
+
+        /// Count a request refused because too many were in flight.
+        pub(crate) fn inflight_rejected(&self) {
+            self.inflight_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Count a relayed exchange cut short by [`crate::Config::request_deadline`].
+        pub(crate) fn deadline_exceeded(&self) {
+            self.deadlines_exceeded.fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Count a request and the status class of the response sent for it.
+        pub(crate) fn record_response(&self, status: StatusCode) {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            let class = usize::from(status.as_u16() / 100).clamp(1, 5) - 1;
+            self.responses[class].fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Record how long the gateway took to answer a forwarded request.
+        pub(crate) fn observe_upstream_latency(&self, latency: Duration) {
+            let secs = latency.as_secs_f64();
+            let bucket = LATENCY_BUCKETS
+                .iter()
+                .position(|&upper| secs <= upper)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+            let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+            self.latency_sum_micros.fetch_add(micros, Ordering::Relaxed);
+        }
+
+        /// Record how long `gateway` took to answer a forwarded request, and how it went.
+        pub(crate) fn observe_gateway_latency(
+            &self,
+            gateway: &GatewayUri,
+            res: Result<StatusCode, &Error>,
+            latency: Duration,
+        ) {
+            self.latencies(gateway, res, |latencies| latencies.upstream.observe(latency));
+        }
+
+        /// Record how long a request relayed to `gateway` took until its response was ready.
+        pub(crate) fn observe_request_duration(
+            &self,
+            gateway: &GatewayUri,
+            res: Result<StatusCode, &Error>,
+            duration: Duration,
+        ) {
+            self.latencies(gateway, res, |latencies| latencies.total.observe(duration));
+        }
+
+        fn latencies(
+            &self,
+            gateway: &GatewayUri,
+            res: Result<StatusCode, &Error>,
+            observe: impl FnOnce(&mut Latencies),
+        ) {
+            let status = res.unwrap_or_else(|e| e.to_response().status());
+            let class = status.as_u16() / 100;
+            let mut latencies = self.latencies.lock().expect("latency metrics poisoned");
+            observe(latencies.entry((gateway.origin(), class)).or_default());
+        }
+
+        /// Count a request forwarded to `gateway` and how it went.
+        pub(crate) fn record_forward(&self, gateway: &GatewayUri, res: Result<StatusCode, &Error>) {
+            let mut gateways = self.gateways.lock().expect("gateway metrics poisoned");
+            let stats = gateways.entry(gateway.origin()).or_default();
+            stats.requests += 1;
+            match res {
+                Ok(status) if status.is_server_error() => stats.server_errors += 1,
+                Ok(_) => {}
+                Err(Error::GatewayTimeout) => {
+                    stats.server_errors += 1;
+                    stats.timeouts += 1;
+                }
+                Err(Error::Upstream(failure)) => {
+                    stats.server_errors += 1;
+                    stats.timeouts += u64::from(failure.status() == StatusCode::GATEWAY_TIMEOUT);
+                    *stats.failures.entry(*failure).or_default() += 1;
+                }
+                Err(_) => stats.server_errors += 1,
+            }
+        }
+
+        /// Count a request to `gateway` the relay held back, e.g. because its circuit is open.
+        pub(crate) fn record_failure(&self, gateway: &GatewayUri, failure: UpstreamFailure) {
+            let mut gateways = self.gateways.lock().expect("gateway metrics poisoned");
+            let stats = gateways.entry(gateway.origin()).or_default();
+            *stats.failures.entry(failure).or_default() += 1;
+        }
+
+        /// Record whether `gateway`'s circuit breaker is open.
+        pub(crate) fn record_circuit(&self, gateway: &GatewayUri, open: bool) {
+            let mut gateways = self.gateways.lock().expect("gateway metrics poisoned");
+            match gateways.get_mut(&gateway.origin()) {
+                Some(stats) => stats.circuit_open = open,
+                None if open => {
+                    let stats = GatewayStats { circuit_open: true, ..GatewayStats::default() };
+                    gateways.insert(gateway.origin(), stats);
+                }
+                None => {}
+            }
+        }
+
+        /// Record whether health probes find `gateway` up.
+        pub(crate) fn record_health(&self, gateway: &GatewayUri, healthy: bool) {
+            let mut gateways = self.gateways.lock().expect("gateway metrics poisoned");
+            match gateways.get_mut(&gateway.origin()) {
+                Some(stats) => stats.unhealthy = !healthy,
+                None if !healthy => {
+                    let stats = GatewayStats { unhealthy: true, ..GatewayStats::default() };
+                    gateways.insert(gateway.origin(), stats);
+                }
+                None => {}
+            }
+        }
+
+        /// The stats of every gateway requests were forwarded to, by origin.
+        pub(crate) fn gateways(&self) -> Vec<(String, GatewayStats)> {
+            let gateways = self.gateways.lock().expect("gateway metrics poisoned");
+            gateways.iter().map(|(origin, stats)| (origin.clone(), stats.clone())).collect()
+        }
+
+        /// A JSON summary of [`Metrics::gateways`].
+        pub(crate) fn gateways_json(&self) -> String {
+            let entries: Vec<String> = self
+                .gateways()
+                .iter()
+                .map(|(origin, stats)| {
+                    // An origin never contains quotes, backslashes or control characters.
+                    format!(
+                        r#"{{"gateway":"{}","requests":{},"server_errors":{},"timeouts":{},"circuit_open":{}}}"#,
+                        origin, stats.requests, stats.server_errors, stats.timeouts, stats.circuit_open
+                    )
+                })
+                .collect();
+            format!(r#"{{"gateways":[{}]}}"#, entries.join(","))
+        }
+
+        /// A JSON overview for operators without Prometheus: uptime, requests and responses,
+        /// open connections and in-flight forwards, and each gateway's health and circuit.
+        pub(crate) fn stats_json(&self) -> String {
+            let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+            let responses: Vec<String> = self
+                .responses
+                .iter()
+                .enumerate()
+                .map(|(class, count)| format!(r#""{}xx":{}"#, class + 1, load(count)))
+                .collect();
+            let gateways: Vec<String> = self
+                .gateways()
+                .iter()
+                .map(|(origin, stats)| {
+                    format!(
+                        r#"{{"gateway":"{}","healthy":{},"circuit_open":{},"requests":{},"server_errors":{},"timeouts":{}}}"#,
+                        origin,
+                        !stats.unhealthy,
+                        stats.circuit_open,
+                        stats.requests,
+                        stats.server_errors,
+                        stats.timeouts
+                    )
+                })
+                .collect();
+            format!(
+                r#"{{"uptime_secs":{},"requests":{},"responses":{{{}}},"active_connections":{},"inflight_requests":{},"gateways":[{}]}}"#,
+                self.started.0.elapsed().as_secs(),
+                load(&self.requests),
+                responses.join(","),
+                self.active_connections.load(Ordering::Relaxed),
+                self.inflight_requests.load(Ordering::Relaxed),
+                gateways.join(",")
+            )
+        }
+
+        /// The current value of every counter, for exporters other than the text format.
+        #[cfg(feature = "otel")]
+        pub(crate) fn snapshot(&self) -> Snapshot {
+            let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+            Snapshot {
+                requests: load(&self.requests),
+                responses: [0, 1, 2, 3, 4].map(|class| load(&self.responses[class])),
+                upstream_latency_sum: Duration::from_micros(load(&self.latency_sum_micros)),
+                upstream_latency_count: self.latency_buckets.iter().map(load).sum(),
+                active_connections: self.active_connections.load(Ordering::Relaxed),
+                accepts_queued: load(&self.accepts_queued),
+                headers_rejected: load(&self.headers_rejected),
+                inflight_requests: self.inflight_requests.load(Ordering::Relaxed),
+                inflight_rejected: load(&self.inflight_rejected),
+                deadlines_exceeded: load(&self.deadlines_exceeded),
+                gateways: self.gateways(),
+            }
+        }
+
+        /// A snapshot of every metric in the Prometheus text format.
+        pub(crate) fn render(&self) -> String {
+            let mut out = String::new();
+            let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+            out.push_str("# HELP ohttp_relay_requests_total Requests received.\n");
+            out.push_str("# TYPE ohttp_relay_requests_total counter\n");
+            let _ = writeln!(out, "ohttp_relay_requests_total {}", load(&self.requests));
+
+            out.push_str("# HELP ohttp_relay_responses_total Responses sent by status class.\n");
+            out.push_str("# TYPE ohttp_relay_responses_total counter\n");
+            for (class, count) in self.responses.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "ohttp_relay_responses_total{{class=\"{}xx\"}} {}",
+                    class + 1,
+                    load(count)
+                );
+            }
+
+            out.push_str(
+                "# HELP ohttp_relay_upstream_latency_seconds Time until the gateway's response \
+                 headers arrive.\n",
+            );
+            out.push_str("# TYPE ohttp_relay_upstream_latency_seconds histogram\n");
+            let mut cumulative = 0;
+            for (bucket, count) in self.latency_buckets.iter().enumerate() {
+                cumulative += load(count);
+                let le = LATENCY_BUCKETS
+                    .get(bucket)
+                    .map_or_else(|| "+Inf".to_owned(), |upper| upper.to_string());
+                let _ = writeln!(
+                    out,
+                    "ohttp_relay_upstream_latency_seconds_bucket{{le=\"{}\"}} {}",
+                    le, cumulative
+                );
+            }
+            let sum = Duration::from_micros(load(&self.latency_sum_micros)).as_secs_f64();
+            let _ = writeln!(out, "ohttp_relay_upstream_latency_seconds_sum {}", sum);
+            let _ = writeln!(out, "ohttp_relay_upstream_latency_seconds_count {}", cumulative);
+
+            out.push_str("# HELP ohttp_relay_active_connections Client connections open.\n");
+            out.push_str("# TYPE ohttp_relay_active_connections gauge\n");
+            let _ = writeln!(
+                out,
+                "ohttp_relay_active_connections {}",
+                self.active_connections.load(Ordering::Relaxed)
+            );
+
+            out.push_str(
+                "# HELP ohttp_relay_accepts_queued_total Accepts delayed by the connection \
+                 limit.\n",
+            );
+            out.push_str("# TYPE ohttp_relay_accepts_queued_total counter\n");
+            let _ =
+                writeln!(out, "ohttp_relay_accepts_queued_total {}", load(&self.accepts_queued));
+
+            out.push_str(
+                "# HELP ohttp_relay_headers_rejected_total Requests rejected for too many or too \
+                 large headers.\n",
+            );
+            out.push_str("# TYPE ohttp_relay_headers_rejected_total counter\n");
+            let _ = writeln!(
+                out,
+                "ohttp_relay_headers_rejected_total {}",
+                load(&self.headers_rejected)
+            );
+
+            out.push_str(
+                "# HELP ohttp_relay_inflight_requests Requests being forwarded to a gateway.\n",
+            );
+            out.push_str("# TYPE ohttp_relay_inflight_requests gauge\n");
+            let _ = writeln!(
+                out,
+                "ohttp_relay_inflight_requests {}",
+                self.inflight_requests.load(Ordering::Relaxed)
+            );
+
+            out.push_str(
+                "# HELP ohttp_relay_inflight_rejected_total Requests refused by the in-flight \
+                 limit.\n",
+            );
+            out.push_str("# TYPE ohttp_relay_inflight_rejected_total counter\n");
+            let _ = writeln!(
+                out,
+                "ohttp_relay_inflight_rejected_total {}",
+                load(&self.inflight_rejected)
+            );
+
+            out.push_str(
+                "# HELP ohttp_relay_deadlines_exceeded_total Relayed exchanges cut short by the \
+                 request deadline.\n",
+            );
+            out.push_str("# TYPE ohttp_relay_deadlines_exceeded_total counter\n");
+            let _ = writeln!(
+                out,
+                "ohttp_relay_deadlines_exceeded_total {}",
+                load(&self.deadlines_exceeded)
+            );
+
+            let gateways = self.gateways();
+            let families: [GatewayFamily; 4] = [
+                (
+                    "ohttp_relay_gateway_requests_total",
+                    "counter",
+                    "Requests forwarded by gateway.",
+                    |stats| stats.requests,
+                ),
+                (
+                    "ohttp_relay_gateway_server_errors_total",
+                    "counter",
+                    "Forwards answered with a 5xx status by gateway.",
+                    |stats| stats.server_errors,
+                ),
+                (
+                    "ohttp_relay_gateway_timeouts_total",
+                    "counter",
+                    "Forwards the gateway did not answer in time by gateway.",
+                    |stats| stats.timeouts,
+                ),
+                (
+                    "ohttp_relay_gateway_circuit_open",
+                    "gauge",
+                    "Whether the gateway's circuit breaker is open.",
+                    |stats| u64::from(stats.circuit_open),
+                ),
+            ];
+            for (name, kind, help, value) in families {
+                let _ = writeln!(out, "# HELP {} {}", name, help);
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                for (origin, stats) in &gateways {
+                    let _ = writeln!(out, "{}{{gateway=\"{}\"}} {}", name, origin, value(stats));
+                }
+            }
+
+            out.push_str(
+                "# HELP ohttp_relay_gateway_failures_total Requests not answered by the gateway \
+                 by gateway and reason.\n",
+            );
+            out.push_str("# TYPE ohttp_relay_gateway_failures_total counter\n");
+            for (origin, stats) in &gateways {
+                for (failure, count) in &stats.failures {
+                    let _ = writeln!(
+                        out,
+                        "ohttp_relay_gateway_failures_total{{gateway=\"{}\",reason=\"{}\"}} {}",
+                        origin,
+                        failure.as_str(),
+                        count
+                    );
+                }
+            }
+
+            let latencies = self.latencies.lock().expect("latency metrics poisoned");
+            let histograms: [LatencyFamily; 2] = [
+                (
+                    "ohttp_relay_gateway_upstream_latency_seconds",
+                    "Time until the gateway's response headers arrive by gateway and status class.",
+                    |latencies| &latencies.upstream,
+                ),
+                (
+                    "ohttp_relay_gateway_request_duration_seconds",
+                    "Time from receiving a relayed request until its response is ready by gateway \
+                     and status class.",
+                    |latencies| &latencies.total,
+                ),
+            ];
+            for (name, help, histogram) in histograms {
+                let _ = writeln!(out, "# HELP {} {}", name, help);
+                let _ = writeln!(out, "# TYPE {} histogram", name);
+                for ((origin, class), latencies) in latencies.iter() {
+                    let labels = format!("gateway=\"{}\",class=\"{}xx\"", origin, class);
+                    histogram(latencies).render(&mut out, name, &labels);
+                }
+            }
+            out
+        }
+    }
+
+    /// See [`Metrics::snapshot`].
+    #[cfg(feature = "otel")]
+    #[derive(Debug)]
+    pub(crate) struct Snapshot {
+        pub requests: u64,
+        pub responses: [u64; 5],
+        pub upstream_latency_sum: Duration,
+        pub upstream_latency_count: u64,
+        pub active_connections: i64,
+        pub accepts_queued: u64,
+        pub headers_rejected: u64,
+        pub inflight_requests: i64,
+        pub inflight_rejected: u64,
+        pub deadlines_exceeded: u64,
+        pub gateways: Vec<(String, GatewayStats)>,
+    }
+
+    /// Counts a client connection as active until dropped.
+    #[derive(Debug)]
+    pub(crate) struct OpenConnection(Arc<Metrics>);
+
+    impl Drop for OpenConnection {
+        fn drop(&mut self) { self.0.active_connections.fetch_sub(1, Ordering::Relaxed); }
+    }
+
+    /// Counts a request as in flight until dropped.
+    #[derive(Debug)]
+    pub(crate) struct ActiveForward(Arc<Metrics>);
+
+    impl Drop for ActiveForward {
+        fn drop(&mut self) { self.0.inflight_requests.fetch_sub(1, Ordering::Relaxed); }
+    }
+
+    /// Serves `GET /metrics` and `GET /stats` on its own listener until dropped.
+    #[derive(Debug)]
+    pub(crate) struct MetricsServer(JoinHandle<()>);
+
+    impl MetricsServer {
+        pub(crate) async fn bind(addr: SocketAddr, metrics: Arc<Metrics>) -> std::io::Result<Self> {
+            let listener = TcpListener::bind(addr).await?;
+            info!("Metrics listening on tcp://{}", listener.local_addr()?);
+            Ok(Self(tokio::spawn(serve_metrics(listener, metrics))))
+        }
+    }
+
+    impl Drop for MetricsServer {
+        fn drop(&mut self) { self.0.abort(); }
+    }
+
+    async fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!("Metrics accept failed: {}", e);
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let res = match (req.method(), req.uri().path()) {
+                        (&Method::GET, "/metrics") => {
+                            let mut res = Response::new(full(metrics.render()));
+                            res.headers_mut()
+                                .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_TEXT));
+                            res
+                        }
+                        (&Method::GET, "/stats") => {
+                            let mut res = Response::new(full(metrics.stats_json()));
+                            res.headers_mut()
+                                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                            res
+                        }
+                        _ => {
+                            let mut res = Response::new(empty());
+                            *res.status_mut() = StatusCode::NOT_FOUND;
+                            res
+                        }
+                    };
+                    std::future::ready(Ok::<_, hyper::Error>(res))
+                });
+                if let Err(e) =
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service).await
+                {
+                    debug!("Error serving metrics connection: {}", e);
+                }
+            });
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn renders_prometheus_text() {
+            let metrics = Arc::new(Metrics::default());
+            let open = metrics.connection_opened();
+            let _forward = metrics.forward_started();
+            metrics.inflight_rejected();
+            metrics.deadline_exceeded();
+            metrics.record_response(StatusCode::OK);
+            metrics.record_response(StatusCode::BAD_GATEWAY);
+            metrics.observe_upstream_latency(Duration::from_millis(30));
+            metrics.observe_upstream_latency(Duration::from_secs(60));
+
+            let text = metrics.render();
+            for line in [
+                "ohttp_relay_requests_total 2",
+                "ohttp_relay_responses_total{class=\"2xx\"} 1",
+                "ohttp_relay_responses_total{class=\"4xx\"} 0",
+                "ohttp_relay_responses_total{class=\"5xx\"} 1",
+                "ohttp_relay_upstream_latency_seconds_bucket{le=\"0.025\"} 0",
+                "ohttp_relay_upstream_latency_seconds_bucket{le=\"0.05\"} 1",
+                "ohttp_relay_upstream_latency_seconds_bucket{le=\"+Inf\"} 2",
+                "ohttp_relay_upstream_latency_seconds_sum 60.03",
+                "ohttp_relay_upstream_latency_seconds_count 2",
+                "ohttp_relay_active_connections 1",
+                "ohttp_relay_accepts_queued_total 0",
+                "ohttp_relay_headers_rejected_total 0",
+                "ohttp_relay_inflight_requests 1",
+                "ohttp_relay_inflight_rejected_total 1",
+                "ohttp_relay_deadlines_exceeded_total 1",
+            ] {
+                assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+            }
+
+            drop(open);
+            assert!(metrics.render().contains("ohttp_relay_active_connections 0\n"));
+        }
+
+        #[test]
+        fn counts_forwards_by_gateway() {
+            let metrics = Metrics::default();
+            let gateway =
+                GatewayUri::new("https://gateway.example".parse().unwrap(), false).unwrap();
+            let other = GatewayUri::new("https://other.example".parse().unwrap(), false).unwrap();
+            metrics.record_forward(&gateway, Ok(StatusCode::OK));
+            metrics.record_forward(&gateway, Ok(StatusCode::SERVICE_UNAVAILABLE));
+            metrics.record_forward(&gateway, Err(&Error::GatewayTimeout));
+            metrics.record_forward(&gateway, Err(&Error::Upstream(UpstreamFailure::Refused)));
+            metrics.record_failure(&gateway, UpstreamFailure::CircuitOpen);
+            metrics.record_circuit(&gateway, true);
+            metrics.record_circuit(&other, false);
+
+            let text = metrics.render();
+            for line in [
+                "ohttp_relay_gateway_requests_total{gateway=\"https://gateway.example:443\"} 4",
+                "ohttp_relay_gateway_server_errors_total{gateway=\"https://gateway.example:443\"} 3",
+                "ohttp_relay_gateway_timeouts_total{gateway=\"https://gateway.example:443\"} 1",
+                "ohttp_relay_gateway_circuit_open{gateway=\"https://gateway.example:443\"} 1",
+                "ohttp_relay_gateway_failures_total{gateway=\"https://gateway.example:443\",reason=\"refused\"} 1",
+                "ohttp_relay_gateway_failures_total{gateway=\"https://gateway.example:443\",reason=\"circuit_open\"} 1",
+            ] {
+                assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+            }
+            assert!(!text.contains("other.example"), "{}", text);
+            assert_eq!(
+                metrics.gateways_json(),
+                r#"{"gateways":[{"gateway":"https://gateway.example:443","requests":4,"server_errors":3,"timeouts":1,"circuit_open":true}]}"#
+            );
+        }
+
+        #[test]
+        fn latency_histograms_by_gateway_and_class() {
+            let metrics = Metrics::default();
+            let gateway =
+                GatewayUri::new("https://gateway.example".parse().unwrap(), false).unwrap();
+            metrics.observe_gateway_latency(
+                &gateway,
+                Ok(StatusCode::OK),
+                Duration::from_millis(30),
+            );
+            metrics.observe_request_duration(
+                &gateway,
+                Ok(StatusCode::OK),
+                Duration::from_millis(40),
+            );
+            metrics.observe_gateway_latency(
+                &gateway,
+                Err(&Error::GatewayTimeout),
+                Duration::from_secs(60),
+            );
+
+            let text = metrics.render();
+            for line in [
+                "ohttp_relay_gateway_upstream_latency_seconds_bucket{gateway=\"https://gateway.example:443\",class=\"2xx\",le=\"0.025\"} 0",
+                "ohttp_relay_gateway_upstream_latency_seconds_bucket{gateway=\"https://gateway.example:443\",class=\"2xx\",le=\"0.05\"} 1",
+                "ohttp_relay_gateway_upstream_latency_seconds_count{gateway=\"https://gateway.example:443\",class=\"5xx\"} 1",
+                "ohttp_relay_gateway_upstream_latency_seconds_sum{gateway=\"https://gateway.example:443\",class=\"5xx\"} 60",
+                "ohttp_relay_gateway_request_duration_seconds_sum{gateway=\"https://gateway.example:443\",class=\"2xx\"} 0.04",
+                "ohttp_relay_gateway_request_duration_seconds_count{gateway=\"https://gateway.example:443\",class=\"5xx\"} 0",
+            ] {
+                assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+            }
+        }
+
+        #[test]
+        fn stats_summarize_metrics() {
+            let metrics = Arc::new(Metrics::default());
+            let _open = metrics.connection_opened();
+            metrics.record_response(StatusCode::OK);
+            let gateway =
+                GatewayUri::new("https://gateway.example".parse().unwrap(), false).unwrap();
+            metrics.record_health(&gateway, false);
+            assert_eq!(
+                metrics.stats_json(),
+                r#"{"uptime_secs":0,"requests":1,"responses":{"1xx":0,"2xx":1,"3xx":0,"4xx":0,"5xx":0},"active_connections":1,"inflight_requests":0,"gateways":[{"gateway":"https://gateway.example:443","healthy":false,"circuit_open":false,"requests":0,"server_errors":0,"timeouts":0}]}"#
+            );
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod disabled {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::StatusCode;
+
+    use crate::error::{Error, UpstreamFailure};
+    use crate::gateway_uri::GatewayUri;
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Metrics {}
+
+    #[derive(Debug)]
+    pub(crate) struct OpenConnection;
+
+    #[derive(Debug)]
+    pub(crate) struct ActiveForward;
+
+    impl Metrics {
+        pub(crate) fn connection_opened(self: &Arc<Self>) -> OpenConnection { OpenConnection }
+
+        pub(crate) fn accept_queued(&self) {}
+
+        pub(crate) fn headers_rejected(&self) {}
+
+        pub(crate) fn forward_started(self: &Arc<Self>) -> ActiveForward { ActiveForward }
+
+        pub(crate) fn inflight_rejected(&self) {}
+
+        pub(crate) fn deadline_exceeded(&self) {}
+
+        pub(crate) fn record_response(&self, _status: StatusCode) {}
+
+        pub(crate) fn observe_upstream_latency(&self, _latency: Duration) {}
+
+        pub(crate) fn observe_gateway_latency(
+            &self,
+            _gateway: &GatewayUri,
+            _res: Result<StatusCode, &Error>,
+            _latency: Duration,
+        ) {
+        }
+
+        pub(crate) fn observe_request_duration(
+            &self,
+            _gateway: &GatewayUri,
+            _res: Result<StatusCode, &Error>,
+            _duration: Duration,
+        ) {
+        }
+
+        pub(crate) fn record_forward(
+            &self,
+            _gateway: &GatewayUri,
+            _res: Result<StatusCode, &Error>,
+        ) {
+        }
+
+        pub(crate) fn record_failure(&self, _gateway: &GatewayUri, _failure: UpstreamFailure) {}
+
+        pub(crate) fn record_circuit(&self, _gateway: &GatewayUri, _open: bool) {}
+
+        pub(crate) fn record_health(&self, _gateway: &GatewayUri, _healthy: bool) {}
+    }
+}
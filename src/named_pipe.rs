@@ -0,0 +1,50 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio_util::net::Listener;
+
+type Connecting = Pin<Box<dyn Future<Output = io::Result<NamedPipeServer>> + Send>>;
+
+/// Accepts clients on a named pipe. Each client gets its own pipe instance, and the next
+/// instance is created as soon as one is taken so the pipe never disappears between clients.
+pub(crate) struct NamedPipeListener {
+    name: String,
+    connecting: Connecting,
+}
+
+impl NamedPipeListener {
+    pub(crate) fn bind(name: &str) -> io::Result<Self> {
+        let server = ServerOptions::new().first_pipe_instance(true).create(name)?;
+        Ok(Self { name: name.to_owned(), connecting: connect(server) })
+    }
+}
+
+fn connect(server: NamedPipeServer) -> Connecting {
+    Box::pin(async move {
+        server.connect().await?;
+        Ok(server)
+    })
+}
+
+impl Listener for NamedPipeListener {
+    type Io = NamedPipeServer;
+    type Addr = String;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Io, Self::Addr)>> {
+        let connected = match self.connecting.as_mut().poll(cx) {
+            Poll::Ready(connected) => connected,
+            Poll::Pending => return Poll::Pending,
+        };
+        // A failure to create the next instance surfaces on the next accept.
+        self.connecting = match ServerOptions::new().create(&self.name) {
+            Ok(next) => connect(next),
+            Err(e) => Box::pin(async move { Err(e) }),
+        };
+        Poll::Ready(connected.map(|server| (server, self.name.clone())))
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> { Ok(self.name.clone()) }
+}
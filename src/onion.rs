@@ -0,0 +1,145 @@
+use std::io;
+use std::path::PathBuf;
+use std::task::{Context, Poll};
+
+use arti_client::config::TorClientConfigBuilder;
+use arti_client::{DataStream, TorClient};
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_util::net::Listener;
+use tor_cell::relaycell::msg::{Connected, End, EndReason};
+use tor_hsservice::config::OnionServiceConfigBuilder;
+use tor_hsservice::{HsNickname, StreamRequest};
+use tor_proto::stream::IncomingStreamRequest;
+use tracing::debug;
+
+use crate::body::BoxError;
+
+/// An onion service publishing the relay over Tor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnionService {
+    /// Names the service among others sharing `state_dir`, e.g. `ohttp-relay`.
+    pub nickname: String,
+    /// Where arti keeps the service's keys and state. Keep it to keep the onion address.
+    pub state_dir: PathBuf,
+    /// Where arti caches Tor directory information.
+    pub cache_dir: PathBuf,
+    /// The virtual port clients connect to. Streams to other ports are refused.
+    pub port: u16,
+}
+
+impl OnionService {
+    /// A service on port 80 keeping its state and cache under `dir`.
+    pub fn new(nickname: impl Into<String>, dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        Self {
+            nickname: nickname.into(),
+            state_dir: dir.join("state"),
+            cache_dir: dir.join("cache"),
+            port: 80,
+        }
+    }
+}
+
+/// Accepts streams to an onion service's port. The service stops once the listener is dropped.
+pub(crate) struct OnionListener {
+    address: String,
+    streams: mpsc::Receiver<DataStream>,
+}
+
+impl OnionListener {
+    /// Bootstrap a Tor client and launch `service` on it, creating its keys on first launch.
+    pub(crate) async fn launch(service: &OnionService) -> Result<Self, BoxError> {
+        let config =
+            TorClientConfigBuilder::from_directories(&service.state_dir, &service.cache_dir)
+                .build()?;
+        let client = TorClient::create_bootstrapped(config).await?;
+        let service_config = OnionServiceConfigBuilder::default()
+            .nickname(HsNickname::new(service.nickname.clone())?)
+            .build()?;
+        let (running, rend_requests) = client.launch_onion_service(service_config)?;
+        let address =
+            running.onion_name().ok_or("Onion service launched without an address")?.to_string();
+        let (sender, streams) = mpsc::channel(64);
+        let stream_requests = tor_hsservice::handle_rend_requests(rend_requests);
+        let port = service.port;
+        tokio::spawn(async move {
+            // The client and the service stay up for as long as streams are accepted.
+            let _published = (client, running);
+            tokio::select! {
+                _ = accept(stream_requests, port, sender.clone()) => {}
+                _ = sender.closed() => {}
+            }
+        });
+        Ok(Self { address, streams })
+    }
+
+    /// The service's `.onion` address.
+    pub(crate) fn address(&self) -> &str { &self.address }
+}
+
+/// Accept each stream request to `port` and refuse the rest.
+async fn accept(
+    requests: impl Stream<Item = StreamRequest>,
+    port: u16,
+    streams: mpsc::Sender<DataStream>,
+) {
+    futures::pin_mut!(requests);
+    while let Some(request) = requests.next().await {
+        let streams = streams.clone();
+        tokio::spawn(async move {
+            if !wanted(request.request(), port) {
+                let _ = request.reject(End::new_with_reason(EndReason::DONE)).await;
+                return;
+            }
+            match request.accept(Connected::new_empty()).await {
+                Ok(stream) => {
+                    let _ = streams.send(stream).await;
+                }
+                Err(e) => debug!("Failed to accept onion service stream: {}", e),
+            }
+        });
+    }
+}
+
+fn wanted(request: &IncomingStreamRequest, port: u16) -> bool {
+    matches!(request, IncomingStreamRequest::Begin(begin) if begin.port() == port)
+}
+
+impl Listener for OnionListener {
+    type Io = DataStream;
+    type Addr = String;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Io, Self::Addr)>> {
+        self.streams.poll_recv(cx).map(|stream| match stream {
+            Some(stream) => Ok((stream, self.address.clone())),
+            None => Err(io::Error::other("Onion service stopped")),
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> { Ok(self.address.clone()) }
+}
+
+#[cfg(test)]
+mod test {
+    use tor_cell::relaycell::msg::Begin;
+
+    use super::*;
+
+    #[test]
+    fn only_streams_to_port_wanted() {
+        let begin = |port| {
+            IncomingStreamRequest::Begin(Begin::new("", port, 0).expect("valid begin message"))
+        };
+        assert!(wanted(&begin(80), 80));
+        assert!(!wanted(&begin(443), 80));
+    }
+
+    #[test]
+    fn state_and_cache_kept_apart() {
+        let service = OnionService::new("ohttp-relay", "/var/lib/ohttp-relay/tor");
+        assert_eq!(service.state_dir, PathBuf::from("/var/lib/ohttp-relay/tor/state"));
+        assert_eq!(service.cache_dir, PathBuf::from("/var/lib/ohttp-relay/tor/cache"));
+        assert_eq!(service.port, 80);
+    }
+}
@@ -0,0 +1,190 @@
+//! Export spans and counters over OTLP with the `otel` feature.
+//!
+//! Spans cover accepting a connection, serving a request and forwarding it to the gateway. They
+//! carry the method, a coarse class of the path, statuses and the gateway's authority, never
+//! client addresses or headers.
+
+use std::sync::Arc;
+
+use opentelemetry::metrics::{CallbackRegistration, Meter};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::Tracer;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::{error, Subscriber};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::body::BoxError;
+use crate::metrics::Metrics;
+
+/// The OTLP pipelines, flushed and shut down when dropped.
+#[derive(Debug)]
+pub struct Otel {
+    tracer: Tracer,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Otel {
+    /// Export to the OTLP gRPC collector at `endpoint`, e.g. `http://localhost:4317`, and make
+    /// this the global tracer and meter provider.
+    pub fn install(endpoint: &str) -> Result<Self, BoxError> {
+        let resource = Resource::new([KeyValue::new("service.name", "ohttp-relay")]);
+        let exporter = || opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint);
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter())
+            .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource.clone()))
+            .install_batch(runtime::Tokio)?;
+        let meter_provider = opentelemetry_otlp::new_pipeline()
+            .metrics(runtime::Tokio)
+            .with_exporter(exporter())
+            .with_resource(resource)
+            .build()?;
+        global::set_meter_provider(meter_provider.clone());
+        Ok(Self { tracer, meter_provider })
+    }
+
+    /// A layer exporting `tracing` spans, to add to the subscriber.
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.tracer.clone())
+    }
+}
+
+impl Drop for Otel {
+    fn drop(&mut self) {
+        global::shutdown_tracer_provider();
+        if let Err(e) = self.meter_provider.shutdown() {
+            error!("Failed to shut down OTLP metrics: {}", e);
+        }
+    }
+}
+
+/// Reports a relay's counters to the global meter provider until dropped.
+pub(crate) struct ObservedMetrics(Box<dyn CallbackRegistration>);
+
+impl ObservedMetrics {
+    pub(crate) fn register(metrics: Arc<Metrics>) -> Option<Self> {
+        match register(&global::meter("ohttp-relay"), metrics) {
+            Ok(registration) => Some(Self(registration)),
+            Err(e) => {
+                error!("Failed to register OTLP metrics: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for ObservedMetrics {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.unregister() {
+            error!("Failed to unregister OTLP metrics: {}", e);
+        }
+    }
+}
+
+fn register(
+    meter: &Meter,
+    metrics: Arc<Metrics>,
+) -> opentelemetry::metrics::Result<Box<dyn CallbackRegistration>> {
+    let requests = meter
+        .u64_observable_counter("ohttp_relay.requests")
+        .with_description("Requests received.")
+        .init();
+    let responses = meter
+        .u64_observable_counter("ohttp_relay.responses")
+        .with_description("Responses sent by status class.")
+        .init();
+    let latency_sum = meter
+        .f64_observable_counter("ohttp_relay.upstream_latency.sum")
+        .with_description("Seconds spent waiting for the gateway's response headers.")
+        .with_unit(opentelemetry::metrics::Unit::new("s"))
+        .init();
+    let latency_count = meter
+        .u64_observable_counter("ohttp_relay.upstream_latency.count")
+        .with_description("Responses received from the gateway.")
+        .init();
+    let active_connections = meter
+        .i64_observable_gauge("ohttp_relay.active_connections")
+        .with_description("Client connections open.")
+        .init();
+    let accepts_queued = meter
+        .u64_observable_counter("ohttp_relay.accepts_queued")
+        .with_description("Accepts delayed by the connection limit.")
+        .init();
+    let headers_rejected = meter
+        .u64_observable_counter("ohttp_relay.headers_rejected")
+        .with_description("Requests rejected for too many or too large headers.")
+        .init();
+    let inflight_requests = meter
+        .i64_observable_gauge("ohttp_relay.inflight_requests")
+        .with_description("Requests being forwarded to a gateway.")
+        .init();
+    let inflight_rejected = meter
+        .u64_observable_counter("ohttp_relay.inflight_rejected")
+        .with_description("Requests refused by the in-flight limit.")
+        .init();
+    let deadlines_exceeded = meter
+        .u64_observable_counter("ohttp_relay.deadlines_exceeded")
+        .with_description("Relayed exchanges cut short by the request deadline.")
+        .init();
+    let gateway_requests = meter
+        .u64_observable_counter("ohttp_relay.gateway.requests")
+        .with_description("Requests forwarded by gateway.")
+        .init();
+    let gateway_server_errors = meter
+        .u64_observable_counter("ohttp_relay.gateway.server_errors")
+        .with_description("Forwards answered with a 5xx status by gateway.")
+        .init();
+    let gateway_timeouts = meter
+        .u64_observable_counter("ohttp_relay.gateway.timeouts")
+        .with_description("Forwards the gateway did not answer in time by gateway.")
+        .init();
+    let gateway_circuit_open = meter
+        .u64_observable_gauge("ohttp_relay.gateway.circuit_open")
+        .with_description("Whether the gateway's circuit breaker is open.")
+        .init();
+    let instruments = [
+        requests.as_any(),
+        responses.as_any(),
+        latency_sum.as_any(),
+        latency_count.as_any(),
+        active_connections.as_any(),
+        accepts_queued.as_any(),
+        headers_rejected.as_any(),
+        inflight_requests.as_any(),
+        inflight_rejected.as_any(),
+        deadlines_exceeded.as_any(),
+        gateway_requests.as_any(),
+        gateway_server_errors.as_any(),
+        gateway_timeouts.as_any(),
+        gateway_circuit_open.as_any(),
+    ];
+    meter.register_callback(&instruments, move |observer| {
+        let snapshot = metrics.snapshot();
+        observer.observe_u64(&requests, snapshot.requests, &[]);
+        for (class, count) in snapshot.responses.iter().enumerate() {
+            let class = KeyValue::new("class", format!("{}xx", class + 1));
+            observer.observe_u64(&responses, *count, &[class]);
+        }
+        observer.observe_f64(&latency_sum, snapshot.upstream_latency_sum.as_secs_f64(), &[]);
+        observer.observe_u64(&latency_count, snapshot.upstream_latency_count, &[]);
+        observer.observe_i64(&active_connections, snapshot.active_connections, &[]);
+        observer.observe_u64(&accepts_queued, snapshot.accepts_queued, &[]);
+        observer.observe_u64(&headers_rejected, snapshot.headers_rejected, &[]);
+        observer.observe_i64(&inflight_requests, snapshot.inflight_requests, &[]);
+        observer.observe_u64(&inflight_rejected, snapshot.inflight_rejected, &[]);
+        observer.observe_u64(&deadlines_exceeded, snapshot.deadlines_exceeded, &[]);
+        for (origin, stats) in snapshot.gateways {
+            let gateway = [KeyValue::new("gateway", origin)];
+            observer.observe_u64(&gateway_requests, stats.requests, &gateway);
+            observer.observe_u64(&gateway_server_errors, stats.server_errors, &gateway);
+            observer.observe_u64(&gateway_timeouts, stats.timeouts, &gateway);
+            observer.observe_u64(&gateway_circuit_open, u64::from(stats.circuit_open), &gateway);
+        }
+    })
+}
@@ -0,0 +1,53 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::Padding;
+
+/// Carries the padding. Gateways and clients ignore headers they don't know.
+pub(crate) static PADDING: HeaderName = HeaderName::from_static("padding");
+
+/// Add a padding header so the body, its `Content-Length` and the padding add up to a bucket.
+///
+/// The header is added even when no padding is needed so its name never tells sizes apart, and
+/// marked sensitive so HTTP/2 never indexes it, which would shrink it to a byte.
+pub(crate) fn pad(headers: &mut HeaderMap, body_len: u64, padding: &Padding) {
+    let len = body_len + body_len.to_string().len() as u64;
+    let padding_len = bucket(&padding.buckets, len) - len;
+    let mut value = HeaderValue::try_from("0".repeat(padding_len as usize))
+        .expect("zeros are a valid header value");
+    value.set_sensitive(true);
+    headers.insert(PADDING.clone(), value);
+}
+
+/// The smallest bucket holding `len`, or the next multiple of the largest one.
+fn bucket(buckets: &[u64], len: u64) -> u64 {
+    match buckets.iter().copied().filter(|&bucket| bucket >= len).min() {
+        Some(bucket) => bucket,
+        None => match buckets.iter().copied().max() {
+            Some(largest) if largest > 0 => len.div_ceil(largest) * largest,
+            _ => len,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn padded_to_bucket() {
+        let padding = Padding { buckets: vec![1024, 256] };
+        assert_eq!(bucket(&padding.buckets, 1), 256);
+        assert_eq!(bucket(&padding.buckets, 257), 1024);
+        assert_eq!(bucket(&padding.buckets, 1025), 2048);
+        assert_eq!(bucket(&[], 7), 7);
+
+        for body_len in [0, 78, 99, 100, 253] {
+            let mut headers = HeaderMap::new();
+            pad(&mut headers, body_len, &padding);
+            let padded =
+                body_len + body_len.to_string().len() as u64 + headers[&PADDING].len() as u64;
+            assert_eq!(padded, 256, "body of {} bytes", body_len);
+            assert!(headers[&PADDING].is_sensitive());
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+
+/// The SHA-256 hash of a certificate's DER SubjectPublicKeyInfo, as used by HPKP and curl's
+/// `--pinnedpubkey`. Parsed from its base64 encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpkiPin(pub [u8; 32]);
+
+impl SpkiPin {
+    /// The pin of a DER SubjectPublicKeyInfo.
+    pub fn of(spki: &[u8]) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+        Self(digest.as_ref().try_into().expect("SHA-256 digests are 32 bytes"))
+    }
+}
+
+impl FromStr for SpkiPin {
+    type Err = String;
+
+    fn from_str(pin: &str) -> Result<Self, Self::Err> {
+        STANDARD
+            .decode(pin)
+            .ok()
+            .and_then(|hash| hash.try_into().ok())
+            .map(Self)
+            .ok_or_else(|| format!("Invalid SPKI pin, expected a base64 SHA-256 hash: {}", pin))
+    }
+}
+
+/// Verifies gateway certificates as usual, then also requires the end-entity certificate's
+/// public key to match one of the pins.
+#[derive(Debug)]
+pub(crate) struct PinnedVerifier {
+    pub(crate) inner: Arc<WebPkiServerVerifier>,
+    pub(crate) pins: Vec<SpkiPin>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let spki = spki(end_entity).ok_or(CertificateError::BadEncoding)?;
+        if !self.pins.contains(&SpkiPin::of(spki)) {
+            return Err(rustls::Error::General("Gateway public key is not pinned".to_owned()));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+const SEQUENCE: u8 = 0x30;
+const EXPLICIT_VERSION: u8 = 0xa0;
+
+/// The DER SubjectPublicKeyInfo of a certificate, the seventh field of its TBSCertificate
+/// counting the optional version.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (certificate, _) = element(cert, SEQUENCE)?;
+    let (tbs, _) = element(certificate.contents, SEQUENCE)?;
+    let mut fields = tbs.contents;
+    if fields.first() == Some(&EXPLICIT_VERSION) {
+        fields = element(fields, EXPLICIT_VERSION)?.1;
+    }
+    // The serial number, signature algorithm, issuer, validity and subject.
+    for _ in 0..5 {
+        let tag = *fields.first()?;
+        fields = element(fields, tag)?.1;
+    }
+    element(fields, SEQUENCE).map(|(spki, _)| spki.encoded)
+}
+
+struct Element<'a> {
+    encoded: &'a [u8],
+    contents: &'a [u8],
+}
+
+/// The DER element with `tag` that `der` starts with, and the bytes following it.
+fn element(der: &[u8], tag: u8) -> Option<(Element<'_>, &[u8])> {
+    if *der.first()? != tag {
+        return None;
+    }
+    let (header_len, len) = match *der.get(1)? {
+        short if short < 0x80 => (2, short as usize),
+        long => {
+            let len_bytes = der.get(2..2 + (long & 0x7f) as usize).filter(|b| b.len() <= 4)?;
+            let len = len_bytes.iter().fold(0, |len, &byte| len << 8 | byte as usize);
+            (2 + len_bytes.len(), len)
+        }
+    };
+    let end = header_len.checked_add(len).filter(|&end| end <= der.len())?;
+    let element = Element { encoded: &der[..end], contents: &der[header_len..end] };
+    Some((element, &der[end..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spki_found_in_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["0.0.0.0".to_string()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        assert_eq!(spki(&der).unwrap(), cert.get_key_pair().public_key_der());
+        assert_eq!(spki(&der[..der.len() - 1]), None);
+    }
+
+    #[test]
+    fn pin_parsed_from_base64() {
+        let pin = SpkiPin::of(b"key");
+        assert_eq!(STANDARD.encode(pin.0).parse(), Ok(pin));
+        assert!("c2hvcnQ=".parse::<SpkiPin>().is_err());
+        assert!("not base64!".parse::<SpkiPin>().is_err());
+    }
+}
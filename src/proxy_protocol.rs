@@ -0,0 +1,141 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header, including its CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read the PROXY protocol v1 or v2 header, as sent by HAProxy or an AWS NLB, from the start
+/// of `stream` without consuming anything after it.
+///
+/// Returns the original client's address, or `None` when the proxy opened the connection
+/// itself (e.g. for a health check) or did not name a TCP client.
+pub(crate) async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both the v2 signature and the shortest v1 header are at least this long.
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("Missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S>(stream: &mut S, start: &[u8]) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = start.to_vec();
+    // Read a byte at a time so none of the request that follows is consumed.
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| invalid("Invalid PROXY protocol v1 header"))?;
+    parse_v1(line).ok_or_else(|| invalid("Invalid PROXY protocol v1 header"))
+}
+
+/// Parse the fields of a v1 header after `PROXY `, e.g. `TCP4 192.0.2.1 192.0.2.2 5000 443`.
+fn parse_v1(fields: &str) -> Option<Option<SocketAddr>> {
+    let mut fields = fields.split(' ');
+    match fields.next()? {
+        "UNKNOWN" => Some(None),
+        "TCP4" | "TCP6" => {
+            let source: IpAddr = fields.next()?.parse().ok()?;
+            let _destination: IpAddr = fields.next()?.parse().ok()?;
+            let port: u16 = fields.next()?.parse().ok()?;
+            let _destination_port: u16 = fields.next()?.parse().ok()?;
+            Some(Some(SocketAddr::new(source, port)))
+        }
+        _ => None,
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len @ ..] = header;
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    let mut addresses = vec![0; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut addresses).await?;
+    match version_command & 0x0f {
+        0 => Ok(None),
+        1 => Ok(parse_v2_source(family, &addresses)),
+        _ => Err(invalid("Unsupported PROXY protocol command")),
+    }
+}
+
+/// The source address from a v2 address block, if `family` is IPv4 or IPv6.
+fn parse_v2_source(family: u8, addresses: &[u8]) -> Option<SocketAddr> {
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().ok()?;
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().ok()?;
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        _ => None,
+    }
+}
+
+fn invalid(message: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, message) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read(input: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = input;
+        let header = read_header(&mut stream).await;
+        (header, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1_header_read() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 192.0.2.2 5000 443\r\nPOST /").await;
+        assert_eq!(header.unwrap(), Some(SocketAddr::from(([192, 0, 2, 1], 5000))));
+        assert_eq!(rest, b"POST /");
+
+        let (header, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(header.unwrap(), None);
+
+        let (header, _) = read(b"POST / HTTP/1.1\r\n").await;
+        assert!(header.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_header_read() {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        input.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0x13, 0x88, 0x01, 0xbb]);
+        input.extend_from_slice(b"POST /");
+        let (header, rest) = read(&input).await;
+        assert_eq!(header.unwrap(), Some(SocketAddr::from(([192, 0, 2, 1], 5000))));
+        assert_eq!(rest, b"POST /");
+
+        // LOCAL connections from the proxy itself name no client.
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read(&input).await.0.unwrap(), None);
+    }
+}
@@ -0,0 +1,242 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use h3::error::StreamError;
+use h3::server::{RequestResolver, RequestStream};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Buf, Bytes, Frame};
+use hyper::header::{CONNECTION, TRANSFER_ENCODING, UPGRADE};
+use hyper::{Request, Response};
+use quinn::crypto::rustls::QuicServerConfig;
+use tokio::sync::mpsc;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info};
+
+use crate::body::BoxError;
+use crate::{events, serve_ohttp_relay, Relay, RelayError};
+
+/// Bind a QUIC endpoint on `addr` terminating TLS with `tls_config`, letting each connection
+/// open at most `max_streams` request streams at once.
+pub(crate) fn bind(
+    addr: SocketAddr,
+    tls_config: Arc<quinn::rustls::ServerConfig>,
+    max_streams: Option<u32>,
+) -> Result<quinn::Endpoint, RelayError> {
+    let crypto = QuicServerConfig::try_from(tls_config).map_err(|e| RelayError::Tls(e.into()))?;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    if let Some(max) = max_streams {
+        let mut transport = quinn::TransportConfig::default();
+        transport.max_concurrent_bidi_streams(max.into());
+        server_config.transport_config(Arc::new(transport));
+    }
+    Ok(quinn::Endpoint::server(server_config, addr)?)
+}
+
+/// Serve the relay over HTTP/3 on every connection to `endpoint` until shutdown, accepting
+/// WebTransport bootstrap sessions if `webtransport` is set.
+pub(crate) async fn serve(endpoint: quinn::Endpoint, relay: Arc<Relay>, webtransport: bool) {
+    let connections = TaskTracker::new();
+    let shutdown = relay.config.shutdown.clone();
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        let relay = relay.clone();
+        connections.spawn(async move {
+            if let Err(e) = serve_connection(incoming, relay, webtransport).await {
+                debug!("Error serving HTTP/3 connection: {}", e);
+            }
+        });
+    }
+
+    connections.close();
+    match relay.config.shutdown_timeout {
+        Some(timeout) =>
+            if tokio::time::timeout(timeout, connections.wait()).await.is_err() {
+                info!("Shutdown timed out with {} connections still open", connections.len());
+            },
+        None => connections.wait().await,
+    }
+    endpoint.close(0u32.into(), b"");
+    endpoint.wait_idle().await;
+}
+
+/// Serve each request on a connection until the client closes it or the relay drains it.
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    relay: Arc<Relay>,
+    webtransport: bool,
+) -> Result<(), BoxError> {
+    let conn = incoming.await?;
+    let peer_addr = Some(conn.remote_address());
+    let _open = relay.metrics.connection_opened();
+    let _connection = events::Connection::accepted(&relay.config.events);
+    let mut conn = h3::server::builder()
+        .enable_webtransport(webtransport)
+        .enable_extended_connect(webtransport)
+        .enable_datagram(webtransport)
+        .max_webtransport_sessions(u64::from(webtransport))
+        .build(h3_quinn::Connection::new(conn))
+        .await?;
+    let requests = TaskTracker::new();
+    let shutdown = relay.config.shutdown.clone();
+    let mut drain = relay.drain.subscribe();
+    let mut served = 0;
+    loop {
+        let resolver = tokio::select! {
+            resolver = conn.accept() => match resolver? {
+                Some(resolver) => resolver,
+                None => break,
+            },
+            // Finish in-flight requests but accept no new ones on this connection.
+            _ = shutdown.cancelled() => {
+                conn.shutdown(0).await?;
+                break;
+            }
+            _ = drain.changed() => {
+                debug!("Draining connection after reload");
+                conn.shutdown(0).await?;
+                break;
+            }
+        };
+        let (req, stream) = match resolve(resolver, relay.config.header_read_timeout).await {
+            Some(resolved) => resolved,
+            None => continue,
+        };
+        #[cfg(feature = "wt-bootstrap")]
+        if webtransport && crate::bootstrap::wt::is_webtransport_request(&req) {
+            // The session takes over the connection.
+            let result =
+                crate::bootstrap::wt::serve_session(req, stream, conn, peer_addr, relay).await;
+            requests.close();
+            requests.wait().await;
+            return result;
+        }
+        requests.spawn(serve_request(req, stream, peer_addr, relay.clone()));
+        served += 1;
+        if relay.config.max_requests_per_connection == Some(served) {
+            debug!("Closing connection that reached its maximum number of requests");
+            conn.shutdown(0).await?;
+            break;
+        }
+    }
+    // Dropping the connection closes it, so keep it until its requests are answered.
+    requests.close();
+    requests.wait().await;
+    Ok(())
+}
+
+type Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Read a request's headers, giving up on it after `timeout`.
+async fn resolve(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    timeout: Option<Duration>,
+) -> Option<(Request<()>, Stream)> {
+    let resolved = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, resolver.resolve_request()).await {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                debug!("Timed out reading HTTP/3 request headers");
+                return None;
+            }
+        },
+        None => resolver.resolve_request().await,
+    };
+    resolved.map_err(|e| debug!("Failed to read HTTP/3 request: {}", e)).ok()
+}
+
+/// Serve a relay request received on `stream`.
+pub(crate) async fn serve_request(
+    req: Request<()>,
+    stream: Stream,
+    peer_addr: Option<SocketAddr>,
+    relay: Arc<Relay>,
+) {
+    let (mut send, recv) = stream.split();
+    let req = req.map(|()| RequestBody::spawn(recv));
+    let deadline = relay.config.request_deadline.map(|t| tokio::time::Instant::now() + t);
+    let res = serve_ohttp_relay(req, peer_addr, deadline, relay).await;
+    if let Err(e) = async { respond(&mut send, res?).await }.await {
+        debug!("Error serving HTTP/3 request: {}", e);
+    }
+}
+
+/// Send `res` on `stream`.
+pub(crate) async fn respond<S>(
+    stream: &mut RequestStream<S, Bytes>,
+    res: Response<BoxBody<Bytes, hyper::Error>>,
+) -> Result<(), BoxError>
+where
+    S: h3::quic::SendStream<Bytes>,
+{
+    let (mut parts, mut body) = res.into_parts();
+    // HTTP/3 has no connection-specific headers.
+    for name in [CONNECTION, TRANSFER_ENCODING, UPGRADE] {
+        parts.headers.remove(name);
+    }
+    stream.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => stream.send_data(data).await?,
+            Err(frame) =>
+                if let Ok(trailers) = frame.into_trailers() {
+                    stream.send_trailers(trailers).await?;
+                },
+        }
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+/// A request body read from an HTTP/3 stream on its own task, so it can be forwarded like any
+/// other body.
+#[derive(Debug)]
+pub(crate) struct RequestBody {
+    frames: mpsc::Receiver<Result<Frame<Bytes>, StreamError>>,
+}
+
+impl RequestBody {
+    fn spawn(mut stream: RequestStream<h3_quinn::RecvStream, Bytes>) -> Self {
+        let (sender, frames) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let frame = match stream.recv_data().await {
+                    Ok(Some(mut data)) => Ok(Frame::data(data.copy_to_bytes(data.remaining()))),
+                    Ok(None) => match stream.recv_trailers().await {
+                        Ok(Some(trailers)) => Ok(Frame::trailers(trailers)),
+                        Ok(None) => break,
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                };
+                let last = frame.as_ref().map_or(true, Frame::is_trailers);
+                // Stop reading once the relay no longer wants the body.
+                if sender.send(frame).await.is_err() || last {
+                    break;
+                }
+            }
+        });
+        Self { frames }
+    }
+}
+
+impl Body for RequestBody {
+    type Data = Bytes;
+    type Error = StreamError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.frames.poll_recv(cx)
+    }
+}
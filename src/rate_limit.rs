@@ -0,0 +1,136 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::error::Error;
+use crate::RateLimit;
+
+/// Token buckets keyed on a salted hash of the client's address.
+///
+/// Addresses are never stored. The salt is replaced and every bucket forgotten each
+/// [`RateLimit::salt_rotation`], so the keys cannot be linked across rotations either. Buckets
+/// that have refilled are forgotten sooner, so the map only holds recently active clients.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    salt: RandomState,
+    salted_at: Instant,
+    swept_at: Instant,
+    buckets: HashMap<u64, Bucket>,
+}
+
+impl State {
+    fn new(now: Instant) -> Self {
+        Self { salt: RandomState::new(), salted_at: now, swept_at: now, buckets: HashMap::new() }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self { limit, state: Mutex::new(State::new(Instant::now())) }
+    }
+
+    pub(crate) fn limit(&self) -> &RateLimit { &self.limit }
+
+    /// Take a token from the bucket for `ip`, or answer 429 Too Many Requests with how long
+    /// until one is available.
+    pub(crate) fn check(&self, ip: IpAddr) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("rate limiter poisoned");
+        if now.duration_since(state.salted_at) >= self.limit.salt_rotation {
+            *state = State::new(now);
+        }
+        let burst = f64::from(self.limit.burst);
+        let per_second = f64::from(self.limit.per_second);
+        // A bucket left alone this long is full again, no different from a new one.
+        let refilled = Duration::from_secs_f64(burst / per_second);
+        if now.duration_since(state.swept_at) >= refilled {
+            state.buckets.retain(|_, bucket| now.duration_since(bucket.updated) < refilled);
+            state.swept_at = now;
+        }
+        let key = key(&state.salt, ip);
+        let bucket = state.buckets.entry(key).or_insert(Bucket { tokens: burst, updated: now });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = ((1.0 - bucket.tokens) / per_second).ceil();
+            Err(Error::TooManyRequests { retry_after: Duration::from_secs(wait as u64) })
+        }
+    }
+}
+
+/// The salted hash identifying `ip`'s bucket. IPv6 clients are keyed by their /64 prefix,
+/// since a single host commonly controls a whole /64.
+fn key(salt: &RandomState, ip: IpAddr) -> u64 {
+    let mut hasher = salt.build_hasher();
+    match ip {
+        IpAddr::V4(ip) => ip.octets().hash(&mut hasher),
+        IpAddr::V6(ip) => ip.octets()[..8].hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimit { burst: 2, per_second: 1, ..RateLimit::default() })
+    }
+
+    #[test]
+    fn burst_then_limited() {
+        let limiter = limiter();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        assert!(limiter.check(ip).is_ok());
+        assert!(limiter.check(ip).is_ok());
+        assert!(matches!(
+            limiter.check(ip),
+            Err(Error::TooManyRequests { retry_after }) if retry_after == Duration::from_secs(1)
+        ));
+        // Other clients have their own buckets.
+        assert!(limiter.check(IpAddr::from([192, 0, 2, 2])).is_ok());
+    }
+
+    #[test]
+    fn refilled_buckets_forgotten() {
+        let limiter =
+            RateLimiter::new(RateLimit { burst: 1, per_second: 1000, ..RateLimit::default() });
+        assert!(limiter.check(IpAddr::from([192, 0, 2, 1])).is_ok());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(limiter.check(IpAddr::from([192, 0, 2, 2])).is_ok());
+        assert_eq!(limiter.state.lock().unwrap().buckets.len(), 1);
+    }
+
+    #[test]
+    fn ipv6_keyed_by_prefix() {
+        let limiter = limiter();
+        let a: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let b: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        assert!(limiter.check(a.into()).is_ok());
+        assert!(limiter.check(b.into()).is_ok());
+        assert!(limiter.check(a.into()).is_err());
+    }
+}
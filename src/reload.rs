@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+use crate::Config;
+
+/// Hands new settings to a running relay.
+///
+/// Keep a clone of [`Config::reload`] and call [`Reload::reload`] with the new [`Config`].
+/// Its `allowed_gateways`, `denied_gateways`, `named_gateways`, `gateway_replicas`,
+/// `connect_targets`, `health_check`, `circuit_breaker`, `rate_limit` and `max_body_size` replace the running ones
+/// for new requests. Open connections and in-flight requests are unaffected, and every other
+/// setting keeps its value from startup.
+#[derive(Clone)]
+pub struct Reload(Arc<watch::Sender<Option<Config>>>);
+
+impl Reload {
+    /// Apply the reloadable settings of `config` to the relay.
+    pub fn reload(&self, config: Config) { self.0.send_replace(Some(config)); }
+
+    /// The settings last sent, if any.
+    pub(crate) fn current(&self) -> Option<Config> { self.0.borrow().clone() }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Option<Config>> { self.0.subscribe() }
+}
+
+impl Default for Reload {
+    fn default() -> Self { Self(Arc::new(watch::channel(None).0)) }
+}
+
+// A reloaded `Config` usually holds this same handle, so printing its contents would recurse.
+impl std::fmt::Debug for Reload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reload").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,27 @@
+use http::header::{HeaderName, HeaderValue};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Carries the ID of the request an error response answers.
+pub(crate) static REQUEST_ID: HeaderName = HeaderName::from_static("request-id");
+
+/// A random ID for one request, to find a failure a client reports in the relay's logs. It is
+/// drawn fresh for every request, so it tells nothing about the client.
+pub(crate) fn generate() -> HeaderValue {
+    let mut id = [0; 8];
+    SystemRandom::new().fill(&mut id).expect("system randomness is available");
+    let hex: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+    HeaderValue::try_from(hex).expect("hex digits are a valid header value")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ids_are_random_hex() {
+        let (a, b) = (generate(), generate());
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 16);
+        assert!(a.to_str().unwrap().bytes().all(|byte| byte.is_ascii_hexdigit()));
+    }
+}
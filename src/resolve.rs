@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::Uri;
+use tracing::{debug, info, warn};
+
+use crate::gateway_uri::internal_ip_kind;
+pub use crate::svcb::ServiceBinding;
+use crate::{svcb, DnsCache};
+
+/// Looks up the addresses of gateway hosts, for forwarded requests and bootstrap tunnels alike.
+///
+/// Implement this to resolve with DNS-over-HTTPS or a fixed table instead of the system
+/// resolver. IP address literals are never passed to the resolver.
+pub trait Resolver: Debug + Send + Sync {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>>;
+
+    /// The HTTPS records of `name`, for [`Config::gateway_discovery`](crate::Config). None by
+    /// default.
+    fn resolve_https<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<ServiceBinding>>> + Send + 'a>> {
+        let _ = name;
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    /// Like [`Resolver::resolve`], with how long the addresses may be cached if the resolver
+    /// knows, e.g. from the records' TTLs, for [`Config::dns_cache`](crate::Config). Unknown by
+    /// default.
+    #[allow(clippy::type_complexity)]
+    fn resolve_with_ttl<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<(Vec<IpAddr>, Option<Duration>)>> + Send + 'a>>
+    {
+        Box::pin(async move { Ok((self.resolve(host).await?, None)) })
+    }
+
+    /// Forget any lookups cached so far, as `POST /dns/flush` on the admin listener asks.
+    /// Nothing is cached by default.
+    fn flush(&self) {}
+}
+
+/// Resolves with the operating system's resolver on a blocking thread, so lookups never stall
+/// the async runtime. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver {
+    /// The DNS server HTTPS records are queried from, or the first `nameserver` of
+    /// `/etc/resolv.conf` if `None`.
+    pub nameserver: Option<SocketAddr>,
+}
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+        Box::pin(async move {
+            Ok(tokio::net::lookup_host((host, 0)).await?.map(|addr| addr.ip()).collect())
+        })
+    }
+
+    /// Queries [`SystemResolver::nameserver`], over UDP and then TCP if the answer is
+    /// truncated, finding none without a nameserver.
+    fn resolve_https<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<ServiceBinding>>> + Send + 'a>> {
+        Box::pin(async move {
+            match self.nameserver.or_else(svcb::system_nameserver) {
+                Some(nameserver) => svcb::query(nameserver, name).await,
+                None => Ok(Vec::new()),
+            }
+        })
+    }
+}
+
+/// Pins each gateway host to the addresses it resolved to for an interval, refusing internal
+/// addresses unless allowed, so a rebinding DNS name cannot point the relay at internal
+/// services between lookups.
+#[derive(Debug)]
+pub(crate) struct PinnedResolver {
+    inner: Arc<dyn Resolver>,
+    interval: Option<Duration>,
+    allow_internal: bool,
+    pins: Mutex<HashMap<String, Pinned>>,
+}
+
+#[derive(Debug)]
+struct Pinned {
+    ips: Vec<IpAddr>,
+    until: Instant,
+}
+
+impl PinnedResolver {
+    pub(crate) fn new(
+        inner: Arc<dyn Resolver>,
+        interval: Option<Duration>,
+        allow_internal: bool,
+    ) -> Self {
+        Self { inner, interval, allow_internal, pins: Mutex::default() }
+    }
+
+    fn pinned(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let pins = self.pins.lock().expect("DNS pins poisoned");
+        pins.get(host).filter(|pin| Instant::now() < pin.until).map(|pin| pin.ips.clone())
+    }
+
+    /// The addresses of `resolved` the relay may connect to.
+    fn validate(&self, host: &str, mut resolved: Vec<IpAddr>) -> io::Result<Vec<IpAddr>> {
+        if !self.allow_internal {
+            resolved.retain(|ip| match internal_ip_kind(*ip) {
+                Some(kind) => {
+                    warn!("Ignoring {} address {} of gateway {}", kind, ip, host);
+                    false
+                }
+                None => true,
+            });
+            if resolved.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} only resolves to internal addresses", host),
+                ));
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn pin(&self, host: &str, ips: &[IpAddr], interval: Duration) {
+        let mut pins = self.pins.lock().expect("DNS pins poisoned");
+        let until = Instant::now() + interval;
+        match pins.get_mut(host) {
+            Some(pin) => {
+                if pin.ips != ips {
+                    info!("Gateway {} now resolves to {:?}", host, ips);
+                    pin.ips = ips.to_vec();
+                }
+                pin.until = until;
+            }
+            None => {
+                pins.insert(host.to_string(), Pinned { ips: ips.to_vec(), until });
+            }
+        }
+    }
+}
+
+impl Resolver for PinnedResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+        Box::pin(async move {
+            if let Some(ips) = self.pinned(host) {
+                return Ok(ips);
+            }
+            let ips = self.validate(host, self.inner.resolve(host).await?)?;
+            if let Some(interval) = self.interval {
+                self.pin(host, &ips, interval);
+            }
+            Ok(ips)
+        })
+    }
+
+    /// Not pinned, since the endpoints the records name are resolved through the pins.
+    fn resolve_https<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<ServiceBinding>>> + Send + 'a>> {
+        self.inner.resolve_https(name)
+    }
+
+    fn flush(&self) {
+        self.pins.lock().expect("DNS pins poisoned").clear();
+        self.inner.flush();
+    }
+}
+
+/// Caches lookups as [`Config::dns_cache`](crate::Config) says, so every connection to a
+/// gateway does not wait for the resolver.
+#[derive(Debug)]
+pub(crate) struct CachingResolver {
+    inner: Arc<dyn Resolver>,
+    settings: DnsCache,
+    entries: Mutex<HashMap<String, Cached>>,
+}
+
+#[derive(Debug)]
+struct Cached {
+    /// The addresses, or the kind and message of the error the lookup failed with.
+    ips: Result<Vec<IpAddr>, (io::ErrorKind, String)>,
+    until: Instant,
+}
+
+impl CachingResolver {
+    pub(crate) fn new(inner: Arc<dyn Resolver>, settings: DnsCache) -> Self {
+        Self { inner, settings, entries: Mutex::default() }
+    }
+
+    fn cached(&self, host: &str) -> Option<io::Result<(Vec<IpAddr>, Option<Duration>)>> {
+        let entries = self.entries.lock().expect("DNS cache poisoned");
+        let cached = entries.get(host)?;
+        let ttl =
+            cached.until.checked_duration_since(Instant::now()).filter(|ttl| !ttl.is_zero())?;
+        Some(match &cached.ips {
+            Ok(ips) => Ok((ips.clone(), Some(ttl))),
+            Err((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+        })
+    }
+
+    fn insert(&self, host: &str, ips: Result<Vec<IpAddr>, (io::ErrorKind, String)>, ttl: Duration) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("DNS cache poisoned");
+        entries.retain(|_, cached| now < cached.until);
+        entries.insert(host.to_owned(), Cached { ips, until: now + ttl });
+    }
+}
+
+impl Resolver for CachingResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+        Box::pin(async move { Ok(self.resolve_with_ttl(host).await?.0) })
+    }
+
+    fn resolve_https<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<ServiceBinding>>> + Send + 'a>> {
+        self.inner.resolve_https(name)
+    }
+
+    fn resolve_with_ttl<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<(Vec<IpAddr>, Option<Duration>)>> + Send + 'a>>
+    {
+        Box::pin(async move {
+            if let Some(cached) = self.cached(host) {
+                return cached;
+            }
+            match self.inner.resolve_with_ttl(host).await {
+                Ok((ips, ttl)) => {
+                    let ttl = ttl.unwrap_or(self.settings.default_ttl).min(self.settings.max_ttl);
+                    self.insert(host, Ok(ips.clone()), ttl);
+                    Ok((ips, Some(ttl)))
+                }
+                Err(e) => {
+                    debug!(
+                        "Caching failed lookup of {} for {:?}",
+                        host, self.settings.negative_ttl
+                    );
+                    self.insert(host, Err((e.kind(), e.to_string())), self.settings.negative_ttl);
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    fn flush(&self) {
+        self.entries.lock().expect("DNS cache poisoned").clear();
+        self.inner.flush();
+    }
+}
+
+/// Every address of the host in `uri`, with the port it names or its scheme's default.
+pub(crate) async fn resolve_uri(uri: &Uri, resolver: &dyn Resolver) -> io::Result<Vec<SocketAddr>> {
+    let host = uri.host().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No host"))?;
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+    let ips = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => resolver.resolve(host).await?,
+    };
+    if ips.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host)));
+    }
+    Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Resolves every host to the same address.
+    #[derive(Debug)]
+    struct Fixed(IpAddr);
+
+    impl Resolver for Fixed {
+        fn resolve<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+            Box::pin(async move { Ok(vec![self.0]) })
+        }
+    }
+
+    /// Resolves every host to the address it was last set to, failing until one is, counting
+    /// lookups.
+    #[derive(Debug, Default)]
+    struct Changing {
+        ip: Mutex<Option<IpAddr>>,
+        lookups: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Changing {
+        fn set(&self, ip: [u8; 4]) { *self.ip.lock().unwrap() = Some(IpAddr::from(ip)); }
+
+        fn ip(&self) -> IpAddr { self.ip.lock().unwrap().expect("address set") }
+
+        fn lookups(&self) -> usize { self.lookups.load(std::sync::atomic::Ordering::Relaxed) }
+    }
+
+    impl Resolver for Changing {
+        fn resolve<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+            self.lookups.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let ip = *self.ip.lock().unwrap();
+            Box::pin(
+                async move { ip.map(|ip| vec![ip]).ok_or_else(|| io::ErrorKind::NotFound.into()) },
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_until_interval_passes() {
+        let inner = Arc::new(Changing::default());
+        let pinned = PinnedResolver::new(inner.clone(), Some(Duration::from_secs(60)), false);
+        inner.set([192, 0, 2, 1]);
+        assert_eq!(pinned.resolve("gateway.example").await.unwrap(), [inner.ip()]);
+        // A rebinding answer is never seen while the pin holds.
+        inner.set([10, 0, 0, 1]);
+        assert_eq!(
+            pinned.resolve("gateway.example").await.unwrap(),
+            [IpAddr::from([192, 0, 2, 1])]
+        );
+        assert_eq!(inner.lookups(), 1);
+    }
+
+    #[tokio::test]
+    async fn internal_addresses_refused_on_change() {
+        let inner = Arc::new(Changing::default());
+        let pinned = PinnedResolver::new(inner.clone(), Some(Duration::ZERO), false);
+        inner.set([192, 0, 2, 1]);
+        assert!(pinned.resolve("gateway.example").await.is_ok());
+        for internal in [[10, 0, 0, 1], [169, 254, 169, 254], [127, 0, 0, 1]] {
+            inner.set(internal);
+            let err = pinned.resolve("gateway.example").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+
+        let allowed = PinnedResolver::new(inner.clone(), None, true);
+        assert_eq!(allowed.resolve("gateway.example").await.unwrap(), [inner.ip()]);
+    }
+
+    #[tokio::test]
+    async fn cached_until_flushed() {
+        let inner = Arc::new(Changing::default());
+        let caching = CachingResolver::new(inner.clone(), DnsCache::default());
+        let resolver = PinnedResolver::new(Arc::new(caching), None, true);
+        // Failed lookups are answered from the cache too.
+        assert!(resolver.resolve("gateway.example").await.is_err());
+        inner.set([192, 0, 2, 1]);
+        assert!(resolver.resolve("gateway.example").await.is_err());
+        assert_eq!(inner.lookups(), 1);
+
+        resolver.flush();
+        assert_eq!(resolver.resolve("gateway.example").await.unwrap(), [inner.ip()]);
+        inner.set([192, 0, 2, 2]);
+        assert_eq!(
+            resolver.resolve("gateway.example").await.unwrap(),
+            [IpAddr::from([192, 0, 2, 1])]
+        );
+        assert_eq!(inner.lookups(), 2);
+    }
+
+    #[tokio::test]
+    async fn cached_ttl_clamped() {
+        let settings = DnsCache { max_ttl: Duration::from_secs(10), ..DnsCache::default() };
+        let caching = CachingResolver::new(Arc::new(Fixed(IpAddr::from([192, 0, 2, 1]))), settings);
+        let (_, ttl) = caching.resolve_with_ttl("gateway.example").await.unwrap();
+        assert!(ttl.unwrap() <= Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn uri_resolved_with_default_port() {
+        let resolver = Fixed(IpAddr::from([192, 0, 2, 1]));
+        let addrs =
+            resolve_uri(&Uri::from_static("https://gateway.example"), &resolver).await.unwrap();
+        assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 1], 443))]);
+
+        // Literals bypass the resolver.
+        let addrs = resolve_uri(&Uri::from_static("http://[::1]:8080"), &resolver).await.unwrap();
+        assert_eq!(addrs, [SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 8080))]);
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use crate::jitter::random;
+use crate::Retry;
+
+/// The delay before the `retry`th retry, counting from 1, shortened by up to half at random.
+/// The jitter comes from the system's randomness, so retries cannot be told apart by their
+/// timing or line up across requests.
+pub(crate) fn backoff(policy: &Retry, retry: u32) -> Duration {
+    let doublings = retry.saturating_sub(1).min(31);
+    let delay = policy.initial_backoff.saturating_mul(1 << doublings).min(policy.max_backoff);
+    delay - delay.mul_f64(random() / 2.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max_with_jitter() {
+        let policy = Retry {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        for (retry, full) in [(1, 100), (2, 200), (3, 400), (4, 500), (40, 500)] {
+            let delay = backoff(&policy, retry);
+            let full = Duration::from_millis(full);
+            assert!(delay <= full && delay >= full / 2, "{:?} for retry {}", delay, retry);
+        }
+        let delays: Vec<_> = (0..10).map(|_| backoff(&policy, 1)).collect();
+        assert!(delays.windows(2).any(|pair| pair[0] != pair[1]), "{:?}", delays);
+    }
+}
@@ -0,0 +1,136 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use http::uri::PathAndQuery;
+use hyper::{HeaderMap, StatusCode};
+
+use crate::gateway_uri::gateway_in_path;
+use crate::GatewayUri;
+
+/// What a [`GatewaySelector`] sees of a relayed request: where it comes from, what it targets
+/// and its headers, but never the encapsulated body.
+#[derive(Debug)]
+pub struct SelectMeta<'a> {
+    /// The address of the connected peer, or `None` when the relay is not serving TCP.
+    pub peer_addr: Option<SocketAddr>,
+    /// The path and query the client requested from the relay.
+    pub target: &'a PathAndQuery,
+    pub headers: &'a HeaderMap,
+}
+
+/// Where a relayed request is forwarded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub gateway: GatewayUri,
+    /// The path and query to request on the gateway, before [`crate::Config::path_rewrite`].
+    pub path_and_query: PathAndQuery,
+}
+
+/// Chooses the gateway each relayed OHTTP request is forwarded to, e.g. by tenant, region or
+/// for A/B tests.
+pub trait GatewaySelector: Debug + Send + Sync {
+    /// The gateway for `req`, or the status to answer the client with instead.
+    fn select(&self, req: &SelectMeta<'_>) -> Result<Selection, StatusCode>;
+}
+
+/// Forwards every request to one gateway with its target unchanged.
+#[derive(Debug, Clone)]
+pub struct SingleGateway(pub GatewayUri);
+
+impl GatewaySelector for SingleGateway {
+    fn select(&self, req: &SelectMeta<'_>) -> Result<Selection, StatusCode> {
+        Ok(Selection { gateway: self.0.clone(), path_and_query: req.target.clone() })
+    }
+}
+
+/// Spreads requests over gateways in turn, with their targets unchanged. Answers 503 Service
+/// Unavailable when there are none.
+#[derive(Debug)]
+pub struct RoundRobin {
+    gateways: Vec<GatewayUri>,
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new(gateways: Vec<GatewayUri>) -> Self { Self { gateways, next: AtomicUsize::new(0) } }
+}
+
+impl GatewaySelector for RoundRobin {
+    fn select(&self, req: &SelectMeta<'_>) -> Result<Selection, StatusCode> {
+        if self.gateways.is_empty() {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed) % self.gateways.len();
+        let gateway = self.gateways[next].clone();
+        Ok(Selection { gateway, path_and_query: req.target.clone() })
+    }
+}
+
+/// Lets clients pick a gateway from `allowed` by prefixing the request path with its origin,
+/// as in `/https://gateway.example/path`, and leaves every other request to `fallback`.
+/// Origins not on the list are refused with 403 Forbidden.
+#[derive(Debug, Clone)]
+pub struct AllowlistByPath<S> {
+    pub allowed: Vec<GatewayUri>,
+    pub fallback: S,
+}
+
+impl<S: GatewaySelector> GatewaySelector for AllowlistByPath<S> {
+    fn select(&self, req: &SelectMeta<'_>) -> Result<Selection, StatusCode> {
+        let named = gateway_in_path(req.target).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let (origin, path_and_query) = match named {
+            Some(named) => named,
+            None => return self.fallback.select(req),
+        };
+        let gateway = self
+            .allowed
+            .iter()
+            .find(|allowed| allowed.same_origin(&origin))
+            .ok_or(StatusCode::FORBIDDEN)?;
+        Ok(Selection { gateway: gateway.clone(), path_and_query })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::Uri;
+
+    use super::*;
+
+    fn gateway(origin: &'static str) -> GatewayUri {
+        GatewayUri::new(Uri::from_static(origin), false).unwrap()
+    }
+
+    fn select(
+        selector: &dyn GatewaySelector,
+        target: &'static str,
+    ) -> Result<Selection, StatusCode> {
+        let target = PathAndQuery::from_static(target);
+        let headers = HeaderMap::new();
+        selector.select(&SelectMeta { peer_addr: None, target: &target, headers: &headers })
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let (a, b) = (gateway("https://a.example"), gateway("https://b.example"));
+        let selector = RoundRobin::new(vec![a.clone(), b.clone()]);
+        let picked: Vec<_> = (0..3).map(|_| select(&selector, "/").unwrap().gateway).collect();
+        assert_eq!(picked, [a.clone(), b, a]);
+        assert_eq!(select(&RoundRobin::new(Vec::new()), "/"), Err(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[test]
+    fn allowlist_selects_by_path() {
+        let (default, allowed) = (gateway("https://a.example"), gateway("https://b.example"));
+        let selector = AllowlistByPath {
+            allowed: vec![allowed.clone()],
+            fallback: SingleGateway(default.clone()),
+        };
+        let selection = select(&selector, "/https://b.example/ohttp?x=1").unwrap();
+        assert_eq!(selection.gateway, allowed);
+        assert_eq!(selection.path_and_query, "/ohttp?x=1");
+        assert_eq!(select(&selector, "/ohttp").unwrap().gateway, default);
+        assert_eq!(select(&selector, "/https://c.example/"), Err(StatusCode::FORBIDDEN));
+    }
+}
@@ -0,0 +1,170 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::Uri;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes};
+use hyper::{Request, Response};
+use tower_service::Service;
+
+use crate::body::{BoxError, Deadline};
+use crate::error::Error;
+use crate::{serve_ohttp_relay, Config, Relay, RelayError, RunningRelay};
+
+/// The relay as a [`Service`], to mount in an existing hyper or axum server instead of binding
+/// a listener of its own.
+///
+/// Clones share the relay's connection pool, limits and background tasks, which stop once the
+/// last clone is dropped. Settings about listeners and client connections, such as
+/// [`Config::idle_timeout`] or [`Config::bandwidth_limit`], are left to the server.
+#[derive(Clone)]
+pub struct OhttpRelayService {
+    relay: Arc<Relay>,
+    peer_addr: Option<SocketAddr>,
+    /// Stripped from the paths of requests under it. Other requests are not relayed.
+    prefix: Option<Arc<str>>,
+    _running: Option<Arc<RunningRelay>>,
+}
+
+/// Set up a relay forwarding to `gateway_origin` that only serves requests under the path
+/// `prefix`, e.g. `/ohttp`, so it can share an origin with other routes. The prefix is
+/// stripped before the request is handled, so `POST /ohttp` is relayed like `POST /` would be,
+/// and requests outside it are answered with 404 Not Found.
+pub async fn make_service(
+    prefix: &str,
+    gateway_origin: Uri,
+    config: Config,
+) -> Result<OhttpRelayService, RelayError> {
+    OhttpRelayService::new(gateway_origin, config).await?.with_prefix(prefix)
+}
+
+impl OhttpRelayService {
+    /// Set up a relay forwarding to `gateway_origin`. Fails like [`crate::listen_tcp`] would
+    /// before binding.
+    pub async fn new(gateway_origin: Uri, config: Config) -> Result<Self, RelayError> {
+        let running = RunningRelay::start(gateway_origin, config).await?;
+        Ok(Self {
+            relay: running.relay.clone(),
+            peer_addr: None,
+            prefix: None,
+            _running: Some(Arc::new(running)),
+        })
+    }
+
+    /// The service for requests under the path `prefix` only, see [`make_service`].
+    pub fn with_prefix(self, prefix: &str) -> Result<Self, RelayError> {
+        let prefix = prefix.trim_end_matches('/');
+        if !prefix.is_empty() && (!prefix.starts_with('/') || prefix.contains(['?', '#'])) {
+            let e = format!("Invalid path prefix {}, expected one like /ohttp", prefix);
+            return Err(RelayError::Config(e.into()));
+        }
+        let prefix = (!prefix.is_empty()).then(|| prefix.into());
+        Ok(Self { prefix, ..self })
+    }
+
+    /// The service for requests from `peer_addr`, which rate limiting, authorization and
+    /// loopback-only endpoints go by. Without one, requests are treated as coming from a unix
+    /// socket.
+    pub fn with_peer_addr(self, peer_addr: SocketAddr) -> Self {
+        Self { peer_addr: Some(peer_addr), ..self }
+    }
+
+    /// The service for one connection accepted by the relay's own listeners.
+    pub(crate) fn for_connection(relay: Arc<Relay>, peer_addr: Option<SocketAddr>) -> Self {
+        Self { relay, peer_addr, prefix: None, _running: None }
+    }
+}
+
+impl std::fmt::Debug for OhttpRelayService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OhttpRelayService")
+            .field("peer_addr", &self.peer_addr)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl<B> Service<Request<B>> for OhttpRelayService
+where
+    B: Body<Data = Bytes> + std::fmt::Debug + Send + Sync + Unpin + 'static,
+    B::Error: Into<BoxError> + Send + Sync + Unpin + 'static,
+{
+    type Response = Response<BoxBody<Bytes, BoxError>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let req = match &self.prefix {
+            Some(prefix) => match strip_prefix(req, prefix) {
+                Some(req) => req,
+                None => {
+                    let res = Error::NotFound.to_response();
+                    return Box::pin(async {
+                        Ok(res.map(|body| body.map_err(Into::into).boxed()))
+                    });
+                }
+            },
+            None => req,
+        };
+        let relay = self.relay.clone();
+        let deadline = relay.config.request_deadline.map(|t| tokio::time::Instant::now() + t);
+        let metrics = relay.metrics.clone();
+        let res = serve_ohttp_relay(req, self.peer_addr, deadline, relay);
+        Box::pin(async move {
+            let res = res.await?;
+            Ok(res.map(|body| Deadline::new(body, deadline, metrics).boxed()))
+        })
+    }
+}
+
+/// `req` with `prefix` stripped from its path, or `None` if the path is not under it.
+fn strip_prefix<B>(req: Request<B>, prefix: &str) -> Option<Request<B>> {
+    let rest = req.uri().path().strip_prefix(prefix)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let path_and_query = match (rest, req.uri().query()) {
+        ("", None) => "/".to_owned(),
+        ("", Some(query)) => format!("/?{}", query),
+        (rest, None) => rest.to_owned(),
+        (rest, Some(query)) => format!("{}?{}", rest, query),
+    };
+    let (mut parts, body) = req.into_parts();
+    let mut uri = parts.uri.into_parts();
+    uri.path_and_query = Some(path_and_query.parse().ok()?);
+    parts.uri = Uri::from_parts(uri).ok()?;
+    Some(Request::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stripped(uri: &'static str) -> Option<String> {
+        let req = Request::builder().uri(uri).body(()).unwrap();
+        strip_prefix(req, "/ohttp").map(|req| req.uri().to_string())
+    }
+
+    #[test]
+    fn prefix_stripped() {
+        assert_eq!(stripped("/ohttp").unwrap(), "/");
+        assert_eq!(stripped("/ohttp?x=1").unwrap(), "/?x=1");
+        assert_eq!(stripped("/ohttp/health").unwrap(), "/health");
+        assert_eq!(
+            stripped("/ohttp/https://gateway.example/").unwrap(),
+            "/https://gateway.example/"
+        );
+        assert_eq!(stripped("https://relay.example/ohttp/").unwrap(), "https://relay.example/");
+        assert_eq!(stripped("/ohttpx"), None);
+        assert_eq!(stripped("/other/ohttp"), None);
+    }
+}
@@ -0,0 +1,43 @@
+use std::io;
+
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Cancels a relay's shutdown token on the first SIGTERM or SIGINT, see
+/// [`crate::Config::signal_handling`], until dropped.
+#[derive(Debug)]
+pub(crate) struct SignalHandler(JoinHandle<()>);
+
+impl SignalHandler {
+    /// Install the handlers now, so signals arriving before the task first runs are not lost.
+    #[cfg(unix)]
+    pub(crate) fn install(shutdown: CancellationToken) -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        Ok(Self(tokio::spawn(async move {
+            let name = tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            };
+            info!("Received {}, draining connections", name);
+            shutdown.cancel();
+        })))
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn install(shutdown: CancellationToken) -> io::Result<Self> {
+        let mut ctrl_c = tokio::signal::windows::ctrl_c()?;
+        Ok(Self(tokio::spawn(async move {
+            ctrl_c.recv().await;
+            info!("Received Ctrl-C, draining connections");
+            shutdown.cancel();
+        })))
+    }
+}
+
+impl Drop for SignalHandler {
+    fn drop(&mut self) { self.0.abort(); }
+}
@@ -0,0 +1,146 @@
+use std::ffi::CString;
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use tokio::net::UnixListener;
+use tracing::{info, warn};
+
+use crate::SocketFile;
+
+/// Bind a unix socket at `path`, applying `settings` to its file.
+///
+/// A path starting with `@`, e.g. `@ohttp-relay`, names a socket in the Linux abstract
+/// namespace instead, which has no file for `settings` to apply to.
+pub(crate) fn bind(path: &Path, settings: &SocketFile) -> io::Result<UnixListener> {
+    if let Some(name) = abstract_name(path) {
+        return bind_abstract(name);
+    }
+    if settings.remove_stale {
+        remove_stale(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = settings.mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    if let Some(gid) = settings.group {
+        chown_group(path, gid)?;
+    }
+    Ok(listener)
+}
+
+/// The name of the abstract socket `path` stands for, without its leading `@`.
+pub(crate) fn abstract_name(path: &Path) -> Option<&[u8]> {
+    match path.as_os_str().as_bytes() {
+        [b'@', name @ ..] => Some(name),
+        _ => None,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_abstract(name: &[u8]) -> io::Result<UnixListener> {
+    use socket2::{Domain, SockAddr, Socket, Type};
+
+    let mut addr = vec![0];
+    addr.extend_from_slice(name);
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::unix(std::ffi::OsStr::from_bytes(&addr))?)?;
+    socket.listen(libc::SOMAXCONN)?;
+    UnixListener::from_std(socket.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_abstract(_: &[u8]) -> io::Result<UnixListener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "abstract unix sockets are Linux-only"))
+}
+
+/// The `@`-prefixed name of the abstract socket `listener` is bound to, if it is one.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn local_abstract_name(listener: &UnixListener) -> io::Result<Option<PathBuf>> {
+    let addr = socket2::SockRef::from(listener).local_addr()?;
+    Ok(addr.as_abstract_namespace().map(|name| {
+        let mut path = b"@".to_vec();
+        path.extend_from_slice(name);
+        PathBuf::from(std::ffi::OsString::from_vec(path))
+    }))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn local_abstract_name(_: &UnixListener) -> io::Result<Option<PathBuf>> { Ok(None) }
+
+/// Removes the socket file at its path when dropped.
+#[derive(Debug)]
+pub(crate) struct Unlink(pub(crate) PathBuf);
+
+impl Drop for Unlink {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("Failed to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Remove the socket at `path` if connecting to it is refused, meaning its listener is gone.
+/// Anything that is not a socket, or a socket still being served, is left for bind to fail on.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            info!("Removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn chown_group(path: &Path, gid: u32) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // An owner of -1 leaves it unchanged.
+    let unchanged = libc::uid_t::MAX;
+    // Safety: `path` is a valid NUL-terminated string for the duration of the call.
+    match unsafe { libc::chown(path.as_ptr(), unchanged, gid) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn stale_socket_replaced_and_mode_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(bind(&path, &SocketFile::default()).is_err(), "stale socket kept by default");
+
+        let settings =
+            SocketFile { mode: Some(0o660), remove_stale: true, ..SocketFile::default() };
+        let listener = bind(&path, &settings).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        assert!(bind(&path, &settings).is_err(), "live socket kept");
+
+        drop(listener);
+        drop(Unlink(path.clone()));
+        assert!(!path.exists());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn abstract_socket_bound_without_file() {
+        let name = format!("@ohttp-relay-test-{}", std::process::id());
+        let settings = SocketFile { mode: Some(0o660), ..SocketFile::default() };
+        let listener = bind(Path::new(&name), &settings).unwrap();
+        assert_eq!(local_abstract_name(&listener).unwrap(), Some(PathBuf::from(&name)));
+        assert!(!Path::new(&name).exists());
+        assert!(bind(Path::new(&name), &settings).is_err(), "name already taken");
+    }
+}
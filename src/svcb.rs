@@ -0,0 +1,581 @@
+//! Gateway discovery from DNS HTTPS records, see RFC 9460.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use http::Uri;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::{info, warn};
+
+use crate::resolve::{resolve_uri, Resolver};
+
+/// The HTTPS resource record type.
+const HTTPS: u16 = 65;
+/// How long a DNS server may take to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many aliases are followed before giving up on a name.
+const MAX_ALIASES: usize = 4;
+/// How long a name without HTTPS records, or whose lookup failed, is dialed as is before it is
+/// looked up again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// The ALPN protocols the relay speaks to gateways.
+const SUPPORTED_ALPN: [&str; 2] = ["h2", "http/1.1"];
+
+/// A service binding from a DNS HTTPS record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceBinding {
+    /// 0 if the record aliases `target`, otherwise the endpoint's preference, lowest first.
+    pub priority: u16,
+    /// The endpoint's host, or empty for the queried name itself.
+    pub target: String,
+    pub port: Option<u16>,
+    /// The ALPN protocols the endpoint supports, including `http/1.1` unless it opted out.
+    pub alpn: Vec<String>,
+    /// How long the record may be cached.
+    pub ttl: Duration,
+}
+
+/// The HTTPS records of `name` from the DNS server at `nameserver`, over UDP and then TCP if
+/// the answer is truncated. Records requiring parameters this module does not know are left
+/// out, as RFC 9460 requires.
+pub(crate) async fn query(nameserver: SocketAddr, name: &str) -> io::Result<Vec<ServiceBinding>> {
+    let mut id = [0; 2];
+    SystemRandom::new().fill(&mut id).map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+    let id = u16::from_be_bytes(id);
+    let request = encode_query(id, name)?;
+    let udp = async {
+        let unspecified: IpAddr = match nameserver {
+            SocketAddr::V4(_) => [0, 0, 0, 0].into(),
+            SocketAddr::V6(_) => [0u16; 8].into(),
+        };
+        let socket = UdpSocket::bind((unspecified, 0)).await?;
+        socket.connect(nameserver).await?;
+        socket.send(&request).await?;
+        let mut response = vec![0; 4096];
+        loop {
+            let len = socket.recv(&mut response).await?;
+            // Ignore stray datagrams, which may be spoofed.
+            if len >= 2 && response[..2] == id.to_be_bytes() {
+                response.truncate(len);
+                return Ok::<_, io::Error>(response);
+            }
+        }
+    };
+    let response = timeout(udp).await?;
+    match parse_response(id, &response)? {
+        Some(bindings) => Ok(bindings),
+        None => {
+            let tcp = async {
+                let mut stream = TcpStream::connect(nameserver).await?;
+                let len = u16::try_from(request.len()).expect("queries are short");
+                stream.write_all(&[&len.to_be_bytes()[..], &request].concat()).await?;
+                let len = stream.read_u16().await?;
+                let mut response = vec![0; usize::from(len)];
+                stream.read_exact(&mut response).await?;
+                Ok(response)
+            };
+            parse_response(id, &timeout(tcp).await?)?.ok_or_else(|| invalid("Truncated answer"))
+        }
+    }
+}
+
+async fn timeout<T>(querying: impl std::future::Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(QUERY_TIMEOUT, querying)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?
+}
+
+/// The first `nameserver` in `/etc/resolv.conf`, if any.
+pub(crate) fn system_nameserver() -> Option<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    conf.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => fields.next()?.parse::<IpAddr>().ok().map(|ip| (ip, 53).into()),
+            _ => None,
+        }
+    })
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid DNS message: {}", reason))
+}
+
+/// A recursive query for the HTTPS records of `name`, advertising a 1232 byte UDP payload
+/// with EDNS since records carrying ECH configurations are often larger than 512 bytes.
+fn encode_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = id.to_be_bytes().to_vec();
+    // Recursion desired, one question and one additional record.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in name.trim_end_matches('.').split('.') {
+        match u8::try_from(label.len()) {
+            Ok(len @ 1..=63) => {
+                query.push(len);
+                query.extend_from_slice(label.as_bytes());
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid DNS name")),
+        }
+    }
+    query.push(0);
+    query.extend_from_slice(&HTTPS.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    // The OPT pseudo-record.
+    query.extend_from_slice(&[0, 0, 41]);
+    query.extend_from_slice(&1232u16.to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Ok(query)
+}
+
+/// The HTTPS records answering query `id`, or `None` if the answer was truncated.
+fn parse_response(id: u16, msg: &[u8]) -> io::Result<Option<Vec<ServiceBinding>>> {
+    let mut reader = Reader { msg, pos: 0 };
+    if reader.u16()? != id {
+        return Err(invalid("Unexpected ID"));
+    }
+    let flags = reader.u16()?;
+    if flags & 0x8000 == 0 {
+        return Err(invalid("Not a response"));
+    }
+    if flags & 0x0200 != 0 {
+        return Ok(None);
+    }
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN
+        3 => return Ok(Some(Vec::new())),
+        rcode => {
+            let e = format!("DNS server failed with response code {}", rcode);
+            return Err(io::Error::other(e));
+        }
+    }
+    let (questions, answers) = (reader.u16()?, reader.u16()?);
+    reader.skip(4)?;
+    for _ in 0..questions {
+        reader.name()?;
+        reader.skip(4)?;
+    }
+    let mut bindings = Vec::new();
+    for _ in 0..answers {
+        reader.name()?;
+        let (kind, class) = (reader.u16()?, reader.u16()?);
+        let ttl = Duration::from_secs(u64::from(reader.u32()?));
+        let len = usize::from(reader.u16()?);
+        let end = reader.pos + len;
+        if end > msg.len() {
+            return Err(invalid("Record overruns message"));
+        }
+        if kind == HTTPS && class == 1 {
+            let rdata = Reader { msg: &msg[..end], pos: reader.pos };
+            bindings.extend(parse_binding(rdata, ttl)?);
+        }
+        reader.pos = end;
+    }
+    Ok(Some(bindings))
+}
+
+/// The service binding in the record data `rdata` ends with, or `None` if it requires an
+/// unknown parameter.
+fn parse_binding(mut rdata: Reader<'_>, ttl: Duration) -> io::Result<Option<ServiceBinding>> {
+    let priority = rdata.u16()?;
+    let target = rdata.name()?;
+    let mut binding = ServiceBinding { priority, target, port: None, alpn: Vec::new(), ttl };
+    let mut default_alpn = true;
+    while rdata.pos < rdata.msg.len() {
+        let (key, len) = (rdata.u16()?, usize::from(rdata.u16()?));
+        let value = rdata.bytes(len)?;
+        match key {
+            // mandatory, where Encrypted ClientHello (5) is unsupported
+            0 => {
+                let unknown = value.chunks(2).any(|key| !matches!(key, [0, 1..=4 | 6]));
+                if unknown {
+                    return Ok(None);
+                }
+            }
+            1 => {
+                let mut alpn = Reader { msg: value, pos: 0 };
+                while alpn.pos < value.len() {
+                    let len = usize::from(alpn.u8()?);
+                    binding.alpn.push(String::from_utf8_lossy(alpn.bytes(len)?).into_owned());
+                }
+            }
+            2 => default_alpn = false,
+            3 => binding.port = Some(Reader { msg: value, pos: 0 }.u16()?),
+            _ => {}
+        }
+    }
+    if default_alpn && priority != 0 && !binding.alpn.iter().any(|alpn| alpn == "http/1.1") {
+        binding.alpn.push("http/1.1".to_owned());
+    }
+    Ok(Some(binding))
+}
+
+struct Reader<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.msg.get(self.pos..self.pos + len).ok_or_else(|| invalid("Too short"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> io::Result<()> { self.bytes(len).map(drop) }
+
+    fn u8(&mut self) -> io::Result<u8> { Ok(self.bytes(1)?[0]) }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().expect("two bytes")))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().expect("four bytes")))
+    }
+
+    /// A domain name without its trailing dot, following compression pointers.
+    fn name(&mut self) -> io::Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // Every pointer must point backwards, so this terminates.
+        let mut limit = pos;
+        loop {
+            let len = *self.msg.get(pos).ok_or_else(|| invalid("Too short"))?;
+            match len {
+                0 => {
+                    if !jumped {
+                        self.pos = pos + 1;
+                    }
+                    return Ok(labels.join("."));
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *self.msg.get(pos + 1).ok_or_else(|| invalid("Too short"))?;
+                    let target = usize::from(len & 0x3f) << 8 | usize::from(low);
+                    if target >= limit {
+                        return Err(invalid("Forward name pointer"));
+                    }
+                    if !jumped {
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+                    pos = target;
+                    limit = target;
+                }
+                len if len < 64 => {
+                    let label = self
+                        .msg
+                        .get(pos + 1..pos + 1 + usize::from(len))
+                        .ok_or_else(|| invalid("Too short"))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + usize::from(len);
+                }
+                _ => return Err(invalid("Unknown label type")),
+            }
+        }
+    }
+}
+
+/// Where a gateway origin is served, as its HTTPS records say.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Endpoint {
+    host: String,
+    port: u16,
+}
+
+#[derive(Debug)]
+struct Discovered {
+    endpoint: Endpoint,
+    until: Instant,
+}
+
+/// Dials gateway origins at the endpoints their HTTPS records advertise, remembering each
+/// for as long as its records may be cached.
+#[derive(Debug, Default)]
+pub(crate) struct Discovery {
+    endpoints: Mutex<HashMap<(String, u16), Discovered>>,
+}
+
+impl Discovery {
+    /// Every address to dial for `dst`: those of the endpoint its HTTPS records advertise, or
+    /// its own if it has none.
+    pub(crate) async fn resolve(
+        &self,
+        dst: &Uri,
+        resolver: &dyn Resolver,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let host = dst.host().unwrap_or("");
+        let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+            Some("http") => 80,
+            _ => 443,
+        });
+        if host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok() {
+            return resolve_uri(dst, resolver).await;
+        }
+        let endpoint = self.endpoint(host, port, resolver).await;
+        let authority = format!("{}:{}", endpoint.host, endpoint.port);
+        let uri = Uri::builder()
+            .scheme(dst.scheme_str().unwrap_or("https"))
+            .authority(authority)
+            .path_and_query("/")
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        resolve_uri(&uri, resolver).await
+    }
+
+    async fn endpoint(&self, host: &str, port: u16, resolver: &dyn Resolver) -> Endpoint {
+        let key = (host.to_ascii_lowercase(), port);
+        let cached = {
+            let endpoints = self.endpoints.lock().expect("discovered endpoints poisoned");
+            endpoints.get(&key).map(|found| (found.endpoint.clone(), Instant::now() < found.until))
+        };
+        let stale = match cached {
+            Some((endpoint, true)) => return endpoint,
+            Some((endpoint, false)) => Some(endpoint),
+            None => None,
+        };
+        let origin = Endpoint { host: host.to_owned(), port };
+        let (endpoint, ttl) = match lookup(host, port, resolver).await {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("Failed to look up HTTPS records of gateway {}: {}", host, e);
+                (stale.clone().unwrap_or_else(|| origin.clone()), RETRY_INTERVAL)
+            }
+        };
+        if endpoint != *stale.as_ref().unwrap_or(&origin) {
+            info!("Gateway {}:{} is served at {}:{}", host, port, endpoint.host, endpoint.port);
+        }
+        let discovered = Discovered { endpoint: endpoint.clone(), until: Instant::now() + ttl };
+        self.endpoints.lock().expect("discovered endpoints poisoned").insert(key, discovered);
+        endpoint
+    }
+}
+
+/// The endpoint the HTTPS records of `host` advertise for `port`, following aliases, and how
+/// long it may be used.
+async fn lookup(
+    host: &str,
+    port: u16,
+    resolver: &dyn Resolver,
+) -> io::Result<(Endpoint, Duration)> {
+    let mut name = match port {
+        443 => host.to_owned(),
+        port => format!("_{}._https.{}", port, host),
+    };
+    let mut owner = host.to_owned();
+    let mut ttl = None::<Duration>;
+    for _ in 0..=MAX_ALIASES {
+        let bindings = resolver.resolve_https(&name).await?;
+        let record_ttl = bindings.iter().map(|binding| binding.ttl).min().unwrap_or(RETRY_INTERVAL);
+        ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        let ttl = ttl.expect("just set");
+        if let Some(alias) = bindings.iter().find(|binding| binding.priority == 0) {
+            if alias.target.is_empty() {
+                // The origin has no alternative endpoints.
+                return Ok((Endpoint { host: host.to_owned(), port }, ttl));
+            }
+            owner = alias.target.clone();
+            name = alias.target.clone();
+            continue;
+        }
+        let best = bindings
+            .iter()
+            .filter(|binding| binding.alpn.iter().any(|alpn| SUPPORTED_ALPN.contains(&&**alpn)))
+            .min_by_key(|binding| binding.priority);
+        let endpoint = match best {
+            Some(binding) => Endpoint {
+                host: match binding.target.is_empty() {
+                    true => owner,
+                    false => binding.target.clone(),
+                },
+                port: binding.port.unwrap_or(port),
+            },
+            None => Endpoint { host: owner, port },
+        };
+        return Ok((endpoint, ttl));
+    }
+    Err(io::Error::other(format!("Too many aliases for {}", host)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::resolve::SystemResolver;
+
+    /// A record's priority, target and parameters.
+    type Record<'a> = (u16, &'a str, &'a [(u16, &'a [u8])]);
+
+    /// An answer to `query` with these HTTPS records, each with a TTL of 300 seconds.
+    fn answer(query: &[u8], bindings: &[Record<'_>]) -> Vec<u8> {
+        let mut msg = query[..2].to_vec();
+        msg.extend_from_slice(&[0x81, 0x80, 0, 1]);
+        msg.extend_from_slice(&u16::try_from(bindings.len()).unwrap().to_be_bytes());
+        msg.extend_from_slice(&[0, 0, 0, 0]);
+        // The question, without the OPT record.
+        msg.extend_from_slice(&query[12..query.len() - 11]);
+        for (priority, target, params) in bindings {
+            let mut rdata = priority.to_be_bytes().to_vec();
+            for label in target.split('.').filter(|label| !label.is_empty()) {
+                rdata.push(label.len() as u8);
+                rdata.extend_from_slice(label.as_bytes());
+            }
+            rdata.push(0);
+            for (key, value) in *params {
+                rdata.extend_from_slice(&key.to_be_bytes());
+                rdata.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                rdata.extend_from_slice(value);
+            }
+            // A pointer to the name in the question.
+            msg.extend_from_slice(&[0xc0, 12]);
+            msg.extend_from_slice(&HTTPS.to_be_bytes());
+            msg.extend_from_slice(&[0, 1, 0, 0, 1, 44]);
+            msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&rdata);
+        }
+        msg
+    }
+
+    #[test]
+    fn bindings_parsed() {
+        let query = encode_query(7, "gateway.example").unwrap();
+        let msg = answer(
+            &query,
+            &[
+                (1, "edge.example", &[(1, b"\x02h2"), (3, &[0x20, 0xfb]), (5, b"ech")]),
+                (2, ".", &[(2, b""), (1, b"\x02h3")]),
+                (3, ".", &[(0, &[0, 5]), (5, b"ech")]),
+            ],
+        );
+        let bindings = parse_response(7, &msg).unwrap().unwrap();
+        assert_eq!(bindings.len(), 2, "the record requiring Encrypted ClientHello is ignored");
+        assert_eq!(
+            bindings[0],
+            ServiceBinding {
+                priority: 1,
+                target: "edge.example".to_owned(),
+                port: Some(8443),
+                alpn: vec!["h2".to_owned(), "http/1.1".to_owned()],
+                ttl: Duration::from_secs(300),
+            }
+        );
+        assert_eq!(bindings[1].target, "");
+        assert_eq!(bindings[1].alpn, ["h3"]);
+        assert!(parse_response(8, &msg).is_err());
+    }
+
+    #[tokio::test]
+    async fn queried_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let nameserver = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut query = [0; 512];
+            let (len, client) = server.recv_from(&mut query).await.unwrap();
+            let msg = answer(&query[..len], &[(1, ".", &[(3, &[0x1f, 0x90])])]);
+            server.send_to(&msg, client).await.unwrap();
+        });
+        let bindings = query(nameserver, "gateway.example").await.unwrap();
+        assert_eq!(bindings.len(), 1);
+        assert_eq!(bindings[0].port, Some(8080));
+    }
+
+    #[tokio::test]
+    async fn truncated_answer_retried_over_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let nameserver = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(nameserver).await.unwrap();
+        tokio::spawn(async move {
+            let mut query = [0; 512];
+            let (len, client) = udp.recv_from(&mut query).await.unwrap();
+            let mut truncated = answer(&query[..len], &[]);
+            truncated[2] |= 0x02;
+            udp.send_to(&truncated, client).await.unwrap();
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut query = vec![0; usize::from(len)];
+            stream.read_exact(&mut query).await.unwrap();
+            let msg = answer(&query, &[(1, ".", &[(3, &[0x1f, 0x90])])]);
+            let len = u16::try_from(msg.len()).unwrap().to_be_bytes();
+            stream.write_all(&[&len[..], &msg].concat()).await.unwrap();
+        });
+        let resolver = SystemResolver { nameserver: Some(nameserver) };
+        let bindings = resolver.resolve_https("gateway.example").await.unwrap();
+        assert_eq!(bindings.len(), 1);
+        assert_eq!(bindings[0].port, Some(8080));
+    }
+
+    /// Answers HTTPS queries from a fixed table, counting them, and resolves every host to
+    /// one address.
+    #[derive(Debug, Default)]
+    struct Records {
+        records: HashMap<&'static str, Vec<ServiceBinding>>,
+        queries: AtomicUsize,
+    }
+
+    impl Resolver for Records {
+        fn resolve<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+            Box::pin(async { Ok(vec![IpAddr::from([192, 0, 2, 1])]) })
+        }
+
+        fn resolve_https<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> Pin<Box<dyn Future<Output = io::Result<Vec<ServiceBinding>>> + Send + 'a>> {
+            self.queries.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move { Ok(self.records.get(name).cloned().unwrap_or_default()) })
+        }
+    }
+
+    fn binding(priority: u16, target: &str, port: Option<u16>, alpn: &str) -> ServiceBinding {
+        ServiceBinding {
+            priority,
+            target: target.to_owned(),
+            port,
+            alpn: vec![alpn.to_owned()],
+            ttl: Duration::from_secs(300),
+        }
+    }
+
+    #[tokio::test]
+    async fn best_supported_endpoint_dialed() {
+        let mut resolver = Records::default();
+        resolver.records.insert("gateway.example", vec![binding(0, "cdn.example", None, "")]);
+        resolver.records.insert(
+            "cdn.example",
+            vec![
+                binding(1, "quic.cdn.example", Some(4433), "h3"),
+                binding(3, "", None, "http/1.1"),
+                binding(2, "edge.cdn.example", Some(8443), "h2"),
+            ],
+        );
+        resolver.records.insert("_8080._https.other.example", vec![binding(1, "", None, "h2")]);
+        let discovery = Discovery::default();
+        let endpoint = discovery.endpoint("gateway.example", 443, &resolver).await;
+        assert_eq!(endpoint, Endpoint { host: "edge.cdn.example".to_owned(), port: 8443 });
+        let endpoint = discovery.endpoint("other.example", 8080, &resolver).await;
+        assert_eq!(endpoint, Endpoint { host: "other.example".to_owned(), port: 8080 });
+        let endpoint = discovery.endpoint("plain.example", 443, &resolver).await;
+        assert_eq!(endpoint, Endpoint { host: "plain.example".to_owned(), port: 443 });
+
+        // Cached until the records expire.
+        let queries = resolver.queries.load(Ordering::Relaxed);
+        discovery.endpoint("gateway.example", 443, &resolver).await;
+        assert_eq!(resolver.queries.load(Ordering::Relaxed), queries);
+        let addrs =
+            discovery.resolve(&Uri::from_static("https://gateway.example"), &resolver).await;
+        assert_eq!(addrs.unwrap(), [SocketAddr::from(([192, 0, 2, 1], 8443))]);
+    }
+}
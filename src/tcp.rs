@@ -0,0 +1,125 @@
+use std::io;
+use std::net::SocketAddr;
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{Keepalive, TcpOptions};
+
+/// Set `options` on a connected `stream`.
+pub(crate) fn apply(stream: &TcpStream, options: &TcpOptions) -> io::Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+    if let Some(keepalive) = &options.keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&tcp_keepalive(keepalive))?;
+    }
+    Ok(())
+}
+
+fn tcp_keepalive(keepalive: &Keepalive) -> TcpKeepalive {
+    let tcp = TcpKeepalive::new().with_time(keepalive.idle);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "windows"
+    ))]
+    let tcp = match keepalive.interval {
+        Some(interval) => tcp.with_interval(interval),
+        None => tcp,
+    };
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd"
+    ))]
+    let tcp = match keepalive.retries {
+        Some(retries) => tcp.with_retries(retries),
+        None => tcp,
+    };
+    tcp
+}
+
+/// Bind a TCP listener on `addr` that queues up to `backlog` connections.
+pub(crate) fn bind(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    listen(socket, addr, backlog)
+}
+
+/// Bind `socket` to `addr` and listen on it, like `TcpListener::bind` does.
+pub(crate) fn listen(socket: Socket, addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    // So a restarted relay can rebind while old connections linger.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind `count` listeners sharing `addr` through `SO_REUSEPORT`, so the kernel spreads incoming
+/// connections across their accept loops.
+pub(crate) fn bind_reuse_port(
+    addr: SocketAddr,
+    backlog: u32,
+    count: usize,
+) -> io::Result<Vec<TcpListener>> {
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    let bind = |addr: SocketAddr| {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_port(true)?;
+        listen(socket, addr, backlog)
+    };
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    let bind = |_: SocketAddr| -> io::Result<TcpListener> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT is unsupported"))
+    };
+    let first = bind(addr)?;
+    // The others must share the port the OS picked if port 0 was asked for.
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(bind(addr)?);
+    }
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn options_applied() {
+        let listener = bind("127.0.0.1:0".parse().unwrap(), 8).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let keepalive = Keepalive {
+            idle: Duration::from_secs(30),
+            interval: Some(Duration::from_secs(5)),
+            retries: Some(3),
+        };
+        let options = TcpOptions { nodelay: true, keepalive: Some(keepalive) };
+        apply(&stream, &options).unwrap();
+        assert!(stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn acceptors_share_port() {
+        let listeners = bind_reuse_port("127.0.0.1:0".parse().unwrap(), 8, 3).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert!(listeners.iter().all(|listener| listener.local_addr().unwrap() == addr));
+        assert!(bind(addr, 8).is_err(), "port taken without SO_REUSEPORT");
+    }
+}
@@ -0,0 +1,127 @@
+//! Helpers for testing clients against the relay, enabled with the `test-util` feature.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use http::Uri;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::{empty, full};
+
+/// A stand-in OHTTP gateway on a loopback port, until dropped.
+///
+/// It answers every `POST` with the canned `message/ohttp-res` body set by
+/// [`MockGateway::set_response`], keeping the `message/ohttp-req` bodies it received, and
+/// every `GET` with the `application/ohttp-keys` key configurations set by
+/// [`MockGateway::set_keys`]. Both are empty until set. It speaks plain HTTP, so relays
+/// forwarding to it need [`crate::Config::danger_allow_insecure_gateway`].
+#[derive(Debug)]
+pub struct MockGateway {
+    addr: SocketAddr,
+    state: Arc<State>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    requests: Mutex<Vec<Bytes>>,
+    response: Mutex<Bytes>,
+    keys: Mutex<Bytes>,
+}
+
+impl MockGateway {
+    /// Bind a free loopback port and start answering.
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(State::default());
+        let task = tokio::spawn(serve(listener, state.clone()));
+        Ok(Self { addr, state, task })
+    }
+
+    /// The address the gateway is bound to.
+    pub fn addr(&self) -> SocketAddr { self.addr }
+
+    /// The gateway's origin, e.g. `http://127.0.0.1:41234`, to give the relay.
+    pub fn origin(&self) -> Uri {
+        format!("http://{}", self.addr).parse().expect("socket addresses are valid authorities")
+    }
+
+    /// Answer `POST` requests with `body` from now on.
+    pub fn set_response(&self, body: impl Into<Bytes>) {
+        *self.state.response.lock().expect("mock gateway poisoned") = body.into();
+    }
+
+    /// Answer `GET` requests with the encoded key configurations `keys` from now on.
+    pub fn set_keys(&self, keys: impl Into<Bytes>) {
+        *self.state.keys.lock().expect("mock gateway poisoned") = keys.into();
+    }
+
+    /// The bodies of the `POST` requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<Bytes> {
+        self.state.requests.lock().expect("mock gateway poisoned").clone()
+    }
+}
+
+impl Drop for MockGateway {
+    fn drop(&mut self) { self.task.abort(); }
+}
+
+async fn serve(listener: TcpListener, state: Arc<State>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("Mock gateway accept failed: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, hyper::Error>(respond(req, &state).await) }
+            });
+            if let Err(e) =
+                http1::Builder::new().serve_connection(TokioIo::new(stream), service).await
+            {
+                debug!("Error serving mock gateway connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(req: Request<Incoming>, state: &State) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let (body, content_type) = match *req.method() {
+        Method::POST => {
+            let body = match req.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return status(StatusCode::BAD_REQUEST),
+            };
+            state.requests.lock().expect("mock gateway poisoned").push(body);
+            (state.response.lock().expect("mock gateway poisoned").clone(), "message/ohttp-res")
+        }
+        Method::GET =>
+            (state.keys.lock().expect("mock gateway poisoned").clone(), "application/ohttp-keys"),
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    let mut res = Response::new(full(body));
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    res
+}
+
+fn status(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = Response::new(empty());
+    *res.status_mut() = status;
+    res
+}
@@ -0,0 +1,362 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use rustls::client::{Resumption, WebPkiServerVerifier};
+use rustls::crypto::{ring, CryptoProvider};
+#[cfg(feature = "dev-tls")]
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig, SupportedProtocolVersion};
+
+use crate::body::BoxError;
+use crate::pinning::PinnedVerifier;
+use crate::Config;
+
+/// The base set of trust anchors used to verify gateway certificates.
+#[derive(Debug, Clone, Default)]
+pub enum Roots {
+    /// Mozilla's root store, compiled in by `webpki-roots`.
+    #[default]
+    Webpki,
+    /// The platform's native certificate store.
+    Native,
+    /// Only these trust anchors, e.g. a private CA's.
+    Custom(Arc<RootCertStore>),
+}
+
+/// A PEM certificate chain and private key the relay presents to gateways that require
+/// client certificates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// How TLS connections to the gateway are negotiated, e.g. to meet compliance requirements.
+///
+/// Encrypted Client Hello is not supported by the TLS library yet, so the gateway's name is
+/// always sent in the clear.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamTls {
+    /// The TLS versions offered. Both 1.2 and 1.3 when empty.
+    pub versions: Vec<TlsVersion>,
+    /// The cipher suites offered, in order of preference, by their IANA names such as
+    /// `TLS13_AES_256_GCM_SHA384`. Every suite supported is offered when empty.
+    pub cipher_suites: Vec<String>,
+    /// How many gateway sessions are remembered so new connections can resume them with an
+    /// abbreviated handshake, using session tickets or IDs. Resumption is disabled when `0`.
+    pub session_cache_size: usize,
+    /// Offer HTTP/1.1 ahead of HTTP/2 with ALPN, for gateways that go by the client's order.
+    /// See also [`Config::force_http1`].
+    pub prefer_http1: bool,
+}
+
+impl UpstreamTls {
+    /// The default [`UpstreamTls::session_cache_size`].
+    pub const SESSION_CACHE_SIZE: usize = 256;
+}
+
+impl Default for UpstreamTls {
+    fn default() -> Self {
+        Self {
+            versions: Vec::new(),
+            cipher_suites: Vec::new(),
+            session_cache_size: Self::SESSION_CACHE_SIZE,
+            prefer_http1: false,
+        }
+    }
+}
+
+/// A TLS version offered to the gateway, parsed from `1.2` or `1.3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn supported(self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+impl FromStr for TlsVersion {
+    type Err = BoxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_start_matches("TLS").trim_start_matches("v") {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("Unsupported TLS version {}, expected 1.2 or 1.3", s).into()),
+        }
+    }
+}
+
+/// Build the TLS configuration for connections to the gateway.
+///
+/// The trust anchors are the configured base [`Roots`] merged with every certificate
+/// found in [`Config::extra_root_certs`], and the gateway's key must match one of
+/// [`Config::pinned_spki`] if any are set. Versions, cipher suites, resumption and ALPN follow
+/// [`Config::upstream_tls`].
+pub(crate) fn client_config(config: &Config) -> Result<ClientConfig, BoxError> {
+    let upstream = &config.upstream_tls;
+    let provider = Arc::new(crypto_provider(&upstream.cipher_suites)?);
+    let versions: Vec<_> = match upstream.versions.as_slice() {
+        [] => rustls::ALL_VERSIONS.to_vec(),
+        versions => versions.iter().map(|version| version.supported()).collect(),
+    };
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&versions)
+        .map_err(|e| format!("No cipher suite offered for the TLS versions: {}", e))?;
+    let roots = root_store(config)?;
+    let builder = match config.pinned_spki.as_slice() {
+        [] => builder.with_root_certificates(roots),
+        pins => {
+            let inner =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            let verifier = PinnedVerifier { inner, pins: pins.to_vec() };
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier))
+        }
+    };
+    let mut tls_config = match &config.client_identity {
+        Some(identity) =>
+            builder.with_client_auth_cert(load_certs(&identity.cert)?, load_key(&identity.key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    tls_config.resumption = match upstream.session_cache_size {
+        0 => Resumption::disabled(),
+        size => Resumption::in_memory_sessions(size),
+    };
+    tls_config.alpn_protocols = match (config.force_http1, upstream.prefer_http1) {
+        (true, _) => Vec::new(),
+        (false, true) => vec![b"http/1.1".to_vec(), b"h2".to_vec()],
+        (false, false) => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    };
+    Ok(tls_config)
+}
+
+/// The default provider offering only `cipher_suites`, in that order, unless empty.
+fn crypto_provider(cipher_suites: &[String]) -> Result<CryptoProvider, BoxError> {
+    let mut provider = ring::default_provider();
+    if cipher_suites.is_empty() {
+        return Ok(provider);
+    }
+    let all = std::mem::take(&mut provider.cipher_suites);
+    for name in cipher_suites {
+        let suite = all
+            .iter()
+            .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("Unsupported cipher suite {}", name))?;
+        provider.cipher_suites.push(*suite);
+    }
+    Ok(provider)
+}
+
+/// Load a TLS configuration for the relay's own listener from a PEM certificate chain and
+/// private key, offering HTTP/2 and HTTP/1.1 with ALPN.
+pub fn server_config_from_pem(cert_path: &Path, key_path: &Path) -> Result<ServerConfig, BoxError> {
+    let (certs, key) = (load_certs(cert_path)?, load_key(key_path)?);
+    let mut config = ServerConfig::builder().with_no_client_auth().with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Like [`server_config_from_pem`], but requiring clients to present a certificate signed by
+/// one of the CAs in `client_ca_path`, so only authorized client software can use the relay.
+/// Handshakes without one are rejected.
+pub fn mtls_server_config_from_pem(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: &Path,
+) -> Result<ServerConfig, BoxError> {
+    let (certs, key) = (load_certs(cert_path)?, load_key(key_path)?);
+    let mut client_roots = RootCertStore::empty();
+    for ca in load_certs(client_ca_path)? {
+        client_roots.add(ca)?;
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(client_roots)).build()?;
+    let mut config =
+        ServerConfig::builder().with_client_cert_verifier(verifier).with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Like [`server_config_from_pem`], but with an ephemeral self-signed certificate for
+/// `hostnames`, to try out TLS and `wss://` bootstrap locally. Clients must trust the returned
+/// certificate explicitly. Never use in production.
+#[cfg(feature = "dev-tls")]
+pub fn self_signed_server_config(
+    hostnames: Vec<String>,
+) -> Result<(ServerConfig, CertificateDer<'static>), BoxError> {
+    let generated = rcgen::generate_simple_self_signed(hostnames)?;
+    let key = PrivatePkcs8KeyDer::from(generated.serialize_private_key_der());
+    let cert = CertificateDer::from(generated.serialize_der()?);
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok((config, cert))
+}
+
+/// Like [`server_config_from_pem`], but for [`crate::listen_quic`], offering HTTP/3 with ALPN.
+#[cfg(feature = "h3")]
+pub fn quic_server_config_from_pem(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<quinn::rustls::ServerConfig, BoxError> {
+    let (certs, key) = (load_certs(cert_path)?, load_key(key_path)?);
+    let mut config = quinn::rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    Ok(config)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, BoxError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, BoxError> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| format!("No private key found in {}", path.display()).into())
+}
+
+fn root_store(config: &Config) -> Result<RootCertStore, BoxError> {
+    let mut roots = RootCertStore::empty();
+    match &config.roots {
+        Roots::Webpki => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        Roots::Native => {
+            let (_, ignored) =
+                roots.add_parsable_certificates(rustls_native_certs::load_native_certs()?);
+            if ignored > 0 {
+                tracing::warn!("Ignored {} unparsable native root certificates", ignored);
+            }
+        }
+        Roots::Custom(custom) => roots.extend(custom.roots.iter().cloned()),
+    }
+    for path in &config.extra_root_certs {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut found = false;
+        for cert in rustls_pemfile::certs(&mut reader) {
+            roots.add(cert?)?;
+            found = true;
+        }
+        if !found {
+            return Err(format!("No certificates found in {}", path.display()).into());
+        }
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn extra_roots_extend_base_roots() {
+        let cert = rcgen::generate_simple_self_signed(vec!["0.0.0.0".to_string()]).unwrap();
+        let mut pem = tempfile::NamedTempFile::new().unwrap();
+        pem.write_all(cert.serialize_pem().unwrap().as_bytes()).unwrap();
+
+        let config =
+            Config { extra_root_certs: vec![pem.path().to_path_buf()], ..Config::default() };
+        let roots = root_store(&config).unwrap();
+        assert_eq!(roots.len(), webpki_roots::TLS_SERVER_ROOTS.len() + 1);
+        for public in webpki_roots::TLS_SERVER_ROOTS {
+            assert!(roots.roots.contains(public), "{:?} missing", public.subject);
+        }
+
+        let config = Config { roots: Roots::Native, ..config };
+        let native =
+            root_store(&Config { extra_root_certs: Vec::new(), ..config.clone() }).unwrap();
+        let roots = root_store(&config).unwrap();
+        assert_eq!(roots.len(), native.len() + 1);
+        for public in &native.roots {
+            assert!(roots.roots.contains(public), "{:?} missing", public.subject);
+        }
+    }
+
+    #[test]
+    fn extra_roots_without_certificates_rejected() {
+        let empty = tempfile::NamedTempFile::new().unwrap();
+        let config =
+            Config { extra_root_certs: vec![empty.path().to_path_buf()], ..Config::default() };
+        assert!(root_store(&config).is_err());
+    }
+
+    #[test]
+    fn server_config_loaded_from_pem() {
+        let cert = rcgen::generate_simple_self_signed(vec!["0.0.0.0".to_string()]).unwrap();
+        let mut cert_pem = tempfile::NamedTempFile::new().unwrap();
+        cert_pem.write_all(cert.serialize_pem().unwrap().as_bytes()).unwrap();
+        let mut key_pem = tempfile::NamedTempFile::new().unwrap();
+        key_pem.write_all(cert.serialize_private_key_pem().as_bytes()).unwrap();
+
+        let config = server_config_from_pem(cert_pem.path(), key_pem.path()).unwrap();
+        assert_eq!(config.alpn_protocols[0], b"h2");
+        assert!(server_config_from_pem(key_pem.path(), key_pem.path()).is_err());
+    }
+
+    #[test]
+    fn upstream_tls_applied() {
+        let config = client_config(&Config::default()).unwrap();
+        assert_eq!(config.alpn_protocols[0], b"h2");
+
+        let upstream_tls = UpstreamTls {
+            versions: vec!["1.3".parse().unwrap()],
+            cipher_suites: vec!["TLS13_CHACHA20_POLY1305_SHA256".to_owned()],
+            session_cache_size: 0,
+            prefer_http1: true,
+        };
+        let config = Config { upstream_tls, ..Config::default() };
+        let tls_config = client_config(&config).unwrap();
+        assert_eq!(tls_config.alpn_protocols[0], b"http/1.1");
+        let suites = crypto_provider(&config.upstream_tls.cipher_suites).unwrap().cipher_suites;
+        assert_eq!(suites.len(), 1);
+        assert_eq!(suites[0].suite(), rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256);
+
+        let force_http1 = Config { force_http1: true, ..config.clone() };
+        assert!(client_config(&force_http1).unwrap().alpn_protocols.is_empty());
+        let mut unknown = config.clone();
+        unknown.upstream_tls.cipher_suites = vec!["TLS_NULL_WITH_NULL_NULL".to_owned()];
+        assert!(client_config(&unknown).is_err());
+        let mut mismatched = config;
+        mismatched.upstream_tls.versions = vec![TlsVersion::Tls12];
+        assert!(client_config(&mismatched).is_err());
+        assert!("1.1".parse::<TlsVersion>().is_err());
+    }
+
+    #[test]
+    fn client_identity_loaded_from_pem() {
+        let cert = rcgen::generate_simple_self_signed(vec!["relay".to_string()]).unwrap();
+        let mut cert_pem = tempfile::NamedTempFile::new().unwrap();
+        cert_pem.write_all(cert.serialize_pem().unwrap().as_bytes()).unwrap();
+        let mut key_pem = tempfile::NamedTempFile::new().unwrap();
+        key_pem.write_all(cert.serialize_private_key_pem().as_bytes()).unwrap();
+
+        let identity = |key: &Path| ClientIdentity {
+            cert: cert_pem.path().to_path_buf(),
+            key: key.to_path_buf(),
+        };
+        let config =
+            Config { client_identity: Some(identity(key_pem.path())), ..Config::default() };
+        assert!(client_config(&config).unwrap().client_auth_cert_resolver.has_certs());
+        let config =
+            Config { client_identity: Some(identity(cert_pem.path())), ..Config::default() };
+        assert!(client_config(&config).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::io;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::net::Listener;
+
+/// A vsock address: the context ID of a VM or the host, and a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct VsockAddr {
+    pub(crate) cid: u32,
+    pub(crate) port: u32,
+}
+
+impl VsockAddr {
+    fn from_sock_addr(addr: &SockAddr) -> io::Result<Self> {
+        match addr.as_vsock_address() {
+            Some((cid, port)) => Ok(Self { cid, port }),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a vsock address")),
+        }
+    }
+}
+
+/// Accepts connections on an `AF_VSOCK` socket, e.g. from the host of the VM the relay runs in.
+pub(crate) struct VsockListener(AsyncFd<Socket>);
+
+impl VsockListener {
+    /// Bind `port` on context ID `cid`, or on every context ID the VM has with
+    /// `libc::VMADDR_CID_ANY`.
+    pub(crate) fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SockAddr::vsock(cid, port))?;
+        socket.listen(libc::SOMAXCONN)?;
+        Ok(Self(AsyncFd::new(socket)?))
+    }
+}
+
+impl Listener for VsockListener {
+    type Io = VsockStream;
+    type Addr = VsockAddr;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Io, Self::Addr)>> {
+        loop {
+            let mut ready = match self.0.poll_read_ready(cx) {
+                Poll::Ready(ready) => ready?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Ok(accepted) = ready.try_io(|socket| socket.get_ref().accept()) {
+                let (socket, addr) = accepted?;
+                socket.set_nonblocking(true)?;
+                let addr = VsockAddr::from_sock_addr(&addr)?;
+                return Poll::Ready(Ok((VsockStream(AsyncFd::new(socket)?), addr)));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        VsockAddr::from_sock_addr(&self.0.get_ref().local_addr()?)
+    }
+}
+
+/// A connection accepted by a [`VsockListener`].
+pub(crate) struct VsockStream(AsyncFd<Socket>);
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut ready = match self.0.poll_read_ready(cx) {
+                Poll::Ready(ready) => ready?,
+                Poll::Pending => return Poll::Pending,
+            };
+            // Safety: the unfilled part is only handed to `recv`, which writes bytes into it but
+            // never de-initializes any.
+            let unfilled = unsafe { buf.unfilled_mut() };
+            let unfilled: &mut [MaybeUninit<u8>] = unfilled;
+            if let Ok(read) = ready.try_io(|socket| socket.get_ref().recv(unfilled)) {
+                let read = read?;
+                // Safety: `recv` returned `read`, so it initialized the first `read` bytes of the
+                // unfilled part.
+                unsafe { buf.assume_init(read) };
+                buf.advance(read);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut ready = match self.0.poll_write_ready(cx) {
+                Poll::Ready(ready) => ready?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Ok(written) = ready.try_io(|socket| socket.get_ref().send(buf)) {
+                return Poll::Ready(written);
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.get_ref().shutdown(std::net::Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs the vsock_loopback kernel module, run with --ignored"]
+    async fn loopback_accept_read_write() {
+        let mut listener =
+            VsockListener::bind(libc::VMADDR_CID_LOCAL, libc::VMADDR_PORT_ANY).unwrap();
+        let port = listener.local_addr().unwrap().port;
+        let mut client = tokio::task::spawn_blocking(move || {
+            let mut socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+            socket.connect(&SockAddr::vsock(libc::VMADDR_CID_LOCAL, port))?;
+            socket.write_all(b"ping")?;
+            let mut pong = [0; 4];
+            socket.read_exact(&mut pong)?;
+            Ok::<_, io::Error>(pong)
+        });
+        let (mut stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted.unwrap(),
+            connected = &mut client => panic!("Failed to connect over vsock: {:?}", connected),
+        };
+        assert_eq!(addr.cid, libc::VMADDR_CID_LOCAL);
+        let mut ping = [0; 4];
+        stream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        stream.write_all(b"pong").await.unwrap();
+        assert_eq!(&client.await.unwrap().unwrap(), b"pong");
+    }
+}
@@ -1,29 +1,51 @@
 #[cfg(test)]
 mod integration {
     use std::fs::File;
+    use std::future::Future;
     use std::io::Read;
-    use std::net::SocketAddr;
+    use std::net::{IpAddr, SocketAddr};
     use std::path::PathBuf;
+    use std::pin::Pin;
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
+    use flate2::read::GzDecoder;
     use hex::FromHex;
+    use http::uri::PathAndQuery;
     use http::Uri;
     use http_body_util::combinators::BoxBody;
     use http_body_util::{BodyExt, Full};
     use hyper::body::{Bytes, Incoming};
-    use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+    use hyper::header::{
+        HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_ORIGIN, ALLOW,
+        AUTHORIZATION, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+        IF_NONE_MATCH, LOCATION, ORIGIN, RETRY_AFTER, SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING,
+        VARY, WWW_AUTHENTICATE,
+    };
     use hyper::server::conn::http1;
     use hyper::service::service_fn;
     use hyper::{Request, Response};
     use hyper_rustls::HttpsConnectorBuilder;
     use hyper_util::client::legacy::Client;
     use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto;
+    use ohttp_relay::auth::{Authorization, Authorizer, RequestMeta};
+    #[cfg(feature = "cli")]
+    use ohttp_relay::bench::Bench;
+    use ohttp_relay::hook::{ForwardMeta, RelayHook, ResponseMeta};
     use ohttp_relay::*;
     use rcgen::Certificate;
     use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::ServerConfig;
     use tempfile::NamedTempFile;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::{TcpListener, TcpStream};
     use tokio::process::Command;
+    use tokio_rustls::TlsAcceptor;
+    use tower_service::Service;
 
     const ENCAPSULATED_REQ: &str = "010020000100014b28f881333e7c164ffc499ad9796f877f4e1051ee6d31bad19dec96c208b4726374e469135906992e1268c594d2a10c695d858c40a026e7965e7d86b83dd440b2c0185204b4d63525";
     const ENCAPSULATED_RES: &str =
@@ -44,15 +66,16 @@ mod integration {
                 .await;
         tokio::select! {
             _ = example_gateway_http(gateway_port) => {
-                assert!(false, "Gateway is long running");
+                panic!("Gateway is long running");
             }
-            _ = listen_tcp(relay_port, gateway) => {
-                assert!(false, "Relay is long running");
+            _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
             }
             _ = ohttp_req(n_https_port, nginx_cert_der) => {}
         }
     }
 
+    #[cfg(unix)]
     #[tokio::test]
     async fn test_request_response_socket() {
         let temp_dir = std::env::temp_dir();
@@ -74,15 +97,3544 @@ mod integration {
                 .await;
         tokio::select! {
             _ = example_gateway_http(gateway_port) => {
-                assert!(false, "Gateway is long running");
+                panic!("Gateway is long running");
             }
-            _ = listen_socket(socket_path_str, gateway) => {
-                assert!(false, "Relay is long running");
+            _ = listen_socket_with_config(socket_path_str, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
             }
             _ = ohttp_req(n_https_port, nginx_cert_der) => {}
         }
     }
 
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_request_response_named_pipe() {
+        let pipe_name = format!(r"\\.\pipe\ohttp-relay-test-{}", uuid::Uuid::new_v4());
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_named_pipe_with_config(&pipe_name, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let pipe =
+                    tokio::net::windows::named_pipe::ClientOptions::new().open(&pipe_name).unwrap();
+                let (mut sender, conn) =
+                    hyper::client::conn::http1::handshake(TokioIo::new(pipe)).await.unwrap();
+                tokio::spawn(conn);
+                sender.send_request(ohttp_request("http://localhost/".to_owned())).await.unwrap()
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_response_tls() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let relay_cert = gen_localhost_cert();
+        let relay_cert_der = cert_to_cert_der(&relay_cert);
+        let (key, cert) = cert_to_key_cert_der(relay_cert);
+        let tls_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_tls_with_config(relay_port, gateway, Arc::new(tls_config), insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            _ = ohttp_req(relay_port, relay_cert_der) => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_required() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let relay_cert = gen_localhost_cert();
+        let relay_cert_der = cert_to_cert_der(&relay_cert);
+        let client_cert = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+        let pem_file = |pem: String| {
+            let mut file = NamedTempFile::new().unwrap();
+            std::io::Write::write_all(&mut file, pem.as_bytes()).unwrap();
+            file
+        };
+        let cert_file = pem_file(relay_cert.serialize_pem().unwrap());
+        let key_file = pem_file(relay_cert.serialize_private_key_pem());
+        let ca_file = pem_file(client_cert.serialize_pem().unwrap());
+        let tls_config =
+            mtls_server_config_from_pem(cert_file.path(), key_file.path(), ca_file.path()).unwrap();
+        let (client_key, client_cert) = cert_to_key_cert_der(client_cert);
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_tls_with_config(relay_port, gateway, Arc::new(tls_config), insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let send = |config: rustls::ClientConfig| {
+                    let https = HttpsConnectorBuilder::new()
+                        .with_tls_config(config)
+                        .https_only()
+                        .enable_http1()
+                        .build();
+                    let client = Client::builder(TokioExecutor::new()).build(https);
+                    client.request(ohttp_request(format!("https://0.0.0.0:{}/", relay_port)))
+                };
+                let mut roots = rustls::RootCertStore::empty();
+                roots.add(relay_cert_der).unwrap();
+                let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+                assert!(send(builder.clone().with_no_client_auth()).await.is_err());
+                let authenticated =
+                    builder.with_client_auth_cert(vec![client_cert], client_key).unwrap();
+                assert_eq!(send(authenticated).await.unwrap().status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[cfg(feature = "dev-tls")]
+    #[tokio::test]
+    async fn test_request_response_self_signed_tls() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let (tls_config, relay_cert_der) =
+            self_signed_server_config(vec!["0.0.0.0".to_string()]).unwrap();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_tls_with_config(relay_port, gateway, Arc::new(tls_config), insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            _ = ohttp_req(relay_port, relay_cert_der) => {}
+        }
+    }
+
+    #[cfg(feature = "h3")]
+    #[tokio::test]
+    async fn test_request_response_quic() {
+        use hyper::body::Buf;
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let relay_cert = gen_localhost_cert();
+        let relay_cert_der = cert_to_cert_der(&relay_cert);
+        let (mut cert_file, mut key_file) =
+            (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        std::io::Write::write_all(&mut cert_file, relay_cert.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        std::io::Write::write_all(&mut key_file, relay_cert.serialize_private_key_pem().as_bytes())
+            .unwrap();
+        let tls_config = quic_server_config_from_pem(cert_file.path(), key_file.path()).unwrap();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_quic_with_config(relay_port, gateway, Arc::new(tls_config), insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            (status, body) = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let mut roots = quinn::rustls::RootCertStore::empty();
+                roots.add(relay_cert_der).unwrap();
+                let mut tls_config = quinn::rustls::ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                tls_config.alpn_protocols = vec![b"h3".to_vec()];
+                let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls_config).unwrap();
+                let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap()).unwrap();
+                endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+                let addr = SocketAddr::from(([127, 0, 0, 1], relay_port));
+                let conn = endpoint.connect(addr, "0.0.0.0").unwrap().await.unwrap();
+                let (mut driver, mut sender) =
+                    h3::client::new(h3_quinn::Connection::new(conn)).await.unwrap();
+                tokio::spawn(async move { driver.wait_idle().await });
+
+                let (parts, body) = ohttp_request(format!("https://0.0.0.0:{}/", relay_port)).into_parts();
+                let mut stream = sender.send_request(Request::from_parts(parts, ())).await.unwrap();
+                stream.send_data(body.collect().await.unwrap().to_bytes()).await.unwrap();
+                stream.finish().await.unwrap();
+                let res = stream.recv_response().await.unwrap();
+                let mut body = Vec::new();
+                while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+                    body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+                }
+                (res.status(), body)
+            } => {
+                assert_eq!(status, hyper::StatusCode::OK);
+                assert_eq!(body, Vec::from_hex(ENCAPSULATED_RES).unwrap());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insecure_gateway_refused_by_default() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let err = listen_tcp(find_free_port(), gateway.clone()).await.unwrap_err();
+        assert!(matches!(err, RelayError::InvalidGateway(_)), "{:?}", err);
+        assert!(err.to_string().contains("plaintext"), "{}", err);
+        let err =
+            listen_tcp(find_free_port(), Uri::from_static("https://0.0.0.0")).await.unwrap_err();
+        assert!(err.to_string().contains("loopback"), "{}", err);
+
+        let config = Config { allowed_gateways: vec![gateway], ..Config::default() };
+        let remote_gateway = Uri::from_static("https://gateway.example");
+        let err = listen_tcp_with_config(find_free_port(), remote_gateway, config).await;
+        assert!(matches!(err, Err(RelayError::InvalidGateway(_))), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_port_in_use_is_a_bind_error() {
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let gateway = Uri::from_static("https://gateway.example");
+        let err = listen_tcp(port, gateway).await.unwrap_err();
+        match err {
+            RelayError::Bind(e) => assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse),
+            e => panic!("expected a bind error, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extra_root_cert() {
+        // That the public roots are kept alongside the extra ones is checked by the unit tests
+        // of the root store, since no public gateway is reachable from here.
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let gateway_cert = gen_localhost_cert();
+        let mut ca_file = NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut ca_file, gateway_cert.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        let config = Config {
+            extra_root_certs: vec![ca_file.path().to_path_buf()],
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_https(gateway_port, gateway_cert) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway_key_pinned_with_custom_roots() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+        let gateway_cert = gen_localhost_cert();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert_to_cert_der(&gateway_cert)).unwrap();
+        let gateway_pin = SpkiPin::of(&gateway_cert.get_key_pair().public_key_der());
+        let other_pin = SpkiPin::of(&gen_localhost_cert().get_key_pair().public_key_der());
+        let config = |pin| Config {
+            roots: Roots::Custom(Arc::new(roots.clone())),
+            pinned_spki: vec![pin],
+            ..insecure_gateway_config()
+        };
+        let (pinned_port, mispinned_port) = (find_free_port(), find_free_port());
+        tokio::select! {
+            _ = example_gateway_https(gateway_port, gateway_cert) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(pinned_port, gateway.clone(), config(gateway_pin)) => {
+                panic!("Relay is long running");
+            }
+            _ = listen_tcp_with_config(mispinned_port, gateway, config(other_pin)) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(pinned_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let res = ohttp_req_direct(mispinned_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_identity_presented_to_gateway() {
+        /// Only serves relays presenting a certificate signed by `client_ca`.
+        async fn mtls_gateway(
+            port: u16,
+            cert: Certificate,
+            client_ca: CertificateDer<'static>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            let mut client_roots = rustls::RootCertStore::empty();
+            client_roots.add(client_ca).unwrap();
+            let verifier = WebPkiClientVerifier::builder(Arc::new(client_roots)).build().unwrap();
+            let (key, cert) = cert_to_key_cert_der(cert);
+            let server_config = ServerConfig::builder()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(vec![cert], key)
+                .unwrap();
+            let acceptor = TlsAcceptor::from(Arc::new(server_config));
+            example_gateway(port, move |stream| {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let io = TokioIo::new(stream);
+                    if let Err(err) =
+                        http1::Builder::new().serve_connection(io, service_fn(handle_gateway)).await
+                    {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+        let gateway_cert = gen_localhost_cert();
+        let mut ca_file = NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut ca_file, gateway_cert.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        let client_cert = rcgen::generate_simple_self_signed(vec!["relay".to_string()]).unwrap();
+        let client_ca = cert_to_cert_der(&client_cert);
+        let mut cert_file = NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut cert_file, client_cert.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        let mut key_file = NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut key_file,
+            client_cert.serialize_private_key_pem().as_bytes(),
+        )
+        .unwrap();
+        let anonymous = Config {
+            extra_root_certs: vec![ca_file.path().to_path_buf()],
+            ..insecure_gateway_config()
+        };
+        let identity = ClientIdentity {
+            cert: cert_file.path().to_path_buf(),
+            key: key_file.path().to_path_buf(),
+        };
+        let authenticated = Config { client_identity: Some(identity), ..anonymous.clone() };
+        let (relay_port, anonymous_port) = (find_free_port(), find_free_port());
+        tokio::select! {
+            _ = mtls_gateway(gateway_port, gateway_cert, client_ca) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway.clone(), authenticated) => {
+                panic!("Relay is long running");
+            }
+            _ = listen_tcp_with_config(anonymous_port, gateway, anonymous) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let res = ohttp_req_direct(anonymous_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http2_gateway_negotiated() {
+        assert_eq!(gateway_version(insecure_gateway_config()).await, "HTTP/2.0");
+    }
+
+    #[tokio::test]
+    async fn test_http1_gateway_forced() {
+        let config = Config { force_http1: true, ..insecure_gateway_config() };
+        assert_eq!(gateway_version(config).await, "HTTP/1.1");
+    }
+
+    /// The HTTP version the relay spoke to an HTTPS gateway offering both h2 and http/1.1.
+    async fn gateway_version(config: Config) -> String {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let gateway_cert = gen_localhost_cert();
+        let mut ca_file = NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut ca_file, gateway_cert.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        let config = Config { extra_root_certs: vec![ca_file.path().to_path_buf()], ..config };
+        tokio::select! {
+            _ = example_gateway_alpn(gateway_port, gateway_cert) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                res.headers()["x-gateway-version"].to_str().unwrap().to_owned()
+            }
+        }
+    }
+
+    /// An HTTPS gateway that negotiates h2 or http/1.1 with ALPN and reports the version used
+    /// in an `x-gateway-version` response header.
+    async fn example_gateway_alpn(
+        port: u16,
+        cert: Certificate,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (key, cert) = cert_to_key_cert_der(cert);
+        let mut server_config =
+            ServerConfig::builder().with_no_client_auth().with_single_cert(vec![cert], key)?;
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        example_gateway(port, move |stream| {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = acceptor.accept(stream).await.expect("TLS error");
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let version = format!("{:?}", req.version());
+                    let mut res = handle_ohttp_req(req).await?;
+                    res.headers_mut()
+                        .insert("x-gateway-version", HeaderValue::from_str(&version).unwrap());
+                    Ok::<_, hyper::Error>(res)
+                });
+                if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    println!("Failed to serve connection: {:?}", err);
+                }
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_gateway_cert() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = example_gateway_https(gateway_port, gen_localhost_cert()) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway_response_validated() {
+        let res = relay_direct(html_gateway, insecure_gateway_config()).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let config = Config { validate_gateway_responses: true, ..insecure_gateway_config() };
+        let res = relay_direct(html_gateway, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+        let config = Config { validate_gateway_responses: true, ..insecure_gateway_config() };
+        let res = relay_direct(example_gateway_http, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_gateway_response_size_limited() {
+        let config = Config { max_response_body_size: Some(35), ..insecure_gateway_config() };
+        let res = relay_direct(example_gateway_http, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Vec::from_hex(ENCAPSULATED_RES).unwrap());
+        let config = Config { max_response_body_size: Some(34), ..insecure_gateway_config() };
+        let res = relay_direct(example_gateway_http, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_messages_padded_to_bucket() {
+        async fn padding_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            example_gateway(port, |stream| {
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        // 78 bytes of body and 2 digits of Content-Length.
+                        assert_eq!(req.headers()["padding"].len(), 256 - 80);
+                        handle_ohttp_req(req).await
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let config = Config { padding: Some(Padding::default()), ..insecure_gateway_config() };
+        let res = relay_direct(padding_gateway, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        // 35 bytes of body and 2 digits of Content-Length.
+        assert_eq!(res.headers()["padding"].len(), 256 - 37);
+    }
+
+    #[tokio::test]
+    async fn test_jittered_request_relayed() {
+        let jitter = Jitter {
+            max_forward_delay: Duration::from_millis(100),
+            max_response_delay: Duration::from_millis(100),
+        };
+        let config = Config { jitter: Some(jitter), ..insecure_gateway_config() };
+        let res = relay_direct(example_gateway_http, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Vec::from_hex(ENCAPSULATED_RES).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_limits() {
+        let streaming =
+            Streaming { max_buffered_bytes: Some(16 * 1024), flush_threshold: Some(4096) };
+        let config = Config { streaming, ..insecure_gateway_config() };
+        let res = relay_direct(example_gateway_http, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Vec::from_hex(ENCAPSULATED_RES).unwrap());
+
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let streaming = Streaming { max_buffered_bytes: Some(1024), ..Streaming::default() };
+        let config = Config { streaming, ..insecure_gateway_config() };
+        let err = listen_tcp_with_config(find_free_port(), gateway, config).await;
+        assert!(matches!(err, Err(RelayError::Config(_))), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_limit() {
+        let bandwidth_limit = Some(BandwidthLimit { burst: 16, per_second: 4096 });
+        let config = Config { bandwidth_limit, ..insecure_gateway_config() };
+        let res = relay_direct(example_gateway_http, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Vec::from_hex(ENCAPSULATED_RES).unwrap());
+
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let listen = Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], find_free_port())));
+        let err = Builder::new(gateway)
+            .danger_allow_insecure_gateway(true)
+            .port(find_free_port())
+            .add_listener_with_bandwidth_limit(listen, BandwidthLimit { burst: 16, per_second: 0 })
+            .serve()
+            .await;
+        assert!(matches!(err, Err(RelayError::Config(_))), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_streamed_both_ways() {
+        /// Echoes the body of a chunked OHTTP request back as it arrives.
+        async fn echo_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            example_gateway(port, |stream| {
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        assert_eq!(req.headers()[CONTENT_TYPE], "message/ohttp-chunked-req");
+                        let mut res = Response::new(req.into_body().boxed());
+                        res.headers_mut().insert(
+                            CONTENT_TYPE,
+                            HeaderValue::from_static("message/ohttp-chunked-res"),
+                        );
+                        Ok::<_, hyper::Error>(res)
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway_origin = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { validate_gateway_responses: true, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = echo_gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway_origin, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let (chunks, body) = tokio::sync::mpsc::channel(1);
+                let mut req = Request::new(ChannelBody(body));
+                *req.method_mut() = hyper::Method::POST;
+                *req.uri_mut() = format!("http://0.0.0.0:{}/", relay_port).parse().unwrap();
+                req.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-chunked-req"));
+                let stream = TcpStream::connect(("127.0.0.1", relay_port)).await.unwrap();
+                let (mut sender, conn) =
+                    hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+                tokio::spawn(conn);
+                chunks.send(Bytes::from_static(b"first")).await.unwrap();
+                let res = sender.send_request(req).await.unwrap();
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let mut body = res.into_body();
+                // Each chunk comes back before the next one is sent, so nothing is buffered.
+                let frame = body.frame().await.unwrap().unwrap();
+                assert_eq!(frame.into_data().unwrap(), "first");
+                chunks.send(Bytes::from_static(b"second")).await.unwrap();
+                let frame = body.frame().await.unwrap().unwrap();
+                assert_eq!(frame.into_data().unwrap(), "second");
+                drop(chunks);
+                assert!(body.frame().await.is_none());
+            } => {}
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {
+                panic!("Chunks were not streamed");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_of_unknown_length_forwarded() {
+        let body = ENCAPSULATED_REQ.len() / 2;
+        // Streamed bodies are re-framed as chunked for the gateway.
+        let (framing, received) = forwarded_framing(insecure_gateway_config()).await;
+        assert_eq!((framing.as_str(), received), ("chunked", body));
+        // Buffered bodies, e.g. to be sent again, get a `Content-Length`.
+        let config = Config { retry: Some(Retry::default()), ..insecure_gateway_config() };
+        let (framing, received) = forwarded_framing(config).await;
+        assert_eq!((framing, received), (body.to_string(), body));
+        // Limits apply as the body arrives.
+        let config = Config { max_body_size: Some(10), ..insecure_gateway_config() };
+        let (framing, _) = forwarded_framing(config).await;
+        assert_eq!(framing, "413");
+    }
+
+    /// How the gateway saw an OHTTP request framed that the client sent without a
+    /// `Content-Length`, in two chunks: `chunked` or its `Content-Length`, along with the body
+    /// length it received. Or the relay's status, if it refused the request.
+    async fn forwarded_framing(config: Config) -> (String, usize) {
+        async fn framing_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            example_gateway(port, |stream| {
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        let framing = match req.headers().get(CONTENT_LENGTH) {
+                            Some(length) => length.clone(),
+                            None => {
+                                assert_eq!(req.headers()[TRANSFER_ENCODING], "chunked");
+                                HeaderValue::from_static("chunked")
+                            }
+                        };
+                        let received = req.into_body().collect().await?.to_bytes().len();
+                        let mut res = Response::new(full(Vec::from_hex(ENCAPSULATED_RES).unwrap()));
+                        res.headers_mut()
+                            .insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-res"));
+                        res.headers_mut().insert("x-gateway-framing", framing);
+                        res.headers_mut().insert("x-gateway-received", HeaderValue::from(received));
+                        Ok::<_, hyper::Error>(res)
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = framing_gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let (chunks, body) = tokio::sync::mpsc::channel(2);
+                let mut req = Request::new(ChannelBody(body));
+                *req.method_mut() = hyper::Method::POST;
+                *req.uri_mut() = format!("http://0.0.0.0:{}/", relay_port).parse().unwrap();
+                req.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-req"));
+                let stream = TcpStream::connect(("127.0.0.1", relay_port)).await.unwrap();
+                let (mut sender, conn) =
+                    hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+                tokio::spawn(conn);
+                let body = Vec::from_hex(ENCAPSULATED_REQ).unwrap();
+                let (first, second) = body.split_at(body.len() / 2);
+                chunks.send(Bytes::copy_from_slice(first)).await.unwrap();
+                chunks.send(Bytes::copy_from_slice(second)).await.unwrap();
+                drop(chunks);
+                sender.send_request(req).await.unwrap()
+            } => {
+                if res.status() != hyper::StatusCode::OK {
+                    return (res.status().as_u16().to_string(), 0);
+                }
+                let header = |name| res.headers()[name].to_str().unwrap().to_owned();
+                (header("x-gateway-framing"), header("x-gateway-received").parse().unwrap())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trailers_passed_through() {
+        // Both are streamed.
+        assert_eq!(echoed_trailer(insecure_gateway_config()).await, "sha-256=:abc:");
+        // The response is buffered to enforce its size limit.
+        let config = Config { max_response_body_size: Some(1024), ..insecure_gateway_config() };
+        assert_eq!(echoed_trailer(config).await, "sha-256=:abc:");
+        // The request is buffered too, to be sent again.
+        let config = Config { retry: Some(Retry::default()), ..insecure_gateway_config() };
+        assert_eq!(echoed_trailer(config).await, "sha-256=:abc:");
+    }
+
+    /// The `digest` trailer the gateway received with an OHTTP request and sent back as a
+    /// response trailer, as the client got it over HTTP/1.
+    async fn echoed_trailer(config: Config) -> HeaderValue {
+        async fn trailer_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            example_gateway(port, |stream| {
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        let body = req.into_body().collect().await?;
+                        let trailers = body.trailers().cloned().unwrap_or_default();
+                        let body = Vec::from_hex(ENCAPSULATED_RES).unwrap();
+                        let mut res =
+                            Response::new(TrailingBody(Some(body.into()), Some(trailers)));
+                        res.headers_mut()
+                            .insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-res"));
+                        res.headers_mut().insert(TRAILER, HeaderValue::from_static("digest"));
+                        Ok::<_, hyper::Error>(res)
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = trailer_gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            trailers = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let mut trailers = HeaderMap::new();
+                trailers.insert("digest", HeaderValue::from_static("sha-256=:abc:"));
+                let body = Vec::from_hex(ENCAPSULATED_REQ).unwrap();
+                let mut req = Request::new(TrailingBody(Some(body.into()), Some(trailers)));
+                *req.method_mut() = hyper::Method::POST;
+                *req.uri_mut() = format!("http://0.0.0.0:{}/", relay_port).parse().unwrap();
+                req.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-req"));
+                req.headers_mut().insert(TRAILER, HeaderValue::from_static("digest"));
+                req.headers_mut().insert(TE, HeaderValue::from_static("trailers"));
+                let stream = TcpStream::connect(("127.0.0.1", relay_port)).await.unwrap();
+                let (mut sender, conn) =
+                    hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+                tokio::spawn(conn);
+                let res = sender.send_request(req).await.unwrap();
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                res.into_body().collect().await.unwrap().trailers().cloned()
+            } => trailers.expect("no trailers")["digest"].clone(),
+        }
+    }
+
+    /// A body of unknown length, so HTTP/1 frames it as chunked, followed by trailers.
+    struct TrailingBody(Option<Bytes>, Option<HeaderMap>);
+
+    impl hyper::body::Body for TrailingBody {
+        type Data = Bytes;
+        type Error = hyper::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<hyper::body::Frame<Bytes>, Self::Error>>> {
+            let frame = match self.0.take() {
+                Some(data) => Some(hyper::body::Frame::data(data)),
+                None => self.1.take().map(hyper::body::Frame::trailers),
+            };
+            std::task::Poll::Ready(frame.map(Ok))
+        }
+    }
+
+    /// A request body whose chunks are sent through a channel, one frame each.
+    struct ChannelBody(tokio::sync::mpsc::Receiver<Bytes>);
+
+    impl hyper::body::Body for ChannelBody {
+        type Data = Bytes;
+        type Error = hyper::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<hyper::body::Frame<Bytes>, Self::Error>>> {
+            self.0.poll_recv(cx).map(|chunk| chunk.map(|chunk| Ok(hyper::body::Frame::data(chunk))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refused_connection_retried() {
+        async fn late_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            tokio::time::sleep(Duration::from_millis(1300)).await;
+            example_gateway_http(port).await
+        }
+
+        let res = relay_direct(late_gateway, insecure_gateway_config()).await;
+        assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+        let retry = Retry {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(200),
+        };
+        let config = Config { retry: Some(retry), ..insecure_gateway_config() };
+        let res = relay_direct(late_gateway, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_opens_for_failing_gateway() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            circuit_breaker: Some(CircuitBreaker {
+                failure_threshold: 2,
+                open_duration: Duration::from_secs(1),
+            }),
+            ..insecure_gateway_config()
+        };
+        let relay = format!("http://0.0.0.0:{}/", relay_port);
+        tokio::select! {
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(1500)).await;
+                example_gateway_http(gateway_port).await
+            } => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                for _ in 0..2 {
+                    let res = send_direct(ohttp_request(relay.clone())).await;
+                    assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+                }
+                let res = send_direct(ohttp_request(relay.clone())).await;
+                assert_eq!(res.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(res.headers().get(RETRY_AFTER), Some(&HeaderValue::from_static("1")));
+
+                tokio::time::sleep(Duration::from_millis(1300)).await;
+                let res = send_direct(ohttp_request(relay.clone())).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK, "probe closes the circuit");
+                let res = send_direct(ohttp_request(relay.clone())).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log_written_without_client_details() {
+        let log = NamedTempFile::new().unwrap();
+        let access_log =
+            AccessLog { sink: AccessLogSink::JsonFile(log.path().into()), ..AccessLog::default() };
+        let config = Config { access_log: Some(access_log), ..insecure_gateway_config() };
+        let res = relay_direct(example_gateway_http, config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let lines = std::fs::read_to_string(log.path()).unwrap();
+        assert_eq!(lines.lines().count(), 1);
+        assert!(lines.contains(r#""method":"POST","path":"relay","status":200"#), "{}", lines);
+        assert!(!lines.contains("127.0.0.1") && !lines.contains("client_addr"), "{}", lines);
+    }
+
+    #[tokio::test]
+    async fn test_path_rewritten_for_gateway() {
+        let fixed = PathRewrite::Fixed(PathAndQuery::from_static("/gateway"));
+        assert_eq!(rewritten_path(fixed, "/client-id/123?tracking=abc").await, "/gateway");
+        let prefix =
+            PathRewrite::Prefix { strip: "/".to_owned(), add: "/ohttp/v1/request".to_owned() };
+        assert_eq!(rewritten_path(prefix, "/").await, "/ohttp/v1/request");
+    }
+
+    /// The path and query a gateway is asked for when a client requests `client_path` through
+    /// a relay rewriting paths with `path_rewrite`.
+    async fn rewritten_path(path_rewrite: PathRewrite, client_path: &str) -> String {
+        async fn path_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            example_gateway(port, |stream| {
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        let path =
+                            HeaderValue::from_str(req.uri().path_and_query().unwrap().as_str());
+                        let mut res = handle_ohttp_req(req).await?;
+                        res.headers_mut().insert("x-gateway-path", path.unwrap());
+                        Ok::<_, hyper::Error>(res)
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { path_rewrite, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = path_gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let uri = format!("http://0.0.0.0:{}{}", relay_port, client_path);
+                send_direct(ohttp_request(uri)).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                res.headers()["x-gateway-path"].to_str().unwrap().to_owned()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_encoding_policy() {
+        assert_eq!(encoding_headers(ContentEncoding::Strip).await, (None, None));
+        assert_eq!(
+            encoding_headers(ContentEncoding::PassThrough).await,
+            (Some("gzip".to_owned()), Some("identity".to_owned()))
+        );
+    }
+
+    /// The `Accept-Encoding` a gateway is sent and the `Content-Encoding` a client receives
+    /// through a relay with `content_encoding`, when each side sets one.
+    async fn encoding_headers(
+        content_encoding: ContentEncoding,
+    ) -> (Option<String>, Option<String>) {
+        async fn encoding_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            example_gateway(port, |stream| {
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        let accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+                        let mut res = handle_ohttp_req(req).await?;
+                        if let Some(accept_encoding) = accept_encoding {
+                            res.headers_mut().insert("x-gateway-accept-encoding", accept_encoding);
+                        }
+                        res.headers_mut()
+                            .insert(CONTENT_ENCODING, HeaderValue::from_static("identity"));
+                        Ok::<_, hyper::Error>(res)
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { content_encoding, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = encoding_gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let mut req = ohttp_request(format!("http://0.0.0.0:{}/", relay_port));
+                req.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+                send_direct(req).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let header = |name| {
+                    res.headers().get(name).map(|value: &HeaderValue| value.to_str().unwrap().to_owned())
+                };
+                (header("x-gateway-accept-encoding"), header(CONTENT_ENCODING.as_str()))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_headers_scrubbed() {
+        let headers = gateway_response_headers(insecure_gateway_config()).await;
+        assert!(headers.get(SET_COOKIE).is_none());
+        assert!(headers.get("x-powered-by").is_none());
+        assert_eq!(headers["x-gateway"], "kept");
+
+        let config = Config {
+            scrubbed_response_headers: vec![HeaderName::from_static("x-gateway")],
+            ..insecure_gateway_config()
+        };
+        let headers = gateway_response_headers(config).await;
+        assert_eq!(headers[SET_COOKIE], "id=1");
+        assert!(headers.get("x-gateway").is_none());
+    }
+
+    /// The headers a client receives through a relay with `config` from a gateway that sets
+    /// a cookie and fingerprintable headers.
+    async fn gateway_response_headers(config: Config) -> HeaderMap {
+        async fn cookie_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            example_gateway(port, |stream| {
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        let mut res = handle_ohttp_req(req).await?;
+                        res.headers_mut().insert(SET_COOKIE, HeaderValue::from_static("id=1"));
+                        res.headers_mut()
+                            .insert("x-powered-by", HeaderValue::from_static("gateway/1.0"));
+                        res.headers_mut().insert("x-gateway", HeaderValue::from_static("kept"));
+                        Ok::<_, hyper::Error>(res)
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = cookie_gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                send_direct(ohttp_request(format!("http://0.0.0.0:{}/", relay_port))).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                res.headers().clone()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redirect_pass_through() {
+        let res =
+            relay_direct(|port| redirecting_gateway(port, "/moved"), insecure_gateway_config())
+                .await;
+        assert_eq!(res.status(), hyper::StatusCode::FOUND);
+        assert_eq!(res.headers().get(LOCATION), Some(&HeaderValue::from_static("/moved")));
+    }
+
+    #[tokio::test]
+    async fn test_redirect_refused() {
+        let config =
+            Config { redirect_policy: RedirectPolicy::Refuse, ..insecure_gateway_config() };
+        let res = relay_direct(|port| redirecting_gateway(port, "/moved"), config).await;
+        assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_followed_same_origin() {
+        let config = Config {
+            redirect_policy: RedirectPolicy::FollowSameOrigin { max_redirects: 1 },
+            ..insecure_gateway_config()
+        };
+        let res = relay_direct(|port| redirecting_gateway(port, "/moved"), config).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static("message/ohttp-res"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builder_applies_options() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let relay = Builder::new(gateway)
+            .danger_allow_insecure_gateway(true)
+            .port(relay_port)
+            .redirect_policy(RedirectPolicy::Refuse)
+            .serve();
+        tokio::select! {
+            _ = redirecting_gateway(gateway_port, "/moved") => {
+                panic!("Gateway is long running");
+            }
+            _ = relay => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder_bind_addr() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let relay_addr = SocketAddr::from(([127, 0, 0, 1], relay_port));
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = Builder::new(gateway).danger_allow_insecure_gateway(true).bind_addr(relay_addr).serve() => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                send_direct(ohttp_request(format!("http://{}/", relay_addr))).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                // Only loopback was bound, so the port is still free on other addresses.
+                assert!(std::net::TcpListener::bind(("0.0.0.0", relay_port)).is_ok());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder_dual_stack() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = Builder::new(gateway).danger_allow_insecure_gateway(true).dual_stack(relay_port).serve() => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                for host in ["127.0.0.1", "[::1]"] {
+                    let uri = format!("http://{}:{}/", host, relay_port);
+                    let res = send_direct(ohttp_request(uri)).await;
+                    assert_eq!(res.status(), hyper::StatusCode::OK, "{}", host);
+                }
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listen_tcp_bind_ip() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { bind_ip: "::1".parse().unwrap(), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let uri = format!("http://[::1]:{}/", relay_port);
+                let res = send_direct(ohttp_request(uri)).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_builder_multiple_listeners() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let socket_path = temp_dir.path().join("relay.socket");
+        let shutdown = tokio_util::sync::CancellationToken::new();
+        let relay = Builder::new(gateway)
+            .danger_allow_insecure_gateway(true)
+            .port(relay_port)
+            .add_listener(Listen::Socket(socket_path.clone()))
+            .socket_file(SocketFile { unlink_on_shutdown: true, ..SocketFile::default() })
+            .shutdown(shutdown.clone())
+            .serve();
+        tokio::pin!(relay);
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = &mut relay => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+                let (mut sender, conn) =
+                    hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+                tokio::spawn(conn);
+                let res =
+                    sender.send_request(ohttp_request("http://localhost/".to_owned())).await.unwrap();
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+        // Both listeners stop on the one shutdown.
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), relay).await.unwrap().unwrap();
+        assert!(!socket_path.exists());
+        assert!(TcpStream::connect(("127.0.0.1", relay_port)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_listener() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = listener.local_addr().unwrap();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = serve_listener(listener, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                send_direct(ohttp_request(format!("http://{}/", relay_addr))).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_tcp_listener() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay_addr = listener.local_addr().unwrap();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = serve_tcp_listener(listener, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                send_direct(ohttp_request(format!("http://{}/", relay_addr))).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_unix_listener() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let socket_path = temp_dir.path().join("relay.socket");
+        let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = serve_unix_listener(listener, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+                let (mut sender, conn) =
+                    hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+                tokio::spawn(conn);
+                sender.send_request(ohttp_request("http://localhost/".to_owned())).await.unwrap()
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn test_mock_gateway() {
+        let gateway = ohttp_relay::test_util::MockGateway::start().await.unwrap();
+        gateway.set_response(Vec::from_hex(ENCAPSULATED_RES).unwrap());
+        gateway.set_keys(&b"keys"[..]);
+        let config = Config { ohttp_keys: Some(OhttpKeys::default()), ..insecure_gateway_config() };
+        let relay = spawn_tcp(SocketAddr::from(([127, 0, 0, 1], 0)), gateway.origin(), config)
+            .await
+            .unwrap();
+
+        let res = send_direct(ohttp_request(format!("http://{}/", relay.local_addr()))).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Vec::from_hex(ENCAPSULATED_RES).unwrap());
+        // The gateway receives as much of the request as its `Content-Length` declares.
+        let req = Vec::from_hex(ENCAPSULATED_REQ).unwrap();
+        assert_eq!(gateway.requests(), vec![Bytes::copy_from_slice(&req[..78])]);
+
+        let res = get_direct(relay.local_addr().port(), "/ohttp-keys").await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/ohttp-keys");
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), &b"keys"[..]);
+        relay.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spawn_tcp_on_ephemeral_port() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let relay = spawn_tcp(addr, gateway, insecure_gateway_config()).await.unwrap();
+        let relay_addr = relay.local_addr();
+        assert_ne!(relay_addr.port(), 0);
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                send_direct(ohttp_request(format!("http://{}/", relay_addr))).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+        relay.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_redirect_cross_origin_not_followed() {
+        let config = Config {
+            redirect_policy: RedirectPolicy::FollowSameOrigin { max_redirects: 1 },
+            ..insecure_gateway_config()
+        };
+        let res =
+            relay_direct(|port| redirecting_gateway(port, "https://example.com/moved"), config)
+                .await;
+        assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_limit_exceeded() {
+        let config = Config {
+            redirect_policy: RedirectPolicy::FollowSameOrigin { max_redirects: 0 },
+            ..insecure_gateway_config()
+        };
+        let res = relay_direct(|port| redirecting_gateway(port, "/moved"), config).await;
+        assert_eq!(res.status(), hyper::StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body_aborted() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            body_read_timeout: Some(Duration::from_millis(200)),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = stalled_body_req(relay_port) => {
+                assert!(res.starts_with("HTTP/1.1 408"), "unexpected response: {}", res);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_rejected() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { max_body_size: Some(32), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::PAYLOAD_TOO_LARGE);
+
+                // Without a declared length the limit is enforced as the body streams in.
+                let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                let chunk = Vec::from_hex(ENCAPSULATED_REQ).unwrap();
+                let mut req = format!(
+                    "POST / HTTP/1.1\r\nHost: 0.0.0.0\r\nContent-Type: message/ohttp-req\r\n\
+                     Transfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+                    chunk.len()
+                )
+                .into_bytes();
+                req.extend_from_slice(&chunk);
+                req.extend_from_slice(b"\r\n0\r\n\r\n");
+                stream.write_all(&req).await.unwrap();
+                let mut response = [0; 12];
+                stream.read_exact(&mut response).await.unwrap();
+                assert_eq!(&response, b"HTTP/1.1 413");
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_continue_sent_once_accepted() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        // Hold forwarding back so a 100 Continue sent only once the gateway is reached is late.
+        let jitter = Jitter { max_forward_delay: Duration::from_secs(3), ..Jitter::default() };
+        let config = Config { jitter: Some(jitter), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let body = Vec::from_hex(ENCAPSULATED_REQ).unwrap();
+                let head = |content_type: &str| {
+                    format!(
+                        "POST / HTTP/1.1\r\nHost: 0.0.0.0\r\nContent-Type: {}\r\n\
+                         Content-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+                        content_type,
+                        body.len()
+                    )
+                };
+
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                stream.write_all(head("text/plain").as_bytes()).await.unwrap();
+                let mut response = [0; 12];
+                stream.read_exact(&mut response).await.unwrap();
+                assert_eq!(&response, b"HTTP/1.1 415");
+
+                let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                stream.write_all(head("message/ohttp-req").as_bytes()).await.unwrap();
+                let mut interim = [0; 25];
+                tokio::time::timeout(Duration::from_millis(500), stream.read_exact(&mut interim))
+                    .await
+                    .expect("100 Continue before the gateway is reached")
+                    .unwrap();
+                assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+                stream.write_all(&body).await.unwrap();
+                stream.read_exact(&mut response).await.unwrap();
+                assert_eq!(&response, b"HTTP/1.1 200");
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let rate_limit = RateLimit { burst: 1, per_second: 1, ..RateLimit::default() };
+        let config = Config { rate_limit: Some(rate_limit), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let uri = format!("http://0.0.0.0:{}/", relay_port);
+                let res = send_direct(ohttp_request(uri)).await;
+                assert_eq!(res.status(), hyper::StatusCode::TOO_MANY_REQUESTS);
+                assert_eq!(res.headers().get(RETRY_AFTER), Some(&HeaderValue::from_static("1")));
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_reloaded() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = insecure_gateway_config();
+        let reload = config.reload.clone();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config.clone()) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let uri = format!("http://0.0.0.0:{}/", relay_port);
+                assert_eq!(ohttp_req_direct(relay_port).await.status(), hyper::StatusCode::OK);
+                let res = send_direct(ohttp_request(uri.clone())).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+
+                let rate_limit = RateLimit { burst: 1, per_second: 1, ..RateLimit::default() };
+                reload.reload(Config { rate_limit: Some(rate_limit), ..config });
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let res = send_direct(ohttp_request(uri.clone())).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let res = send_direct(ohttp_request(uri)).await;
+                assert_eq!(res.status(), hyper::StatusCode::TOO_MANY_REQUESTS);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_named_gateways_routed_and_reloaded() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        // Nothing listens at the default gateway, so only tenant routes succeed.
+        let default_gateway =
+            Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            named_gateways: [("acme".to_owned(), gateway.clone())].into(),
+            ..insecure_gateway_config()
+        };
+        let reload = config.reload.clone();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, default_gateway, config.clone()) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let tenant = |name| format!("http://0.0.0.0:{}/gw/{}/", relay_port, name);
+                let res = send_direct(ohttp_request(tenant("acme"))).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let res = send_direct(ohttp_request(tenant("beta"))).await;
+                assert_eq!(res.status(), hyper::StatusCode::NOT_FOUND);
+
+                let named_gateways = [("beta".to_owned(), gateway)].into();
+                reload.reload(Config { named_gateways, ..config });
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let res = send_direct(ohttp_request(tenant("beta"))).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let res = send_direct(ohttp_request(tenant("acme"))).await;
+                assert_eq!(res.status(), hyper::StatusCode::NOT_FOUND);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway_config_overrides_limits() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let strict_port = find_free_port();
+        let strict = Uri::from_str(&format!("http://0.0.0.0:{}", strict_port)).unwrap();
+        let relay_port = find_free_port();
+        let strict_config = GatewayConfig { max_body_size: Some(10), ..GatewayConfig::default() };
+        let config = Config {
+            allowed_gateways: vec![strict.clone()],
+            gateway_configs: [(strict, strict_config)].into(),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = example_gateway_http(strict_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let url = format!("http://0.0.0.0:{}/http://0.0.0.0:{}/", relay_port, strict_port);
+                let res = send_direct(ohttp_request(url)).await;
+                assert_eq!(res.status(), hyper::StatusCode::PAYLOAD_TOO_LARGE);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway_config_rate_limits() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let (one_port, two_port) = (find_free_port(), find_free_port());
+        let one = Uri::from_str(&format!("http://0.0.0.0:{}", one_port)).unwrap();
+        let two = Uri::from_str(&format!("http://0.0.0.0:{}", two_port)).unwrap();
+        let relay_port = find_free_port();
+        let limited = |burst| GatewayConfig {
+            rate_limit: Some(RateLimit { burst, per_second: 1, ..RateLimit::default() }),
+            ..GatewayConfig::default()
+        };
+        let config = Config {
+            allowed_gateways: vec![one.clone(), two.clone()],
+            gateway_configs: [(one, limited(1)), (two, limited(2))].into(),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = example_gateway_http(one_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = example_gateway_http(two_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let status = |port| async move {
+                    let url = format!("http://0.0.0.0:{}/http://0.0.0.0:{}/", relay_port, port);
+                    send_direct(ohttp_request(url)).await.status()
+                };
+                assert_eq!(status(one_port).await, hyper::StatusCode::OK);
+                assert_eq!(status(one_port).await, hyper::StatusCode::TOO_MANY_REQUESTS);
+                // Each gateway's limit is enforced on its own buckets.
+                assert_eq!(status(two_port).await, hyper::StatusCode::OK);
+                assert_eq!(status(two_port).await, hyper::StatusCode::OK);
+                assert_eq!(status(two_port).await, hyper::StatusCode::TOO_MANY_REQUESTS);
+                // Gateways without their own limit keep the relay-wide one, none here.
+                for _ in 0..3 {
+                    let res = send_direct(ohttp_request(format!("http://0.0.0.0:{}/", relay_port))).await;
+                    assert_eq!(res.status(), hyper::StatusCode::OK);
+                }
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_by_proxy_protocol_client() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let rate_limit = RateLimit { burst: 1, per_second: 1, ..RateLimit::default() };
+        let config = Config {
+            rate_limit: Some(rate_limit),
+            proxy_protocol: true,
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let uri = format!("http://0.0.0.0:{}/", relay_port);
+                let res = send_proxied(relay_port, "192.0.2.1", ohttp_request(uri.clone())).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let res = send_proxied(relay_port, "192.0.2.1", ohttp_request(uri.clone())).await;
+                assert_eq!(res.status(), hyper::StatusCode::TOO_MANY_REQUESTS);
+                // Every client behind the proxy has its own bucket.
+                let res = send_proxied(relay_port, "192.0.2.2", ohttp_request(uri)).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn test_bench_reports_relayed_requests() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            report = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let bench = Bench {
+                    relay: format!("http://127.0.0.1:{}/", relay_port).parse().unwrap(),
+                    concurrency: 4,
+                    requests: 20,
+                    body: Vec::from_hex(ENCAPSULATED_REQ).unwrap().into(),
+                };
+                bench.run().await.unwrap()
+            } => {
+                assert_eq!(report.statuses.get(&200), Some(&20));
+                assert_eq!(report.latencies.len(), 20);
+                assert_eq!(report.error_rate(), 0.0);
+                assert!(report.to_string().contains("p99"));
+            }
+        }
+    }
+
+    /// Send `req` on a new connection opened with a PROXY protocol v1 header naming `client`.
+    async fn send_proxied(
+        relay_port: u16,
+        client: &str,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Response<Incoming> {
+        let mut stream = TcpStream::connect(("127.0.0.1", relay_port)).await.unwrap();
+        let header = format!("PROXY TCP4 {} 127.0.0.1 40000 {}\r\n", client, relay_port);
+        stream.write_all(header.as_bytes()).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+        sender.send_request(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_relay_service_mounted_in_own_server() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let service = OhttpRelayService::new(gateway, insecure_gateway_config()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = listener.local_addr().unwrap().port();
+        let server = async move {
+            loop {
+                let (stream, peer_addr) = listener.accept().await.unwrap();
+                let service = service.clone().with_peer_addr(peer_addr);
+                tokio::spawn(async move {
+                    let io = TokioIo::new(stream);
+                    let service = service_fn(move |req| service.clone().call(req));
+                    auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(io, service)
+                        .await
+                        .unwrap();
+                });
+            }
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = server => {
+                panic!("Server is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_service_under_prefix() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let service = make_service("/ohttp/", gateway, insecure_gateway_config()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = listener.local_addr().unwrap().port();
+        let server = async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| service.clone().call(req));
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service).await
+                });
+            }
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = server => {
+                panic!("Server is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let url = format!("http://127.0.0.1:{}/ohttp", relay_port);
+                let res = send_direct(ohttp_request(url)).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let url = format!("http://127.0.0.1:{}/", relay_port);
+                let res = send_direct(ohttp_request(url)).await;
+                assert_eq!(res.status(), hyper::StatusCode::NOT_FOUND);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_resolver() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://gateway.invalid:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { resolver: Arc::new(Loopback), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    /// Resolves only `gateway.invalid`, to the loopback address.
+    #[derive(Debug)]
+    struct Loopback;
+
+    impl resolve::Resolver for Loopback {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+        ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<IpAddr>>> + Send + 'a>> {
+            Box::pin(async move {
+                assert_eq!(host, "gateway.invalid");
+                Ok(vec![IpAddr::from([127, 0, 0, 1])])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway_discovery() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_static("http://gateway.invalid");
+        let relay_port = find_free_port();
+        let config = Config {
+            resolver: Arc::new(Advertised(gateway_port)),
+            gateway_discovery: true,
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    /// Advertises `edge.invalid` on this port as the endpoint of `gateway.invalid`, and
+    /// resolves only the endpoint, to the loopback address.
+    #[derive(Debug)]
+    struct Advertised(u16);
+
+    impl resolve::Resolver for Advertised {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+        ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<IpAddr>>> + Send + 'a>> {
+            Box::pin(async move {
+                assert_eq!(host, "edge.invalid");
+                Ok(vec![IpAddr::from([127, 0, 0, 1])])
+            })
+        }
+
+        fn resolve_https<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<resolve::ServiceBinding>>> + Send + 'a>>
+        {
+            Box::pin(async move {
+                assert_eq!(name, "_80._https.gateway.invalid");
+                Ok(vec![resolve::ServiceBinding {
+                    priority: 1,
+                    target: "edge.invalid".to_owned(),
+                    port: Some(self.0),
+                    alpn: vec!["http/1.1".to_owned()],
+                    ttl: Duration::from_secs(300),
+                }])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway_reached_through_socks5() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://localhost:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config =
+            Config { socks5_proxy: Some(proxy.local_addr().unwrap()), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = socks5_proxy(proxy) => {
+                panic!("Proxy is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    /// A SOCKS5 proxy that only accepts domain name targets, tunnelling each to localhost.
+    async fn socks5_proxy(listener: TcpListener) {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            client.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 5];
+            client.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 3], "expected a domain name target");
+            let mut host = vec![0; usize::from(request[4])];
+            client.read_exact(&mut host).await.unwrap();
+            let port = client.read_u16().await.unwrap();
+            assert_eq!(host, b"localhost");
+
+            let mut gateway = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await.unwrap();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut gateway).await;
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connections_limited() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { max_connections: Some(1), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let held = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                let queued =
+                    tokio::time::timeout(Duration::from_secs(1), get_direct(relay_port, "/health"));
+                assert!(queued.await.is_err(), "second connection should wait for a slot");
+
+                drop(held);
+                let res = get_direct(relay_port, "/health").await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_headers_closed() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            header_read_timeout: Some(Duration::from_millis(300)),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                stream.write_all(b"POST / HTTP/1.1\r\nHost: 0.0.0.0\r\n").await.unwrap();
+                let mut response = Vec::new();
+                tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                    .await
+                    .expect("relay should close the connection")
+                    .unwrap();
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_closed() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config =
+            Config { idle_timeout: Some(Duration::from_millis(300)), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                stream.write_all(b"GET /health HTTP/1.1\r\nHost: 0.0.0.0\r\n\r\n").await.unwrap();
+                let mut response = Vec::new();
+                tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                    .await
+                    .expect("relay should close the idle connection")
+                    .unwrap();
+                let response = String::from_utf8_lossy(&response);
+                assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listening_reported() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let (listening, mut listening_rx) = tokio::sync::mpsc::unbounded_channel();
+        let relay = tokio::spawn(
+            Builder::new(gateway)
+                .danger_allow_insecure_gateway(true)
+                .bind_addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .on_listening(listening)
+                .serve(),
+        );
+        let addr = match listening_rx.recv().await {
+            Some(Listening::Tcp(addr)) => addr,
+            other => panic!("unexpected listener: {:?}", other),
+        };
+        assert_ne!(addr.port(), 0);
+        // No waiting: the relay serves as soon as it reports the listener.
+        assert!(get_direct(addr.port(), "/health").await.status().is_success());
+        relay.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_after_max_requests() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { max_requests_per_connection: Some(2), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                let req = b"GET /health HTTP/1.1\r\nHost: 0.0.0.0\r\n\r\n";
+                stream.write_all(&[&req[..], &req[..], &req[..]].concat()).await.unwrap();
+                let mut response = Vec::new();
+                tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                    .await
+                    .expect("relay should close the connection after the second request")
+                    .unwrap();
+                let response = String::from_utf8_lossy(&response);
+                assert_eq!(response.matches("HTTP/1.1 200").count(), 2, "{}", response);
+                assert!(response.contains("connection: close"), "{}", response);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http1_keep_alive_disabled() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let http1 = Http1Server { keep_alive: false, ..Http1Server::default() };
+        let config = Config { http1, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                stream.write_all(b"GET /health HTTP/1.1\r\nHost: 0.0.0.0\r\n\r\n").await.unwrap();
+                let mut response = Vec::new();
+                tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut response))
+                    .await
+                    .expect("relay should close the connection after one response")
+                    .unwrap();
+                let response = String::from_utf8_lossy(&response);
+                assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http1_buffer_too_small_rejected() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let http1 = Http1Server { max_buf_size: Some(1024), ..Http1Server::default() };
+        let config = Config { http1, ..insecure_gateway_config() };
+        let res = listen_tcp_with_config(find_free_port(), gateway, config).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_headers_rejected() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            max_header_bytes: Some(256),
+            max_header_count: Some(8),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+
+                let uri = format!("http://0.0.0.0:{}/", relay_port);
+                let mut req = ohttp_request(uri.clone());
+                for i in 0..8 {
+                    let name = hyper::header::HeaderName::from_str(&format!("x-extra-{}", i)).unwrap();
+                    req.headers_mut().insert(name, HeaderValue::from_static("1"));
+                }
+                let res = send_direct(req).await;
+                assert_eq!(res.status(), hyper::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+                let mut req = ohttp_request(uri);
+                let value = HeaderValue::from_str(&"a".repeat(300)).unwrap();
+                req.headers_mut().insert("x-extra", value);
+                let res = send_direct(req).await;
+                assert_eq!(res.status(), hyper::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            } => {}
+        }
+    }
+
+    /// Send headers and part of the declared body, then stall until the relay hangs up.
+    async fn stalled_body_req(relay_port: u16) -> String {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: 0.0.0.0\r\nContent-Type: message/ohttp-req\r\n\
+                  Content-Length: 78\r\n\r\n0123456789",
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("relay should abort the stalled connection")
+            .unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_gateway_selected_from_allowlist() {
+        let default_port = find_free_port();
+        let default_gateway = Uri::from_str(&format!("http://0.0.0.0:{}", default_port)).unwrap();
+        let allowed_port = find_free_port();
+        let allowed_gateway = format!("http://0.0.0.0:{}", allowed_port);
+        let relay_port = find_free_port();
+        let config = Config {
+            allowed_gateways: vec![allowed_gateway.parse().unwrap()],
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(allowed_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, default_gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                // Only the allowed gateway is running, so success means it was selected.
+                let url = format!("http://0.0.0.0:{}/{}/", relay_port, allowed_gateway);
+                let res = send_direct(ohttp_request(url)).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+
+                let unlisted = format!("http://0.0.0.0:{}", find_free_port());
+                let url = format!("http://0.0.0.0:{}/{}/", relay_port, unlisted);
+                let res = send_direct(ohttp_request(url)).await;
+                assert_eq!(res.status(), hyper::StatusCode::FORBIDDEN);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_spread_across_replicas() {
+        let (port_a, port_b) = (find_free_port(), find_free_port());
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", port_a)).unwrap();
+        let replica = Uri::from_str(&format!("http://0.0.0.0:{}", port_b)).unwrap();
+        let relay_port = find_free_port();
+        let (count_a, count_b) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let config = Config { gateway_replicas: vec![replica], ..insecure_gateway_config() };
+        tokio::select! {
+            _ = counting_gateway(port_a, count_a.clone()) => {
+                panic!("Gateway is long running");
+            }
+            _ = counting_gateway(port_b, count_b.clone()) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                for _ in 0..4 {
+                    let url = format!("http://0.0.0.0:{}/", relay_port);
+                    let res = send_direct(ohttp_request(url)).await;
+                    assert_eq!(res.status(), hyper::StatusCode::OK);
+                }
+            } => {}
+        }
+        assert_eq!(count_a.load(Ordering::SeqCst), 2);
+        assert_eq!(count_b.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_healthy_replica() {
+        let down_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", down_port)).unwrap();
+        let replica_port = find_free_port();
+        let replica = Uri::from_str(&format!("http://0.0.0.0:{}", replica_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            gateway_replicas: vec![replica],
+            health_check: Some(fast_health_check()),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(replica_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                for _ in 0..4 {
+                    let url = format!("http://0.0.0.0:{}/", relay_port);
+                    let res = send_direct(ohttp_request(url)).await;
+                    assert_eq!(res.status(), hyper::StatusCode::OK);
+                }
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_without_healthy_gateway() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config =
+            Config { health_check: Some(fast_health_check()), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(res.headers().get(RETRY_AFTER), Some(&HeaderValue::from_static("1")));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_follows_gateway_health() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config =
+            Config { health_check: Some(fast_health_check()), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let health = get_direct(relay_port, "/health").await;
+                assert_eq!(health.status(), hyper::StatusCode::OK);
+                let ready = get_direct(relay_port, "/ready").await;
+                assert_eq!(ready.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints_disabled() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { health_endpoints: false, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                for path in ["/health", "/ready"] {
+                    let res = get_direct(relay_port, path).await;
+                    assert_ne!(res.status(), hyper::StatusCode::OK, "{} answered", path);
+                }
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_document() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let named_gateways =
+            [("alice".to_owned(), Uri::from_static("http://127.0.0.1:9"))].into_iter().collect();
+        let config = Config {
+            capabilities: true,
+            max_body_size: Some(4096),
+            named_gateways,
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let res = get_direct(relay_port, "/.well-known/ohttp-relay").await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                let doc = String::from_utf8(body.to_vec()).unwrap();
+                assert!(doc.contains(r#""bootstrap":["connect""#), "{}", doc);
+                assert!(doc.contains(r#""max_body_size":4096"#), "{}", doc);
+                assert!(doc.contains(r#""gateway_paths":["/","/gw/alice/"]"#), "{}", doc);
+            } => {}
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_admin_listener_on_socket() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let socket_path = temp_dir.path().join("admin.socket");
+        let config = insecure_gateway_config();
+        let reloaded = config.clone();
+        let reload: AdminReload = Arc::new(move || {
+            let rate_limit = RateLimit { burst: 1, per_second: 1, ..RateLimit::default() };
+            Ok(Config { rate_limit: Some(rate_limit), ..reloaded.clone() })
+        });
+        let admin = Admin { listen: Listen::Socket(socket_path.clone()), reload: Some(reload) };
+        let config = Config { admin: Some(admin), ..config };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let uri = format!("http://0.0.0.0:{}/", relay_port);
+                assert_eq!(ohttp_req_direct(relay_port).await.status(), hyper::StatusCode::OK);
+                // Operational endpoints are no longer served to relay clients.
+                assert_ne!(get_direct(relay_port, "/health").await.status(), hyper::StatusCode::OK);
+
+                let admin = |method: &str, path: &str| {
+                    let req = Request::builder()
+                        .method(method)
+                        .uri(format!("http://localhost{}", path))
+                        .body(Full::new(Bytes::new()))
+                        .unwrap();
+                    let socket_path = socket_path.clone();
+                    async move {
+                        let stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+                        let (mut sender, conn) =
+                            hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                                .await
+                                .unwrap();
+                        tokio::spawn(conn);
+                        sender.send_request(req).await.unwrap().status()
+                    }
+                };
+                assert_eq!(admin("GET", "/health").await, hyper::StatusCode::OK);
+                assert_eq!(admin("GET", "/ready").await, hyper::StatusCode::OK);
+                assert_eq!(admin("GET", "/nope").await, hyper::StatusCode::NOT_FOUND);
+                assert_eq!(admin("POST", "/reload").await, hyper::StatusCode::ACCEPTED);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let res = send_direct(ohttp_request(uri.clone())).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let res = send_direct(ohttp_request(uri)).await;
+                assert_eq!(res.status(), hyper::StatusCode::TOO_MANY_REQUESTS);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateways_replaced_through_admin() {
+        let gateway_port = find_free_port();
+        // Nothing listens on the gateway the relay starts with.
+        let old_gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let admin_port = find_free_port();
+        let admin = Admin::new(Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], admin_port))));
+        let config = Config { admin: Some(admin), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, old_gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                assert_eq!(ohttp_req_direct(relay_port).await.status(), hyper::StatusCode::BAD_GATEWAY);
+                let put = |body: String| {
+                    let mut req = Request::new(full(body));
+                    *req.method_mut() = hyper::Method::PUT;
+                    *req.uri_mut() =
+                        format!("http://127.0.0.1:{}/config/gateways", admin_port).parse().unwrap();
+                    send_direct(req)
+                };
+                let res = put("gateway_origin = \"ftp://nope\"".to_owned()).await;
+                assert_eq!(res.status(), hyper::StatusCode::UNPROCESSABLE_ENTITY);
+                let body = format!(
+                    "gateway_origin = \"http://0.0.0.0:{}\"\nallowed_gateways = [\"http://0.0.0.0:1\"]",
+                    gateway_port
+                );
+                assert_eq!(put(body).await.status(), hyper::StatusCode::ACCEPTED);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+
+                let res = get_direct(admin_port, "/config/gateways").await;
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                assert_eq!(
+                    String::from_utf8_lossy(&body),
+                    format!(
+                        r#"{{"gateway_origin":"http://0.0.0.0:{}","allowed_gateways":["http://0.0.0.0:1"]}}"#,
+                        gateway_port
+                    )
+                );
+                let res = send_direct(ohttp_request(format!("http://0.0.0.0:{}/", relay_port))).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    /// Resolves only `gateway.invalid`, to the loopback address, counting flushes.
+    #[derive(Debug, Default)]
+    struct Flushable(AtomicUsize);
+
+    impl resolve::Resolver for Flushable {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+        ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<IpAddr>>> + Send + 'a>> {
+            Loopback.resolve(host)
+        }
+
+        fn flush(&self) { self.0.fetch_add(1, Ordering::Relaxed); }
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache_flushed_through_admin() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://gateway.invalid:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let admin_port = find_free_port();
+        let resolver = Arc::new(Flushable::default());
+        let config = Config {
+            admin: Some(Admin::new(Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], admin_port))))),
+            resolver: resolver.clone(),
+            dns_cache: Some(DnsCache::default()),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                assert_eq!(ohttp_req_direct(relay_port).await.status(), hyper::StatusCode::OK);
+                let mut req = Request::new(full(""));
+                *req.method_mut() = hyper::Method::POST;
+                *req.uri_mut() = format!("http://127.0.0.1:{}/dns/flush", admin_port).parse().unwrap();
+                assert_eq!(send_direct(req).await.status(), hyper::StatusCode::NO_CONTENT);
+                assert_eq!(resolver.0.load(Ordering::Relaxed), 1);
+                assert_eq!(ohttp_req_direct(relay_port).await.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_broadcast() {
+        async fn next(subscriber: &mut tokio::sync::broadcast::Receiver<RelayEvent>) -> RelayEvent {
+            tokio::time::timeout(Duration::from_secs(5), subscriber.recv()).await.unwrap().unwrap()
+        }
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let (events, mut subscriber) = tokio::sync::broadcast::channel(16);
+        let config = Config { events: Some(events), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                assert_eq!(ohttp_req_direct(relay_port).await.status(), hyper::StatusCode::OK);
+                assert_eq!(next(&mut subscriber).await, RelayEvent::ConnectionAccepted);
+                match next(&mut subscriber).await {
+                    RelayEvent::RequestForwarded { gateway, status, .. } => {
+                        assert_eq!(gateway.port_u16(), Some(gateway_port));
+                        assert_eq!(status, hyper::StatusCode::OK);
+                    }
+                    event => panic!("Unexpected event {:?}", event),
+                }
+                assert_eq!(next(&mut subscriber).await, RelayEvent::ConnectionClosed);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_must_allow_requests() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let limit = RateLimit { per_second: 0, ..RateLimit::default() };
+        let config = Config { rate_limit: Some(limit), ..insecure_gateway_config() };
+        let res = listen_tcp_with_config(find_free_port(), gateway, config).await;
+        assert!(matches!(res, Err(RelayError::Config(_))), "{:?}", res);
+    }
+
+    #[tokio::test]
+    async fn test_admin_listener_must_be_local() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let admin = Admin::new(Listen::Tcp(SocketAddr::from(([0, 0, 0, 0], find_free_port()))));
+        let config = Config { admin: Some(admin), ..insecure_gateway_config() };
+        let res = listen_tcp_with_config(find_free_port(), gateway, config).await;
+        assert!(matches!(res, Err(RelayError::Config(_))), "{:?}", res);
+    }
+
+    #[tokio::test]
+    async fn test_methods_other_than_post_answered() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let bootstrap = cfg!(any(feature = "connect-bootstrap", feature = "ws-bootstrap"));
+                let request = |method, path| {
+                    let mut req = Request::new(full(Bytes::new()));
+                    *req.method_mut() = method;
+                    *req.uri_mut() =
+                        format!("http://127.0.0.1:{}{}", relay_port, path).parse().unwrap();
+                    send_direct(req)
+                };
+
+                let res = request(hyper::Method::OPTIONS, "/").await;
+                assert_eq!(res.status(), hyper::StatusCode::NO_CONTENT);
+                let allow = if bootstrap { "CONNECT, GET, POST" } else { "POST" };
+                assert_eq!(res.headers()[ALLOW], allow);
+                assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+                let res = request(hyper::Method::OPTIONS, "/health").await;
+                let allow = if bootstrap { "CONNECT, GET, HEAD, POST" } else { "GET, HEAD, POST" };
+                assert_eq!(res.headers()[ALLOW], allow);
+
+                let res = request(hyper::Method::HEAD, "/health").await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                assert!(res.into_body().collect().await.unwrap().to_bytes().is_empty());
+
+                let res = request(hyper::Method::PUT, "/").await;
+                assert_eq!(res.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+                let allow = if bootstrap { "CONNECT, GET, POST" } else { "POST" };
+                assert_eq!(res.headers()[ALLOW], allow);
+                assert_eq!(res.headers()["request-id"].len(), 16);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ohttp_keys_cached_with_cors() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let count = Arc::new(AtomicUsize::new(0));
+        let config = Config {
+            ohttp_keys: Some(OhttpKeys::default()),
+            cors: Cors {
+                allowed_origins: vec![HeaderValue::from_static("https://wallet.example")],
+                ..Cors::default()
+            },
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = counting_gateway(gateway_port, count.clone()) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                for path in ["/ohttp-keys", "/.well-known/ohttp-gateway"] {
+                    let mut req = Request::new(full(Bytes::new()));
+                    *req.uri_mut() =
+                        format!("http://127.0.0.1:{}{}", relay_port, path).parse().unwrap();
+                    req.headers_mut()
+                        .insert(ORIGIN, HeaderValue::from_static("https://wallet.example"));
+                    let res = send_direct(req).await;
+                    assert_eq!(res.status(), hyper::StatusCode::OK);
+                    assert_eq!(
+                        res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN),
+                        Some(&HeaderValue::from_static("https://wallet.example"))
+                    );
+                    assert_eq!(res.headers().get(VARY), Some(&HeaderValue::from_static("Origin")));
+                    let body = res.into_body().collect().await.unwrap().to_bytes();
+                    assert_eq!(body, Vec::from_hex(ENCAPSULATED_RES).unwrap());
+                }
+                let mut req = Request::new(full(Bytes::new()));
+                *req.method_mut() = hyper::Method::HEAD;
+                *req.uri_mut() = format!("http://127.0.0.1:{}/ohttp-keys", relay_port).parse().unwrap();
+                let res = send_direct(req).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let len = Vec::from_hex(ENCAPSULATED_RES).unwrap().len();
+                assert_eq!(res.headers()[CONTENT_LENGTH], len.to_string().as_str());
+                assert!(res.into_body().collect().await.unwrap().to_bytes().is_empty());
+                assert_eq!(count.load(Ordering::SeqCst), 1, "keys should be served from cache");
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ohttp_keys_long_poll() {
+        async fn rotating_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            let rotations = Arc::new(AtomicUsize::new(0));
+            example_gateway(port, move |stream| {
+                let rotations = rotations.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |_: Request<Incoming>| {
+                        let keys = rotations.fetch_add(1, Ordering::SeqCst).to_string();
+                        async move { Ok::<_, hyper::Error>(Response::new(full(keys))) }
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let keys = OhttpKeys { max_age: Duration::from_secs(1), ..OhttpKeys::default() };
+        let config = Config { ohttp_keys: Some(keys), ..insecure_gateway_config() };
+        let conditional = |etag: &HeaderValue, prefer: Option<&'static str>| {
+            let mut req = Request::new(full(Bytes::new()));
+            *req.uri_mut() = format!("http://127.0.0.1:{}/ohttp-keys", relay_port).parse().unwrap();
+            req.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+            if let Some(prefer) = prefer {
+                req.headers_mut().insert("prefer", HeaderValue::from_static(prefer));
+            }
+            send_direct(req)
+        };
+        tokio::select! {
+            _ = rotating_gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let res = get_direct(relay_port, "/ohttp-keys").await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let etag = res.headers()[ETAG].clone();
+
+                let res = conditional(&etag, None).await;
+                assert_eq!(res.status(), hyper::StatusCode::NOT_MODIFIED);
+                assert_eq!(res.headers()[ETAG], etag);
+
+                let started = std::time::Instant::now();
+                let res = conditional(&etag, Some("wait=10")).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                assert!(started.elapsed() < Duration::from_secs(5), "held past the rotation");
+                assert_ne!(res.headers()[ETAG], etag);
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                assert_eq!(body, "1");
+            } => {}
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port_acceptors() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let count = Arc::new(AtomicUsize::new(0));
+        let config = Config { acceptors: 4, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = counting_gateway(gateway_port, count.clone()) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                for _ in 0..8 {
+                    let res = ohttp_req_direct(relay_port).await;
+                    assert_eq!(res.status(), hyper::StatusCode::OK);
+                }
+            } => {
+                assert_eq!(count.load(Ordering::SeqCst), 8);
+            }
+        }
+    }
+
+    async fn get_direct(relay_port: u16, path: &str) -> Response<Incoming> {
+        let mut req = Request::new(full(Bytes::new()));
+        *req.uri_mut() = format!("http://127.0.0.1:{}{}", relay_port, path).parse().unwrap();
+        send_direct(req).await
+    }
+
+    fn fast_health_check() -> HealthCheck {
+        HealthCheck { path: "/".to_owned(), interval: Duration::from_secs(1), failure_threshold: 1 }
+    }
+
+    /// A gateway that counts the OHTTP requests it answers.
+    async fn counting_gateway(
+        port: u16,
+        count: Arc<AtomicUsize>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        example_gateway(port, move |stream| {
+            let count = count.clone();
+            tokio::spawn(async move {
+                let io = TokioIo::new(stream);
+                let service = service_fn(move |req: Request<Incoming>| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    handle_ohttp_req(req)
+                });
+                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                    println!("Failed to serve connection: {:?}", err);
+                }
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_custom_authorizer() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config =
+            Config { authorizer: Arc::new(DenyPath("/denied")), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let url = format!("http://0.0.0.0:{}/denied", relay_port);
+                let res = send_direct(ohttp_request(url)).await;
+                assert_eq!(res.status(), hyper::StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_required() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let authorizer = auth::StaticToken::any(["old", "new"]);
+        let config = Config { authorizer: Arc::new(authorizer), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::UNAUTHORIZED);
+                assert!(res.headers()[WWW_AUTHENTICATE].to_str().unwrap().starts_with("Bearer "));
+
+                let mut req = ohttp_request(format!("http://0.0.0.0:{}/", relay_port));
+                req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_static("Bearer new"));
+                let res = send_direct(req).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_privacy_pass_challenge() {
+        /// A Privacy Pass issuer's public token key.
+        const TOKEN_KEY: &str =
+            "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAuErjSiO9wUt3tnUyu9zEUAMaUw17Sczz6YI1fFOj\
+            Aoix8mAoMdwNLtsfzbIcNWBeTJphY4yCy2NF3Pzb7GDZCYqBI8EJVeH1SRJk3U-wATJxEhLGgQAMRV63o-4U\
+            VYRcz7c5FSQg135Lptl8FIXZXVm4Db0tdM7yERUSl5GwPaKcclV3RfOIKsXXibdFAoshzxosYfCUof0pksgf\
+            gcxRNErVdhkUM8kwKQHYDP601QkdYEHvBJSMatVvlUycZJwnnP-NTAXEUsF0yF0Bzdiv7oLTpfagtLFZEhuY\
+            fTvJ8kqkzUUMbWvfHfDrineN2hA1wyL6NNK7TW8APSuyjRsyzwIDAQAB";
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let authorizer = auth::PrivacyPass::new("issuer.example", TOKEN_KEY).unwrap();
+        let config = Config { authorizer: Arc::new(authorizer), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::UNAUTHORIZED);
+                let challenge = res.headers()[WWW_AUTHENTICATE].to_str().unwrap();
+                assert!(challenge.starts_with("PrivateToken challenge="), "{}", challenge);
+                assert!(challenge.contains(TOKEN_KEY), "{}", challenge);
+            } => {}
+        }
+    }
+
+    /// Denies requests to a single path with 451 Unavailable For Legal Reasons.
+    #[derive(Debug)]
+    struct DenyPath(&'static str);
+
+    impl Authorizer for DenyPath {
+        fn authorize<'a>(
+            &'a self,
+            req: &'a RequestMeta<'a>,
+        ) -> Pin<Box<dyn Future<Output = Authorization> + Send + 'a>> {
+            let denied = req.path == self.0;
+            assert!(req.peer_addr.is_some(), "TCP peers have a socket address");
+            Box::pin(async move {
+                if denied {
+                    Authorization::Deny(Some(hyper::StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS))
+                } else {
+                    Authorization::Allow
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_see_metadata_around_forwarding() {
+        /// Only answers requests carrying the header the hook adds.
+        async fn tagged_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+            example_gateway(port, |stream| {
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        assert_eq!(req.headers()["relay-tag"], "tagged");
+                        assert!(req.headers().get("quota").is_none(), "client headers dropped");
+                        handle_ohttp_req(req).await
+                    });
+                    let io = TokioIo::new(stream);
+                    if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            })
+            .await
+        }
+
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let relay = Builder::new(gateway).port(relay_port).config(insecure_gateway_config());
+        tokio::select! {
+            _ = tagged_gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = relay.hook(Arc::new(Quota)).serve() => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                assert_eq!(res.headers()["hooked"], "200");
+
+                let url = format!("http://0.0.0.0:{}/", relay_port);
+                let mut req = ohttp_request(url);
+                req.headers_mut().insert("quota", HeaderValue::from_static("exhausted"));
+                let res = send_direct(req).await;
+                assert_eq!(res.status(), hyper::StatusCode::TOO_MANY_REQUESTS);
+                assert!(res.headers().get("hooked").is_none());
+            } => {}
+        }
+    }
+
+    /// Refuses clients whose quota is exhausted and tags what it lets through.
+    #[derive(Debug)]
+    struct Quota;
+
+    impl RelayHook for Quota {
+        fn before_forward<'a>(
+            &'a self,
+            req: ForwardMeta<'a>,
+        ) -> Pin<Box<dyn Future<Output = Authorization> + Send + 'a>> {
+            let exhausted = req.client_headers.get("quota").is_some();
+            assert_eq!(req.uri.path(), "/");
+            req.headers.insert("relay-tag", HeaderValue::from_static("tagged"));
+            Box::pin(async move {
+                match exhausted {
+                    true => Authorization::Deny(Some(hyper::StatusCode::TOO_MANY_REQUESTS)),
+                    false => Authorization::Allow,
+                }
+            })
+        }
+
+        fn after_response<'a>(
+            &'a self,
+            res: ResponseMeta<'a>,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+            res.headers.insert("hooked", HeaderValue::from(res.status.as_u16()));
+            Box::pin(async {})
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway_body_never_compressed() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { compress_error_bodies: true, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let mut req = ohttp_request(format!("http://0.0.0.0:{}/", relay_port));
+                req.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+                send_direct(req).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                assert!(res.headers().get(CONTENT_ENCODING).is_none());
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                assert_eq!(body.to_vec(), Vec::from_hex(ENCAPSULATED_RES).unwrap());
+            }
+        }
+    }
+
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+    #[tokio::test]
+    async fn test_error_body_compressed() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { compress_error_bodies: true, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                // A plain GET is neither a health check nor a bootstrap upgrade.
+                let mut req = Request::new(full(Bytes::new()));
+                *req.uri_mut() = format!("http://0.0.0.0:{}/", relay_port).parse().unwrap();
+                req.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+                send_direct(req).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::BAD_REQUEST);
+                assert_eq!(
+                    res.headers().get(CONTENT_ENCODING),
+                    Some(&HeaderValue::from_static("gzip"))
+                );
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                let mut message = String::new();
+                GzDecoder::new(&body[..]).read_to_string(&mut message).unwrap();
+                assert_eq!(message, "Not a supported proxy upgrade request");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_problem_details() {
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", find_free_port())).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { problem_details: true, ..insecure_gateway_config() };
+        tokio::select! {
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let mut req = Request::new(full(Bytes::new()));
+                *req.method_mut() = hyper::Method::PUT;
+                *req.uri_mut() = format!("http://0.0.0.0:{}/", relay_port).parse().unwrap();
+                send_direct(req).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+                assert_eq!(res.headers()[CONTENT_TYPE], "application/problem+json");
+                assert!(res.headers().contains_key(hyper::header::ALLOW));
+                let request_id = res.headers()["request-id"].to_str().unwrap().to_owned();
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                assert_eq!(
+                    body,
+                    format!(
+                        r#"{{"type":"about:blank","title":"Method Not Allowed","status":405,"request_id":"{}"}}"#,
+                        request_id
+                    )
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_retried_until_port_free() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        // Hold the relay's port so its first bind attempt fails.
+        let squatter = std::net::TcpListener::bind(("0.0.0.0", relay_port)).unwrap();
+        let config =
+            Config { bind_retry: Some(Duration::from_secs(5)), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                drop(squatter);
+                ohttp_req_direct(relay_port).await
+            } => {
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_inflight_lists_slow_forward() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            admin_token: Some(auth::StaticToken::new("admin")),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = slow_gateway(gateway_port, Duration::from_secs(3)) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = ohttp_req_direct(relay_port) => {
+                panic!("The slow forward should still be in flight");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_millis(1500)).await;
+                let inflight_url = format!("http://127.0.0.1:{}/admin/inflight", relay_port);
+                let mut req = Request::new(full(Bytes::new()));
+                *req.uri_mut() = inflight_url.parse().unwrap();
+                let res = send_direct(req).await;
+                assert_eq!(res.status(), hyper::StatusCode::UNAUTHORIZED);
+
+                let mut req = Request::new(full(Bytes::new()));
+                *req.uri_mut() = inflight_url.parse().unwrap();
+                req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_static("Bearer admin"));
+                let res = send_direct(req).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                let snapshot = String::from_utf8(body.to_vec()).unwrap();
+                let target = format!(r#""target":"http://0.0.0.0:{}/""#, gateway_port);
+                assert!(snapshot.contains(&target), "unexpected snapshot: {}", snapshot);
+            } => {}
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics_served() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let metrics_port = find_free_port();
+        let config = Config {
+            admin_token: Some(auth::StaticToken::new("admin")),
+            metrics_addr: Some(SocketAddr::from(([127, 0, 0, 1], metrics_port))),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                res.into_body().collect().await.unwrap();
+
+                let metrics = |url: String, token: Option<&'static str>| async move {
+                    let mut req = Request::new(full(Bytes::new()));
+                    *req.uri_mut() = url.parse().unwrap();
+                    if let Some(token) = token {
+                        req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_static(token));
+                    }
+                    let res = send_direct(req).await;
+                    assert_eq!(res.status(), hyper::StatusCode::OK);
+                    let body = res.into_body().collect().await.unwrap().to_bytes();
+                    String::from_utf8(body.to_vec()).unwrap()
+                };
+                let text = metrics(format!("http://127.0.0.1:{}/metrics", metrics_port), None).await;
+                assert!(text.contains("ohttp_relay_requests_total 1\n"), "{}", text);
+                assert!(text.contains("ohttp_relay_responses_total{class=\"2xx\"} 1\n"), "{}", text);
+                assert!(text.contains("ohttp_relay_upstream_latency_seconds_count 1\n"), "{}", text);
+
+                let admin_url = format!("http://127.0.0.1:{}/admin/metrics", relay_port);
+                let text = metrics(admin_url, Some("Bearer admin")).await;
+                assert!(text.contains("ohttp_relay_active_connections 1\n"), "{}", text);
+                let requests = format!(
+                    "ohttp_relay_gateway_requests_total{{gateway=\"http://0.0.0.0:{}\"}} 1\n",
+                    gateway_port
+                );
+                assert!(text.contains(&requests), "{}", text);
+
+                let admin_url = format!("http://127.0.0.1:{}/admin/gateways", relay_port);
+                let summary = metrics(admin_url, Some("Bearer admin")).await;
+                let gateway = format!(
+                    r#"{{"gateway":"http://0.0.0.0:{}","requests":1,"server_errors":0,"timeouts":0,"circuit_open":false}}"#,
+                    gateway_port
+                );
+                assert!(summary.contains(&gateway), "{}", summary);
+
+                let stats = metrics(format!("http://127.0.0.1:{}/stats", metrics_port), None).await;
+                let gateway = format!(
+                    r#"{{"gateway":"http://0.0.0.0:{}","healthy":true,"circuit_open":false,"requests":1,"#,
+                    gateway_port
+                );
+                assert!(stats.contains(&gateway), "{}", stats);
+                // The relay's own listener serves it to admins only, since a reverse proxy in
+                // front of it makes every client look like a loopback one.
+                let admin_url = format!("http://127.0.0.1:{}/admin/stats", relay_port);
+                let stats = metrics(admin_url, Some("Bearer admin")).await;
+                assert!(stats.starts_with(r#"{"uptime_secs":"#), "{}", stats);
+                let mut req = Request::new(full(Bytes::new()));
+                *req.uri_mut() = format!("http://127.0.0.1:{}/stats", relay_port).parse().unwrap();
+                assert_ne!(send_direct(req).await.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_drains_inflight() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = insecure_gateway_config();
+        let shutdown = config.shutdown.clone();
+        let relay = tokio::spawn(listen_tcp_with_config(relay_port, gateway, config));
+        tokio::select! {
+            _ = slow_gateway(gateway_port, Duration::from_secs(2)) => {
+                panic!("Gateway is long running");
+            }
+            _ = async {
+                let res = tokio::join!(ohttp_req_direct(relay_port), async {
+                    // Shut down while the request waits on the slow gateway.
+                    tokio::time::sleep(Duration::from_millis(1500)).await;
+                    shutdown.cancel();
+                })
+                .0;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let relay = tokio::time::timeout(Duration::from_secs(5), relay)
+                    .await
+                    .expect("relay should stop after draining");
+                assert!(relay.unwrap().is_ok());
+                assert!(TcpStream::connect(("0.0.0.0", relay_port)).await.is_err());
+            } => {}
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sigterm_drains_inflight() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { signal_handling: true, ..insecure_gateway_config() };
+        let relay = tokio::spawn(listen_tcp_with_config(relay_port, gateway, config));
+        tokio::select! {
+            _ = slow_gateway(gateway_port, Duration::from_secs(2)) => {
+                panic!("Gateway is long running");
+            }
+            _ = async {
+                // The handlers are installed once the relay answers.
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                assert!(get_direct(relay_port, "/health").await.status().is_success());
+                let res = tokio::join!(ohttp_req_direct(relay_port), async {
+                    tokio::time::sleep(Duration::from_millis(1000)).await;
+                    let status = Command::new("kill")
+                        .args(["-TERM", &std::process::id().to_string()])
+                        .status()
+                        .await
+                        .unwrap();
+                    assert!(status.success());
+                })
+                .0;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let relay = tokio::time::timeout(Duration::from_secs(5), relay)
+                    .await
+                    .expect("relay should stop after draining");
+                assert!(relay.unwrap().is_ok());
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_connections_past_drain_deadline() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            shutdown_timeout: Some(Duration::from_millis(500)),
+            ..insecure_gateway_config()
+        };
+        let shutdown = config.shutdown.clone();
+        let relay = tokio::spawn(listen_tcp_with_config(relay_port, gateway, config));
+        tokio::select! {
+            _ = slow_gateway(gateway_port, Duration::from_secs(5)) => {
+                panic!("Gateway is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let client = Client::builder(TokioExecutor::new()).build_http();
+                let req = ohttp_request(format!("http://0.0.0.0:{}/", relay_port));
+                let res = tokio::join!(client.request(req), async {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    shutdown.cancel();
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    assert!(
+                        TcpStream::connect(("0.0.0.0", relay_port)).await.is_err(),
+                        "new connections refused while draining"
+                    );
+                })
+                .0;
+                assert!(res.is_err(), "connection closed at the drain deadline");
+                let relay = tokio::time::timeout(Duration::from_secs(2), relay)
+                    .await
+                    .expect("relay should stop at the drain deadline");
+                assert!(relay.unwrap().is_ok());
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_on_reload_closes_keep_alive() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { drain_on_reload: true, ..insecure_gateway_config() };
+        let reload = config.reload.clone();
+        tokio::select! {
+            _ = slow_gateway(gateway_port, Duration::from_secs(1)) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config.clone()) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = tokio::join!(ohttp_req_direct(relay_port), async {
+                    // Reload while the request waits on the slow gateway.
+                    tokio::time::sleep(Duration::from_millis(1500)).await;
+                    reload.reload(config);
+                })
+                .0;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                assert_eq!(res.headers().get(CONNECTION).unwrap(), "close");
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_gateway_times_out() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            response_timeout: Some(Duration::from_millis(500)),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = slow_gateway(gateway_port, Duration::from_secs(3)) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::GATEWAY_TIMEOUT);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inflight_limit() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config { max_inflight_requests: Some(1), ..insecure_gateway_config() };
+        tokio::select! {
+            _ = slow_gateway(gateway_port, Duration::from_secs(1)) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let (first, second) = tokio::join!(ohttp_req_direct(relay_port), async {
+                    // Sent while the first request waits on the slow gateway.
+                    tokio::time::sleep(Duration::from_millis(1500)).await;
+                    send_direct(ohttp_request(format!("http://0.0.0.0:{}/", relay_port))).await
+                });
+                assert_eq!(first.status(), hyper::StatusCode::OK);
+                assert_eq!(second.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(second.headers()[RETRY_AFTER], "1");
+                // The slot is free again once the first request is answered.
+                assert_eq!(ohttp_req_direct(relay_port).await.status(), hyper::StatusCode::OK);
+            } => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline_exceeded() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            request_deadline: Some(Duration::from_millis(500)),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            _ = slow_gateway(gateway_port, Duration::from_secs(3)) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => {
+                assert_eq!(res.status(), hyper::StatusCode::GATEWAY_TIMEOUT);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline_aborts_streaming() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let config = Config {
+            request_deadline: Some(Duration::from_millis(500)),
+            ..insecure_gateway_config()
+        };
+        tokio::select! {
+            // Answer at once but never finish the declared body.
+            _ = example_gateway(gateway_port, |mut stream| {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nContent-Type: message/ohttp-res\r\n\
+                              Content-Length: 100\r\n\r\n0123456789",
+                        )
+                        .await;
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                });
+            }) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, config) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                let res = ohttp_req_direct(relay_port).await;
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let body = tokio::time::timeout(Duration::from_secs(5), res.into_body().collect())
+                    .await
+                    .expect("relay should abort the response once the deadline passes");
+                assert!(body.is_err());
+            } => {}
+        }
+    }
+
+    /// A gateway that waits for `delay` before answering each OHTTP request.
+    async fn slow_gateway(port: u16, delay: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        example_gateway(port, move |stream| {
+            tokio::spawn(async move {
+                let io = TokioIo::new(stream);
+                let service = service_fn(move |req: Request<Incoming>| async move {
+                    tokio::time::sleep(delay).await;
+                    handle_ohttp_req(req).await
+                });
+                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                    println!("Failed to serve connection: {:?}", err);
+                }
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_gateway_connection_reused() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let gateway_connections = connections.clone();
+        tokio::select! {
+            _ = example_gateway(gateway_port, move |stream| {
+                gateway_connections.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let io = TokioIo::new(stream);
+                    if let Err(err) =
+                        http1::Builder::new().serve_connection(io, service_fn(handle_gateway)).await
+                    {
+                        println!("Failed to serve connection: {:?}", err);
+                    }
+                });
+            }) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                for _ in 0..3 {
+                    let res = ohttp_req_direct(relay_port).await;
+                    assert_eq!(res.status(), hyper::StatusCode::OK);
+                    // Drain the body so the gateway connection returns to the pool.
+                    res.into_body().collect().await.unwrap();
+                }
+            } => {}
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_http2_client_accepted() {
+        let gateway_port = find_free_port();
+        let gateway = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = example_gateway_http(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                panic!("Relay is long running");
+            }
+            _ = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let client = Client::builder(TokioExecutor::new()).http2_only(true).build_http();
+                let url = format!("http://0.0.0.0:{}/", relay_port);
+                let h2_request = |url: String| {
+                    let mut req = ohttp_request(url);
+                    // HTTP/2 frames the body itself and holds it to an exact Content-Length.
+                    req.headers_mut().remove(CONTENT_LENGTH);
+                    req
+                };
+                // Multiplex several OHTTP requests over one HTTP/2 connection.
+                let (a, b) = tokio::join!(
+                    client.request(h2_request(url.clone())),
+                    client.request(h2_request(url))
+                );
+                for res in [a.unwrap(), b.unwrap()] {
+                    assert_eq!(res.version(), hyper::Version::HTTP_2);
+                    assert_eq!(res.status(), hyper::StatusCode::OK);
+                }
+            } => {}
+        }
+    }
+
+    /// Run `gateway` on a free port behind a relay configured with `config`, then send an
+    /// OHTTP request directly to the relay.
+    async fn relay_direct<F, Fut>(gateway: F, config: Config) -> Response<Incoming>
+    where
+        F: FnOnce(u16) -> Fut,
+        Fut: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+    {
+        let gateway_port = find_free_port();
+        let gateway_origin = Uri::from_str(&format!("http://0.0.0.0:{}", gateway_port)).unwrap();
+        let relay_port = find_free_port();
+        tokio::select! {
+            _ = gateway(gateway_port) => {
+                panic!("Gateway is long running");
+            }
+            _ = listen_tcp_with_config(relay_port, gateway_origin, config) => {
+                panic!("Relay is long running");
+            }
+            res = ohttp_req_direct(relay_port) => res,
+        }
+    }
+
+    /// A gateway that redirects everything except `/moved` to `location`.
+    async fn redirecting_gateway(
+        port: u16,
+        location: &'static str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        example_gateway(port, move |stream| {
+            tokio::spawn(async move {
+                let io = TokioIo::new(stream);
+                let service = service_fn(move |req: Request<Incoming>| async move {
+                    if req.uri().path() == "/moved" {
+                        return handle_ohttp_req(req).await;
+                    }
+                    let mut res = Response::new(full(Bytes::new()));
+                    *res.status_mut() = hyper::StatusCode::FOUND;
+                    res.headers_mut().insert(LOCATION, HeaderValue::from_static(location));
+                    Ok(res)
+                });
+                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                    println!("Failed to serve connection: {:?}", err);
+                }
+            });
+        })
+        .await
+    }
+
+    /// A gateway answering every request with an HTML page, like a misconfigured web server.
+    async fn html_gateway(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        example_gateway(port, |stream| {
+            tokio::spawn(async move {
+                let io = TokioIo::new(stream);
+                let service = service_fn(|_: Request<Incoming>| async {
+                    let mut res = Response::new(full("<html></html>"));
+                    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+                    Ok::<_, hyper::Error>(res)
+                });
+                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                    println!("Failed to serve connection: {:?}", err);
+                }
+            });
+        })
+        .await
+    }
+
     async fn example_gateway_http(port: u16) -> Result<(), Box<dyn std::error::Error>> {
         example_gateway(port, |stream| {
             tokio::spawn(async move {
@@ -111,8 +3663,10 @@ mod integration {
     }
 
     async fn handle_ohttp_req(
-        _: Request<Incoming>,
+        req: Request<Incoming>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        // A real gateway must read the whole encapsulated request before it can respond.
+        req.into_body().collect().await?;
         let mut res = Response::new(full(Vec::from_hex(ENCAPSULATED_RES).unwrap()).boxed());
         *res.status_mut() = hyper::StatusCode::OK;
         res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-res"));
@@ -120,13 +3674,29 @@ mod integration {
         Ok(res)
     }
 
-    async fn ohttp_req(relay_port: u16, cert: CertificateDer<'static>) -> () {
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    fn ohttp_request(uri: String) -> Request<BoxBody<Bytes, hyper::Error>> {
         let mut req = Request::new(full(Vec::from_hex(ENCAPSULATED_REQ).unwrap()).boxed());
         *req.method_mut() = hyper::Method::POST;
-        *req.uri_mut() = format!("https://0.0.0.0:{}/", relay_port).parse().unwrap();
+        *req.uri_mut() = uri.parse().unwrap();
         req.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-req"));
         req.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from_static("78"));
+        req
+    }
+
+    /// Send an OHTTP request straight to the relay over plain HTTP, without nginx in front.
+    async fn ohttp_req_direct(relay_port: u16) -> Response<Incoming> {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        send_direct(ohttp_request(format!("http://0.0.0.0:{}/", relay_port))).await
+    }
+
+    async fn send_direct(req: Request<BoxBody<Bytes, hyper::Error>>) -> Response<Incoming> {
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        client.request(req).await.unwrap()
+    }
+
+    async fn ohttp_req(relay_port: u16, cert: CertificateDer<'static>) -> () {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let req = ohttp_request(format!("https://0.0.0.0:{}/", relay_port));
 
         let mut root_store = rustls::RootCertStore::empty();
         root_store.add(cert).unwrap();
@@ -168,6 +3738,41 @@ mod integration {
         }
     }
 
+    async fn example_gateway_https(
+        port: u16,
+        cert: Certificate,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let acceptor = Arc::new(build_tls_acceptor(cert));
+
+        example_gateway(port, move |stream| {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = acceptor.accept(stream).await.expect("TLS error");
+                let io = TokioIo::new(stream);
+                if let Err(err) =
+                    http1::Builder::new().serve_connection(io, service_fn(handle_gateway)).await
+                {
+                    println!("Failed to serve connection: {:?}", err);
+                }
+            });
+        })
+        .await
+    }
+
+    fn build_tls_acceptor(cert: Certificate) -> TlsAcceptor {
+        let (key, cert) = cert_to_key_cert_der(cert);
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        tokio_rustls::TlsAcceptor::from(Arc::new(server_config))
+    }
+
+    /// The default configuration, but allowing the plaintext and local gateways tests relay to.
+    fn insecure_gateway_config() -> Config {
+        Config { danger_allow_insecure_gateway: true, ..Config::default() }
+    }
+
     fn find_free_port() -> u16 {
         let listener = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
         listener.local_addr().unwrap().port()
@@ -175,15 +3780,10 @@ mod integration {
 
     #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
     mod bootstrap {
-        use std::future::Future;
         use std::io::Write;
-        use std::pin::Pin;
-        use std::sync::Arc;
 
         use rustls::pki_types::{self, CertificateDer};
-        use rustls::ServerConfig;
-        use tokio::io::{AsyncReadExt, AsyncWriteExt};
-        use tokio_rustls::{TlsAcceptor, TlsConnector};
+        use tokio_rustls::TlsConnector;
 
         use super::*;
 
@@ -191,7 +3791,9 @@ mod integration {
 
         #[cfg(feature = "ws-bootstrap")]
         mod ws_bootstrap {
+            use hyper::header::SEC_WEBSOCKET_PROTOCOL;
             use tokio_tungstenite::connect_async;
+            use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 
             use super::*;
 
@@ -203,6 +3805,183 @@ mod integration {
                 .await;
             }
 
+            #[tokio::test]
+            async fn test_wss_bootstrap() {
+                let gateway_port = find_free_port();
+                let gateway = Uri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+                let relay_port = find_free_port();
+                let gateway_cert = gen_localhost_cert();
+                let gateway_cert_der = cert_to_cert_der(&gateway_cert);
+                let relay_cert = gen_localhost_cert();
+                let relay_cert_der = cert_to_cert_der(&relay_cert);
+                let (key, cert) = cert_to_key_cert_der(relay_cert);
+                let mut tls_config = ServerConfig::builder()
+                    .with_no_client_auth()
+                    .with_single_cert(vec![cert], key)
+                    .unwrap();
+                tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+                tokio::select! {
+                    _ = example_gateway_https(gateway_port, gateway_cert) => {
+                        panic!("Gateway is long running");
+                    }
+                    _ = listen_tcp_tls_with_config(relay_port, gateway, Arc::new(tls_config), insecure_gateway_config()) => {
+                        panic!("Relay is long running");
+                    }
+                    _ = ohttp_keys_wss_client(relay_port, relay_cert_der, gateway_cert_der) => {}
+                }
+            }
+
+            #[tokio::test]
+            async fn test_ws_bootstrap_requires_subprotocol() {
+                let gateway =
+                    Uri::from_str(&format!("https://0.0.0.0:{}", find_free_port())).unwrap();
+                let relay_port = find_free_port();
+                tokio::select! {
+                    _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                        panic!("Relay is long running");
+                    }
+                    err = async {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        connect_async(format!("ws://0.0.0.0:{}", relay_port)).await.unwrap_err()
+                    } => {
+                        match err {
+                            tokio_tungstenite::tungstenite::Error::Http(res) =>
+                                assert_eq!(res.status(), hyper::StatusCode::BAD_REQUEST),
+                            err => panic!("Unexpected error: {}", err),
+                        }
+                    }
+                }
+            }
+
+            #[tokio::test]
+            async fn test_h2_extended_connect_bootstrap() {
+                use ohttp_relay::bootstrap::ws::WsIo;
+                use tokio_tungstenite::tungstenite::protocol::Role;
+                use tokio_tungstenite::WebSocketStream;
+
+                let gateway_port = find_free_port();
+                let gateway = Uri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+                let relay_port = find_free_port();
+                let gateway_cert = gen_localhost_cert();
+                let gateway_cert_der = cert_to_cert_der(&gateway_cert);
+                tokio::select! {
+                    _ = example_gateway_https(gateway_port, gateway_cert) => {
+                        panic!("Gateway is long running");
+                    }
+                    _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                        panic!("Relay is long running");
+                    }
+                    plaintext = async {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        let tcp = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                        let (mut sender, conn) = hyper::client::conn::http2::handshake(
+                            TokioExecutor::new(),
+                            TokioIo::new(tcp),
+                        )
+                        .await
+                        .unwrap();
+                        tokio::spawn(conn);
+                        // Wait for the relay's settings to allow extended CONNECT.
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        let mut req = Request::builder()
+                            .method(hyper::Method::CONNECT)
+                            .uri(format!("http://0.0.0.0:{}/", relay_port))
+                            .header(SEC_WEBSOCKET_PROTOCOL, "ohttp-bootstrap")
+                            .header("sec-websocket-version", "13")
+                            .body(http_body_util::Empty::<Bytes>::new())
+                            .unwrap();
+                        req.extensions_mut().insert(hyper::ext::Protocol::from_static("websocket"));
+                        let res = sender.send_request(req).await.unwrap();
+                        assert_eq!(res.status(), hyper::StatusCode::OK);
+                        assert_eq!(res.headers()[SEC_WEBSOCKET_PROTOCOL], "ohttp-bootstrap");
+                        let upgraded = TokioIo::new(hyper::upgrade::on(res).await.unwrap());
+                        let ws_stream =
+                            WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await;
+
+                        let mut gateway_roots = rustls::RootCertStore::empty();
+                        gateway_roots.add(gateway_cert_der).unwrap();
+                        let gateway_tls = tokio_rustls::rustls::ClientConfig::builder()
+                            .with_root_certificates(gateway_roots)
+                            .with_no_client_auth();
+                        let domain = pki_types::ServerName::try_from("0.0.0.0").unwrap().to_owned();
+                        let mut tls_stream = TlsConnector::from(Arc::new(gateway_tls))
+                            .connect(domain, WsIo::new(ws_stream))
+                            .await
+                            .unwrap();
+                        let content =
+                            b"GET /ohttp-keys HTTP/1.1\r\nHost: 0.0.0.0\r\nConnection: close\r\n\r\n";
+                        tls_stream.write_all(content).await.unwrap();
+                        tls_stream.flush().await.unwrap();
+                        let mut plaintext = Vec::new();
+                        let _ = tls_stream.read_to_end(&mut plaintext).await;
+                        String::from_utf8_lossy(&plaintext).into_owned()
+                    } => {
+                        assert!(plaintext.starts_with("HTTP/1.1 200 OK"), "{}", plaintext);
+                        assert!(plaintext.contains("application/ohttp-keys"), "{}", plaintext);
+                    }
+                }
+            }
+
+            /// A WebSocket upgrade to `uri` offering the bootstrap subprotocol.
+            fn bootstrap_request(
+                uri: String,
+            ) -> tokio_tungstenite::tungstenite::handshake::client::Request {
+                let mut req = uri.into_client_request().unwrap();
+                req.headers_mut()
+                    .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("ohttp-bootstrap"));
+                req
+            }
+
+            /// Fetch the gateway's keys through a WebSocket tunnel over the relay's own TLS, as
+            /// a browser in a secure context would.
+            async fn ohttp_keys_wss_client(
+                relay_port: u16,
+                relay_cert: CertificateDer<'_>,
+                gateway_cert: CertificateDer<'_>,
+            ) {
+                use ohttp_relay::bootstrap::ws::WsIo;
+
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+                let domain = pki_types::ServerName::try_from("0.0.0.0").unwrap().to_owned();
+                let mut relay_roots = rustls::RootCertStore::empty();
+                relay_roots.add(relay_cert).unwrap();
+                let mut relay_tls = tokio_rustls::rustls::ClientConfig::builder()
+                    .with_root_certificates(relay_roots)
+                    .with_no_client_auth();
+                relay_tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+                let tcp = tokio::net::TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                let relay_stream = TlsConnector::from(Arc::new(relay_tls))
+                    .connect(domain.clone(), tcp)
+                    .await
+                    .unwrap();
+                let (ws_stream, _res) = tokio_tungstenite::client_async(
+                    bootstrap_request(format!("wss://0.0.0.0:{}", relay_port)),
+                    relay_stream,
+                )
+                .await
+                .expect("Failed to upgrade");
+
+                let mut gateway_roots = rustls::RootCertStore::empty();
+                gateway_roots.add(gateway_cert).unwrap();
+                let gateway_tls = tokio_rustls::rustls::ClientConfig::builder()
+                    .with_root_certificates(gateway_roots)
+                    .with_no_client_auth();
+                let mut tls_stream = TlsConnector::from(Arc::new(gateway_tls))
+                    .connect(domain, WsIo::new(ws_stream))
+                    .await
+                    .unwrap();
+                let content =
+                    b"GET /ohttp-keys HTTP/1.1\r\nHost: 0.0.0.0\r\nConnection: close\r\n\r\n";
+                tls_stream.write_all(content).await.unwrap();
+                tls_stream.flush().await.unwrap();
+                let mut plaintext = Vec::new();
+                let _ = tls_stream.read_to_end(&mut plaintext).await;
+                let plaintext = String::from_utf8_lossy(&plaintext);
+                assert!(plaintext.starts_with("HTTP/1.1 200 OK"), "{}", plaintext);
+                assert!(plaintext.contains("application/ohttp-keys"), "{}", plaintext);
+            }
+
             async fn ohttp_keys_ws_client(relay_port: u16, cert: CertificateDer<'_>) {
                 use ohttp_relay::bootstrap::ws::WsIo;
 
@@ -214,9 +3993,10 @@ mod integration {
                     .with_root_certificates(root_store)
                     .with_no_client_auth();
 
-                let (ws_stream, _res) = connect_async(format!("ws://0.0.0.0:{}", relay_port))
-                    .await
-                    .expect("Failed to connect");
+                let (ws_stream, _res) =
+                    connect_async(bootstrap_request(format!("ws://0.0.0.0:{}", relay_port)))
+                        .await
+                        .expect("Failed to connect");
                 println!("Connected to ws");
                 let ws_io = WsIo::new(ws_stream);
                 let connector = TlsConnector::from(Arc::new(config));
@@ -250,6 +4030,31 @@ mod integration {
                 .await;
             }
 
+            #[tokio::test]
+            async fn test_connect_to_other_host_forbidden() {
+                let gateway_port = find_free_port();
+                let gateway = Uri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+                let relay_port = find_free_port();
+                tokio::select! {
+                    _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                        panic!("Relay is long running");
+                    }
+                    _ = async {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        for target in ["example.com:443", &format!("0.0.0.0:{}", gateway_port + 1)] {
+                            let mut stream =
+                                tokio::net::TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                            let connect =
+                                format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+                            stream.write_all(connect.as_bytes()).await.unwrap();
+                            let mut status_line = [0; 12];
+                            stream.read_exact(&mut status_line).await.unwrap();
+                            assert_eq!(&status_line, b"HTTP/1.1 403", "{}", target);
+                        }
+                    } => {}
+                }
+            }
+
             async fn ohttp_keys_connect_client(
                 relay_port: u16,
                 gateway_port: u16,
@@ -280,6 +4085,70 @@ mod integration {
             }
         }
 
+        #[cfg(feature = "connect-udp-bootstrap")]
+        mod connect_udp_bootstrap {
+            use tokio::net::UdpSocket;
+
+            use super::*;
+
+            #[tokio::test]
+            async fn test_connect_udp_bootstrap() {
+                let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let echo_port = echo.local_addr().unwrap().port();
+                let gateway = Uri::from_str(&format!("https://127.0.0.1:{}", echo_port)).unwrap();
+                let relay_port = find_free_port();
+                tokio::select! {
+                    _ = async {
+                        let mut buf = [0; 1500];
+                        loop {
+                            let (len, peer) = echo.recv_from(&mut buf).await.unwrap();
+                            echo.send_to(&buf[..len], peer).await.unwrap();
+                        }
+                    } => {}
+                    _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                        panic!("Relay is long running");
+                    }
+                    _ = async {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        let mut stream = connect_udp(relay_port, "example.com", 443).await;
+                        let mut status_line = [0; 12];
+                        stream.read_exact(&mut status_line).await.unwrap();
+                        assert_eq!(&status_line, b"HTTP/1.1 403");
+
+                        let mut stream = connect_udp(relay_port, "127.0.0.1", echo_port).await;
+                        let head = read_head(&mut stream).await;
+                        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+                        assert!(head.to_lowercase().contains("capsule-protocol: ?1"), "{}", head);
+                        // A datagram capsule with context ID 0.
+                        let capsule = [&[0x00, 0x0d, 0x00][..], b"key request!"].concat();
+                        stream.write_all(&capsule).await.unwrap();
+                        let mut echoed = vec![0; capsule.len()];
+                        stream.read_exact(&mut echoed).await.unwrap();
+                        assert_eq!(echoed, capsule);
+                    } => {}
+                }
+            }
+
+            async fn connect_udp(relay_port: u16, host: &str, port: u16) -> TcpStream {
+                let mut stream = TcpStream::connect(("0.0.0.0", relay_port)).await.unwrap();
+                let req = format!(
+                    "GET /.well-known/masque/udp/{}/{}/ HTTP/1.1\r\nHost: 0.0.0.0\r\n\
+                     Connection: Upgrade\r\nUpgrade: connect-udp\r\nCapsule-Protocol: ?1\r\n\r\n",
+                    host, port
+                );
+                stream.write_all(req.as_bytes()).await.unwrap();
+                stream
+            }
+
+            async fn read_head(stream: &mut TcpStream) -> String {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                String::from_utf8(head).unwrap()
+            }
+        }
+
         async fn test_bootstrap<F>(client_fn: F)
         where
             F: FnOnce(u16, u16, CertificateDer<'static>) -> Pin<Box<dyn Future<Output = ()>>>,
@@ -301,36 +4170,15 @@ mod integration {
             .await;
             tokio::select! {
                 _ = example_gateway_https(gateway_port, gateway_cert) => {
-                    assert!(false, "Gateway is long running");
+                    panic!("Gateway is long running");
                 }
-                _ = listen_tcp(relay_port, gateway) => {
-                    assert!(false, "Relay is long running");
+                _ = listen_tcp_with_config(relay_port, gateway, insecure_gateway_config()) => {
+                    panic!("Relay is long running");
                 }
                 _ = client_fn(n_http_port, gateway_port, gateway_cert_der) => {}
             }
         }
 
-        async fn example_gateway_https(
-            port: u16,
-            cert: Certificate,
-        ) -> Result<(), Box<dyn std::error::Error>> {
-            let acceptor = Arc::new(build_tls_acceptor(cert));
-
-            example_gateway(port, move |stream| {
-                let acceptor = acceptor.clone();
-                tokio::spawn(async move {
-                    let stream = acceptor.accept(stream).await.expect("TLS error");
-                    let io = TokioIo::new(stream);
-                    if let Err(err) =
-                        http1::Builder::new().serve_connection(io, service_fn(handle_gateway)).await
-                    {
-                        println!("Failed to serve connection: {:?}", err);
-                    }
-                });
-            })
-            .await
-        }
-
         pub(crate) async fn handle_ohttp_keys(
             _: Request<Incoming>,
         ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
@@ -341,15 +4189,6 @@ mod integration {
             res.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from_static("45"));
             Ok(res)
         }
-
-        fn build_tls_acceptor(cert: Certificate) -> TlsAcceptor {
-            let (key, cert) = cert_to_key_cert_der(cert);
-            let server_config = ServerConfig::builder()
-                .with_no_client_auth()
-                .with_single_cert(vec![cert], key)
-                .unwrap();
-            tokio_rustls::TlsAcceptor::from(Arc::new(server_config))
-        }
     }
 
     fn gen_localhost_cert() -> Certificate {
@@ -376,7 +4215,7 @@ mod integration {
     impl Drop for NginxProcess {
         fn drop(&mut self) {
             // NGINX spawns child processes. Gracefully shut them all down.
-            let _ = Command::new("nginx")
+            let _ = std::process::Command::new("nginx")
                 .arg("-s")
                 .arg("stop")
                 .arg("-c")